@@ -3,16 +3,28 @@
 //! [dependencies]
 //! anyhow = "1.0"
 //! clap = { version = "4.5", features = ["derive"] }
+//! notify-rust = "4"
 //! serde = { version = "1.0", features = ["derive"] }
 //! serde_json = "1.0"
+//! toml = "1.1"
+//!
+//! [dev-dependencies]
+//! proptest = "1.0"
+//!
+//! [features]
+//! bench = []
 //! ```
 
 use anyhow::{bail, Context};
 use clap::Parser;
-use std::collections::HashSet;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum FileType {
@@ -20,14 +32,168 @@ enum FileType {
     CargoToml,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+    /// Reviewdog Diagnostic Format
+    Rdjson,
+    /// TeamCity service messages
+    Teamcity,
+}
+
+/// Which stages `--phase` runs, so cheap and expensive checks can be wired
+/// into different hooks.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Phase {
+    /// Grouping, `Cargo.toml` organizing and formatting the changed files
+    Quick,
+    /// Clippy, the lockfile check and Miri
+    Full,
+}
+
+impl Phase {
+    fn stages(self) -> &'static [config::Stage] {
+        match self {
+            Phase::Quick => &[
+                config::Stage::Group,
+                config::Stage::Toml,
+                config::Stage::Fmt,
+            ],
+            Phase::Full => &[
+                config::Stage::Clippy,
+                config::Stage::Lockfile,
+                config::Stage::Miri,
+            ],
+        }
+    }
+}
+
+/// What `--emit` writes instead of (or besides) the summary.
+#[derive(Debug, Clone, PartialEq)]
+enum Emit {
+    /// Paths of the files that need changes, on stdout
+    ChangedFiles,
+    /// All changes as one diff for `git apply`, written to the given file
+    Patch(PathBuf),
+    /// Each change as a rustc-style JSON diagnostic with a suggestion, on
+    /// stdout
+    SuggestionsJson,
+}
+
+fn parse_lines(arg: &str) -> Result<(PathBuf, rustfmt::LineRange), String> {
+    let parsed = arg.rsplit_once(':').and_then(|(file, range)| {
+        let (start, end) = range.split_once('-')?;
+        let range = rustfmt::LineRange {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+        };
+        (!file.is_empty() && range.start >= 1 && range.start <= range.end)
+            .then(|| (PathBuf::from(file), range))
+    });
+    parsed.ok_or_else(|| format!("expected `FILE:START-END` with 1 <= START <= END, got `{arg}`"))
+}
+
+fn parse_emit(arg: &str) -> Result<Emit, String> {
+    match arg.split_once('=') {
+        None if arg == "changed-files" => Ok(Emit::ChangedFiles),
+        None if arg == "suggestions-json" => Ok(Emit::SuggestionsJson),
+        Some(("patch", path)) if !path.is_empty() => Ok(Emit::Patch(PathBuf::from(path))),
+        _ => Err(format!(
+            "expected `changed-files`, `suggestions-json` or `patch=<FILE>`, got `{arg}`"
+        )),
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// Apply the edits recorded by a `--check --output json` run
+    Apply {
+        /// JSON report to read the edits from
+        #[arg(long, value_name = "REPORT")]
+        from: PathBuf,
+    },
+    /// Serve declaration grouping and Cargo.toml organizing to editors as a
+    /// language server over stdio
+    Lsp,
+    /// Keep running and answer JSON-RPC requests (`polishFile`, `checkFile`,
+    /// `affectedPackages`) on a unix socket
+    Serve {
+        #[arg(long, value_name = "PATH")]
+        socket: PathBuf,
+    },
+    /// Measure grouping and Cargo.toml organizing over a corpus of files,
+    /// failing if polishing a file twice changes it again
+    Bench {
+        #[arg(long, value_name = "DIR")]
+        corpus: PathBuf,
+        /// Times each file is polished
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+    },
+    /// Group randomly generated headers, failing if grouping rejects one,
+    /// isn't idempotent on it or loses or duplicates a line
+    Fuzz {
+        /// Number of headers to generate
+        #[arg(long, default_value_t = 1000)]
+        cases: usize,
+        /// Seed of the first header, to reproduce a failure (default: the
+        /// current time)
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Check golden files: every `*.input.rs` / `*.input.toml` under DIR,
+    /// polished, must equal its `*.expected.*` counterpart
+    TestFixtures {
+        dir: PathBuf,
+        /// Write the actual output to the expected files instead
+        #[arg(long)]
+        bless: bool,
+    },
+    /// Check that every file `cargo package` would publish for a package is
+    /// grouped and formatted, leaving the files as they are
+    VerifyPackage {
+        /// Workspace member to package
+        #[arg(short, long)]
+        package: String,
+    },
+    /// Inspect polish.toml
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCommand {
+    /// Validate polish.toml, reporting unknown keys, and print the effective
+    /// configuration after POLISH_SKIP and command-line flags are applied
+    Check,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "polish-rs")]
 #[command(about = "Format and lint Rust code in git repository", long_about = None)]
+#[command(args_override_self = true)]
 struct Cli {
+    #[command(subcommand)]
+    action: Option<Action>,
+
+    /// Apply the `[profile.NAME]` section of polish.toml: its settings
+    /// override the rest of the file, and its `args` come before the ones
+    /// given
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
     /// Skip grouping declarations
     #[arg(long)]
     no_grouping: bool,
 
+    /// Skip grouping each file a second time to check that the result
+    /// doesn't change again
+    #[arg(long)]
+    no_idempotency_check: bool,
+
     /// Skip running cargo fmt
     #[arg(long)]
     no_fmt: bool,
@@ -36,14 +202,980 @@ struct Cli {
     #[arg(long)]
     no_clippy: bool,
 
+    /// Only organize changed `Cargo.toml` files, leaving Rust files alone
+    #[arg(long, conflicts_with_all = ["rust_only", "lines"])]
+    toml_only: bool,
+
+    /// Only group, format and lint changed Rust files, leaving `Cargo.toml`
+    /// files alone
+    #[arg(long)]
+    rust_only: bool,
+
     /// Process specific files instead of using git to detect changes
     #[arg(long, num_args = 1..)]
     files: Vec<PathBuf>,
+
+    /// Only group and format the given lines of a file, e.g.
+    /// `src/lib.rs:10-20`, instead of using git to detect changes; may be
+    /// repeated. Formatting line ranges needs a nightly rustfmt
+    #[arg(
+        long,
+        value_name = "FILE:START-END",
+        value_parser = parse_lines,
+        num_args = 1..,
+        conflicts_with_all = ["files", "staged"]
+    )]
+    lines: Vec<(PathBuf, rustfmt::LineRange)>,
+
+    /// Only process changed files you own according to CODEOWNERS (or the
+    /// `[owners]` rules in polish.toml)
+    #[arg(long)]
+    owned_only: bool,
+
+    /// Polish the staged content of the files with staged changes, leaving
+    /// unstaged changes in the working tree alone
+    #[arg(
+        long,
+        conflicts_with_all = ["files", "check", "emit", "fix", "commit_fixes", "amend"]
+    )]
+    staged: bool,
+
+    /// Set unstaged changes aside while polishing and put them back after
+    #[arg(long, conflicts_with_all = ["staged", "check", "emit"])]
+    autostash: bool,
+
+    /// Fail instead of warning when files polish may change have unstaged
+    /// edits
+    #[arg(long)]
+    require_clean: bool,
+
+    /// Print a timing breakdown (slowest stages, files and packages)
+    #[arg(long)]
+    timings: bool,
+
+    /// Format of the final run summary printed to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Show clippy diagnostics for all files, not just the changed ones
+    #[arg(long)]
+    show_all_lints: bool,
+
+    /// Format only the changed lines instead of whole packages
+    #[arg(long)]
+    changed_lines: bool,
+
+    /// Detect changes against REV instead of HEAD~1
+    #[arg(long, value_name = "REV")]
+    since: Option<String>,
+
+    /// Detect changes against the most recent tag matching PATTERN (`v*`
+    /// by default), e.g. everything since the last release
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        num_args = 0..=1,
+        default_missing_value = "v*",
+        conflicts_with = "since"
+    )]
+    since_tag: Option<String>,
+
+    /// Statuses of the changes to detect, passed to `git diff
+    /// --diff-filter`; `d` (everything but deletions) by default.
+    /// `--include-deleted` adds deletions to it
+    #[arg(long, value_name = "FILTER", conflicts_with_all = ["files", "lines", "staged"])]
+    diff_filter: Option<String>,
+
+    /// Also count the packages of deleted Rust files as affected, since
+    /// deleting a module can break its package
+    #[arg(long, conflicts_with_all = ["files", "lines", "staged"])]
+    include_deleted: bool,
+
+    /// In a shallow clone missing the diff base, fetch N more commits of
+    /// history at a time until it is found
+    #[arg(long, value_name = "N")]
+    deepen: Option<usize>,
+
+    /// CI preset: check mode, JSON summary, GitHub annotations on stderr, no
+    /// colors, `--locked`, sorted reports, and changes since the merge base
+    /// with origin's default branch
+    #[arg(
+        long,
+        conflicts_with_all = ["fix", "commit_fixes", "amend", "autostash", "staged"]
+    )]
+    ci: bool,
+
+    /// Fail if any list in the report isn't in its sorted order, to catch
+    /// output that varies between runs or platforms
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Run rustfmt on just the changed files instead of `cargo fmt -p`
+    #[arg(long)]
+    fast_fmt: bool,
+
+    /// Run only the cheap stages (`quick`: grouping, Cargo.toml and
+    /// formatting the changed files) or only the expensive ones (`full`:
+    /// clippy and the lockfile check)
+    #[arg(long, value_enum)]
+    phase: Option<Phase>,
+
+    /// Also format the Rust examples in doc comments of the changed files
+    #[arg(long)]
+    fmt_doc_examples: bool,
+
+    /// Group only the file's own header, leaving inline `mod { ... }` bodies as written
+    #[arg(long)]
+    group_top_level_only: bool,
+
+    /// Apply automatic fixes for reported problems (e.g. module file layout)
+    #[arg(long)]
+    fix: bool,
+
+    /// Install the rustfmt or clippy rustup component when it's missing
+    #[arg(long)]
+    install_components: bool,
+
+    /// Update `Cargo.lock` when it is out of date with the changed manifests
+    /// (runs the lockfile stage even if `polish.toml` doesn't list it)
+    #[arg(long, conflicts_with_all = ["check", "emit", "ci", "staged"])]
+    fix_lockfile: bool,
+
+    /// Sort `#[derive(...)]` lists on the changed lines
+    #[arg(long)]
+    sort_derives: bool,
+
+    /// Rewrap doc comment paragraphs in the changed files that exceed WIDTH
+    #[arg(long, value_name = "WIDTH")]
+    wrap_doc_comments: Option<usize>,
+
+    /// Override a rustfmt option, e.g. `max_width=120` (repeatable)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = rustfmt::parse_override)]
+    rustfmt_config: Vec<(String, String)>,
+
+    /// Report the files the run would change, leaving them untouched
+    #[arg(long, conflicts_with = "fix")]
+    check: bool,
+
+    /// Report the changes without making them (implies --check):
+    /// `changed-files` prints their paths instead of the summary,
+    /// `suggestions-json` prints them as rustc-style JSON suggestions,
+    /// `patch=FILE` writes them to FILE as a diff for `git apply`
+    #[arg(long, value_name = "WHAT", value_parser = parse_emit, conflicts_with = "fix")]
+    emit: Option<Emit>,
+
+    /// Separate `--emit changed-files` entries with NUL instead of newline
+    #[arg(short = '0', requires = "emit")]
+    null: bool,
+
+    /// Commit the files polish changed, and only those, with MESSAGE
+    #[arg(long, value_name = "MESSAGE", conflicts_with_all = ["check", "emit"])]
+    commit_fixes: Option<String>,
+
+    /// Push the commit made by --commit-fixes
+    #[arg(long, requires = "commit_fixes")]
+    push: bool,
+
+    /// Fold the fixes into HEAD with `git commit --amend --no-edit` after a
+    /// successful run; the working tree must be clean beforehand
+    #[arg(long, conflicts_with_all = ["check", "emit", "commit_fixes"])]
+    amend: bool,
+
+    /// With --amend, allow amending a commit that was already pushed
+    #[arg(long, requires = "amend")]
+    force: bool,
+
+    /// Print the commands the fmt and clippy stages would run, without
+    /// running them or changing any file
+    #[arg(long)]
+    print_commands: bool,
+
+    /// Print which package each changed file resolved to and why, without
+    /// running any stage
+    #[arg(long)]
+    explain_affected: bool,
+
+    /// Stop at the first failing stage instead of running the rest
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Run cargo check before clippy and skip clippy if compilation fails
+    #[arg(long)]
+    check_first: bool,
+
+    /// Run cargo without accessing the network
+    #[arg(long)]
+    offline: bool,
+
+    /// Fail instead of updating Cargo.lock
+    #[arg(long)]
+    locked: bool,
+
+    /// Also run the lint libraries the workspace lists for dylint, with
+    /// `cargo dylint --all`, and report their findings with clippy's
+    #[arg(long)]
+    dylint: bool,
+
+    /// Run the tests of affected members whose changed files use `unsafe`
+    /// under Miri, on nightly
+    #[arg(long)]
+    miri: bool,
+
+    /// Run a separate clippy process per affected member, in parallel
+    #[arg(long)]
+    clippy_parallel: bool,
+
+    /// Maximum number of parallel clippy processes (defaults to the CPU count)
+    #[arg(long, short = 'j', value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Stop clippy after SECS seconds, skip the stage and exit with code 124
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Always run clippy, even for members unchanged since their last clean run
+    #[arg(long)]
+    no_clippy_cache: bool,
+
+    /// Only move the dependencies that changed since the base, leaving every
+    /// other Cargo.toml entry as it is, to keep out of the way of
+    /// dependency-bump bots
+    #[arg(long)]
+    bot_friendly: bool,
+
+    /// Stream cargo's output as is instead of prefixing each line with the
+    /// stage and package it comes from
+    #[arg(long)]
+    no_capture: bool,
+
+    /// Write progress events as JSON lines to file descriptor FD, or to the
+    /// Unix socket at PATH
+    #[arg(long, value_name = "FD|PATH")]
+    progress_fd: Option<String>,
+
+    /// Run even if nothing changed since the last run, instead of replaying
+    /// its result
+    #[arg(long)]
+    no_results_cache: bool,
+
+    /// Write machine-readable run metrics (files scanned and modified,
+    /// packages linted, cache hits, durations) as JSON to PATH
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Allow up to N clippy warnings instead of failing on the first one
+    #[arg(long, value_name = "N")]
+    max_warnings: Option<usize>,
+
+    /// Send a desktop notification when a run that took at least SECONDS
+    /// (default 30) finishes
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "30",
+        require_equals = true
+    )]
+    notify: Option<u64>,
+}
+
+impl Cli {
+    /// The `--lines` ranges given for `file`, if the run is limited to them.
+    fn requested_lines(&self, file: &Path) -> Option<Vec<rustfmt::LineRange>> {
+        (!self.lines.is_empty()).then(|| {
+            self.lines
+                .iter()
+                .filter(|(path, _)| path == file)
+                .map(|(_, range)| *range)
+                .collect()
+        })
+    }
+
+    /// The `--diff-filter` for detecting changes. `--include-deleted` needs
+    /// the deletions, so they are selected whatever `--diff-filter` says:
+    /// an excluding `d` is dropped, and `D` joins selecting letters.
+    fn diff_filter(&self) -> Option<String> {
+        let Some(filter) = &self.diff_filter else {
+            return (!self.include_deleted).then(|| "d".to_string());
+        };
+        if !self.include_deleted {
+            return Some(filter.clone());
+        }
+        let mut filter: String = filter.chars().filter(|c| *c != 'd').collect();
+        if filter.chars().any(|c| c.is_ascii_uppercase()) && !filter.contains('D') {
+            filter.push('D');
+        }
+        (!filter.is_empty()).then_some(filter)
+    }
+
+    /// Whether rustfmt runs on just the changed files, as `--phase quick`
+    /// does too.
+    fn fast_fmt(&self) -> bool {
+        self.fast_fmt || self.phase == Some(Phase::Quick)
+    }
+
+    /// Whether the run reports changes instead of leaving them in place.
+    fn check_mode(&self) -> bool {
+        self.check || self.emit.is_some() || self.ci
+    }
+
+    /// Whether the outcome only depends on the repository state and the
+    /// options, so an identical repeated run can replay it. Runs that commit,
+    /// or that print something other than the verdict, can't; neither can
+    /// `--ci` runs, whose annotations go to stderr and whose base depends on
+    /// the remote.
+    fn cacheable(&self) -> bool {
+        !self.no_results_cache
+            && !self.ci
+            && !self.print_commands
+            && !self.explain_affected
+            && !self.timings
+            && self.commit_fixes.is_none()
+            && !self.amend
+            && !matches!(self.emit, Some(Emit::Patch(_)))
+    }
+
+    fn output_format(&self) -> OutputFormat {
+        if self.ci {
+            OutputFormat::Json
+        } else {
+            self.output
+        }
+    }
+}
+
+/// Exit code of a run whose clippy stage exceeded `--timeout`, the same as
+/// coreutils' `timeout`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Outcome of a run, printed at the end (as JSON with `--output json`).
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    success: bool,
+    error: Option<String>,
+    files: Vec<PathBuf>,
+    /// Changed files detected as generated, left untouched by grouping and
+    /// changed-lines formatting.
+    generated: Vec<PathBuf>,
+    /// Problems found by polish's own checks that weren't fixed.
+    findings: Vec<Finding>,
+    /// Problems reported without failing the run.
+    warnings: Vec<Finding>,
+    /// With `--check`, the changes the run would have made.
+    edits: Vec<snapshot::FileEdit>,
+    /// With `--emit suggestions-json` or `--output rdjson`, the edits split
+    /// into suggestions.
+    #[serde(skip)]
+    suggestions: Vec<suggestions::Diagnostic>,
+    /// Files the run changed, or with `--check` would have changed.
+    modified: Vec<PathBuf>,
+    /// Hash of the commit made by `--commit-fixes`.
+    commit: Option<String>,
+    members: Vec<String>,
+    /// With `--print-commands`, the commands the run would have executed.
+    commands: Vec<String>,
+    /// With `--explain-affected`, how each changed Rust file was resolved.
+    affected: Vec<AffectedFile>,
+    /// The toolchain cargo ran with for each affected member.
+    toolchains: Vec<PackageToolchain>,
+    /// Stages that were stopped before finishing, with the reason.
+    skipped: BTreeMap<String, String>,
+    clippy: Option<ClippyReport>,
+    /// Undefined Behavior found by the miri stage.
+    miri: Vec<miri::Finding>,
+    timings: timings::Timings,
+}
+
+impl Summary {
+    /// Fails naming the reported lists that aren't in their sorted order.
+    fn ensure_sorted(&self) -> anyhow::Result<()> {
+        let mut unsorted = Vec::new();
+        let mut check = |name: &'static str, sorted: bool| {
+            if !sorted {
+                unsorted.push(name);
+            }
+        };
+        check("files", self.files.is_sorted());
+        check("generated", self.generated.is_sorted());
+        check("members", self.members.is_sorted());
+        check(
+            "findings",
+            self.findings.is_sorted_by_key(Finding::sort_key),
+        );
+        check(
+            "warnings",
+            self.warnings.is_sorted_by_key(Finding::sort_key),
+        );
+        check("edits", self.edits.is_sorted_by_key(|e| &e.file));
+        check("modified", self.modified.is_sorted());
+        check("affected", self.affected.is_sorted_by_key(|a| &a.file));
+        check(
+            "toolchains",
+            self.toolchains.is_sorted_by_key(|t| &t.package),
+        );
+        if let Some(clippy) = &self.clippy {
+            check(
+                "clippy.diagnostics",
+                clippy.diagnostics.is_sorted_by_key(|d| (&d.file, d.line)),
+            );
+        }
+        if !unsorted.is_empty() {
+            bail!("Report lists out of order: {}", unsorted.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// A problem found by one of polish's checks, shown as `file:line: message`.
+#[derive(Debug, Serialize)]
+struct Finding {
+    file: PathBuf,
+    line: usize,
+    message: String,
+}
+
+impl Finding {
+    fn sort_key(&self) -> (&Path, usize, &str) {
+        (&self.file, self.line, &self.message)
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+    }
+}
+
+/// A changed file, the package it resolved to and the reasoning, for
+/// `--explain-affected`.
+#[derive(Debug, Serialize)]
+struct AffectedFile {
+    file: PathBuf,
+    package: Option<String>,
+    manifest: Option<PathBuf>,
+    reasons: Vec<String>,
+}
+
+/// The toolchain fmt and clippy ran with for a package. cargo runs from the
+/// repository root, so a `rust-toolchain.toml` in the package's own
+/// directory doesn't apply; `pinned` is the toolchain it asks for then.
+#[derive(Debug, Serialize)]
+struct PackageToolchain {
+    package: String,
+    toolchain: String,
+    pinned: Option<String>,
+}
+
+/// What was reported from a clippy run after filtering and deduplication.
+#[derive(Debug, Default, Serialize)]
+struct ClippyReport {
+    shown: usize,
+    hidden: usize,
+    duplicates: usize,
+    /// Number of shown diagnostics per lint name.
+    lints: BTreeMap<String, usize>,
+    budget: Option<clippy_diagnostics::BudgetReport>,
+    /// Members skipped because their last clean run is still valid.
+    cached: Vec<String>,
+    /// Location of every shown diagnostic, relative to the git root.
+    diagnostics: Vec<ClippyLocation>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClippyLocation {
+    file: PathBuf,
+    line: usize,
+    level: String,
+    lint: String,
+    message: String,
+}
+
+/// Settings applied to every cargo invocation.
+#[derive(Debug, Clone, Default)]
+struct CargoOptions {
+    /// Passed as `CARGO_TARGET_DIR`, keeping polish builds apart from the
+    /// IDE's so neither invalidates the other's cache.
+    target_dir: Option<PathBuf>,
+    offline: bool,
+    locked: bool,
+    /// Prefix the stderr of cargo runs with their stage and package.
+    capture: bool,
+}
+
+impl CargoOptions {
+    fn new(git_root: &Path, cli: &Cli, config: &config::CargoConfig) -> Self {
+        CargoOptions {
+            target_dir: config
+                .target_dir
+                .as_ref()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(|dir| git_root.join(dir)),
+            offline: cli.offline,
+            locked: cli.locked || cli.ci,
+            capture: !cli.no_capture,
+        }
+    }
+
+    fn command(&self, subcommand: &str) -> Command {
+        let mut cmd = Command::new("cargo");
+        cmd.arg(subcommand);
+        if let Some(target_dir) = &self.target_dir {
+            cmd.env("CARGO_TARGET_DIR", target_dir);
+        }
+        // cargo-fmt, cargo-dylint and cargo-miri are external subcommands
+        // that reject cargo's global flags, but still honour the environment
+        // for offline mode
+        if self.offline {
+            cmd.env("CARGO_NET_OFFLINE", "true");
+        }
+        if !matches!(subcommand, "fmt" | "dylint" | "miri") {
+            if self.offline {
+                cmd.arg("--offline");
+            }
+            if self.locked {
+                cmd.arg("--locked");
+            }
+        }
+        cmd
+    }
+
+    /// What prefixes the output of a `stage` run for `members` when it's
+    /// captured: `fmt:a` for a single member, just the stage otherwise.
+    fn label(&self, stage: &str, members: &BTreeSet<String>) -> Option<String> {
+        if !self.capture {
+            return None;
+        }
+        Some(match members.first().filter(|_| members.len() == 1) {
+            Some(member) => format!("{stage}:{member}"),
+            None => stage.to_string(),
+        })
+    }
+}
+
+/// Spawns `cmd`, with its stderr printed line by line as `[label] line` by
+/// a thread that finishes once the process and its children are gone.
+fn spawn_labeled(
+    cmd: &mut Command,
+    label: Option<String>,
+) -> anyhow::Result<(std::process::Child, Option<std::thread::JoinHandle<()>>)> {
+    if label.is_some() {
+        cmd.stderr(Stdio::piped());
+    }
+    let mut child = interrupt::spawn(cmd).with_context(|| format!("Failed to run {cmd:?}"))?;
+    let forward = label.zip(child.stderr.take()).map(|(label, stderr)| {
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("[{label}] {line}");
+            }
+        })
+    });
+    Ok((child, forward))
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let cli = with_profile_args(Cli::parse())?;
+    match &cli.action {
+        Some(Action::Apply { from }) => return apply_report(from),
+        Some(Action::Lsp) => return lsp::serve(),
+        Some(Action::Serve { socket }) => return serve::serve(&cli, socket),
+        Some(Action::Bench { corpus, iterations }) => {
+            let report = bench::run(corpus, *iterations as usize)?;
+            match cli.output_format() {
+                OutputFormat::Text | OutputFormat::Teamcity => print!("{}", report.render()),
+                OutputFormat::Json | OutputFormat::Rdjson => {
+                    println!("{}", serde_json::to_string_pretty(&report)?)
+                }
+            }
+            if !report.is_idempotent() {
+                bail!("Polishing is not idempotent on every file");
+            }
+            return Ok(());
+        }
+        Some(Action::Fuzz { cases, seed }) => {
+            let seed = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64)
+            });
+            eprintln!("Fuzzing grouping with seed {seed}");
+            let report = fuzz::run(seed, *cases);
+            match cli.output_format() {
+                OutputFormat::Text | OutputFormat::Teamcity => print!("{}", report.render()),
+                OutputFormat::Json | OutputFormat::Rdjson => {
+                    println!("{}", serde_json::to_string_pretty(&report)?)
+                }
+            }
+            if !report.failures.is_empty() {
+                bail!(
+                    "Grouping broke a property on {} header(s)",
+                    report.failures.len()
+                );
+            }
+            return Ok(());
+        }
+        Some(Action::TestFixtures { dir, bless }) => return test_fixtures(dir, *bless),
+        Some(Action::VerifyPackage { package }) => return verify_package(&cli, package),
+        Some(Action::Config {
+            command: ConfigCommand::Check,
+        }) => return check_config(&cli),
+        None => {}
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cache = cli
+        .cacheable()
+        .then(|| results_cache::ResultsCache::open(&cli, &args))
+        .flatten();
+    let start = Instant::now();
+    if let Some(entry) = cache.as_ref().and_then(|cache| cache.hit()) {
+        eprintln!("Nothing changed since the last identical run, reusing its result");
+        print!("{}", entry.stdout);
+        if let (Some(path), Some(metrics)) = (&cli.metrics_file, entry.metrics) {
+            metrics
+                .replayed(start.elapsed().as_secs_f64())
+                .write(path)?;
+        }
+        return match entry.error {
+            Some(error) if !entry.success => Err(anyhow::anyhow!(error)),
+            _ => Ok(()),
+        };
+    }
+
+    interrupt::install();
+    if let Some(target) = &cli.progress_fd {
+        progress::open(target)?;
+    }
+    let mut summary = Summary::default();
+    let mut result = run(&cli, &mut summary);
+    if cli.deterministic && result.is_ok() {
+        result = summary.ensure_sorted();
+    }
+    summary.success = result.is_ok();
+    summary.error = result.as_ref().err().map(|e| format!("{e:#}"));
+
+    if let Some(threshold) = cli.notify {
+        let elapsed = start.elapsed();
+        if elapsed.as_secs() >= threshold {
+            let title = if result.is_ok() {
+                "polish-rs passed"
+            } else {
+                "polish-rs failed"
+            };
+            let body = match &summary.error {
+                Some(error) => format!("{error} ({:.0}s)", elapsed.as_secs_f64()),
+                None => format!("All checks passed ({:.0}s)", elapsed.as_secs_f64()),
+            };
+            if let Err(e) = notify::send(title, &body) {
+                eprintln!("Failed to send notification: {e:#}");
+            }
+        }
+    }
+
+    if cli.timings {
+        eprint!("{}", summary.timings.render());
+    }
+
+    if cli.ci {
+        for line in annotations::from_summary(&summary) {
+            eprintln!("{line}");
+        }
+    }
+
+    let metrics = cli
+        .metrics_file
+        .as_ref()
+        .map(|_| metrics::Metrics::new(&summary, start.elapsed().as_secs_f64()));
+    if let (Some(path), Some(metrics)) = (&cli.metrics_file, &metrics) {
+        metrics.write(path)?;
+    }
+
+    let stdout = render_stdout(&cli, &summary, result.is_ok())?;
+    print!("{stdout}");
+    let timed_out = summary.skipped.contains_key("clippy");
+    if let Some(cache) = cache.as_ref().filter(|_| !interrupt::interrupted()) {
+        let outcome = results_cache::Entry {
+            key: String::new(),
+            success: summary.success,
+            error: summary.error.clone(),
+            stdout,
+            metrics,
+        };
+        if !timed_out {
+            if let Err(e) = cache.store(&cli, &args, outcome) {
+                eprintln!("Failed to cache the result: {e:#}");
+            }
+        }
+    }
+
+    if interrupt::interrupted() {
+        eprintln!("Interrupted");
+        std::process::exit(interrupt::EXIT_CODE);
+    }
+    // A distinct exit code lets hooks tell a timeout from a failed check
+    if timed_out {
+        if let Err(e) = &result {
+            eprintln!("Error: {e:?}");
+        }
+        std::process::exit(TIMEOUT_EXIT_CODE);
+    }
+    result
+}
+
+/// What a run prints to stdout: the summary in the requested format, or just
+/// the changed files with `--emit changed-files`, or the suggestions with
+/// `--emit suggestions-json`.
+fn render_stdout(cli: &Cli, summary: &Summary, success: bool) -> anyhow::Result<String> {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    if cli.emit == Some(Emit::ChangedFiles) {
+        let separator = if cli.null { '\0' } else { '\n' };
+        for edit in &summary.edits {
+            write!(out, "{}{separator}", edit.file.display())?;
+        }
+        return Ok(out);
+    }
+    if cli.emit == Some(Emit::SuggestionsJson) {
+        for diagnostic in &summary.suggestions {
+            writeln!(out, "{}", serde_json::to_string(diagnostic)?)?;
+        }
+        return Ok(out);
+    }
+
+    match cli.output_format() {
+        OutputFormat::Text => {
+            if cli.print_commands {
+                for command in &summary.commands {
+                    writeln!(out, "{command}")?;
+                }
+            } else if cli.explain_affected {
+                for affected in &summary.affected {
+                    match (&affected.package, &affected.manifest) {
+                        (Some(package), Some(manifest)) => writeln!(
+                            out,
+                            "{} -> {package} ({})",
+                            affected.file.display(),
+                            manifest.display()
+                        )?,
+                        _ => writeln!(out, "{} -> no package", affected.file.display())?,
+                    }
+                    for reason in &affected.reasons {
+                        writeln!(out, "    {reason}")?;
+                    }
+                }
+            } else if success {
+                writeln!(out, "✓ All checks passed!")?;
+            }
+        }
+        OutputFormat::Json => {
+            writeln!(out, "{}", serde_json::to_string_pretty(summary)?)?;
+        }
+        OutputFormat::Rdjson => {
+            writeln!(out, "{}", rdjson::from_summary(summary))?;
+        }
+        OutputFormat::Teamcity => {
+            for line in teamcity::from_summary(summary) {
+                writeln!(out, "{line}")?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A diff for stderr, highlighted when that's a terminal that takes colors.
+fn render_diff(diff: &str) -> String {
+    use std::io::IsTerminal;
+
+    let colors = std::io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none()
+        && std::env::var("CARGO_TERM_COLOR").map_or(true, |c| c != "never");
+    if colors {
+        diff::highlight(diff)
+    } else {
+        diff.to_string()
+    }
+}
+
+/// Applies the edits from a `--check` report to the working tree. Files that
+/// changed since the report are skipped and make the run fail.
+fn apply_report(report: &Path) -> anyhow::Result<()> {
+    #[derive(serde::Deserialize)]
+    struct Report {
+        #[serde(default)]
+        edits: Vec<snapshot::FileEdit>,
+    }
+
+    let content = std::fs::read_to_string(report)
+        .with_context(|| format!("Failed to read {}", report.display()))?;
+    let report: Report = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid report in {}", report.display()))?;
+    if report.edits.is_empty() {
+        eprintln!("No edits in the report (was it made with --check --output json?)");
+        return Ok(());
+    }
+
+    let git_root = get_git_root()?;
+    let mut failed = 0;
+    for edit in &report.edits {
+        match edit.apply(&git_root) {
+            Ok(true) => eprintln!("Applied edit to {}", edit.file.display()),
+            Ok(false) => eprintln!("Already applied to {}", edit.file.display()),
+            Err(e) => {
+                eprintln!("{e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} edit(s) could not be applied");
+    }
+    println!("✓ Applied {} edit(s)", report.edits.len());
+    Ok(())
+}
+
+/// `verify-package`: lists what `cargo package` would publish for `package`
+/// and reports each Rust file that isn't grouped or formatted, and the
+/// manifest if its dependencies aren't organized. Nothing is written.
+fn verify_package(cli: &Cli, package: &str) -> anyhow::Result<()> {
+    if !is_git_repo()? {
+        bail!("Not in a git repository");
+    }
+    let git_root = get_git_root()?;
+    let config = effective_config(cli, &git_root)?;
+    let cargo = CargoOptions::new(&git_root, cli, &config.cargo);
+    let metadata = metadata::Metadata::load(&git_root, &cargo)?;
+    let manifest = metadata
+        .packages
+        .iter()
+        .find(|p| p.name == package)
+        .map(|p| p.manifest_path.clone())
+        .with_context(|| format!("No workspace package named {package}"))?;
+    let dir = manifest.parent().unwrap_or(&git_root);
+    let edition = find_package_edition(&git_root, &manifest)?;
+    let overrides = rustfmt::ConfigOverrides::new(&config.rustfmt, &cli.rustfmt_config)?;
+
+    let mut cmd = cargo.command("package");
+    cmd.args(["--list", "--allow-dirty", "-p", package])
+        .current_dir(&git_root);
+    eprintln!("Running {cmd:?}");
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run {cmd:?}"))?;
+    if !output.status.success() {
+        bail!(
+            "cargo package --list failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let published = String::from_utf8(output.stdout)?;
+    // The published Cargo.toml is generated; `.orig` is the one in the
+    // repository
+    let files: Vec<(PathBuf, bool)> = published
+        .lines()
+        .filter_map(|listed| match listed {
+            "Cargo.toml.orig" => Some((dir.join("Cargo.toml"), true)),
+            _ if listed.ends_with(".rs") => Some((dir.join(listed), false)),
+            _ => None,
+        })
+        .collect();
+    // The same checks a run would make on these files: only the stages it
+    // runs, and no grouping of generated files or ones outside `[adoption]`
+    let runs = |stage, skipped: bool| !skipped && config.stages.contains(&stage);
+    let relative: Vec<PathBuf> = files
+        .iter()
+        .map(|(path, _)| path.strip_prefix(&git_root).unwrap_or(path).to_path_buf())
+        .collect();
+    let attributed = generated::marked_in_gitattributes(&git_root, &relative)?;
+
+    let mut problems = Vec::new();
+    for ((path, is_manifest), shown) in files.iter().zip(&relative) {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let generated = attributed.contains(shown)
+            || generated::is_generated(&git_root, shown, &config.generated.globs);
+        let groups = !generated && config.adoption.includes(shown);
+        if *is_manifest {
+            if groups
+                && runs(config::Stage::Toml, cli.no_grouping)
+                && toml_grouping::organize_toml_with(&content, &config.dependencies)? != content
+            {
+                problems.push(format!("{}: dependencies not organized", shown.display()));
+            }
+            continue;
+        }
+        if groups
+            && runs(config::Stage::Group, cli.no_grouping)
+            && rust_grouping::group_items_with(&content, &config.grouping)? != content
+        {
+            problems.push(format!("{}: declarations not grouped", shown.display()));
+        }
+        if !runs(config::Stage::Fmt, cli.no_fmt) {
+            continue;
+        }
+        let file_dir = path.parent().unwrap_or(dir);
+        match rustfmt::format_snippet(&content, &edition, file_dir, &overrides)? {
+            Some(formatted) if formatted != content => {
+                problems.push(format!("{}: not formatted", shown.display()))
+            }
+            Some(_) => {}
+            None => problems.push(format!("{}: rustfmt failed", shown.display())),
+        }
+    }
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        bail!(
+            "{} problem(s) in the files published for {package}",
+            problems.len()
+        );
+    }
+    eprintln!("✓ Every published file of {package} is polished");
+    Ok(())
+}
+
+/// Runs the golden tests under `dir` with the grouping settings of its
+/// `polish.toml`, else those of the repository it's in.
+fn test_fixtures(dir: &Path, bless: bool) -> anyhow::Result<()> {
+    let root = if dir.join(config::CONFIG_FILE).exists() {
+        Some(dir.to_path_buf())
+    } else {
+        lsp::repo_root(&normalize_path(dir).join(config::CONFIG_FILE))
+    };
+    let config = match root {
+        Some(root) => config::load(&root)?,
+        None => config::Config::default(),
+    };
+
+    let fixtures = fixtures::find(dir)?;
+    if fixtures.is_empty() {
+        bail!(
+            "No *.input.rs or *.input.toml fixtures in {}",
+            dir.display()
+        );
+    }
+    let mut failed = 0;
+    for fixture in &fixtures {
+        let input = fixture.input.display();
+        match fixture.run(&config, bless)? {
+            fixtures::Outcome::Passed => continue,
+            fixtures::Outcome::Failed(diff) => println!("FAIL {input}\n{diff}"),
+            fixtures::Outcome::Missing => {
+                println!("FAIL {input}: missing {}", fixture.expected.display())
+            }
+            fixtures::Outcome::Error(e) => println!("FAIL {input}: {e}"),
+        }
+        failed += 1;
+    }
+    println!("{} passed, {failed} failed", fixtures.len() - failed);
+    if failed > 0 {
+        bail!("{failed} fixture(s) failed");
+    }
+    Ok(())
+}
 
+fn run(cli: &Cli, summary: &mut Summary) -> anyhow::Result<()> {
     // Check if we're in a git repository
     if !is_git_repo()? {
         bail!("Not in a git repository");
@@ -51,268 +1183,12861 @@ fn main() -> anyhow::Result<()> {
 
     // Get the root of the git repository
     let git_root = get_git_root()?;
-    println!("Git root: {}", git_root.display());
+    eprintln!("Git root: {}", git_root.display());
 
-    // Get files to process
-    let files_to_process = if cli.files.is_empty() {
+    // Stashed changes are put back even if the run fails; a failure to put
+    // them back only takes precedence over a successful run
+    let mut autostash = if cli.autostash {
+        Some(autostash::Autostash::stash(&git_root)?)
+    } else {
+        None
+    };
+    let result = polish(cli, git_root, summary);
+    if let Some(autostash) = autostash.as_mut() {
+        let restored = autostash.restore();
+        match restored {
+            Err(e) if result.is_ok() => return Err(e),
+            Err(e) => eprintln!("{e:#}"),
+            Ok(()) => {}
+        }
+    }
+    result
+}
+
+/// polish.toml with `POLISH_SKIP` and the command-line flags that override
+/// it applied.
+fn effective_config(cli: &Cli, git_root: &Path) -> anyhow::Result<config::Config> {
+    let mut config = config::load_profile(git_root, cli.profile.as_deref())?;
+    if cli.group_top_level_only {
+        config.grouping.max_depth = Some(0);
+    }
+    if let Ok(skip) = std::env::var(config::SKIP_VAR) {
+        let skip =
+            config::parse_skip(&skip).with_context(|| format!("Invalid {}", config::SKIP_VAR))?;
+        if !skip.is_empty() {
+            let names: Vec<String> = skip.iter().map(ToString::to_string).collect();
+            eprintln!("Skipping {} (from {})", names.join(", "), config::SKIP_VAR);
+        }
+        config.stages.retain(|stage| !skip.contains(stage));
+    }
+    if let Some(phase) = cli.phase {
+        config.stages.retain(|stage| phase.stages().contains(stage));
+    }
+    if cli.fix_lockfile && !config.stages.contains(&config::Stage::Lockfile) {
+        config.stages.push(config::Stage::Lockfile);
+    }
+    if cli.miri && !config.stages.contains(&config::Stage::Miri) {
+        config.stages.push(config::Stage::Miri);
+    }
+    Ok(config)
+}
+
+/// Parses the command line again with the `args` of `--profile` in front,
+/// so that flags given explicitly win.
+fn with_profile_args(cli: Cli) -> anyhow::Result<Cli> {
+    let Some(profile) = &cli.profile else {
+        return Ok(cli);
+    };
+    let config = config::load(&get_git_root()?)?;
+    let args = config.profile_args(profile)?;
+    if args.is_empty() {
+        return Ok(cli);
+    }
+    let mut argv = std::env::args_os();
+    let program = argv.next().unwrap_or_else(|| "polish".into());
+    let argv = std::iter::once(program)
+        .chain(args.into_iter().map(Into::into))
+        .chain(argv);
+    Cli::try_parse_from(argv)
+        .map_err(|e| anyhow::anyhow!("{e}").context(format!("Invalid args in profile `{profile}`")))
+}
+
+/// `config check`: reports every problem in polish.toml, then prints the
+/// configuration a run would use.
+fn check_config(cli: &Cli) -> anyhow::Result<()> {
+    if !is_git_repo()? {
+        bail!("Not in a git repository");
+    }
+    let git_root = get_git_root()?;
+    let path = git_root.join(config::CONFIG_FILE);
+    let mut problems = if path.exists() {
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        config::check(&content)
+    } else {
+        eprintln!("No {}, using the defaults", config::CONFIG_FILE);
+        Vec::new()
+    };
+    let config = effective_config(cli, &git_root);
+    if let Ok(config) = &config {
+        if let Err(e) = rustfmt::ConfigOverrides::new(&config.rustfmt, &cli.rustfmt_config) {
+            problems.push(format!("{e:#}"));
+        }
+    }
+    for problem in &problems {
+        eprintln!("{}: {problem}", path.display());
+    }
+    if let Ok(config) = config {
+        print!("{}", toml::to_string(&config)?);
+    }
+    if !problems.is_empty() {
+        bail!("{} problem(s) in {}", problems.len(), path.display());
+    }
+    Ok(())
+}
+
+fn polish(cli: &Cli, git_root: PathBuf, summary: &mut Summary) -> anyhow::Result<()> {
+    if cli.amend {
+        ensure_amendable(&git_root, cli.force)?;
+    }
+
+    let config = effective_config(cli, &git_root)?;
+    // Every command spawned from here on (cargo, rustfmt, git) inherits these
+    if cli.ci {
+        std::env::set_var("CARGO_TERM_COLOR", "never");
+    }
+    for (key, value) in &config.env {
+        std::env::set_var(key, value);
+    }
+    check_toolchain(cli, &config, &git_root)?;
+    let base = diff_base(cli, &git_root)?;
+    let cargo = CargoOptions::new(&git_root, cli, &config.cargo);
+
+    // Get files to process, and the files moved or deleted since the base
+    // whose package lost them
+    let mut renames = Vec::new();
+    let mut deleted = Vec::new();
+    let files_to_process = if cli.staged {
+        let excluded = excluded_dirs(&git_root, &cargo, &config.exclude_dirs);
+        let staged = staged::changed_files(&git_root)?;
+        if staged.paths.is_empty() {
+            eprintln!("No staged changes");
+            return Ok(());
+        }
+        eprintln!("Staged files: {:?}", staged.paths);
+        renames = staged.renames;
+        classify_files(&staged.paths, &excluded)?
+    } else if !cli.lines.is_empty() {
+        let mut files: Vec<PathBuf> = cli.lines.iter().map(|(file, _)| file.clone()).collect();
+        // `--lines a.rs:1 --lines b.rs:1 --lines a.rs:9` names a.rs once
+        files.sort();
+        files.dedup();
+        eprintln!("Processing lines of: {:?}", files);
+        // Files named explicitly are processed wherever they are
+        classify_files(&files, &[])?
+    } else if cli.files.is_empty() {
+        if cli.print_commands {
+            summary.commands.push(format!(
+                "{:?}",
+                changed_files_command(&base, &config.rust_roots, cli.diff_filter().as_deref())
+            ));
+        }
         // Get changed files from git
-        let changed = get_changed_files()?;
-        if changed.is_empty() {
-            println!("No files changed in current commit");
+        let changed = get_changed_files(&base, &config.rust_roots, cli.diff_filter().as_deref())?;
+        if cli.include_deleted {
+            deleted = changed
+                .deleted
+                .into_iter()
+                .filter(|p| p.extension() == Some(ffi::OsStr::new("rs")))
+                .collect();
+        }
+        let excluded = excluded_dirs(&git_root, &cargo, &config.exclude_dirs);
+        let files = classify_files(&changed.paths, &excluded)?;
+        if files.is_empty() && deleted.is_empty() {
+            eprintln!("No files changed since {base}");
+            return Ok(());
+        }
+        eprintln!("Changed files: {:?}", files);
+        if !deleted.is_empty() {
+            eprintln!("Deleted files: {:?}", deleted);
+        }
+        renames = changed.renames;
+        files
+    } else {
+        // Use explicitly provided files
+        eprintln!("Processing specified files: {:?}", cli.files);
+        classify_files(&cli.files, &[])?
+    };
+    // git diff is already limited to the roots, `--files` and `--staged`
+    // aren't
+    let files_to_process = if config.rust_roots.is_empty() {
+        files_to_process
+    } else {
+        let (inside, outside): (Vec<_>, Vec<_>) = files_to_process
+            .into_iter()
+            .partition(|(file, _)| in_rust_roots(&git_root, &config.rust_roots, file));
+        if !outside.is_empty() {
+            eprintln!("Skipping {} file(s) outside rust_roots", outside.len());
+        }
+        if inside.is_empty() && deleted.is_empty() {
+            eprintln!("No changed files under rust_roots");
+            return Ok(());
+        }
+        inside
+    };
+    let files_to_process = if cli.owned_only {
+        // Deleted files count as owned by whoever owned them
+        let deleted_files = deleted.iter().map(|p| (p.clone(), FileType::Rust));
+        let owned = owned_files(
+            &git_root,
+            &config.owners,
+            files_to_process.into_iter().chain(deleted_files).collect(),
+        )?;
+        let (owned_deleted, owned): (Vec<_>, Vec<_>) =
+            owned.into_iter().partition(|(p, _)| deleted.contains(p));
+        deleted = owned_deleted.into_iter().map(|(p, _)| p).collect();
+        if owned.is_empty() && deleted.is_empty() {
+            eprintln!("No changed files you own");
+            return Ok(());
+        }
+        owned
+    } else {
+        files_to_process
+    };
+    let only = match (cli.toml_only, cli.rust_only) {
+        (true, _) => Some((FileType::CargoToml, "--toml-only")),
+        (_, true) => Some((FileType::Rust, "--rust-only")),
+        _ => None,
+    };
+    let files_to_process = if let Some((kept, flag)) = only {
+        let (kept, skipped): (Vec<_>, Vec<_>) = files_to_process
+            .into_iter()
+            .partition(|(_, ft)| *ft == kept);
+        if !skipped.is_empty() {
+            eprintln!("Skipping {} file(s) ({flag})", skipped.len());
+        }
+        if cli.toml_only {
+            deleted.clear();
+        }
+        if kept.is_empty() && deleted.is_empty() {
+            eprintln!("No changed files left to process ({flag})");
             return Ok(());
         }
-        println!("Changed files: {:?}", changed);
-        changed
-    } else {
-        // Use explicitly provided files
-        println!("Processing specified files: {:?}", cli.files);
-        classify_files(&cli.files)?
-    };
+        kept
+    } else {
+        files_to_process
+    };
+    // git lists paths sorted, `--files` and the index needn't be
+    let mut files_to_process = files_to_process;
+    files_to_process.sort_by(|(a, _), (b, _)| a.cmp(b));
+    summary.files = files_to_process.iter().map(|(p, _)| p.clone()).collect();
+    let attributed = generated::marked_in_gitattributes(&git_root, &summary.files)?;
+    summary.generated = files_to_process
+        .iter()
+        .map(|(p, _)| p)
+        .filter(|p| {
+            attributed.contains(*p)
+                || generated::is_generated(&git_root, p, &config.generated.globs)
+        })
+        .cloned()
+        .collect();
+    if !summary.generated.is_empty() {
+        eprintln!("Generated files: {:?}", summary.generated);
+    }
+
+    let rust_files: Vec<PathBuf> = files_to_process
+        .iter()
+        .filter(|(_, ft)| *ft == FileType::Rust)
+        .map(|(p, _)| p.clone())
+        .collect();
+    let handwritten: Vec<PathBuf> = rust_files
+        .iter()
+        .filter(|p| !summary.generated.contains(p))
+        .cloned()
+        .collect();
+    let manifests: Vec<PathBuf> = files_to_process
+        .iter()
+        .filter(|(_, ft)| *ft == FileType::CargoToml)
+        .map(|(p, _)| p.clone())
+        .collect();
+    let adopted = |path: &Path| {
+        config
+            .adoption
+            .includes(path.strip_prefix(&git_root).unwrap_or(path))
+    };
+    let unadopted: HashSet<PathBuf> = summary
+        .files
+        .iter()
+        .filter(|p| !adopted(p))
+        .cloned()
+        .collect();
+    if !unadopted.is_empty() {
+        eprintln!(
+            "Skipping grouping and strict clippy for {} file(s) outside [adoption] include",
+            unadopted.len()
+        );
+    }
+
+    // Map each changed Rust file to its package and the target kind it
+    // belongs to
+    let mut members = BTreeMap::new();
+    let mut file_manifests = HashMap::new();
+    let mut target_args = Vec::new();
+    let mut target_roots = HashSet::new();
+    if rust_files.is_empty() && deleted.is_empty() {
+        eprintln!("No Rust files to format/lint");
+    } else {
+        let metadata = metadata::Metadata::load(&git_root, &cargo)?;
+        let mut file_targets = Vec::new();
+        for file in &rust_files {
+            let relative = file.strip_prefix(&git_root).unwrap_or(file);
+            if let Some((glob, targets)) =
+                metadata.resolve_extra(relative, &config.extra_sources)?
+            {
+                for target in targets {
+                    if cli.explain_affected {
+                        summary.affected.push(AffectedFile {
+                            file: file.clone(),
+                            package: Some(target.package.name.clone()),
+                            manifest: Some(target.package.manifest_path.clone()),
+                            reasons: vec![format!(
+                                "listed under [extra_sources] as `{glob}`, so every target is linted"
+                            )],
+                        });
+                        continue;
+                    }
+                    file_manifests
+                        .entry(file.clone())
+                        .or_insert_with(|| target.package.manifest_path.clone());
+                    file_targets.push(target);
+                }
+                continue;
+            }
+            if cli.explain_affected {
+                let (target, reasons) = metadata.explain(&git_root.join(file));
+                summary.affected.push(AffectedFile {
+                    file: file.clone(),
+                    package: target.as_ref().map(|t| t.package.name.clone()),
+                    manifest: target.map(|t| t.package.manifest_path.clone()),
+                    reasons,
+                });
+                continue;
+            }
+            let target = metadata.resolve(&git_root.join(file)).with_context(|| {
+                format!(
+                    "No workspace package contains {}; list it under [extra_sources] in {}",
+                    file.display(),
+                    config::CONFIG_FILE
+                )
+            })?;
+            file_manifests.insert(file.clone(), target.package.manifest_path.clone());
+            file_targets.push(target);
+        }
+        // A file moved between packages changes the module tree of both
+        for (from, to) in &renames {
+            if from.extension() != Some(ffi::OsStr::new("rs")) || !rust_files.contains(to) {
+                continue;
+            }
+            if cli.explain_affected {
+                let (target, mut reasons) = metadata.explain(&git_root.join(from));
+                reasons.insert(0, format!("moved to {}", to.display()));
+                summary.affected.push(AffectedFile {
+                    file: from.clone(),
+                    package: target.as_ref().map(|t| t.package.name.clone()),
+                    manifest: target.map(|t| t.package.manifest_path.clone()),
+                    reasons,
+                });
+            } else if let Some(target) = metadata.resolve(&git_root.join(from)) {
+                file_targets.push(target);
+            }
+        }
+        // So does a deleted file, with `--include-deleted`
+        for file in &deleted {
+            if cli.explain_affected {
+                let (target, mut reasons) = metadata.explain(&git_root.join(file));
+                reasons.insert(0, "deleted".to_string());
+                summary.affected.push(AffectedFile {
+                    file: file.clone(),
+                    package: target.as_ref().map(|t| t.package.name.clone()),
+                    manifest: target.map(|t| t.package.manifest_path.clone()),
+                    reasons,
+                });
+            } else if let Some(target) = metadata.resolve(&git_root.join(file)) {
+                file_targets.push(target);
+            }
+        }
+        members = file_targets
+            .iter()
+            .map(|t| (t.package.name.clone(), t.package.manifest_path.clone()))
+            .collect();
+        target_args = targets::cargo_args(&config.clippy.targets, &file_targets);
+        target_roots = metadata.target_roots();
+        eprintln!("Affected workspace members: {:?}", members.keys());
+        summary.members = members.keys().cloned().collect();
+    }
+    let lenient = members.values().any(|manifest| !adopted(manifest));
+    if cli.explain_affected {
+        return Ok(());
+    }
+
+    let lockfile = if config.stages.contains(&config::Stage::Lockfile) && !manifests.is_empty() {
+        Some(get_workspace_root(&git_root, &cargo)?.join("Cargo.lock")).filter(|p| p.exists())
+    } else {
+        None
+    };
+
+    let changes = Changes {
+        git_root,
+        base,
+        rust_roots: config.rust_roots.clone(),
+        lockfile,
+        cargo,
+        rust_files,
+        handwritten,
+        manifests,
+        unadopted,
+        members,
+        file_manifests,
+        lenient,
+        target_args,
+        target_roots,
+    };
+
+    if cli.print_commands {
+        summary
+            .commands
+            .extend(preview_commands(cli, &config, &changes)?);
+        return Ok(());
+    }
+
+    if !changes.members.is_empty() && (!cli.no_fmt || !cli.no_clippy) {
+        summary.toolchains = package_toolchains(&changes.git_root, &changes.members);
+        if let Some(first) = summary.toolchains.first() {
+            eprintln!("Toolchain: {}", first.toolchain);
+        }
+        for package in &summary.toolchains {
+            if let Some(pinned) = &package.pinned {
+                eprintln!(
+                    "warning: {} pins {pinned}, but cargo runs from the repository root with {}",
+                    package.package, package.toolchain
+                );
+            }
+        }
+    }
+
+    // Fixes written over unstaged edits can't be told apart from them
+    // afterwards; modes that keep the working tree out of it don't need this
+    if !cli.check_mode() && !cli.staged {
+        let overlap = dirty_overlap(cli, &config, &changes, &summary.files)?;
+        if !overlap.is_empty() {
+            let list: Vec<String> = overlap.iter().map(|f| f.display().to_string()).collect();
+            if cli.require_clean {
+                bail!(
+                    "Files polish may change have unstaged edits: {}",
+                    list.join(", ")
+                );
+            }
+            eprintln!(
+                "warning: fixes will be mixed with unstaged edits in: {}",
+                list.join(", ")
+            );
+        }
+    }
+
+    // Check mode runs the stages that fix files in a copy of the working
+    // tree, and reports what they changed there
+    let scratch = cli
+        .check_mode()
+        .then(|| scratch::Scratch::new(&changes.git_root))
+        .transpose()?;
+    let scratch_changes = scratch.as_ref().map(|s| changes.rebased(&s.root));
+
+    // Snapshot every file the stages may touch: the changed files and, for
+    // `cargo fmt -p`, the sources of the affected members
+    let mut snapshot =
+        (cli.check_mode() || cli.commit_fixes.is_some() || cli.amend || cli.metrics_file.is_some())
+            .then(|| {
+                let fixed = scratch_changes.as_ref().unwrap_or(&changes);
+                let package_files = fixed
+                    .members
+                    .values()
+                    .filter_map(|manifest| manifest.parent())
+                    .flat_map(snapshot::rust_files_under);
+                let files =
+                    summary
+                        .files
+                        .iter()
+                        .map(|file| match file.strip_prefix(&changes.git_root) {
+                            Ok(relative) => relative.to_path_buf(),
+                            Err(_) => file.clone(),
+                        });
+                snapshot::Snapshot::take(
+                    &fixed.git_root,
+                    files.chain(package_files).chain(fixed.lockfile.clone()),
+                )
+            });
+
+    // Run every stage even after a failure unless asked to stop early, so
+    // one run reports everything that needs fixing
+    let mut failures = Vec::new();
+    let teamcity = cli.output_format() == OutputFormat::Teamcity;
+    for stage in &config.stages {
+        interrupt::check()?;
+        let stage_name = stage.to_string();
+        progress::emit(progress::Event::StageStarted { stage: &stage_name });
+        let stage_start = Instant::now();
+        let block = format!("polish: {stage}");
+        if teamcity {
+            println!("{}", teamcity::message("blockOpened", &[("name", &block)]));
+        }
+        let (changes, _entered) = match (&scratch, &scratch_changes) {
+            (Some(scratch), Some(fixed)) if stage.fixes() => (fixed, Some(scratch.enter()?)),
+            _ => (&changes, None),
+        };
+        let result = match stage {
+            config::Stage::Group => run_group_stage(cli, &config, changes, summary),
+            config::Stage::Toml => run_toml_stage(cli, &config, changes, summary),
+            config::Stage::Fmt => run_fmt_stage(cli, &config, changes, summary),
+            config::Stage::Clippy => run_clippy_stage(cli, &config, changes, summary),
+            config::Stage::Lockfile => run_lockfile_stage(cli, changes, summary),
+            config::Stage::Miri => run_miri_stage(cli, changes, summary),
+        };
+        if let (true, Err(e)) = (teamcity, &result) {
+            let text = format!("{e:#}");
+            println!(
+                "{}",
+                teamcity::message("message", &[("text", &text), ("status", "ERROR")])
+            );
+        }
+        if teamcity {
+            println!("{}", teamcity::message("blockClosed", &[("name", &block)]));
+        }
+        progress::emit(progress::Event::StageFinished {
+            stage: &stage_name,
+            success: result.is_ok(),
+            seconds: stage_start.elapsed().as_secs_f64(),
+        });
+        if let Err(e) = result {
+            let e = e.context(format!("{stage} stage failed"));
+            if cli.fail_fast {
+                return Err(e);
+            }
+            eprintln!("{e:#}");
+            failures.push(e);
+        }
+    }
+    interrupt::check()?;
+
+    if let (Some(snapshot), Some(message)) = (snapshot.as_mut(), &cli.commit_fixes) {
+        let fixed: Vec<PathBuf> = snapshot.keep()?.into_iter().map(|e| e.file).collect();
+        summary.commit = commit_files(&changes.git_root, &fixed, Commit::New(message))?;
+        summary.modified = fixed;
+        if cli.push && summary.commit.is_some() {
+            git_push(&changes.git_root)?;
+        }
+    } else if let Some(snapshot) = snapshot.as_mut().filter(|_| cli.amend) {
+        let fixed: Vec<PathBuf> = snapshot.keep()?.into_iter().map(|e| e.file).collect();
+        if failures.is_empty() {
+            summary.commit = commit_files(&changes.git_root, &fixed, Commit::Amend)?;
+        } else {
+            eprintln!("Not amending HEAD since the run failed");
+        }
+        summary.modified = fixed;
+    } else if let Some(snapshot) = snapshot.as_mut().filter(|_| !cli.check_mode()) {
+        // Only taken to count modified files for `--metrics-file`
+        summary.modified = snapshot.keep()?.into_iter().map(|e| e.file).collect();
+    } else if let Some(snapshot) = snapshot.as_mut() {
+        summary.edits = snapshot.keep()?;
+        summary.modified = summary.edits.iter().map(|e| e.file.clone()).collect();
+        for edit in &summary.edits {
+            eprintln!("Would change {} ({})", edit.file.display(), edit.stats);
+            if matches!(
+                cli.output_format(),
+                OutputFormat::Text | OutputFormat::Teamcity
+            ) {
+                eprint!("{}", render_diff(&edit.diff));
+            }
+        }
+        if let Some(Emit::Patch(path)) = &cli.emit {
+            let patch: String = summary.edits.iter().map(|e| e.diff.as_str()).collect();
+            std::fs::write(path, patch)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!("Wrote patch to {}", path.display());
+        }
+        if cli.emit == Some(Emit::SuggestionsJson) || cli.output_format() == OutputFormat::Rdjson {
+            // The working tree holds the contents the suggestions apply to
+            for edit in &summary.edits {
+                let path = changes.git_root.join(&edit.file);
+                let original = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                summary.suggestions.extend(suggestions::from_edit(
+                    &edit.file,
+                    &original,
+                    &edit.after,
+                ));
+            }
+        }
+        if !summary.edits.is_empty() {
+            failures.push(anyhow::anyhow!(
+                "{} file(s) would be changed",
+                summary.edits.len()
+            ));
+        }
+    }
+
+    summary
+        .findings
+        .sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    summary
+        .warnings
+        .sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.remove(0)),
+        n => bail!(
+            "{n} stages failed: {}",
+            failures
+                .iter()
+                .map(|e| format!("{e:#}"))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+    }
+}
+
+/// The changed files and what they map to in the workspace, shared by every
+/// stage of a run.
+struct Changes {
+    git_root: PathBuf,
+    /// Revision the changes are detected against.
+    base: String,
+    /// Directories holding Rust code, relative to the git root; empty for
+    /// the whole repository.
+    rust_roots: Vec<PathBuf>,
+    /// The workspace's `Cargo.lock`, when the lockfile stage has changed
+    /// manifests to check it against.
+    lockfile: Option<PathBuf>,
+    cargo: CargoOptions,
+    rust_files: Vec<PathBuf>,
+    /// Changed Rust files that aren't generated.
+    handwritten: Vec<PathBuf>,
+    /// Changed `Cargo.toml` files.
+    manifests: Vec<PathBuf>,
+    /// Changed files outside `[adoption] include`, which only get the
+    /// non-invasive stages.
+    unadopted: HashSet<PathBuf>,
+    /// Affected workspace members and their manifests.
+    members: BTreeMap<String, PathBuf>,
+    /// Manifest of the package each changed Rust file was resolved to.
+    file_manifests: HashMap<PathBuf, PathBuf>,
+    /// Whether an affected member is outside `[adoption] include`, so clippy
+    /// can't deny warnings outright.
+    lenient: bool,
+    /// Target selection for cargo check and clippy.
+    target_args: Vec<String>,
+    /// Crate roots of every workspace target.
+    target_roots: HashSet<PathBuf>,
+}
+
+impl Changes {
+    fn member_names(&self) -> BTreeSet<String> {
+        self.members.keys().cloned().collect()
+    }
+
+    /// The manifest of the package `file` was resolved to, else the closest
+    /// `Cargo.toml`.
+    fn manifest_for(&self, file: &Path) -> anyhow::Result<PathBuf> {
+        match self.file_manifests.get(file) {
+            Some(manifest) => Ok(manifest.clone()),
+            None => find_manifest_for_file(&self.git_root, &self.rust_roots, file),
+        }
+    }
+
+    /// Whether `file` was resolved to a package whose directory doesn't
+    /// contain it.
+    fn outside_package(&self, file: &Path) -> bool {
+        self.file_manifests
+            .get(file)
+            .and_then(|manifest| manifest.parent())
+            .is_some_and(|dir| !normalize_path(&self.git_root.join(file)).starts_with(dir))
+    }
+
+    /// Changed Rust files that are grouped and checked.
+    fn adopted(&self) -> impl Iterator<Item = &PathBuf> {
+        self.handwritten
+            .iter()
+            .filter(|file| !self.unadopted.contains(*file))
+    }
+
+    /// The same changes in a copy of the working tree at `root`: absolute
+    /// paths into the git root point into the copy instead. Relative paths
+    /// stay as they are and resolve into the copy once it's the current
+    /// directory.
+    fn rebased(&self, root: &Path) -> Changes {
+        let rebase = |path: &PathBuf| match path.strip_prefix(&self.git_root) {
+            Ok(relative) if path.is_absolute() => root.join(relative),
+            _ => path.clone(),
+        };
+        Changes {
+            git_root: root.to_path_buf(),
+            base: self.base.clone(),
+            rust_roots: self.rust_roots.clone(),
+            lockfile: self.lockfile.as_ref().map(rebase),
+            cargo: self.cargo.clone(),
+            rust_files: self.rust_files.iter().map(rebase).collect(),
+            handwritten: self.handwritten.iter().map(rebase).collect(),
+            manifests: self.manifests.iter().map(rebase).collect(),
+            unadopted: self.unadopted.iter().map(rebase).collect(),
+            members: self
+                .members
+                .iter()
+                .map(|(name, manifest)| (name.clone(), rebase(manifest)))
+                .collect(),
+            file_manifests: self
+                .file_manifests
+                .iter()
+                .map(|(file, manifest)| (rebase(file), rebase(manifest)))
+                .collect(),
+            lenient: self.lenient,
+            target_args: self.target_args.clone(),
+            target_roots: self.target_roots.iter().map(rebase).collect(),
+        }
+    }
+}
+
+/// Groups declarations in the changed Rust files, sorts their derives and
+/// runs polish's own checks on them.
+fn run_group_stage(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    if !cli.no_grouping {
+        let stage_start = Instant::now();
+        for file_path in changes.adopted() {
+            let file_start = Instant::now();
+            let mut options = config.grouping.clone();
+            options.pin_macro_use = changes
+                .manifest_for(file_path)
+                .and_then(|manifest| find_package_edition(&changes.git_root, &manifest))
+                .is_ok_and(|edition| edition == "2015");
+            let grouped = if cli.staged {
+                staged::update(&changes.git_root, file_path, |content| {
+                    group_content(cli, file_path, content, &options)
+                })
+                .and_then(|_| staged::read(&changes.git_root, file_path))
+            } else if let Some(lines) = cli.requested_lines(file_path) {
+                group_lines(cli, file_path, &lines, &options)
+            } else {
+                group_file(cli, file_path, &options)
+            };
+            let content = match grouped.map_err(|e| e.downcast::<NotIdempotent>()) {
+                Ok(content) => content,
+                Err(Ok(not_idempotent)) => {
+                    let finding = Finding {
+                        file: file_path.clone(),
+                        line: 1,
+                        message: not_idempotent.to_string(),
+                    };
+                    progress::emit(progress::Event::Diagnostic {
+                        file: Some(&finding.file),
+                        line: Some(finding.line),
+                        level: "error",
+                        lint: "polish",
+                        message: &finding.message,
+                    });
+                    eprintln!("error: {finding}");
+                    summary.findings.push(finding);
+                    continue;
+                }
+                Err(Err(e)) => return Err(e),
+            };
+            for violation in rust_grouping::violations(&content, &config.grouping) {
+                let finding = Finding {
+                    file: file_path.clone(),
+                    line: violation.line,
+                    message: violation.message,
+                };
+                progress::emit(progress::Event::Diagnostic {
+                    file: Some(&finding.file),
+                    line: Some(finding.line),
+                    level: if violation.deny { "error" } else { "warning" },
+                    lint: "polish",
+                    message: &finding.message,
+                });
+                if violation.deny {
+                    summary.findings.push(finding);
+                } else {
+                    eprintln!("warning: {finding}");
+                    summary.warnings.push(finding);
+                }
+            }
+            summary.timings.record_file(file_path, file_start.elapsed());
+            progress::emit(progress::Event::FileProcessed {
+                stage: "group",
+                file: file_path,
+            });
+        }
+        summary
+            .timings
+            .record_stage("grouping", stage_start.elapsed());
+    }
+
+    if cli.sort_derives {
+        for file_path in changes.adopted() {
+            let sorted = if cli.staged {
+                let ranges = staged::changed_line_ranges(&changes.git_root, file_path)?;
+                staged::update(&changes.git_root, file_path, |content| {
+                    Ok(derive_sort::sort_derives(
+                        content,
+                        &ranges,
+                        &config.derive.order,
+                    ))
+                })?
+            } else {
+                derive_sort::sort_file(
+                    &changes.git_root,
+                    file_path,
+                    &changes.base,
+                    cli.requested_lines(file_path),
+                    &config.derive.order,
+                )?
+            };
+            if sorted {
+                eprintln!("Sorted derives in {}", file_path.display());
+            }
+        }
+    }
+
+    run_checks(cli, config, changes, summary)
+}
+
+/// Groups declarations of `file` within `lines` only, returning its new
+/// content. Grouping that would move declarations across the edge of the
+/// ranges is left out entirely.
+fn group_lines(
+    cli: &Cli,
+    file: &Path,
+    lines: &[rustfmt::LineRange],
+    options: &rust_grouping::Options,
+) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let grouped = group_content(cli, file, &content, options)?;
+    let Some(within) = diff::apply_within(&content, &grouped, lines) else {
+        eprintln!(
+            "Not grouping {}: declarations would move in or out of the requested lines",
+            file.display()
+        );
+        return Ok(content);
+    };
+    if within != content {
+        interrupt::write(file, &within)
+            .with_context(|| format!("Failed to write {}", file.display()))?;
+    }
+    Ok(within)
+}
+
+/// Groups the declarations of `file` in place and returns its new contents.
+fn group_file(cli: &Cli, file: &Path, options: &rust_grouping::Options) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let grouped = group_content(cli, file, &content, options)?;
+    if grouped != content {
+        interrupt::write(file, &grouped)
+            .with_context(|| format!("Failed to write {}", file.display()))?;
+    }
+    Ok(grouped)
+}
+
+/// A grouped file that a second pass of grouping changes, with the hunks it
+/// changes. The file is reported as a finding and left as it was.
+#[derive(Debug)]
+struct NotIdempotent {
+    hunks: String,
+}
+
+impl std::fmt::Display for NotIdempotent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "grouping is not idempotent, so the file was left unchanged. This is a bug \
+             in polish-rs; please report it with the file, or with these lines that a \
+             second pass changes:\n{}",
+            self.hunks
+        )
+    }
+}
+
+impl std::error::Error for NotIdempotent {}
+
+/// Groups the declarations in `content`, the contents of `file`. The run
+/// fails, and the caller leaves the file as it was, if grouping lost or
+/// duplicated a line. Unless `--no-idempotency-check` is given, a result that
+/// grouping once more changes is a [`NotIdempotent`] error, which the stage
+/// reports for this file before going on with the others. Either is a bug in
+/// polish, not in the file.
+fn group_content(
+    cli: &Cli,
+    file: &Path,
+    content: &str,
+    options: &rust_grouping::Options,
+) -> anyhow::Result<String> {
+    let grouped = rust_grouping::group_items_with(content, options).map_err(|e| e.in_file(file))?;
+    if let Some(lines) = rust_grouping::lost_lines(content, &grouped, options) {
+        bail!(
+            "Grouping {} would lose or duplicate lines, so it was left unchanged. This \
+             is a bug in polish-rs; please report it with the file. Lines with their \
+             count before and after:\n{lines}",
+            file.display()
+        );
+    }
+    if !cli.no_idempotency_check {
+        if let Some(hunks) = rust_grouping::regrouping_diff(&grouped, options) {
+            return Err(NotIdempotent { hunks }.into());
+        }
+    }
+    Ok(grouped)
+}
+
+/// Organizes the dependency tables of the changed `Cargo.toml` files.
+fn run_toml_stage(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    if cli.no_grouping {
+        return Ok(());
+    }
+    let stage_start = Instant::now();
+    for file_path in &changes.manifests {
+        if summary.generated.contains(file_path) || changes.unadopted.contains(file_path) {
+            continue;
+        }
+        let file_start = Instant::now();
+        let base_content = if cli.bot_friendly {
+            let base = if cli.staged { "HEAD" } else { &changes.base };
+            Some(file_at_revision(&changes.git_root, file_path, base)?)
+        } else {
+            None
+        };
+        if cli.staged {
+            staged::update(&changes.git_root, file_path, |content| {
+                Ok(match &base_content {
+                    Some(base) => {
+                        toml_grouping::organize_changed(content, base, &config.dependencies)
+                    }
+                    None => toml_grouping::organize_toml_with(content, &config.dependencies),
+                }
+                .map_err(|e| e.in_file(file_path))?)
+            })?;
+        } else {
+            toml_grouping::organize_dependencies(
+                file_path,
+                base_content.as_deref(),
+                &config.dependencies,
+            )?;
+        }
+        summary.timings.record_file(file_path, file_start.elapsed());
+        progress::emit(progress::Event::FileProcessed {
+            stage: "toml",
+            file: file_path,
+        });
+    }
+    summary.timings.record_stage("toml", stage_start.elapsed());
+    Ok(())
+}
+
+/// The contents of `file` at `revision`, empty if it didn't exist there.
+fn file_at_revision(git_root: &Path, file: &Path, revision: &str) -> anyhow::Result<String> {
+    let absolute = git_root.join(file);
+    let relative = absolute.strip_prefix(git_root).unwrap_or(file);
+    let object = format!(
+        "{revision}:{}",
+        relative.to_string_lossy().replace('\\', "/")
+    );
+    let exists = Command::new("git")
+        .args(["cat-file", "-e", &object])
+        .current_dir(git_root)
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to run git cat-file")?;
+    if !exists.success() {
+        return Ok(String::new());
+    }
+    let output = Command::new("git")
+        .args(["show", &object])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to run git show")?;
+    if !output.status.success() {
+        bail!(
+            "git show {object} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Toolchain the miri stage runs on; Miri only ships with nightly.
+const MIRI_TOOLCHAIN: &str = "nightly";
+
+/// Runs the tests of the affected members whose changed files use `unsafe`
+/// under Miri, which catches Undefined Behavior that only sometimes crashes
+/// a native run. Skipped with a note when Miri isn't installed.
+fn run_miri_stage(cli: &Cli, changes: &Changes, summary: &mut Summary) -> anyhow::Result<()> {
+    if cli.explain_affected {
+        return Ok(());
+    }
+    let git_root = &changes.git_root;
+    let mut members = BTreeSet::new();
+    for file in &changes.rust_files {
+        let Ok(source) = std::fs::read_to_string(git_root.join(file)) else {
+            continue;
+        };
+        if !miri::uses_unsafe(&source) {
+            continue;
+        }
+        let manifest = changes.manifest_for(file)?;
+        if let Some((member, _)) = changes.members.iter().find(|(_, m)| **m == manifest) {
+            members.insert(member.clone());
+        }
+    }
+    if members.is_empty() {
+        return Ok(());
+    }
+
+    let stage_start = Instant::now();
+    let cargo = &changes.cargo;
+    let installed = cargo
+        .command("miri")
+        .env("RUSTUP_TOOLCHAIN", MIRI_TOOLCHAIN)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !installed {
+        eprintln!(
+            "Miri is not installed, skipping it; install it with `rustup +{MIRI_TOOLCHAIN} \
+             component add miri`"
+        );
+        summary
+            .skipped
+            .insert("miri".to_string(), "skipped (not installed)".to_string());
+        return Ok(());
+    }
+
+    let mut failed = Vec::new();
+    for member in &members {
+        interrupt::check()?;
+        let member_start = Instant::now();
+        let mut cmd = cargo.command("miri");
+        cmd.env("RUSTUP_TOOLCHAIN", MIRI_TOOLCHAIN)
+            .args(["test", "-p", member])
+            .current_dir(git_root)
+            // Test output goes with the rest of the progress, off stdout
+            .stdout(Stdio::from(std::io::stderr()))
+            .stderr(Stdio::piped());
+        eprintln!("Running {cmd:?}");
+        let mut child =
+            interrupt::spawn(&mut cmd).with_context(|| format!("Failed to run {cmd:?}"))?;
+        let label = cargo.label("miri", &BTreeSet::from([member.clone()]));
+        let stderr = child.stderr.take();
+        let reader = std::thread::spawn(move || {
+            let mut output = String::new();
+            for line in stderr
+                .into_iter()
+                .flat_map(|stderr| BufReader::new(stderr).lines().map_while(Result::ok))
+            {
+                match &label {
+                    Some(label) => eprintln!("[{label}] {line}"),
+                    None => eprintln!("{line}"),
+                }
+                output.push_str(&line);
+                output.push('\n');
+            }
+            output
+        });
+        let status =
+            interrupt::wait(&mut child, None).with_context(|| format!("Failed to run {cmd:?}"))?;
+        let output = reader.join().unwrap_or_default();
+        summary
+            .timings
+            .record_package(member, member_start.elapsed());
+        if !status.is_some_and(|status| status.success()) {
+            failed.push(member.clone());
+        }
+        summary.miri.extend(miri::findings(member, &output));
+    }
+    summary.timings.record_stage("miri", stage_start.elapsed());
+
+    for finding in &summary.miri {
+        let location = match (&finding.file, finding.line) {
+            (Some(file), Some(line)) => format!("{}:{line}: ", file.display()),
+            _ => String::new(),
+        };
+        eprintln!(
+            "{}: {location}Undefined Behavior: {}",
+            finding.package, finding.message
+        );
+    }
+    if !failed.is_empty() {
+        bail!(
+            "cargo miri test failed for {} ({} Undefined Behavior error(s))",
+            failed.join(", "),
+            summary.miri.len()
+        );
+    }
+    Ok(())
+}
+
+/// Fails if `Cargo.lock` no longer matches the changed manifests (cargo would
+/// rewrite it on the next build), or brings it up to date with
+/// `--fix-lockfile`.
+fn run_lockfile_stage(cli: &Cli, changes: &Changes, summary: &mut Summary) -> anyhow::Result<()> {
+    let Some(lockfile) = &changes.lockfile else {
+        return Ok(());
+    };
+    let stage_start = Instant::now();
+    let manifest = lockfile.with_file_name("Cargo.toml");
+    // `--locked` is added here, and must not be passed twice
+    let cargo = CargoOptions {
+        locked: false,
+        ..changes.cargo.clone()
+    };
+    let output = cargo
+        .command("metadata")
+        .args(["--format-version", "1", "--locked", "--manifest-path"])
+        .arg(&manifest)
+        .stdout(Stdio::null())
+        .output()
+        .context("Failed to run cargo metadata")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let result = if output.status.success() {
+        Ok(())
+    } else if !lockfile_outdated(&stderr) {
+        // A broken manifest or an unreachable dependency, which updating
+        // the lock file can't fix
+        eprint!("{stderr}");
+        Err(anyhow::anyhow!(
+            "cargo metadata failed for {}",
+            manifest.display()
+        ))
+    } else if cli.fix_lockfile {
+        eprintln!("Updating {}", lockfile.display());
+        let status = cargo
+            .command("update")
+            .args(["--workspace", "--manifest-path"])
+            .arg(&manifest)
+            .status()
+            .context("Failed to run cargo update")?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("cargo update --workspace failed"))
+        }
+    } else {
+        eprint!("{stderr}");
+        Err(anyhow::anyhow!(
+            "{} is out of date with the changed manifests (run `cargo update --workspace` \
+             or pass --fix-lockfile)",
+            lockfile.display()
+        ))
+    };
+    summary
+        .timings
+        .record_stage("lockfile", stage_start.elapsed());
+    result
+}
+
+/// Whether cargo failed under `--locked` because the lock file needs
+/// updating: "the lock file ... needs to be updated but --locked was passed"
+/// from older cargo, "cannot update the lock file ... because --locked was
+/// passed" from newer.
+fn lockfile_outdated(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("lock file")
+            && (line.contains("needs to be updated") || line.contains("cannot update"))
+    })
+}
+
+/// The toolchain each of `members` (names and manifests) is formatted and
+/// linted with, and the one it pins when that differs. Empty without rustup.
+fn package_toolchains(
+    git_root: &Path,
+    members: &BTreeMap<String, PathBuf>,
+) -> Vec<PackageToolchain> {
+    let Some(toolchain) = active_toolchain(git_root) else {
+        return Vec::new();
+    };
+    let root_file = toolchain_file(git_root, git_root);
+    // Toolchains by pinning file, so rustup runs once per file
+    let mut pins: HashMap<PathBuf, Option<String>> = HashMap::new();
+    members
+        .iter()
+        .map(|(package, manifest)| {
+            let dir = git_root.join(manifest);
+            let dir = dir.parent().unwrap_or(git_root);
+            let pinned = toolchain_file(dir, git_root)
+                .filter(|file| Some(file) != root_file.as_ref())
+                .and_then(|file| {
+                    pins.entry(file)
+                        .or_insert_with(|| active_toolchain(dir))
+                        .clone()
+                })
+                .filter(|pinned| *pinned != toolchain);
+            PackageToolchain {
+                package: package.clone(),
+                toolchain: toolchain.clone(),
+                pinned,
+            }
+        })
+        .collect()
+}
+
+/// The toolchain rustup picks in `dir`, e.g. `stable-x86_64-unknown-linux-gnu`.
+fn active_toolchain(dir: &Path) -> Option<String> {
+    let output = Command::new("rustup")
+        .args(["show", "active-toolchain"])
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    stdout.split_whitespace().next().map(str::to_string)
+}
+
+/// The `rust-toolchain.toml` (or `rust-toolchain`) closest to `dir`,
+/// looking no higher than `git_root`.
+fn toolchain_file(dir: &Path, git_root: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .take_while(|ancestor| ancestor.starts_with(git_root))
+        .flat_map(|ancestor| {
+            ["rust-toolchain.toml", "rust-toolchain"].map(|name| ancestor.join(name))
+        })
+        .find(|file| file.is_file())
+}
+
+/// Fails early if cargo, or rustfmt or clippy when their stage runs, is
+/// older than `[toolchain]` in polish.toml allows.
+fn check_toolchain(cli: &Cli, config: &config::Config, git_root: &Path) -> anyhow::Result<()> {
+    let toolchain = &config.toolchain;
+    let runs = |stage, skipped: bool| !skipped && config.stages.contains(&stage);
+    let checks = [
+        ("cargo", None, toolchain.cargo.as_ref()),
+        (
+            "rustfmt",
+            Some("fmt"),
+            toolchain
+                .rustfmt
+                .as_ref()
+                .filter(|_| runs(config::Stage::Fmt, cli.no_fmt)),
+        ),
+        (
+            "clippy",
+            Some("clippy"),
+            toolchain
+                .clippy
+                .as_ref()
+                .filter(|_| runs(config::Stage::Clippy, cli.no_clippy)),
+        ),
+    ];
+    for (tool, subcommand, minimum) in checks {
+        let Some(minimum) = minimum else {
+            continue;
+        };
+        // A missing component has no version to compare: report it (or
+        // install it) the way its stage would
+        if let Some(subcommand) = subcommand {
+            ensure_component(cli, git_root, subcommand, tool)?;
+        }
+        toolchain::require(git_root, tool, subcommand, minimum)?;
+    }
+    Ok(())
+}
+
+/// Makes sure `cargo <subcommand>` runs, so that a missing rustup component
+/// fails with what to do about it rather than as a failed stage. With
+/// `--install-components` the missing `component` is installed instead.
+fn ensure_component(
+    cli: &Cli,
+    git_root: &Path,
+    subcommand: &str,
+    component: &str,
+) -> anyhow::Result<()> {
+    let output = Command::new("cargo")
+        .args([subcommand, "--version"])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to run cargo")?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = stderr.lines().next().unwrap_or_default().trim();
+    if !cli.install_components {
+        bail!(
+            "`cargo {subcommand}` is not available ({reason}); install it with `rustup \
+             component add {component}` or pass --install-components"
+        );
+    }
+    eprintln!("Installing the {component} component");
+    let status = Command::new("rustup")
+        .args(["component", "add", component])
+        .current_dir(git_root)
+        .status()
+        .context("Failed to run rustup")?;
+    if !status.success() {
+        bail!("`rustup component add {component}` failed");
+    }
+    Ok(())
+}
+
+/// Runs cargo fmt on all affected members in a single call, or rustfmt on
+/// just the changed files or lines.
+fn run_fmt_stage(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    if cli.no_fmt || changes.members.is_empty() {
+        return Ok(());
+    }
+    let git_root = &changes.git_root;
+    ensure_component(cli, git_root, "fmt", "rustfmt")?;
+    let stage_start = Instant::now();
+    let overrides = rustfmt::ConfigOverrides::new(&config.rustfmt, &cli.rustfmt_config)?;
+    if cli.staged {
+        format_staged(cli, changes, &overrides)?;
+    } else if !cli.lines.is_empty() {
+        format_requested_lines(cli, changes, &overrides)?;
+    } else if cli.changed_lines {
+        format_changed_lines(changes, &overrides)?;
+    } else if cli.fast_fmt() {
+        format_changed_files(changes, &overrides)?;
+    } else {
+        run_cargo_fmt(
+            git_root,
+            &changes.cargo,
+            &changes.member_names(),
+            &overrides,
+        )?;
+    }
+    if cli.fmt_doc_examples && !cli.staged {
+        for file in &changes.handwritten {
+            let manifest = changes.manifest_for(file)?;
+            let edition = find_package_edition(git_root, &manifest)?;
+            if doc_examples::format_file(file, &edition, &overrides)? {
+                eprintln!("Formatted doc examples in {}", file.display());
+            }
+        }
+    }
+    if let Some(width) = cli.wrap_doc_comments.filter(|_| !cli.staged) {
+        for file in &changes.handwritten {
+            if doc_wrap::wrap_file(file, width)? {
+                eprintln!("Rewrapped doc comments in {}", file.display());
+            }
+        }
+    }
+    summary.timings.record_stage("fmt", stage_start.elapsed());
+    Ok(())
+}
+
+/// `--staged` formatting: rustfmt, and the doc comment passes that were asked
+/// for, applied to the staged content of each file.
+fn format_staged(
+    cli: &Cli,
+    changes: &Changes,
+    overrides: &rustfmt::ConfigOverrides,
+) -> anyhow::Result<()> {
+    let git_root = &changes.git_root;
+    for file in &changes.handwritten {
+        let manifest = changes.manifest_for(file)?;
+        let edition = find_package_edition(git_root, &manifest)?;
+        let dir = file
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        staged::update(git_root, file, |content| {
+            let mut content = rustfmt::format_snippet(content, &edition, dir, overrides)?
+                .with_context(|| format!("rustfmt failed on {}", file.display()))?;
+            if cli.fmt_doc_examples {
+                content = doc_examples::format_content(&content, file, &edition, overrides)?;
+            }
+            if let Some(width) = cli.wrap_doc_comments {
+                content = doc_wrap::wrap(&content, width);
+            }
+            Ok(content)
+        })?;
+    }
+    Ok(())
+}
+
+/// Runs polish's own checks on the changed files, applying their fixes with
+/// `--fix`, and fails if any problem remains.
+fn run_checks(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let stage_start = Instant::now();
+    let git_root = &changes.git_root;
+    let is_root = |file: &Path| {
+        changes
+            .target_roots
+            .contains(&normalize_path(&git_root.join(file)))
+    };
+    // A module file and its parent can both be changed and both report it
+    let mut moved = HashSet::new();
+    for file in changes.adopted() {
+        let mut issues = Vec::new();
+        if config.modules.enable {
+            issues = modules::check_file(file, is_root(file), config.modules.style)?;
+            if let Some(style) = config.modules.style {
+                issues.extend(modules::check_layout(file, is_root, style));
+            }
+        }
+        // Files outside their package's directory are pulled in with
+        // `#[path]` or `include!` rather than declared as modules
+        if !changes.outside_package(file) {
+            issues.extend(modules::check_new_file(
+                git_root,
+                file,
+                &changes.base,
+                is_root,
+            )?);
+        }
+        if config.safety.require_comments {
+            let (content, changed) = if cli.staged {
+                (
+                    staged::read(git_root, file)?,
+                    staged::changed_line_ranges(git_root, file)?,
+                )
+            } else {
+                (
+                    std::fs::read_to_string(git_root.join(file))
+                        .with_context(|| format!("Failed to read {}", file.display()))?,
+                    rustfmt::changed_line_ranges(git_root, file, &changes.base)?,
+                )
+            };
+            for violation in safety::unjustified(&content, &changed) {
+                summary.findings.push(Finding {
+                    file: file.clone(),
+                    line: violation.line,
+                    message: violation.message,
+                });
+            }
+        }
+        for issue in issues {
+            if let Some(modules::ModuleFix::Rename { from, .. }) = &issue.fix {
+                if !moved.insert(from.clone()) {
+                    continue;
+                }
+            }
+            match issue.fix {
+                Some(fix) if cli.fix => {
+                    eprintln!("{}", fix.apply()?);
+                    if let modules::ModuleFix::Declare { parent, .. } = &fix {
+                        if !cli.no_grouping {
+                            group_file(cli, parent, &config.grouping)?;
+                        }
+                    }
+                }
+                _ => summary.findings.push(Finding {
+                    file: file.clone(),
+                    line: issue.line,
+                    message: issue.message,
+                }),
+            }
+        }
+    }
+    for manifest in &changes.manifests {
+        if summary.generated.contains(manifest) || changes.unadopted.contains(manifest) {
+            continue;
+        }
+        let mut issues = manifest_checks::check_path_dependencies(manifest)?;
+        issues.extend(manifest_checks::check_policy(manifest, &config.manifest)?);
+        for issue in issues {
+            match issue.fix {
+                Some(fix) if cli.fix => eprintln!("{}", fix.apply()?),
+                _ => summary.findings.push(Finding {
+                    file: manifest.clone(),
+                    line: issue.line,
+                    message: issue.message,
+                }),
+            }
+        }
+    }
+    summary
+        .timings
+        .record_stage("checks", stage_start.elapsed());
+
+    for finding in &summary.findings {
+        eprintln!("{finding}");
+    }
+    if !summary.findings.is_empty() {
+        bail!(
+            "found {} problem(s) in changed files",
+            summary.findings.len()
+        );
+    }
+    Ok(())
+}
+
+/// Runs cargo clippy on all affected members, after a plain cargo check with
+/// `--check-first`.
+fn run_clippy_stage(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    if cli.no_clippy || changes.members.is_empty() {
+        return Ok(());
+    }
+    let git_root = &changes.git_root;
+    if config.clippy.is_stock() {
+        ensure_component(cli, git_root, "clippy", "clippy")?;
+    }
+    let cargo = &changes.cargo;
+    let member_manifests = &changes.members;
+    let rust_files = changes.rust_files.as_slice();
+    let target_args = &changes.target_args;
+
+    // Build with plain cargo check first so broken code fails fast, without
+    // paying for the slower clippy build
+    if cli.check_first {
+        let stage_start = Instant::now();
+        let result = run_cargo_check(git_root, cargo, &changes.member_names(), target_args);
+        summary.timings.record_stage("check", stage_start.elapsed());
+        let check = result?;
+        if !check.success {
+            let errors: Vec<_> = clippy_diagnostics::dedup(&check.diagnostics)
+                .into_iter()
+                .filter(|d| d.level == "error" && !d.is_summary())
+                .collect();
+            for error in &errors {
+                eprint!("{}", error.render());
+            }
+            bail!(
+                "cargo check failed with {} error(s), skipping clippy",
+                errors.len()
+            );
+        }
+    }
+
+    let stage_start = Instant::now();
+    let deadline = cli
+        .timeout
+        .map(|secs| stage_start + Duration::from_secs(secs));
+    let budget = clippy_diagnostics::WarningBudget::from_config(
+        cli.max_warnings.or(config.clippy.max_warnings),
+        &config.clippy.lint_budgets,
+    );
+    let deny_warnings = budget.is_none() && !changes.lenient;
+    let workspace_root = get_workspace_root(git_root, cargo)?;
+    let args = ClippyArgs {
+        command: &config.clippy.command,
+        dylint: use_dylint(cli, &workspace_root),
+        deny_warnings,
+        target_args,
+        deadline,
+    };
+
+    // Skip members whose sources haven't changed since their last clean run
+    let mut cache = (!cli.no_clippy_cache)
+        .then(|| clippy_cache::ClippyCache::load(&clippy_cache::cache_path(git_root, cargo)));
+    let mut fingerprints = BTreeMap::new();
+    let mut members: BTreeSet<String> = member_manifests.keys().cloned().collect();
+    let mut cached = Vec::new();
+    if let Some(cache) = &cache {
+        fingerprints = clippy_fingerprints(git_root, member_manifests, args)?;
+        for (member, fingerprint) in &fingerprints {
+            if cache.is_fresh(member, fingerprint) {
+                eprintln!("Skipping clippy for {member} (cached)");
+                members.remove(member);
+                cached.push(member.clone());
+            }
+        }
+    }
+
+    let clippy = if members.is_empty() {
+        CargoRun {
+            success: true,
+            diagnostics: Vec::new(),
+            timed_out: false,
+        }
+    } else if cli.clippy_parallel {
+        let jobs = cli
+            .jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        run_cargo_clippy_parallel(git_root, cargo, &members, args, jobs, &mut summary.timings)?
+    } else {
+        run_cargo_clippy_batched(
+            git_root,
+            cargo,
+            &members,
+            args,
+            config.clippy.batch_size,
+            &mut summary.timings,
+        )?
+    };
+    summary
+        .timings
+        .record_stage("clippy", stage_start.elapsed());
+    if clippy.timed_out {
+        summary
+            .skipped
+            .insert("clippy".to_string(), "skipped (timeout)".to_string());
+        bail!(
+            "Clippy did not finish within {}s, skipped (timeout)",
+            cli.timeout.unwrap_or_default()
+        );
+    }
+
+    // Only clean runs are cached: a cached member reports no diagnostics, so
+    // caching a run with warnings would hide them from the budget
+    let clean = clippy.success && clippy.diagnostics.iter().all(|d| d.is_summary());
+    if let Some(cache) = cache.as_mut().filter(|_| clean) {
+        for member in &members {
+            if let Some(fingerprint) = fingerprints.get(member) {
+                cache.record_pass(member, fingerprint);
+            }
+        }
+        if let Err(e) = cache.save() {
+            eprintln!("Failed to save clippy cache: {e:#}");
+        }
+    }
+
+    let changed_files = (!cli.show_all_lints).then_some(rust_files);
+    let (mut report, shown) = report_clippy_diagnostics(
+        git_root,
+        &workspace_root,
+        &clippy.diagnostics,
+        changed_files,
+        cli.ci || cli.deterministic,
+    );
+    for (lint, count) in &report.lints {
+        eprintln!("{count:>5} {lint}");
+    }
+    if report.hidden > 0 {
+        eprintln!(
+            "{} diagnostic(s) outside the changed files hidden (use --show-all-lints to show them)",
+            report.hidden
+        );
+    }
+    // Without `-D warnings`, warnings outside `[adoption] include` are only
+    // shown; those inside still fail the run or count against the budget
+    let adopted_warnings: Vec<_> = shown
+        .iter()
+        .copied()
+        .filter(|d| {
+            d.primary_file().is_none_or(|file| {
+                let file = workspace_root.join(file);
+                config
+                    .adoption
+                    .includes(file.strip_prefix(git_root).unwrap_or(&file))
+            })
+        })
+        .collect();
+    let strict_failed = changes.lenient && budget.is_none() && !adopted_warnings.is_empty();
+    let budget_report = budget.map(|budget| budget.consume(&adopted_warnings));
+    if let Some(budget_report) = &budget_report {
+        eprint!("{}", budget_report.render());
+    }
+    let budget_exceeded = budget_report.as_ref().is_some_and(|b| b.exceeded);
+    report.budget = budget_report;
+    cached.sort();
+    report.cached = cached;
+    summary.clippy = Some(report);
+
+    if !clippy.success {
+        bail!("cargo clippy found warnings");
+    }
+    if strict_failed {
+        bail!(
+            "cargo clippy found {} warning(s) under [adoption] include",
+            adopted_warnings.len()
+        );
+    }
+    if budget_exceeded {
+        bail!("clippy warnings exceed the configured budget");
+    }
+    Ok(())
+}
+
+/// Cache fingerprints of the affected members for a clippy run with these
+/// settings.
+fn clippy_fingerprints(
+    git_root: &Path,
+    member_manifests: &BTreeMap<String, PathBuf>,
+    args: ClippyArgs,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let args = format!(
+        "command={:?} dylint={} deny_warnings={} targets={:?}",
+        args.command, args.dylint, args.deny_warnings, args.target_args
+    );
+    member_manifests
+        .iter()
+        .map(|(member, manifest)| {
+            Ok((
+                member.clone(),
+                clippy_cache::fingerprint(git_root, manifest, &args)?,
+            ))
+        })
+        .collect()
+}
+
+/// The commands the fmt and clippy stages would run for `changes`, in stage
+/// order. Read-only queries (changed lines, the clippy cache) are still made
+/// to work them out.
+fn preview_commands(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+) -> anyhow::Result<Vec<String>> {
+    let git_root = &changes.git_root;
+    let cargo = &changes.cargo;
+    let mut commands = Vec::new();
+    if changes.members.is_empty() {
+        return Ok(commands);
+    }
+    for stage in &config.stages {
+        match stage {
+            config::Stage::Fmt if !cli.no_fmt => {
+                let overrides =
+                    rustfmt::ConfigOverrides::new(&config.rustfmt, &cli.rustfmt_config)?;
+                let mut plan = FastFmtPlan::default();
+                if cli.changed_lines {
+                    let nightly = rustfmt::is_nightly();
+                    for file in &changes.handwritten {
+                        let manifest = changes.manifest_for(file)?;
+                        let edition = find_package_edition(git_root, &manifest)?;
+                        if !nightly {
+                            plan.alone.push((file.clone(), edition));
+                            continue;
+                        }
+                        let ranges = rustfmt::changed_line_ranges(git_root, file, &changes.base)?;
+                        if !ranges.is_empty() {
+                            let cmd =
+                                rustfmt::line_ranges_command(file, &edition, &ranges, &overrides);
+                            commands.push(format!("{cmd:?}"));
+                        }
+                    }
+                } else if cli.fast_fmt() {
+                    plan = plan_changed_files(changes)?;
+                } else {
+                    plan.fallback_members = changes.member_names();
+                }
+                for (file, edition) in &plan.alone {
+                    let cmd = rustfmt::file_alone_command(file, edition, &overrides);
+                    commands.push(format!("{cmd:?} < {}", git_root.join(file).display()));
+                }
+                if !plan.fallback_members.is_empty() {
+                    let cmd =
+                        cargo_fmt_command(git_root, cargo, &plan.fallback_members, &overrides);
+                    commands.push(format!("{cmd:?}"));
+                }
+            }
+            config::Stage::Clippy if !cli.no_clippy => {
+                let members = changes.member_names();
+                if cli.check_first {
+                    let cmd = cargo_check_command(git_root, cargo, &members, &changes.target_args);
+                    commands.push(format!("{cmd:?}"));
+                }
+                let budget = clippy_diagnostics::WarningBudget::from_config(
+                    cli.max_warnings.or(config.clippy.max_warnings),
+                    &config.clippy.lint_budgets,
+                );
+                let workspace_root = get_workspace_root(git_root, cargo)?;
+                let args = ClippyArgs {
+                    command: &config.clippy.command,
+                    dylint: use_dylint(cli, &workspace_root),
+                    deny_warnings: budget.is_none() && !changes.lenient,
+                    target_args: &changes.target_args,
+                    deadline: None,
+                };
+                let mut members: Vec<String> = changes.members.keys().cloned().collect();
+                if !cli.no_clippy_cache {
+                    let cache =
+                        clippy_cache::ClippyCache::load(&clippy_cache::cache_path(git_root, cargo));
+                    let fingerprints = clippy_fingerprints(git_root, &changes.members, args)?;
+                    members.retain(|member| !cache.is_fresh(member, &fingerprints[member]));
+                }
+                if members.is_empty() {
+                    continue;
+                }
+                let mut lint = |cargo: &CargoOptions, members: &BTreeSet<String>| {
+                    let cmd = cargo_clippy_command(git_root, cargo, members, args);
+                    commands.push(format!("{cmd:?}"));
+                    if args.dylint {
+                        let cmd = cargo_dylint_command(git_root, cargo, members, args.target_args);
+                        commands.push(format!("{cmd:?}"));
+                    }
+                };
+                if cli.clippy_parallel {
+                    // Jobs take members from the queue in this order, though
+                    // which slot runs each one depends on timing
+                    let jobs = cli.jobs.unwrap_or_else(|| {
+                        std::thread::available_parallelism().map_or(1, |n| n.get())
+                    });
+                    let slots = jobs.clamp(1, members.len());
+                    for (i, member) in members.iter().enumerate() {
+                        lint(
+                            &clippy_slot_options(git_root, cargo, i % slots),
+                            &BTreeSet::from([member.clone()]),
+                        );
+                    }
+                } else {
+                    let members = members.into_iter().collect();
+                    for batch in clippy_batches(&members, config.clippy.batch_size) {
+                        lint(cargo, &batch);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(commands)
+}
+
+/// How `commit_files` records the fixes.
+enum Commit<'a> {
+    /// A new commit with this message
+    New(&'a str),
+    /// Folded into HEAD, keeping its message
+    Amend,
+}
+
+/// Commits just `files`, whatever else is staged or modified, and returns the
+/// new commit's hash. Nothing is committed if `files` is empty.
+fn commit_files(
+    git_root: &Path,
+    files: &[PathBuf],
+    commit: Commit,
+) -> anyhow::Result<Option<String>> {
+    if files.is_empty() {
+        eprintln!("No fixes to commit");
+        return Ok(None);
+    }
+    let mut cmd = Command::new("git");
+    cmd.args(["commit", "--quiet", "--only"]);
+    match commit {
+        Commit::New(message) => cmd.args(["-m", message]),
+        Commit::Amend => cmd.args(["--amend", "--no-edit"]),
+    };
+    cmd.arg("--").args(files).current_dir(git_root);
+    eprintln!("Running {cmd:?}");
+    let status = cmd.status().context("Failed to run git commit")?;
+    if !status.success() {
+        bail!("git commit failed");
+    }
+
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to read the new commit")?;
+    let hash = String::from_utf8(output.stdout)?.trim().to_string();
+    eprintln!("Committed {} fixed file(s) in {hash}", files.len());
+    Ok(Some(hash))
+}
+
+/// Refuses `--amend` when HEAD shouldn't be rewritten or the fixes couldn't
+/// be told apart from other changes: during a merge, with uncommitted
+/// changes, or (without `force`) when HEAD is on a remote branch already.
+fn ensure_amendable(git_root: &Path, force: bool) -> anyhow::Result<()> {
+    let git = |args: &[&str]| -> anyhow::Result<std::process::Output> {
+        Command::new("git")
+            .args(args)
+            .current_dir(git_root)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))
+    };
+
+    if git(&["rev-parse", "-q", "--verify", "MERGE_HEAD"])?
+        .status
+        .success()
+    {
+        bail!("Can't amend while a merge is in progress");
+    }
+    let status = git(&["status", "--porcelain", "--untracked-files=no"])?;
+    if !status.stdout.is_empty() {
+        bail!("Can't amend with uncommitted changes; commit or stash them first");
+    }
+    if !force {
+        let remotes = git(&["branch", "-r", "--contains", "HEAD"])?;
+        let remotes = String::from_utf8_lossy(&remotes.stdout);
+        if !remotes.trim().is_empty() {
+            bail!(
+                "HEAD is already pushed to {}; use --force to amend it anyway",
+                remotes.split_whitespace().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn git_push(git_root: &Path) -> anyhow::Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.arg("push").current_dir(git_root);
+    eprintln!("Running {cmd:?}");
+    let status = cmd.status().context("Failed to run git push")?;
+    if !status.success() {
+        bail!("git push failed");
+    }
+    Ok(())
+}
+
+fn is_git_repo() -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-dir"])
+        .output()?;
+    Ok(output.status.success())
+}
+
+fn get_git_root() -> anyhow::Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .context("Failed to get git root")?;
+
+    if !output.status.success() {
+        bail!("Failed to get git root");
+    }
+
+    let path = String::from_utf8(output.stdout)?.trim().to_string();
+    Ok(PathBuf::from(path))
+}
+
+fn changed_files_command(base: &str, roots: &[PathBuf], filter: Option<&str>) -> Command {
+    // Get changed files (staged and unstaged), by default excluding deleted
+    // files. NUL-separated output keeps paths unquoted, whatever
+    // core.quotepath says
+    let mut cmd = Command::new("git");
+    cmd.args(["diff", "-z", "--find-renames"]);
+    if let Some(filter) = filter {
+        cmd.arg(format!("--diff-filter={filter}"));
+    }
+    cmd.args(["--name-status", base]);
+    // Pathspecs are relative to the current directory; `:/` anchors them
+    // at the repository root
+    if !roots.is_empty() {
+        cmd.arg("--");
+        cmd.args(roots.iter().map(|root| format!(":/{}", root.display())));
+    }
+    cmd
+}
+
+/// Whether `file` (relative to the git root) is under one of `roots`.
+fn in_rust_roots(git_root: &Path, roots: &[PathBuf], file: &Path) -> bool {
+    let file = git_root.join(file);
+    roots
+        .iter()
+        .any(|root| file.starts_with(git_root.join(root)))
+}
+
+/// Revision changes are detected against: `--since`, or with `--ci` the
+/// merge base with the default branch (what `origin/main...HEAD` diffs
+/// against), or else the parent commit.
+fn diff_base(cli: &Cli, git_root: &Path) -> anyhow::Result<String> {
+    if let Some(pattern) = &cli.since_tag {
+        let tag = latest_tag(git_root, pattern)?;
+        eprintln!("Diff base: {tag}");
+        return Ok(tag);
+    }
+    if !cli.ci || cli.since.is_some() {
+        let rev = cli.since.clone().unwrap_or_else(|| "HEAD~1".to_string());
+        // Explicit files and the index don't come from the diff
+        if cli.files.is_empty() && cli.lines.is_empty() && !cli.staged {
+            ensure_available(cli, git_root, &rev)?;
+        }
+        return Ok(rev);
+    }
+
+    let branch = default_branch::detect(git_root, |var| std::env::var(var).ok())?;
+    let mut deepened = 0;
+    loop {
+        if let Some(base) = merge_base(git_root, &branch)? {
+            eprintln!("Diff base: {branch} ({base})");
+            return Ok(base);
+        }
+        if !is_shallow(git_root)? {
+            bail!("{branch} and HEAD have no common ancestor; pass --since <REV>");
+        }
+        match cli.deepen {
+            Some(depth) if deepened < MAX_DEEPEN => {
+                deepen(git_root, depth)?;
+                deepened += 1;
+            }
+            _ => {
+                eprintln!(
+                    "warning: the merge base of {branch} and HEAD is outside this shallow \
+                     clone; diffing against {branch} itself, which also picks up its newer \
+                     changes (fetch more history or pass --deepen <N>)"
+                );
+                return Ok(branch);
+            }
+        }
+    }
+}
+
+/// How many times `--deepen` fetches more history before giving up.
+const MAX_DEEPEN: usize = 10;
+
+/// Makes sure `rev` is present, deepening a shallow clone with `--deepen`.
+fn ensure_available(cli: &Cli, git_root: &Path, rev: &str) -> anyhow::Result<()> {
+    let mut deepened = 0;
+    while !default_branch::exists(git_root, rev) {
+        if !is_shallow(git_root)? {
+            bail!(
+                "Unknown revision {rev} (is HEAD the first commit?); pass --since <REV> or --files"
+            );
+        }
+        match cli.deepen {
+            Some(depth) if deepened < MAX_DEEPEN => {
+                deepen(git_root, depth)?;
+                deepened += 1;
+            }
+            _ => bail!(
+                "{rev} is missing from this shallow clone: fetch more history (`git fetch \
+                 --deepen=1`, or `fetch-depth: 0` with actions/checkout), or pass --deepen <N>"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Merge base of `branch` and `HEAD`, `None` if git finds none.
+fn merge_base(git_root: &Path, branch: &str) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .args(["merge-base", branch, "HEAD"])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to run git merge-base")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
+}
+
+/// The most recent tag reachable from HEAD whose name matches the glob
+/// `pattern`.
+fn latest_tag(git_root: &Path, pattern: &str) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .args(["describe", "--tags", "--abbrev=0", "--match", pattern])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to run git describe")?;
+    if !output.status.success() {
+        bail!(
+            "No tag matching `{pattern}` is reachable from HEAD (in a shallow clone, fetch \
+             with `git fetch --tags --unshallow`)"
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+fn is_shallow(git_root: &Path) -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--is-shallow-repository"])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to check for a shallow clone")?;
+    Ok(String::from_utf8(output.stdout)?.trim() == "true")
+}
+
+fn deepen(git_root: &Path, depth: usize) -> anyhow::Result<()> {
+    eprintln!("Shallow clone: fetching {depth} more commit(s) of history");
+    let status = Command::new("git")
+        .args(["fetch", "--quiet", &format!("--deepen={depth}"), "origin"])
+        .current_dir(git_root)
+        .status()
+        .context("Failed to run git fetch")?;
+    if !status.success() {
+        bail!("git fetch --deepen={depth} failed");
+    }
+    Ok(())
+}
+
+/// Files with unstaged edits that the run may modify: the processed files,
+/// and the affected members' sources when `cargo fmt -p` formats them whole.
+fn dirty_overlap(
+    cli: &Cli,
+    config: &config::Config,
+    changes: &Changes,
+    files: &[PathBuf],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .args(["diff", "-z", "--name-only", "--no-color"])
+        .current_dir(&changes.git_root)
+        .output()
+        .context("Failed to list unstaged changes")?;
+    if !output.status.success() {
+        bail!("Failed to list unstaged changes");
+    }
+
+    let formats_packages = config.stages.contains(&config::Stage::Fmt)
+        && !cli.no_fmt
+        && !cli.changed_lines
+        && !cli.fast_fmt();
+    let member_dirs: Vec<PathBuf> = changes
+        .members
+        .values()
+        .filter_map(|manifest| manifest.parent())
+        .map(Path::to_path_buf)
+        .collect();
+    Ok(git_output::split_paths(&output.stdout)
+        .into_iter()
+        .filter(|file| {
+            files.contains(file)
+                || (formats_packages
+                    && file.extension() == Some(ffi::OsStr::new("rs"))
+                    && member_dirs
+                        .iter()
+                        .any(|dir| normalize_path(&changes.git_root.join(file)).starts_with(dir)))
+        })
+        .collect())
+}
+
+fn get_changed_files(
+    base: &str,
+    roots: &[PathBuf],
+    filter: Option<&str>,
+) -> anyhow::Result<git_output::NameStatus> {
+    let output = changed_files_command(base, roots, filter)
+        .output()
+        .context("Failed to get changed files")?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to get changed files: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    git_output::parse_name_status(&output.stdout)
+}
+
+/// The files owned by `config.me` (or git's `user.email`), per the
+/// configured rules or else the repository's CODEOWNERS.
+fn owned_files(
+    git_root: &Path,
+    config: &config::OwnersConfig,
+    files: Vec<(PathBuf, FileType)>,
+) -> anyhow::Result<Vec<(PathBuf, FileType)>> {
+    let rules = if !config.rules.is_empty() {
+        config.rules.clone()
+    } else {
+        let path = codeowners::find(git_root)
+            .context("--owned-only needs a CODEOWNERS file or [[owners.rules]] in polish.toml")?;
+        codeowners::load(&path)?
+    };
+    let me = if config.me.is_empty() {
+        let output = Command::new("git")
+            .args(["config", "user.email"])
+            .current_dir(git_root)
+            .output()
+            .context("Failed to run git config")?;
+        let email = String::from_utf8(output.stdout)?.trim().to_string();
+        if email.is_empty() {
+            bail!("--owned-only needs `me` in [owners] of polish.toml or git's user.email");
+        }
+        // Owners are usually listed as `@handle`s and `@org/team`s, which
+        // no email matches; filtering by it would skip every file
+        if !codeowners::mentions(&rules, &email) {
+            bail!(
+                "No ownership rule lists {email}, git's user.email; set `me` in [owners] of \
+                 polish.toml to your handles and teams, e.g. me = [\"@you\", \"@org/team\"]"
+            );
+        }
+        vec![email]
+    } else {
+        config.me.clone()
+    };
+
+    let (owned, others): (Vec<_>, Vec<_>) = files.into_iter().partition(|(file, _)| {
+        let absolute = git_root.join(file);
+        let relative = absolute.strip_prefix(git_root).unwrap_or(file);
+        codeowners::is_owned(&rules, &relative.to_string_lossy().replace('\\', "/"), &me)
+    });
+    if !others.is_empty() {
+        eprintln!(
+            "Skipping {} changed file(s) not owned by {}",
+            others.len(),
+            me.join(", ")
+        );
+    }
+    if owned.is_empty() && !others.is_empty() {
+        eprintln!(
+            "warning: {} own none of the changed files, so nothing is checked",
+            me.join(", ")
+        );
+    }
+    Ok(owned)
+}
+
+/// Directories whose changes are never processed, relative to the
+/// repository root: cargo's target directory, which git only reports when
+/// `.gitignore` misses it, and `exclude_dirs`. The target directory is
+/// `target/` when cargo metadata can't tell.
+fn excluded_dirs(git_root: &Path, cargo: &CargoOptions, exclude_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    // Quietly: a repository without a root workspace falls back
+    let target_directory = cargo
+        .command("metadata")
+        .args(["--format-version", "1", "--no-deps"])
+        .current_dir(git_root)
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| metadata::Metadata::parse(&output.stdout).ok())
+        .and_then(|metadata| metadata.target_directory)
+        .unwrap_or_else(|| git_root.join("target"));
+    // A target directory outside the repository has nothing git reports
+    let target = target_directory
+        .strip_prefix(git_root)
+        .ok()
+        .map(Path::to_path_buf);
+    target
+        .into_iter()
+        .chain(exclude_dirs.iter().cloned())
+        .collect()
+}
+
+/// The Rust files and manifests among `paths`, skipping those under
+/// `excluded` (relative to the repository root).
+fn classify_files(
+    paths: &[PathBuf],
+    excluded: &[PathBuf],
+) -> anyhow::Result<Vec<(PathBuf, FileType)>> {
+    let mut result = Vec::new();
+    let mut skipped = Vec::new();
+
+    for path in paths {
+        if excluded.iter().any(|dir| path.starts_with(dir)) {
+            skipped.push(path);
+            continue;
+        }
+        if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
+            if file_name == "Cargo.toml" {
+                result.push((path.clone(), FileType::CargoToml));
+            } else if path.extension() == Some(ffi::OsStr::new("rs")) {
+                result.push((path.clone(), FileType::Rust));
+            }
+            // Ignore other file types
+        }
+    }
+    if !skipped.is_empty() {
+        eprintln!(
+            "Skipping {} file(s) under the target directory or exclude_dirs: {:?}",
+            skipped.len(),
+            skipped
+        );
+    }
+
+    Ok(result)
+}
+
+/// The `Cargo.toml` closest to `file`, looking no higher than the Rust root
+/// containing it (the git root when no `rust_roots` are configured).
+fn find_manifest_for_file(
+    git_root: &Path,
+    roots: &[PathBuf],
+    file: &Path,
+) -> anyhow::Result<PathBuf> {
+    // Start from the file's directory
+    let full_path = git_root.join(file);
+    let boundary = roots
+        .iter()
+        .map(|root| git_root.join(root))
+        .filter(|root| full_path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .unwrap_or_else(|| git_root.to_path_buf());
+    let mut current_dir = if full_path.is_file() {
+        full_path.parent()
+    } else {
+        Some(full_path.as_path())
+    };
+
+    // Walk up the directory tree until we find a Cargo.toml or reach the root
+    while let Some(dir) = current_dir {
+        let cargo_toml = dir.join("Cargo.toml");
+        if cargo_toml.exists() {
+            return Ok(cargo_toml);
+        }
+        anyhow::ensure!(
+            dir != boundary,
+            "Can't go beyond {} looking for the Cargo.toml of {}",
+            if boundary == git_root {
+                "git's root directory".to_string()
+            } else {
+                boundary.display().to_string()
+            },
+            file.display()
+        );
+        // Go up one directory
+        current_dir = dir.parent();
+    }
+    anyhow::bail!("There are no Cargo.toml in the repo?")
+}
+
+fn extract_package_name(toml_content: &str) -> anyhow::Result<String> {
+    extract_section_field(toml_content, "package", "name")
+        .context("Could not find package name in Cargo.toml")
+}
+
+/// Reads the edition of the package owning `manifest`, following
+/// `edition.workspace = true` to the workspace root. Cargo defaults to 2015.
+fn find_package_edition(git_root: &Path, manifest: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {}", manifest.display()))?;
+    if let Some(edition) = extract_section_field(&content, "package", "edition") {
+        return Ok(edition);
+    }
+    if extract_section_field(&content, "package", "edition.workspace").is_none() {
+        return Ok("2015".to_string());
+    }
+
+    let mut current_dir = manifest.parent().and_then(Path::parent);
+    while let Some(dir) = current_dir {
+        let cargo_toml = dir.join("Cargo.toml");
+        if let Ok(content) = std::fs::read_to_string(&cargo_toml) {
+            if let Some(edition) = extract_section_field(&content, "workspace.package", "edition") {
+                return Ok(edition);
+            }
+        }
+        if dir == git_root {
+            break;
+        }
+        current_dir = dir.parent();
+    }
+    bail!(
+        "{} inherits its edition but no [workspace.package] edition was found",
+        manifest.display()
+    )
+}
+
+fn extract_section_field(toml_content: &str, section: &str, field: &str) -> Option<String> {
+    let header = format!("[{section}]");
+    let mut in_section = false;
+
+    for line in toml_content.lines() {
+        let trimmed = line.trim();
+
+        if trimmed == header {
+            in_section = true;
+            continue;
+        }
+
+        if in_section {
+            // Stop at next section
+            if trimmed.starts_with('[') {
+                break;
+            }
+
+            // Split on = and check if we have exactly 2 parts
+            let parts: Vec<&str> = trimmed.split('=').collect();
+            if parts.len() == 2 {
+                let key = parts[0].trim();
+                if key == field {
+                    let value = parts[1].trim();
+                    // Remove quotes
+                    let value = value.trim_matches('"').trim_matches('\'');
+                    return Some(value.to_string());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn cargo_fmt_command(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    overrides: &rustfmt::ConfigOverrides,
+) -> Command {
+    let mut cmd = cargo.command("fmt");
+    // Add -p flag for each member
+    for member in members {
+        cmd.arg("-p").arg(member);
+    }
+    if let Some(config) = overrides.to_arg(&[]) {
+        cmd.args(["--", "--config", &config]);
+    }
+    cmd.current_dir(git_root);
+    cmd
+}
+
+fn run_cargo_fmt(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    overrides: &rustfmt::ConfigOverrides,
+) -> anyhow::Result<()> {
+    let mut cmd = cargo_fmt_command(git_root, cargo, members, overrides);
+    eprintln!("Running {cmd:?}");
+    let (mut child, forward) = spawn_labeled(&mut cmd, cargo.label("fmt", members))?;
+    // Killing rustfmt could leave a file half-written
+    let status = interrupt::finish(&mut child);
+    if let Some(forward) = forward {
+        let _ = forward.join();
+    }
+    if !status?.success() {
+        bail!("cargo fmt failed");
+    }
+    Ok(())
+}
+
+/// Formats only what changed: with a nightly rustfmt the changed line ranges
+/// are passed via `--file-lines`, otherwise each changed file is formatted on
+/// its own (through stdin, so its child modules are left untouched).
+fn format_changed_lines(
+    changes: &Changes,
+    overrides: &rustfmt::ConfigOverrides,
+) -> anyhow::Result<()> {
+    let git_root = &changes.git_root;
+    let nightly = rustfmt::is_nightly();
+    if !nightly {
+        eprintln!("rustfmt is not nightly, formatting changed files instead of changed lines");
+    }
+
+    for file in &changes.handwritten {
+        let manifest = changes.manifest_for(file)?;
+        let edition = find_package_edition(git_root, &manifest)?;
+        if nightly {
+            let ranges = rustfmt::changed_line_ranges(git_root, file, &changes.base)?;
+            if ranges.is_empty() {
+                continue;
+            }
+            rustfmt::format_line_ranges(file, &edition, &ranges, overrides)?;
+        } else {
+            rustfmt::format_file_alone(file, &edition, overrides)?;
+        }
+    }
+    Ok(())
+}
+
+/// `--lines` formatting: rustfmt on just the requested ranges of each file.
+fn format_requested_lines(
+    cli: &Cli,
+    changes: &Changes,
+    overrides: &rustfmt::ConfigOverrides,
+) -> anyhow::Result<()> {
+    if !rustfmt::is_nightly() {
+        eprintln!("rustfmt is not nightly, leaving the requested lines unformatted");
+        return Ok(());
+    }
+    for file in &changes.handwritten {
+        let manifest = changes.manifest_for(file)?;
+        let edition = find_package_edition(&changes.git_root, &manifest)?;
+        let ranges = cli.requested_lines(file).unwrap_or_default();
+        rustfmt::format_line_ranges(file, &edition, &ranges, overrides)?;
+    }
+    Ok(())
+}
+
+/// How `--fast-fmt` formats the changed files.
+#[derive(Default)]
+struct FastFmtPlan {
+    /// Files rustfmt formats on their own, with their package's edition.
+    alone: Vec<(PathBuf, String)>,
+    /// Members that need `cargo fmt -p` instead.
+    fallback_members: BTreeSet<String>,
+}
+
+fn plan_changed_files(changes: &Changes) -> anyhow::Result<FastFmtPlan> {
+    let git_root = &changes.git_root;
+    let changed_manifests: HashSet<PathBuf> = changes
+        .manifests
+        .iter()
+        .map(|m| normalize_path(&git_root.join(m)))
+        .collect();
+
+    let mut files_by_manifest: BTreeMap<PathBuf, Vec<&PathBuf>> = BTreeMap::new();
+    for file in &changes.rust_files {
+        let manifest = changes.manifest_for(file)?;
+        files_by_manifest.entry(manifest).or_default().push(file);
+    }
+
+    let mut alone = Vec::new();
+    let mut fallback_members = BTreeSet::new();
+    for (manifest, files) in &files_by_manifest {
+        let mut needs_package = changed_manifests.contains(&normalize_path(manifest));
+        for file in files {
+            if needs_package {
+                break;
+            }
+            needs_package = rustfmt::declares_new_modules(&rustfmt::added_lines(
+                git_root,
+                file,
+                &changes.base,
+            )?);
+        }
+
+        if needs_package {
+            let content = std::fs::read_to_string(manifest)
+                .with_context(|| format!("Failed to read {}", manifest.display()))?;
+            fallback_members.insert(extract_package_name(&content)?);
+            continue;
+        }
+
+        let edition = find_package_edition(git_root, manifest)?;
+        alone.extend(files.iter().map(|file| ((*file).clone(), edition.clone())));
+    }
+    Ok(FastFmtPlan {
+        alone,
+        fallback_members,
+    })
+}
+
+/// Formats exactly the changed files with rustfmt. Packages whose manifest
+/// changed, or whose changed files declare new modules, still go through
+/// `cargo fmt -p` since formatting single files would miss part of them.
+fn format_changed_files(
+    changes: &Changes,
+    overrides: &rustfmt::ConfigOverrides,
+) -> anyhow::Result<()> {
+    let plan = plan_changed_files(changes)?;
+    for (file, edition) in &plan.alone {
+        rustfmt::format_file_alone(file, edition, overrides)?;
+    }
+
+    if !plan.fallback_members.is_empty() {
+        run_cargo_fmt(
+            &changes.git_root,
+            &changes.cargo,
+            &plan.fallback_members,
+            overrides,
+        )?;
+    }
+    Ok(())
+}
+
+/// Result of a cargo check/clippy invocation: the exit status and every
+/// diagnostic rustc reported, in the order they were emitted.
+struct CargoRun {
+    success: bool,
+    diagnostics: Vec<clippy_diagnostics::Diagnostic>,
+    /// The process was killed for running past its deadline.
+    timed_out: bool,
+}
+
+/// With `deny_warnings` unset, warnings are left as warnings so that they
+/// can be counted against a budget instead of failing compilation.
+///
+/// A `clippy.command` other than clippy gets the `-p` flags right after its
+/// subcommand, and `--message-format=json` with the target selection in
+/// place of a `{cargo_args}` argument, else at the end. It doesn't get
+/// `-- -D warnings`, which it may not pass on to rustc: its warnings are
+/// denied by [`run_cargo_clippy`] instead.
+fn cargo_clippy_command(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    args: ClippyArgs,
+) -> Command {
+    let (subcommand, subcommand_args) = args
+        .command
+        .split_first()
+        .map_or(("clippy", &[][..]), |(first, rest)| (first.as_str(), rest));
+    let mut cmd = cargo.command(subcommand);
+    if !args.is_stock() {
+        for member in members {
+            cmd.arg("-p").arg(member);
+        }
+        let cargo_args = std::iter::once("--message-format=json".to_string())
+            .chain(args.target_args.iter().cloned());
+        if subcommand_args.iter().any(|arg| arg == CARGO_ARGS) {
+            let cargo_args: Vec<String> = cargo_args.collect();
+            for arg in subcommand_args {
+                match arg.as_str() {
+                    CARGO_ARGS => cmd.args(&cargo_args),
+                    _ => cmd.arg(arg),
+                };
+            }
+        } else {
+            cmd.args(subcommand_args).args(cargo_args);
+        }
+        cmd.current_dir(git_root);
+        return cmd;
+    }
+    cmd.args(subcommand_args);
+    cmd.arg("--message-format=json");
+    // Add -p flag for each member
+    for member in members {
+        cmd.arg("-p").arg(member);
+    }
+    cmd.args(args.target_args);
+    if args.deny_warnings {
+        cmd.args(["--", "-D", "warnings"]);
+    }
+    cmd.current_dir(git_root);
+    cmd
+}
+
+/// Stands for cargo's own arguments in a custom `clippy.command`.
+const CARGO_ARGS: &str = "{cargo_args}";
+
+/// How clippy is invoked for every batch or job of a clippy stage.
+#[derive(Clone, Copy)]
+struct ClippyArgs<'a> {
+    /// The cargo subcommand and its arguments, from `clippy.command`.
+    command: &'a [String],
+    /// Run dylint on the same members after clippy.
+    dylint: bool,
+    deny_warnings: bool,
+    target_args: &'a [String],
+    /// When clippy is killed, shared by all batches and jobs.
+    deadline: Option<Instant>,
+}
+
+impl ClippyArgs<'_> {
+    /// Whether the lint command is plain `cargo clippy`.
+    fn is_stock(&self) -> bool {
+        self.command.first().is_none_or(|first| first == "clippy")
+    }
+}
+
+fn run_cargo_clippy(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    args: ClippyArgs,
+    timings: Option<&mut timings::Timings>,
+) -> anyhow::Result<CargoRun> {
+    let cmd = cargo_clippy_command(git_root, cargo, members, args);
+    let label = cargo.label("clippy", members);
+    let mut run = run_cargo_with_diagnostics(cmd, label, members, args.deadline, timings)?;
+    if !args.is_stock() && args.deny_warnings {
+        run.success &= !run.diagnostics.iter().any(|d| !d.is_summary());
+    }
+    if args.dylint && !run.timed_out {
+        let cmd = cargo_dylint_command(git_root, cargo, members, args.target_args);
+        let label = cargo.label("dylint", members);
+        let dylint = run_cargo_with_diagnostics(cmd, label, members, args.deadline, None)?;
+        // Nothing passes `-D warnings` on to dylint's lints, so it's
+        // enforced on their findings here
+        let warned = dylint.diagnostics.iter().any(|d| !d.is_summary());
+        run.success &= dylint.success && !(args.deny_warnings && warned);
+        run.diagnostics.extend(dylint.diagnostics);
+        run.timed_out = dylint.timed_out;
+    }
+    Ok(run)
+}
+
+/// `cargo dylint` with every library the workspace lists; the arguments
+/// after `--` go to the `cargo check` it runs.
+fn cargo_dylint_command(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    target_args: &[String],
+) -> Command {
+    let mut cmd = cargo.command("dylint");
+    cmd.arg("--all");
+    for member in members {
+        cmd.arg("-p").arg(member);
+    }
+    cmd.arg("--").arg("--message-format=json").args(target_args);
+    cmd.current_dir(git_root);
+    cmd
+}
+
+/// Whether the workspace lists dylint libraries, under
+/// `[workspace.metadata.dylint]` in its manifest or in `dylint.toml`.
+fn dylint_configured(workspace_root: &Path) -> bool {
+    ["Cargo.toml", "dylint.toml"].iter().any(|file| {
+        let Ok(content) = std::fs::read_to_string(workspace_root.join(file)) else {
+            return false;
+        };
+        let Ok(table) = content.parse::<toml::Table>() else {
+            return false;
+        };
+        ["workspace", "metadata", "dylint", "libraries"]
+            .iter()
+            .try_fold(&toml::Value::Table(table), |value, key| value.get(key))
+            .and_then(toml::Value::as_array)
+            .is_some_and(|libraries| !libraries.is_empty())
+    })
+}
+
+/// Whether `--dylint` applies, printing why not if the workspace doesn't
+/// list any dylint libraries.
+fn use_dylint(cli: &Cli, workspace_root: &Path) -> bool {
+    if !cli.dylint {
+        return false;
+    }
+    let configured = dylint_configured(workspace_root);
+    if !configured {
+        eprintln!("No dylint libraries listed in the workspace, skipping dylint");
+    }
+    configured
+}
+
+/// `members` split into batches of at most `batch_size` packages.
+fn clippy_batches(members: &BTreeSet<String>, batch_size: usize) -> Vec<BTreeSet<String>> {
+    let members: Vec<String> = members.iter().cloned().collect();
+    members
+        .chunks(batch_size.max(1))
+        .map(|batch| batch.iter().cloned().collect())
+        .collect()
+}
+
+/// Runs clippy on `members` one batch after the other, so that hundreds of
+/// `-p` flags neither exceed the OS limit on arguments nor make one long
+/// command without progress.
+fn run_cargo_clippy_batched(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    args: ClippyArgs,
+    batch_size: usize,
+    timings: &mut timings::Timings,
+) -> anyhow::Result<CargoRun> {
+    let batches = clippy_batches(members, batch_size);
+    let mut merged = CargoRun {
+        success: true,
+        diagnostics: Vec::new(),
+        timed_out: false,
+    };
+    for (i, batch) in batches.iter().enumerate() {
+        if batches.len() > 1 {
+            eprintln!(
+                "Clippy batch {}/{} ({} package(s))",
+                i + 1,
+                batches.len(),
+                batch.len()
+            );
+        }
+        let run = run_cargo_clippy(git_root, cargo, batch, args, Some(timings))?;
+        merged.success &= run.success;
+        merged.diagnostics.extend(run.diagnostics);
+        if run.timed_out {
+            merged.timed_out = true;
+            break;
+        }
+    }
+    Ok(merged)
+}
+
+/// Cargo settings for one job slot of a parallel clippy run.
+fn clippy_slot_options(git_root: &Path, cargo: &CargoOptions, slot: usize) -> CargoOptions {
+    let base_dir = cargo
+        .target_dir
+        .clone()
+        .unwrap_or_else(|| git_root.join("target"));
+    CargoOptions {
+        target_dir: Some(base_dir.join(format!("clippy-{slot}"))),
+        ..cargo.clone()
+    }
+}
+
+/// Runs one clippy process per member, at most `jobs` at a time, and merges
+/// their diagnostics. Each job slot builds into its own target directory so
+/// the processes don't serialize on cargo's build directory lock; the slot
+/// directories are reused across runs to keep their caches warm.
+fn run_cargo_clippy_parallel(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    args: ClippyArgs,
+    jobs: usize,
+    timings: &mut timings::Timings,
+) -> anyhow::Result<CargoRun> {
+    let mut queue: Vec<String> = members.iter().cloned().collect();
+    queue.sort_by(|a, b| b.cmp(a));
+    let queue = Mutex::new(queue);
+
+    let slots = jobs.clamp(1, members.len().max(1));
+    let results = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..slots)
+            .map(|slot| {
+                let cargo = clippy_slot_options(git_root, cargo, slot);
+                let queue = &queue;
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    loop {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop();
+                        let Some(member) = next else {
+                            break;
+                        };
+                        let start = Instant::now();
+                        let single = BTreeSet::from([member.clone()]);
+                        let run = run_cargo_clippy(git_root, &cargo, &single, args, None);
+                        results.push((member, start.elapsed(), run));
+                    }
+                    results
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .collect::<Vec<_>>()
+    });
+
+    let mut merged = CargoRun {
+        success: true,
+        diagnostics: Vec::new(),
+        timed_out: false,
+    };
+    for slot_results in results {
+        let slot_results =
+            slot_results.map_err(|_| anyhow::anyhow!("A parallel clippy job panicked"))?;
+        for (member, elapsed, run) in slot_results {
+            let run = run?;
+            timings.record_package(&member, elapsed);
+            merged.success &= run.success;
+            merged.diagnostics.extend(run.diagnostics);
+            merged.timed_out |= run.timed_out;
+        }
+    }
+    Ok(merged)
+}
+
+fn cargo_check_command(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    target_args: &[String],
+) -> Command {
+    let mut cmd = cargo.command("check");
+    cmd.arg("--message-format=json");
+    // Add -p flag for each member
+    for member in members {
+        cmd.arg("-p").arg(member);
+    }
+    cmd.args(target_args);
+    cmd.current_dir(git_root);
+    cmd
+}
+
+fn run_cargo_check(
+    git_root: &Path,
+    cargo: &CargoOptions,
+    members: &BTreeSet<String>,
+    target_args: &[String],
+) -> anyhow::Result<CargoRun> {
+    let cmd = cargo_check_command(git_root, cargo, members, target_args);
+    let label = cargo.label("check", members);
+    run_cargo_with_diagnostics(cmd, label, members, None, None)
+}
+
+/// Runs a cargo command with `--message-format=json`, collecting its
+/// diagnostics and, if `timings` is given, the time spent per member. A
+/// command still running at `deadline` is killed. With a `label`, its
+/// stderr is prefixed by it.
+fn run_cargo_with_diagnostics(
+    mut cmd: Command,
+    label: Option<String>,
+    members: &BTreeSet<String>,
+    deadline: Option<Instant>,
+    mut timings: Option<&mut timings::Timings>,
+) -> anyhow::Result<CargoRun> {
+    eprintln!("Running {cmd:?}");
+    let (mut child, forward) = spawn_labeled(cmd.stdout(Stdio::piped()), label)?;
+
+    // Each finished artifact closes the interval since the previous one,
+    // which approximates per-package cost.
+    let stdout = child
+        .stdout
+        .take()
+        .context("Failed to capture cargo output")?;
+    let read_output = || -> anyhow::Result<Vec<clippy_diagnostics::Diagnostic>> {
+        let mut package_names: HashMap<PathBuf, Option<String>> = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let mut last_mark = Instant::now();
+        for line in BufReader::new(stdout).lines() {
+            let line = line.context("Failed to read cargo output")?;
+            let Some(message) = clippy_diagnostics::parse_message(&line) else {
+                continue;
+            };
+            match message {
+                clippy_diagnostics::CargoMessage::CompilerMessage { message } => {
+                    if !message.is_summary() {
+                        let span = message.primary_span();
+                        progress::emit(progress::Event::Diagnostic {
+                            file: span.map(|s| Path::new(&s.file_name)),
+                            line: span.map(|s| s.line_start),
+                            level: &message.level,
+                            lint: &message.lint_name(),
+                            message: &message.message,
+                        });
+                    }
+                    diagnostics.push(message);
+                }
+                clippy_diagnostics::CargoMessage::CompilerArtifact { manifest_path } => {
+                    let elapsed = last_mark.elapsed();
+                    last_mark = Instant::now();
+                    let Some(timings) = timings.as_deref_mut() else {
+                        continue;
+                    };
+                    let name = package_names
+                        .entry(manifest_path.clone())
+                        .or_insert_with(|| {
+                            std::fs::read_to_string(&manifest_path)
+                                .ok()
+                                .and_then(|content| extract_package_name(&content).ok())
+                        });
+                    if let Some(name) = name.as_ref().filter(|n| members.contains(*n)) {
+                        timings.record_package(name, elapsed);
+                    }
+                }
+                clippy_diagnostics::CargoMessage::Other => {}
+            }
+        }
+        Ok(diagnostics)
+    };
+
+    // Output is read on its own thread so that this one can watch the clock
+    let (status, diagnostics) = std::thread::scope(|scope| {
+        let reader = scope.spawn(read_output);
+        let status = interrupt::wait(&mut child, deadline);
+        let diagnostics = reader
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Reading cargo output panicked")));
+        if let Some(forward) = forward {
+            let _ = forward.join();
+        }
+        (status, diagnostics)
+    });
+    let status = status.with_context(|| format!("Failed to run {cmd:?}"))?;
+    if status.is_none() {
+        eprintln!("Killed {cmd:?} (timeout)");
+    }
+    Ok(CargoRun {
+        success: status.is_some_and(|s| s.success()),
+        diagnostics: diagnostics?,
+        timed_out: status.is_none(),
+    })
+}
+
+/// Prints clippy diagnostics, limited to the changed files unless
+/// `changed_files` is `None`. Diagnostics repeated across targets or packages
+/// (same lint at the same span) are printed once. With `sort`, they are
+/// printed by location rather than in the order cargo finished the crates.
+fn report_clippy_diagnostics<'a>(
+    git_root: &Path,
+    workspace_root: &Path,
+    diagnostics: &'a [clippy_diagnostics::Diagnostic],
+    changed_files: Option<&[PathBuf]>,
+    sort: bool,
+) -> (ClippyReport, Vec<&'a clippy_diagnostics::Diagnostic>) {
+    let changed: Option<HashSet<PathBuf>> =
+        changed_files.map(|files| files.iter().map(|f| normalize_path(f)).collect());
+
+    let mut unique = clippy_diagnostics::dedup(diagnostics);
+    if sort {
+        unique.sort_by_key(|d| d.sort_key());
+    }
+    let mut report = ClippyReport {
+        duplicates: diagnostics.len() - unique.len(),
+        ..ClippyReport::default()
+    };
+    let mut shown_diagnostics = Vec::new();
+    for diagnostic in unique {
+        if diagnostic.is_summary() {
+            continue;
+        }
+        let shown = match (&changed, diagnostic.primary_file()) {
+            (None, _) | (Some(_), None) => true,
+            (Some(changed), Some(file)) => {
+                changed.contains(&normalize_path(&workspace_root.join(file)))
+            }
+        };
+        if shown {
+            eprint!("{}", diagnostic.render());
+            report.shown += 1;
+            *report.lints.entry(diagnostic.lint_name()).or_default() += 1;
+            if let Some(span) = diagnostic.primary_span() {
+                let file = workspace_root.join(&span.file_name);
+                report.diagnostics.push(ClippyLocation {
+                    file: file.strip_prefix(git_root).unwrap_or(&file).to_path_buf(),
+                    line: span.line_start,
+                    level: diagnostic.level.clone(),
+                    lint: diagnostic.lint_name(),
+                    message: diagnostic.message.clone(),
+                });
+            }
+            shown_diagnostics.push(diagnostic);
+        } else {
+            report.hidden += 1;
+        }
+    }
+    (report, shown_diagnostics)
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn get_workspace_root(git_root: &Path, cargo: &CargoOptions) -> anyhow::Result<PathBuf> {
+    let output = cargo
+        .command("locate-project")
+        .args(["--workspace", "--message-format", "plain"])
+        .current_dir(git_root)
+        .output()
+        .context("Failed to locate cargo workspace")?;
+
+    if !output.status.success() {
+        bail!("Failed to locate cargo workspace");
+    }
+
+    let manifest = PathBuf::from(String::from_utf8(output.stdout)?.trim());
+    manifest
+        .parent()
+        .map(Path::to_path_buf)
+        .context("Workspace manifest has no parent directory")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_lockfile_outdated() {
+        assert!(lockfile_outdated(
+            "error: the lock file /ws/Cargo.lock needs to be updated but --locked was passed \
+             to prevent this\n"
+        ));
+        assert!(lockfile_outdated(
+            "error: cannot update the lock file /ws/Cargo.lock because --locked was passed \
+             to prevent this\nhelp: to generate the lock file without accessing the network\n"
+        ));
+        assert!(!lockfile_outdated(
+            "error: failed to get `local` as a dependency of package `app`\n\nCaused by:\n  \
+             failed to read `/ws/local/Cargo.toml`\n"
+        ));
+    }
+
+    #[test]
+    fn test_diff_filter() {
+        let filter = |args: &[&str]| {
+            Cli::parse_from(["polish"].iter().chain(args))
+                .diff_filter()
+                .unwrap_or_default()
+        };
+        assert_eq!(filter(&[]), "d");
+        assert_eq!(filter(&["--include-deleted"]), "");
+        assert_eq!(filter(&["--diff-filter", "AM"]), "AM");
+        assert_eq!(filter(&["--diff-filter", "AM", "--include-deleted"]), "AMD");
+        assert_eq!(filter(&["--diff-filter", "dr", "--include-deleted"]), "r");
+        assert_eq!(filter(&["--diff-filter", "d", "--include-deleted"]), "");
+    }
+
+    #[test]
+    fn test_classify_files() {
+        let paths = [
+            "target/debug/build/out.rs",
+            "crates/app/src/target/mod.rs",
+            "crates/app/vendor/lib.rs",
+            "vendor/dep/src/lib.rs",
+            "crates/app/Cargo.toml",
+            "README.md",
+        ]
+        .map(PathBuf::from);
+        let excluded = [PathBuf::from("target"), PathBuf::from("vendor/")];
+        let kept: Vec<_> = classify_files(&paths, &excluded)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(kept, [paths[1].clone(), paths[2].clone(), paths[4].clone()]);
+        assert_eq!(classify_files(&paths, &[]).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_cargo_clippy_command() {
+        let members = BTreeSet::from(["a".to_string()]);
+        let target_args = ["--lib".to_string()];
+        let stock = ClippyArgs {
+            command: &[],
+            dylint: false,
+            deny_warnings: true,
+            target_args: &target_args,
+            deadline: None,
+        };
+        let cmd = cargo_clippy_command(Path::new("."), &CargoOptions::default(), &members, stock);
+        assert_eq!(
+            args(&cmd),
+            [
+                "clippy",
+                "--message-format=json",
+                "-p",
+                "a",
+                "--lib",
+                "--",
+                "-D",
+                "warnings"
+            ]
+        );
+
+        let dylint = ["dylint", "--all", "--", "{cargo_args}"].map(String::from);
+        let custom = ClippyArgs {
+            command: &dylint,
+            ..stock
+        };
+        let cmd = cargo_clippy_command(Path::new("."), &CargoOptions::default(), &members, custom);
+        assert_eq!(
+            args(&cmd),
+            [
+                "dylint",
+                "-p",
+                "a",
+                "--all",
+                "--",
+                "--message-format=json",
+                "--lib"
+            ]
+        );
+
+        let xlint = ["xlint", "--strict"].map(String::from);
+        let custom = ClippyArgs {
+            command: &xlint,
+            ..stock
+        };
+        let cmd = cargo_clippy_command(Path::new("."), &CargoOptions::default(), &members, custom);
+        assert_eq!(
+            args(&cmd),
+            [
+                "xlint",
+                "-p",
+                "a",
+                "--strict",
+                "--message-format=json",
+                "--lib"
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod temp_dir {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh directory under the system temp dir, removed with
+    /// everything in it when dropped, so a failing test cleans up too.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Creates `polish-{name}-{pid}-{n}`, unique across the tests
+        /// running in parallel in this process.
+        pub fn new(name: &str) -> TempDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "polish-{name}-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_removed_on_drop() {
+            let dir = TempDir::new("temp-dir");
+            let other = TempDir::new("temp-dir");
+            assert_ne!(dir.to_path_buf(), other.to_path_buf());
+            std::fs::create_dir_all(dir.join("a/b")).unwrap();
+            std::fs::write(dir.join("a/b/c.rs"), "").unwrap();
+            let path = dir.to_path_buf();
+            drop(dir);
+            assert!(!path.exists());
+            assert!(other.is_dir());
+        }
+    }
+}
+
+mod clippy_diagnostics {
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::PathBuf;
+
+    /// The subset of cargo's `--message-format=json` messages we care about.
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "reason", rename_all = "kebab-case")]
+    pub enum CargoMessage {
+        CompilerMessage {
+            message: Diagnostic,
+        },
+        CompilerArtifact {
+            manifest_path: PathBuf,
+        },
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Diagnostic {
+        pub message: String,
+        pub code: Option<DiagnosticCode>,
+        pub level: String,
+        pub spans: Vec<Span>,
+        pub rendered: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct DiagnosticCode {
+        pub code: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Span {
+        pub file_name: String,
+        pub line_start: usize,
+        pub line_end: usize,
+        pub column_start: usize,
+        pub column_end: usize,
+        pub is_primary: bool,
+    }
+
+    /// Identifies a diagnostic independently of which target or package
+    /// invocation reported it.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct DedupKey {
+        lint: String,
+        message: String,
+        span: Option<(String, usize, usize, usize, usize)>,
+    }
+
+    pub fn parse_message(line: &str) -> Option<CargoMessage> {
+        serde_json::from_str(line).ok()
+    }
+
+    /// Number of clippy warnings tolerated before the run fails. Lints with
+    /// their own budget don't count against `max_warnings`.
+    #[derive(Debug, Clone)]
+    pub struct WarningBudget {
+        pub max_warnings: usize,
+        pub lints: BTreeMap<String, usize>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct BudgetReport {
+        pub warnings: usize,
+        pub max_warnings: usize,
+        pub lints: BTreeMap<String, BudgetUsage>,
+        pub exceeded: bool,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct BudgetUsage {
+        pub used: usize,
+        pub allowed: usize,
+    }
+
+    impl WarningBudget {
+        /// Returns `None` when no budget is configured, i.e. any warning fails.
+        pub fn from_config(
+            max_warnings: Option<usize>,
+            lint_budgets: &BTreeMap<String, usize>,
+        ) -> Option<Self> {
+            if max_warnings.is_none() && lint_budgets.is_empty() {
+                return None;
+            }
+            Some(WarningBudget {
+                max_warnings: max_warnings.unwrap_or(0),
+                lints: lint_budgets.clone(),
+            })
+        }
+
+        pub fn consume(&self, diagnostics: &[&Diagnostic]) -> BudgetReport {
+            let mut report = BudgetReport {
+                warnings: 0,
+                max_warnings: self.max_warnings,
+                lints: self
+                    .lints
+                    .iter()
+                    .map(|(lint, &allowed)| (lint.clone(), BudgetUsage { used: 0, allowed }))
+                    .collect(),
+                exceeded: false,
+            };
+
+            for diagnostic in diagnostics.iter().filter(|d| d.level == "warning") {
+                match report.lints.get_mut(&diagnostic.lint_name()) {
+                    Some(usage) => usage.used += 1,
+                    None => report.warnings += 1,
+                }
+            }
+
+            report.exceeded = report.warnings > report.max_warnings
+                || report.lints.values().any(|u| u.used > u.allowed);
+            report
+        }
+    }
+
+    impl BudgetReport {
+        pub fn render(&self) -> String {
+            let mut out = format!(
+                "Warning budget: {}/{} used\n",
+                self.warnings, self.max_warnings
+            );
+            for (lint, usage) in &self.lints {
+                out.push_str(&format!(
+                    "  {lint}: {}/{} used\n",
+                    usage.used, usage.allowed
+                ));
+            }
+            out
+        }
+    }
+
+    /// Drops repeated diagnostics (same lint at the same span), keeping the
+    /// first occurrence. With `--all-targets` the lib and test targets of one
+    /// package routinely report identical warnings.
+    pub fn dedup(diagnostics: &[Diagnostic]) -> Vec<&Diagnostic> {
+        let mut seen = HashSet::new();
+        diagnostics
+            .iter()
+            .filter(|d| seen.insert(d.dedup_key()))
+            .collect()
+    }
+
+    impl Diagnostic {
+        pub fn primary_span(&self) -> Option<&Span> {
+            self.spans.iter().find(|s| s.is_primary)
+        }
+
+        /// Lint (or error code) name, e.g. `clippy::needless_return`.
+        pub fn lint_name(&self) -> String {
+            match &self.code {
+                Some(code) => code.code.clone(),
+                None => self.level.clone(),
+            }
+        }
+
+        fn dedup_key(&self) -> DedupKey {
+            DedupKey {
+                lint: self.lint_name(),
+                // Lints without a code (plain errors) are told apart by message
+                message: if self.code.is_some() {
+                    String::new()
+                } else {
+                    self.message.clone()
+                },
+                span: self.primary_span().map(|s| {
+                    (
+                        s.file_name.clone(),
+                        s.line_start,
+                        s.column_start,
+                        s.line_end,
+                        s.column_end,
+                    )
+                }),
+            }
+        }
+
+        /// Orders diagnostics by location, then lint and message.
+        pub fn sort_key(&self) -> (Option<(String, usize, usize)>, String, String) {
+            (
+                self.primary_span()
+                    .map(|s| (s.file_name.clone(), s.line_start, s.column_start)),
+                self.lint_name(),
+                self.message.clone(),
+            )
+        }
+
+        /// File of the primary span, relative to the workspace root.
+        pub fn primary_file(&self) -> Option<&str> {
+            self.primary_span().map(|s| s.file_name.as_str())
+        }
+
+        /// Whether this is one of rustc's trailing "aborting due to ..." or
+        /// "N warnings emitted" messages, which carry no location.
+        pub fn is_summary(&self) -> bool {
+            self.spans.is_empty()
+                && (self.message.starts_with("aborting due to")
+                    || self.message.ends_with("warning emitted")
+                    || self.message.ends_with("warnings emitted"))
+        }
+
+        pub fn render(&self) -> String {
+            match &self.rendered {
+                Some(rendered) => rendered.clone(),
+                None => format!("{}: {}\n", self.level, self.message),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_compiler_message() {
+            let line = r#"{"reason":"compiler-message","package_id":"a 0.1.0","manifest_path":"/ws/a/Cargo.toml","target":{},"message":{"message":"unused import: `std::fs`","code":{"code":"unused_imports","explanation":null},"level":"error","spans":[{"file_name":"a/src/lib.rs","byte_start":4,"byte_end":11,"line_start":2,"line_end":2,"column_start":5,"column_end":12,"is_primary":true,"text":[],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error: unused import\n"}}"#;
+
+            let Some(CargoMessage::CompilerMessage { message }) = parse_message(line) else {
+                panic!("expected a compiler message");
+            };
+            assert_eq!(message.primary_file(), Some("a/src/lib.rs"));
+            assert_eq!(message.lint_name(), "unused_imports");
+            assert_eq!(message.render(), "error: unused import\n");
+        }
+
+        #[test]
+        fn test_parse_other_messages() {
+            let artifact = r#"{"reason":"compiler-artifact","package_id":"a 0.1.0","manifest_path":"/ws/a/Cargo.toml","fresh":true}"#;
+            assert!(matches!(
+                parse_message(artifact),
+                Some(CargoMessage::CompilerArtifact { .. })
+            ));
+
+            let finished = r#"{"reason":"build-finished","success":true}"#;
+            assert!(matches!(parse_message(finished), Some(CargoMessage::Other)));
+
+            assert!(parse_message("not json").is_none());
+        }
+
+        #[test]
+        fn test_summary_messages() {
+            let summary = Diagnostic {
+                message: "aborting due to 2 previous errors".to_string(),
+                code: None,
+                level: "error".to_string(),
+                spans: Vec::new(),
+                rendered: None,
+            };
+            assert!(summary.is_summary());
+
+            let real = Diagnostic {
+                message: "can't find crate for `foo`".to_string(),
+                ..summary
+            };
+            assert!(!real.is_summary());
+        }
+
+        fn lint_at(lint: &str, file: &str, line: usize) -> Diagnostic {
+            Diagnostic {
+                message: format!("{lint} triggered"),
+                code: Some(DiagnosticCode {
+                    code: lint.to_string(),
+                }),
+                level: "warning".to_string(),
+                spans: vec![Span {
+                    file_name: file.to_string(),
+                    line_start: line,
+                    line_end: line,
+                    column_start: 1,
+                    column_end: 10,
+                    is_primary: true,
+                }],
+                rendered: None,
+            }
+        }
+
+        #[test]
+        fn test_budget_not_configured() {
+            assert!(WarningBudget::from_config(None, &BTreeMap::new()).is_none());
+        }
+
+        #[test]
+        fn test_budget_consumption() {
+            let lint_budgets = BTreeMap::from([("clippy::too_many_arguments".to_string(), 1)]);
+            let budget = WarningBudget::from_config(Some(2), &lint_budgets).unwrap();
+            let diagnostics = [
+                lint_at("clippy::needless_return", "a/src/lib.rs", 1),
+                lint_at("clippy::needless_return", "a/src/lib.rs", 2),
+                lint_at("clippy::too_many_arguments", "a/src/lib.rs", 3),
+            ];
+            let refs: Vec<&Diagnostic> = diagnostics.iter().collect();
+
+            let report = budget.consume(&refs);
+            assert_eq!(report.warnings, 2);
+            assert_eq!(
+                report.lints["clippy::too_many_arguments"],
+                BudgetUsage {
+                    used: 1,
+                    allowed: 1
+                }
+            );
+            assert!(!report.exceeded);
+
+            let over = budget.consume(&[refs[0], refs[1], refs[2], refs[2]]);
+            assert!(over.exceeded);
+        }
+
+        #[test]
+        fn test_budget_ignores_errors() {
+            let budget = WarningBudget::from_config(Some(0), &BTreeMap::new()).unwrap();
+            let mut error = lint_at("E0425", "a/src/lib.rs", 1);
+            error.level = "error".to_string();
+
+            let report = budget.consume(&[&error]);
+            assert_eq!(report.warnings, 0);
+            assert!(!report.exceeded);
+        }
+
+        #[test]
+        fn test_dedup_by_lint_and_span() {
+            let diagnostics = vec![
+                lint_at("clippy::needless_return", "a/src/lib.rs", 3),
+                lint_at("clippy::needless_return", "a/src/lib.rs", 3),
+                lint_at("clippy::needless_return", "a/src/lib.rs", 7),
+                lint_at("unused_imports", "a/src/lib.rs", 3),
+                lint_at("clippy::needless_return", "b/src/lib.rs", 3),
+            ];
+
+            let unique = dedup(&diagnostics);
+            assert_eq!(unique.len(), 4);
+        }
+    }
+}
+
+mod rustfmt {
+    use anyhow::{bail, Context};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    /// rustfmt options forced through `--config`, from the `[rustfmt]`
+    /// section of polish.toml and `--rustfmt-config` (which wins).
+    #[derive(Debug, Clone, Default)]
+    pub struct ConfigOverrides(BTreeMap<String, String>);
+
+    impl ConfigOverrides {
+        /// Fails on values `--config` can't carry: it splits its argument
+        /// on commas, so arrays, tables and values with a comma would turn
+        /// into other options.
+        pub fn new(
+            config: &BTreeMap<String, toml::Value>,
+            cli: &[(String, String)],
+        ) -> anyhow::Result<Self> {
+            let mut overrides = BTreeMap::new();
+            for (key, value) in config {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Array(_) | toml::Value::Table(_) => bail!(
+                        "[rustfmt] {key} in polish.toml is {}, which rustfmt's --config \
+                         can't take; set it in rustfmt.toml instead",
+                        value.type_str()
+                    ),
+                    other => other.to_string(),
+                };
+                overrides.insert(key.clone(), value);
+            }
+            overrides.extend(cli.iter().cloned());
+            if let Some((key, value)) = overrides.iter().find(|(_, value)| value.contains(',')) {
+                bail!(
+                    "rustfmt option {key} = {value} has a comma, which rustfmt's --config \
+                     can't take; set it in rustfmt.toml instead"
+                );
+            }
+            Ok(ConfigOverrides(overrides))
+        }
+
+        /// Value for rustfmt's `--config`; rustfmt rejects the flag given
+        /// twice, so `extra` options are merged into the same argument.
+        pub fn to_arg(&self, extra: &[(&str, &str)]) -> Option<String> {
+            let pairs: Vec<String> = self
+                .0
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .chain(extra.iter().map(|(k, v)| format!("{k}={v}")))
+                .collect();
+            (!pairs.is_empty()).then(|| pairs.join(","))
+        }
+
+        /// A copy with `key` set to `value`, replacing any existing override.
+        pub fn with(&self, key: &str, value: &str) -> ConfigOverrides {
+            let mut overrides = self.0.clone();
+            overrides.insert(key.to_string(), value.to_string());
+            ConfigOverrides(overrides)
+        }
+
+        pub fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).map(String::as_str)
+        }
+    }
+
+    pub fn parse_override(arg: &str) -> Result<(String, String), String> {
+        match arg.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(format!("expected KEY=VALUE, got `{arg}`")),
+        }
+    }
+
+    /// Inclusive range of 1-based line numbers.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LineRange {
+        pub start: usize,
+        pub end: usize,
+    }
+
+    pub fn is_nightly() -> bool {
+        Command::new("rustfmt")
+            .arg("--version")
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).contains("nightly"))
+            .unwrap_or(false)
+    }
+
+    /// Line ranges of `file` added or modified since `base`, in the
+    /// working tree. Untracked files count as changed entirely.
+    pub fn changed_line_ranges(
+        git_root: &Path,
+        file: &Path,
+        base: &str,
+    ) -> anyhow::Result<Vec<LineRange>> {
+        match diff_against_base(git_root, file, base)? {
+            Some(diff) => Ok(parse_hunk_ranges(&diff)),
+            None => {
+                let lines = std::fs::read_to_string(git_root.join(file))?
+                    .lines()
+                    .count();
+                Ok(vec![LineRange {
+                    start: 1,
+                    end: lines.max(1),
+                }])
+            }
+        }
+    }
+
+    /// Lines of `file` added since `base` (all lines for untracked files).
+    pub fn added_lines(git_root: &Path, file: &Path, base: &str) -> anyhow::Result<Vec<String>> {
+        match diff_against_base(git_root, file, base)? {
+            Some(diff) => Ok(diff
+                .lines()
+                .filter(|l| l.starts_with('+') && !l.starts_with("+++"))
+                .map(|l| l[1..].to_string())
+                .collect()),
+            None => Ok(std::fs::read_to_string(git_root.join(file))?
+                .lines()
+                .map(str::to_string)
+                .collect()),
+        }
+    }
+
+    /// `git diff -U0 <base>` of a single file, `None` if it is untracked.
+    fn diff_against_base(
+        git_root: &Path,
+        file: &Path,
+        base: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let output = Command::new("git")
+            .args(["diff", "--no-color", "-U0", base, "--"])
+            .arg(file)
+            .current_dir(git_root)
+            .output()
+            .context("Failed to get changed lines")?;
+
+        if !output.status.success() {
+            bail!("Failed to get changed lines of {}", file.display());
+        }
+
+        let diff = String::from_utf8(output.stdout)?;
+        if diff.is_empty() && !is_tracked(git_root, file)? {
+            return Ok(None);
+        }
+        Ok(Some(diff))
+    }
+
+    /// Whether the added lines attach modules to the tree (`mod foo;` or a
+    /// `#[path]` attribute). Formatting the file alone would not reach those
+    /// modules, so the whole package has to go through `cargo fmt`.
+    pub fn declares_new_modules(added_lines: &[String]) -> bool {
+        added_lines.iter().any(|line| {
+            let trimmed = line.trim();
+            if trimmed.starts_with("#[path") {
+                return true;
+            }
+            let decl = match trimmed.strip_prefix("pub") {
+                Some(rest) if rest.starts_with('(') => {
+                    rest.find(')').map_or(rest, |end| &rest[end + 1..])
+                }
+                Some(rest) => rest,
+                None => trimmed,
+            };
+            decl.trim_start()
+                .strip_prefix("mod ")
+                .is_some_and(|rest| rest.trim_end().ends_with(';'))
+        })
+    }
+
+    fn is_tracked(git_root: &Path, file: &Path) -> anyhow::Result<bool> {
+        let status = Command::new("git")
+            .args(["ls-files", "--error-unmatch", "--"])
+            .arg(file)
+            .current_dir(git_root)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run git ls-files")?;
+        Ok(status.success())
+    }
+
+    /// Extracts the new-file side of `@@ -a,b +c,d @@` hunk headers. Pure
+    /// deletions (`d == 0`) leave no lines to format and are skipped.
+    pub fn parse_hunk_ranges(diff: &str) -> Vec<LineRange> {
+        let mut ranges = Vec::new();
+        for line in diff.lines() {
+            let Some(rest) = line.strip_prefix("@@ ") else {
+                continue;
+            };
+            let Some(new_side) = rest.split_whitespace().find(|part| part.starts_with('+')) else {
+                continue;
+            };
+            let mut numbers = new_side[1..].splitn(2, ',');
+            let Some(Ok(start)) = numbers.next().map(str::parse::<usize>) else {
+                continue;
+            };
+            let count = match numbers.next() {
+                Some(count) => count.parse::<usize>().unwrap_or(0),
+                None => 1,
+            };
+            if count > 0 {
+                ranges.push(LineRange {
+                    start,
+                    end: start + count - 1,
+                });
+            }
+        }
+        ranges
+    }
+
+    /// Formats only `ranges` of `file` (requires a nightly rustfmt).
+    pub fn format_line_ranges(
+        file: &Path,
+        edition: &str,
+        ranges: &[LineRange],
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<()> {
+        let mut cmd = line_ranges_command(file, edition, ranges, overrides);
+        eprintln!("Running {cmd:?}");
+        let mut child = super::interrupt::spawn(&mut cmd).context("Failed to run rustfmt")?;
+        // Ctrl-C mustn't stop rustfmt halfway through rewriting the file
+        let status = super::interrupt::finish(&mut child)?;
+        if !status.success() {
+            bail!("rustfmt failed on {}", file.display());
+        }
+        Ok(())
+    }
+
+    pub fn line_ranges_command(
+        file: &Path,
+        edition: &str,
+        ranges: &[LineRange],
+        overrides: &ConfigOverrides,
+    ) -> Command {
+        let file_lines = file_lines_json(file, ranges);
+        let config = overrides
+            .to_arg(&[("skip_children", "true")])
+            .unwrap_or_default();
+        let mut cmd = Command::new("rustfmt");
+        cmd.args(["--edition", edition, "--unstable-features"])
+            .args(["--config", &config])
+            .arg("--file-lines")
+            .arg(&file_lines)
+            .arg(file);
+        cmd
+    }
+
+    fn file_lines_json(file: &Path, ranges: &[LineRange]) -> String {
+        let entries: Vec<serde_json::Value> = ranges
+            .iter()
+            .map(|r| serde_json::json!({ "file": file, "range": [r.start, r.end] }))
+            .collect();
+        serde_json::Value::Array(entries).to_string()
+    }
+
+    /// Formats `file` through stdin, so out-of-line child modules it declares
+    /// are not formatted along with it. Runs from the file's directory so
+    /// that the nearest rustfmt.toml still applies.
+    pub fn format_file_alone(
+        file: &Path,
+        edition: &str,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<()> {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let mut cmd = file_alone_command(file, edition, overrides);
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+        eprintln!("Running {cmd:?} < {}", file.display());
+        let mut child = cmd.spawn().context("Failed to run rustfmt")?;
+        child
+            .stdin
+            .take()
+            .context("Failed to open rustfmt stdin")?
+            .write_all(content.as_bytes())?;
+        let output = child.wait_with_output().context("Failed to run rustfmt")?;
+        if !output.status.success() {
+            bail!("rustfmt failed on {}", file.display());
+        }
+
+        let formatted = String::from_utf8(output.stdout)?;
+        if formatted != content {
+            super::interrupt::write(file, formatted)
+                .with_context(|| format!("Failed to write file: {}", file.display()))?;
+        }
+        Ok(())
+    }
+
+    /// rustfmt reading `file` from stdin in the file's directory.
+    pub fn file_alone_command(file: &Path, edition: &str, overrides: &ConfigOverrides) -> Command {
+        let dir = file
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("."));
+        let mut cmd = Command::new("rustfmt");
+        cmd.args(["--edition", edition, "--emit", "stdout"]);
+        if let Some(config) = overrides.to_arg(&[]) {
+            cmd.args(["--config", &config]);
+        }
+        cmd.current_dir(dir);
+        cmd
+    }
+
+    /// The `max_width` rustfmt applies to `file`, taking rustfmt.toml into
+    /// account unless overridden.
+    pub fn max_width(file: &Path, overrides: &ConfigOverrides) -> usize {
+        if let Some(width) = overrides.get("max_width").and_then(|w| w.parse().ok()) {
+            return width;
+        }
+        Command::new("rustfmt")
+            .args(["--print-config", "current"])
+            .arg(file)
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .find_map(|line| line.strip_prefix("max_width = ")?.trim().parse().ok())
+            })
+            .unwrap_or(100)
+    }
+
+    /// Formats a code snippet through rustfmt's stdin. Returns `None` when
+    /// rustfmt can't parse it.
+    pub fn format_snippet(
+        source: &str,
+        edition: &str,
+        dir: &Path,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<Option<String>> {
+        let mut cmd = Command::new("rustfmt");
+        cmd.args(["--edition", edition, "--emit", "stdout"]);
+        if let Some(config) = overrides.to_arg(&[]) {
+            cmd.args(["--config", &config]);
+        }
+        let mut child = cmd
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Failed to run rustfmt")?;
+        child
+            .stdin
+            .take()
+            .context("Failed to open rustfmt stdin")?
+            .write_all(source.as_bytes())?;
+        let output = child.wait_with_output().context("Failed to run rustfmt")?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_hunk_ranges() {
+            let diff = r#"diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -3 +3 @@ fn a() {}
+-old
++new
+@@ -10,0 +11,4 @@ fn b() {}
++added
+@@ -20,2 +24,0 @@ fn c() {}
+-removed
+"#;
+
+            assert_eq!(
+                parse_hunk_ranges(diff),
+                vec![
+                    LineRange { start: 3, end: 3 },
+                    LineRange { start: 11, end: 14 },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_config_overrides() {
+            let config = BTreeMap::from([
+                ("max_width".to_string(), toml::Value::Integer(100)),
+                (
+                    "imports_granularity".to_string(),
+                    toml::Value::String("Crate".to_string()),
+                ),
+            ]);
+            let cli = [("max_width".to_string(), "120".to_string())];
+
+            let overrides = ConfigOverrides::new(&config, &cli).unwrap();
+            assert_eq!(
+                overrides.to_arg(&[]).unwrap(),
+                "imports_granularity=Crate,max_width=120"
+            );
+            assert_eq!(
+                overrides.to_arg(&[("skip_children", "true")]).unwrap(),
+                "imports_granularity=Crate,max_width=120,skip_children=true"
+            );
+            assert_eq!(ConfigOverrides::default().to_arg(&[]), None);
+
+            let array = BTreeMap::from([(
+                "skip_macro_invocations".to_string(),
+                toml::Value::Array(vec!["a".into(), "b".into()]),
+            )]);
+            let error = ConfigOverrides::new(&array, &[]).unwrap_err();
+            assert!(error.to_string().contains("skip_macro_invocations"));
+            let cli = [("skip_macro_invocations".to_string(), "a,b".to_string())];
+            assert!(ConfigOverrides::new(&BTreeMap::new(), &cli).is_err());
+        }
+
+        #[test]
+        fn test_parse_override() {
+            assert_eq!(
+                parse_override("max_width = 120"),
+                Ok(("max_width".to_string(), "120".to_string()))
+            );
+            assert!(parse_override("max_width").is_err());
+            assert!(parse_override("=1").is_err());
+        }
+
+        #[test]
+        fn test_declares_new_modules() {
+            let lines = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+            assert!(declares_new_modules(&lines(&["mod foo;"])));
+            assert!(declares_new_modules(&lines(&["    pub(crate) mod foo;"])));
+            assert!(declares_new_modules(&lines(&["#[path = \"x.rs\"]"])));
+            assert!(!declares_new_modules(&lines(&["mod tests {", "use foo;"])));
+            assert!(!declares_new_modules(&lines(&["fn module() {}"])));
+        }
+
+        #[test]
+        fn test_file_lines_json() {
+            let json = file_lines_json(Path::new("src/lib.rs"), &[LineRange { start: 1, end: 4 }]);
+            assert_eq!(json, r#"[{"file":"src/lib.rs","range":[1,4]}]"#);
+        }
+    }
+}
+
+mod metadata {
+    use super::targets::TargetKind;
+    use super::CargoOptions;
+    use anyhow::{bail, Context};
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::{Path, PathBuf};
+
+    /// Workspace packages and their targets, as reported by
+    /// `cargo metadata --no-deps`.
+    #[derive(Debug, Deserialize)]
+    pub struct Metadata {
+        pub packages: Vec<Package>,
+        #[serde(default)]
+        pub workspace_root: PathBuf,
+        #[serde(default)]
+        pub target_directory: Option<PathBuf>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Package {
+        pub name: String,
+        pub manifest_path: PathBuf,
+        pub targets: Vec<Target>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Target {
+        pub kind: Vec<String>,
+        pub src_path: PathBuf,
+    }
+
+    /// The package owning a changed file and the only target kind it can
+    /// affect, or `None` when it can affect every target of the package.
+    #[derive(Debug)]
+    pub struct FileTarget<'a> {
+        pub package: &'a Package,
+        pub kind: Option<TargetKind>,
+    }
+
+    impl Metadata {
+        pub fn load(git_root: &Path, cargo: &CargoOptions) -> anyhow::Result<Metadata> {
+            let output = cargo
+                .command("metadata")
+                .args(["--format-version", "1", "--no-deps"])
+                .current_dir(git_root)
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .context("Failed to run cargo metadata")?;
+            if !output.status.success() {
+                bail!("cargo metadata failed");
+            }
+            Self::parse(&output.stdout)
+        }
+
+        pub fn parse(json: &[u8]) -> anyhow::Result<Metadata> {
+            let mut metadata: Metadata =
+                serde_json::from_slice(json).context("Failed to parse cargo metadata")?;
+            metadata.workspace_root = super::normalize_path(&metadata.workspace_root);
+            metadata.target_directory = metadata
+                .target_directory
+                .as_deref()
+                .map(super::normalize_path);
+            for package in &mut metadata.packages {
+                package.manifest_path = super::normalize_path(&package.manifest_path);
+                for target in &mut package.targets {
+                    target.src_path = super::normalize_path(&target.src_path);
+                }
+            }
+            Ok(metadata)
+        }
+
+        /// Root source files of every target (`lib.rs`, `main.rs`, tests...).
+        pub fn target_roots(&self) -> HashSet<PathBuf> {
+            self.packages
+                .iter()
+                .flat_map(|p| &p.targets)
+                .map(|t| t.src_path.clone())
+                .collect()
+        }
+
+        /// Why `file` (absolute) resolves the way [`Metadata::resolve`] does:
+        /// the package whose directory contains it, then the targets that
+        /// decide which target kinds get linted.
+        pub fn explain(&self, file: &Path) -> (Option<FileTarget<'_>>, Vec<String>) {
+            let file = super::normalize_path(file);
+            let candidates = self
+                .packages
+                .iter()
+                .filter(|p| p.dir().is_some_and(|dir| file.starts_with(dir)))
+                .count();
+            let Some(target) = self.resolve(&file) else {
+                return (
+                    None,
+                    vec!["not under any workspace package directory".to_string()],
+                );
+            };
+            let dir = target.package.dir().unwrap_or(Path::new(""));
+            let mut reasons = vec![if candidates == 0 {
+                format!(
+                    "outside every package directory, under a target root of {}",
+                    target.package.name
+                )
+            } else if candidates > 1 {
+                format!(
+                    "innermost of {candidates} package directories containing it: {}",
+                    dir.display()
+                )
+            } else {
+                format!("in package directory {}", dir.display())
+            }];
+
+            let linted = match target.kind {
+                Some(TargetKind::Lib) | None => "every target is linted".to_string(),
+                Some(kind) => format!("only {} are linted", format!("{kind:?}").to_lowercase()),
+            };
+            let package = target.package;
+            if let Some(root) = package.targets.iter().find(|t| t.src_path == file) {
+                let kind = root.kind.first().map_or("unknown", String::as_str);
+                reasons.push(format!("root file of a {kind} target, so {linted}"));
+            } else if let Some(nearest) = package
+                .targets
+                .iter()
+                .filter_map(|t| t.src_path.parent())
+                .filter(|d| file.starts_with(d))
+                .max_by_key(|d| d.components().count())
+            {
+                let roots: Vec<String> = package
+                    .targets
+                    .iter()
+                    .filter(|t| t.src_path.parent() == Some(nearest))
+                    .map(|t| {
+                        let name = t.src_path.file_name().unwrap_or_default();
+                        let kind = t.kind.first().map_or("unknown", String::as_str);
+                        format!("{} ({kind})", name.to_string_lossy())
+                    })
+                    .collect();
+                let disagree = if target.kind.is_none() && roots.len() > 1 {
+                    ", which disagree on a kind"
+                } else {
+                    ""
+                };
+                reasons.push(format!(
+                    "in {}, the directory of target root(s) {}{disagree}, so {linted}",
+                    nearest.display(),
+                    roots.join(", ")
+                ));
+            } else {
+                reasons.push(format!("outside every target's directory, so {linted}"));
+            }
+            (Some(target), reasons)
+        }
+
+        /// Resolves `file` (absolute) to the innermost package containing it,
+        /// or else to the package with the innermost target root directory
+        /// containing it, for targets whose `path` leads out of the package.
+        pub fn resolve(&self, file: &Path) -> Option<FileTarget<'_>> {
+            let file = super::normalize_path(file);
+            let package = self
+                .packages
+                .iter()
+                .filter(|p| p.dir().is_some_and(|dir| file.starts_with(dir)))
+                .max_by_key(|p| p.manifest_path.components().count())
+                .or_else(|| {
+                    self.packages
+                        .iter()
+                        .flat_map(|p| p.targets.iter().map(move |t| (p, t)))
+                        .filter_map(|(p, t)| Some((p, t.src_path.parent()?)))
+                        .filter(|(_, dir)| file.starts_with(dir))
+                        .max_by_key(|(_, dir)| dir.components().count())
+                        .map(|(p, _)| p)
+                })?;
+            Some(FileTarget {
+                package,
+                kind: package.target_kind_for(&file),
+            })
+        }
+
+        /// The packages `[extra_sources]` lists for `relative` (a path
+        /// relative to the repository root), with the glob that matched.
+        /// Such files may be compiled into every target of them.
+        pub fn resolve_extra<'a>(
+            &'a self,
+            relative: &Path,
+            extra_sources: &'a BTreeMap<String, Vec<String>>,
+        ) -> anyhow::Result<Option<(&'a str, Vec<FileTarget<'a>>)>> {
+            let path = relative.to_string_lossy().replace('\\', "/");
+            let Some((glob, names)) = extra_sources
+                .iter()
+                .find(|(glob, _)| super::generated::glob_matches(glob, &path))
+            else {
+                return Ok(None);
+            };
+            let targets = names
+                .iter()
+                .map(|name| {
+                    let package = self
+                        .packages
+                        .iter()
+                        .find(|p| &p.name == name)
+                        .with_context(|| {
+                            format!("[extra_sources] `{glob}` lists `{name}`, which isn't a workspace package")
+                        })?;
+                    Ok(FileTarget {
+                        package,
+                        kind: None,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(Some((glob, targets)))
+        }
+    }
+
+    impl Package {
+        pub fn dir(&self) -> Option<&Path> {
+            self.manifest_path.parent()
+        }
+
+        pub fn has_lib(&self) -> bool {
+            self.targets
+                .iter()
+                .any(|t| t.kind().is_some_and(|k| k == TargetKind::Lib))
+        }
+
+        /// A target's root file maps to that target; other files map to the
+        /// targets rooted in their nearest ancestor directory, as long as
+        /// those agree on a kind. Build scripts affect everything.
+        fn target_kind_for(&self, file: &Path) -> Option<TargetKind> {
+            if let Some(target) = self.targets.iter().find(|t| t.src_path == file) {
+                return target.kind();
+            }
+            let nearest = self
+                .targets
+                .iter()
+                .filter_map(|t| Some((t, t.src_path.parent()?)))
+                .filter(|(_, dir)| file.starts_with(dir))
+                .map(|(_, dir)| dir.components().count())
+                .max()?;
+            let mut kinds = self
+                .targets
+                .iter()
+                .filter(|t| {
+                    t.src_path.parent().is_some_and(|dir| {
+                        file.starts_with(dir) && dir.components().count() == nearest
+                    })
+                })
+                .map(Target::kind);
+            let first = kinds.next()?;
+            kinds.all(|k| k == first).then_some(first).flatten()
+        }
+    }
+
+    impl Target {
+        pub fn kind(&self) -> Option<TargetKind> {
+            match self.kind.first()?.as_str() {
+                "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro" => {
+                    Some(TargetKind::Lib)
+                }
+                "bin" => Some(TargetKind::Bins),
+                "test" => Some(TargetKind::Tests),
+                "bench" => Some(TargetKind::Benches),
+                "example" => Some(TargetKind::Examples),
+                _ => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const METADATA: &str = r#"{"packages": [
+            {"name": "app", "manifest_path": "/ws/app/Cargo.toml", "targets": [
+                {"kind": ["lib"], "src_path": "/ws/app/src/lib.rs"},
+                {"kind": ["bin"], "src_path": "/ws/app/src/main.rs"},
+                {"kind": ["bin"], "src_path": "/ws/app/src/bin/tool/main.rs"},
+                {"kind": ["test"], "src_path": "/ws/app/tests/it.rs"},
+                {"kind": ["test"], "src_path": "/ws/app/tests/other.rs"},
+                {"kind": ["bench"], "src_path": "/ws/app/benches/parse.rs"},
+                {"kind": ["example"], "src_path": "/ws/app/examples/demo.rs"},
+                {"kind": ["custom-build"], "src_path": "/ws/app/build.rs"}
+            ]},
+            {"name": "cli", "manifest_path": "/ws/app/cli/Cargo.toml", "targets": [
+                {"kind": ["bin"], "src_path": "/ws/app/cli/src/main.rs"}
+            ]},
+            {"name": "gen", "manifest_path": "/ws/gen/Cargo.toml", "targets": [
+                {"kind": ["lib"], "src_path": "/ws/shared/gen/lib.rs"}
+            ]}
+        ]}"#;
+
+        fn resolve(file: &str) -> Option<(String, Option<TargetKind>)> {
+            let metadata = Metadata::parse(METADATA.as_bytes()).unwrap();
+            let target = metadata.resolve(Path::new(file))?;
+            Some((target.package.name.clone(), target.kind))
+        }
+
+        #[test]
+        fn test_resolve_target_roots() {
+            let app = |kind| Some(("app".to_string(), kind));
+            assert_eq!(resolve("/ws/app/tests/it.rs"), app(Some(TargetKind::Tests)));
+            assert_eq!(
+                resolve("/ws/app/benches/parse.rs"),
+                app(Some(TargetKind::Benches))
+            );
+            assert_eq!(
+                resolve("/ws/app/examples/demo.rs"),
+                app(Some(TargetKind::Examples))
+            );
+            assert_eq!(resolve("/ws/app/src/main.rs"), app(Some(TargetKind::Bins)));
+            assert_eq!(resolve("/ws/app/src/lib.rs"), app(Some(TargetKind::Lib)));
+            assert_eq!(resolve("/ws/app/build.rs"), app(None));
+        }
+
+        #[test]
+        fn test_resolve_modules() {
+            let app = |kind| Some(("app".to_string(), kind));
+            assert_eq!(
+                resolve("/ws/app/tests/common/mod.rs"),
+                app(Some(TargetKind::Tests))
+            );
+            assert_eq!(
+                resolve("/ws/app/src/bin/tool/args.rs"),
+                app(Some(TargetKind::Bins))
+            );
+            // Shared by the lib and the main binary
+            assert_eq!(resolve("/ws/app/src/parser.rs"), app(None));
+            assert_eq!(
+                resolve("/ws/app/cli/src/args.rs"),
+                Some(("cli".to_string(), Some(TargetKind::Bins)))
+            );
+            assert_eq!(resolve("/elsewhere/lib.rs"), None);
+        }
+
+        #[test]
+        fn test_resolve_outside_package_dirs() {
+            assert_eq!(
+                resolve("/ws/shared/gen/types.rs"),
+                Some(("gen".to_string(), Some(TargetKind::Lib)))
+            );
+
+            let metadata = Metadata::parse(METADATA.as_bytes()).unwrap();
+            let extra = BTreeMap::from([(
+                "proto/**/*.rs".to_string(),
+                vec!["app".to_string(), "cli".to_string()],
+            )]);
+            let (glob, targets) = metadata
+                .resolve_extra(Path::new("proto/v1/api.rs"), &extra)
+                .unwrap()
+                .unwrap();
+            assert_eq!(glob, "proto/**/*.rs");
+            let names: Vec<_> = targets.iter().map(|t| t.package.name.as_str()).collect();
+            assert_eq!(names, ["app", "cli"]);
+            assert!(targets.iter().all(|t| t.kind.is_none()));
+            assert!(metadata
+                .resolve_extra(Path::new("src/lib.rs"), &extra)
+                .unwrap()
+                .is_none());
+
+            let unknown = BTreeMap::from([("proto/*.rs".to_string(), vec!["nope".to_string()])]);
+            assert!(metadata
+                .resolve_extra(Path::new("proto/a.rs"), &unknown)
+                .is_err());
+        }
+
+        #[test]
+        fn test_explain() {
+            let metadata = Metadata::parse(METADATA.as_bytes()).unwrap();
+            let reasons = |file: &str| metadata.explain(Path::new(file)).1;
+            assert_eq!(
+                reasons("/ws/app/cli/src/args.rs"),
+                [
+                    "innermost of 2 package directories containing it: /ws/app/cli",
+                    "in /ws/app/cli/src, the directory of target root(s) main.rs (bin), so only bins are linted",
+                ]
+            );
+            assert_eq!(
+                reasons("/ws/app/src/lib.rs"),
+                [
+                    "in package directory /ws/app",
+                    "root file of a lib target, so every target is linted",
+                ]
+            );
+            assert_eq!(
+                reasons("/ws/app/src/parser.rs")[1],
+                "in /ws/app/src, the directory of target root(s) lib.rs (lib), main.rs (bin), which disagree on a kind, so every target is linted"
+            );
+            assert_eq!(
+                reasons("/elsewhere/lib.rs"),
+                ["not under any workspace package directory"]
+            );
+        }
+
+        #[test]
+        fn test_has_lib() {
+            let metadata = Metadata::parse(METADATA.as_bytes()).unwrap();
+            assert!(metadata.packages[0].has_lib());
+            assert!(!metadata.packages[1].has_lib());
+        }
+    }
+}
+
+mod doc_examples {
+    use super::rustfmt::{self, ConfigOverrides};
+    use anyhow::Context;
+    use std::path::Path;
+
+    /// A fenced Rust example inside a `///` or `//!` comment.
+    #[derive(Debug, PartialEq)]
+    pub struct Example {
+        /// Index of the first code line (after the opening fence)
+        pub start: usize,
+        /// Index of the closing fence
+        pub end: usize,
+        /// Everything before the code on each line, e.g. `    /// `
+        pub prefix: String,
+        /// Edition from an `edition20xx` fence attribute
+        pub edition: Option<String>,
+        pub code: String,
+    }
+
+    /// Formats the Rust examples in the doc comments of `file`, returning
+    /// whether anything changed. Examples rustfmt can't parse, even wrapped
+    /// in `fn main`, and examples with hidden `# ` lines are left alone.
+    pub fn format_file(
+        file: &Path,
+        edition: &str,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<bool> {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let formatted = format_content(&content, file, edition, overrides)?;
+        if formatted == content {
+            return Ok(false);
+        }
+        super::interrupt::write(file, formatted)
+            .with_context(|| format!("Failed to write file: {}", file.display()))?;
+        Ok(true)
+    }
+
+    /// Formats the examples in `content`, which is read from (or staged for)
+    /// `file`; the file's location decides which rustfmt.toml applies.
+    pub fn format_content(
+        content: &str,
+        file: &Path,
+        edition: &str,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<String> {
+        let dir = file
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let max_width = rustfmt::max_width(file, overrides);
+
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let mut changed = false;
+        // Splice from the bottom so earlier indices stay valid
+        for example in find_examples(content).into_iter().rev() {
+            let edition = example.edition.as_deref().unwrap_or(edition);
+            let width = max_width.saturating_sub(example.prefix.len());
+            let Some(formatted) = format_code(&example.code, edition, dir, overrides, width)?
+            else {
+                continue;
+            };
+            if formatted == example.code {
+                continue;
+            }
+            let replacement = formatted.lines().map(|line| {
+                if line.is_empty() {
+                    example.prefix.trim_end().to_string()
+                } else {
+                    format!("{}{line}", example.prefix)
+                }
+            });
+            lines.splice(example.start..example.end, replacement);
+            changed = true;
+        }
+
+        if !changed {
+            return Ok(content.to_string());
+        }
+        let mut output = lines.join("\n");
+        if content.ends_with('\n') {
+            output.push('\n');
+        }
+        Ok(output)
+    }
+
+    /// Formats `code` as items, or else as the body of `fn main`, the way
+    /// rustdoc compiles it.
+    fn format_code(
+        code: &str,
+        edition: &str,
+        dir: &Path,
+        overrides: &ConfigOverrides,
+        width: usize,
+    ) -> anyhow::Result<Option<String>> {
+        let as_items = overrides.with("max_width", &width.to_string());
+        if let Some(formatted) = rustfmt::format_snippet(code, edition, dir, &as_items)? {
+            return Ok(Some(formatted.trim_end().to_string()));
+        }
+
+        let wrapped = format!("fn main() {{\n{code}\n}}\n");
+        let in_main = overrides.with("max_width", &(width + 4).to_string());
+        let Some(formatted) = rustfmt::format_snippet(&wrapped, edition, dir, &in_main)? else {
+            return Ok(None);
+        };
+        Ok(unwrap_main(&formatted))
+    }
+
+    /// Strips the `fn main() {` wrapper and one indentation level.
+    pub fn unwrap_main(formatted: &str) -> Option<String> {
+        let body = formatted
+            .trim_end()
+            .strip_prefix("fn main() {\n")?
+            .strip_suffix("\n}")?;
+        // One level as rustfmt indents it, which `tab_spaces` or
+        // `hard_tabs` in rustfmt.toml can change
+        let first = body.lines().find(|line| !line.trim().is_empty())?;
+        let indent = &first[..first.len() - first.trim_start().len()];
+        let lines: Vec<&str> = body
+            .lines()
+            .map(|line| line.strip_prefix(indent).unwrap_or(line))
+            .collect();
+        Some(lines.join("\n"))
+    }
+
+    pub fn find_examples(content: &str) -> Vec<Example> {
+        let lines: Vec<&str> = content.lines().collect();
+        let mut examples = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let Some((prefix, text)) = split_doc_line(lines[i]) else {
+                i += 1;
+                continue;
+            };
+            let fence_indent = text.len() - text.trim_start().len();
+            let Some(info) = text.trim_start().strip_prefix("```") else {
+                i += 1;
+                continue;
+            };
+            let prefix = format!("{prefix}{}", &text[..fence_indent]);
+
+            let mut code = Vec::new();
+            let mut end = None;
+            for (j, line) in lines.iter().enumerate().skip(i + 1) {
+                let Some(rest) = strip_prefix(line, &prefix) else {
+                    break;
+                };
+                if rest.trim_start().starts_with("```") {
+                    end = Some(j);
+                    break;
+                }
+                code.push(rest);
+            }
+            let Some(end) = end else {
+                i += 1;
+                continue;
+            };
+
+            if let Some(edition) = rust_fence(info) {
+                if !code.is_empty() && !code.iter().any(|line| is_hidden(line)) {
+                    examples.push(Example {
+                        start: i + 1,
+                        end,
+                        prefix,
+                        edition,
+                        code: code.join("\n"),
+                    });
+                }
+            }
+            i = end + 1;
+        }
+        examples
+    }
+
+    /// Splits a doc comment line into its prefix (indentation, `///` or
+    /// `//!` and one space) and text.
+    pub fn split_doc_line(line: &str) -> Option<(&str, &str)> {
+        let indent = line.len() - line.trim_start().len();
+        let rest = &line[indent..];
+        let is_doc =
+            (rest.starts_with("///") && !rest.starts_with("////")) || rest.starts_with("//!");
+        if !is_doc {
+            return None;
+        }
+        let prefix_len = indent + 3 + usize::from(rest[3..].starts_with(' '));
+        Some(line.split_at(prefix_len))
+    }
+
+    /// Strips `prefix` from a code line, allowing blank lines that only
+    /// carry the comment marker.
+    fn strip_prefix<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+        line.strip_prefix(prefix)
+            .or_else(|| (line.trim_end() == prefix.trim_end()).then_some(""))
+    }
+
+    /// Whether a fence info string denotes a Rust example rustdoc compiles,
+    /// and the edition it asks for.
+    fn rust_fence(info: &str) -> Option<Option<String>> {
+        let mut edition = None;
+        for token in info.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match token {
+                "rust" | "no_run" | "should_panic" | "test_harness" => {}
+                _ => match token.strip_prefix("edition") {
+                    Some(year) if year.parse::<u16>().is_ok() => edition = Some(year.to_string()),
+                    _ => return None,
+                },
+            }
+        }
+        Some(edition)
+    }
+
+    /// Lines rustdoc hides from the rendered example (`# use foo;`).
+    fn is_hidden(line: &str) -> bool {
+        let line = line.trim_start();
+        line == "#" || line.starts_with("# ") || line.starts_with("##")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_find_examples() {
+            let content = "\
+/// Adds numbers.
+///
+/// ```
+/// let x = add(1,2);
+///
+/// assert_eq!(x, 3);
+/// ```
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+impl Foo {
+    /// ```rust,no_run,edition2021
+    /// Foo::run( )
+    /// ```
+    fn run() {}
+}
+";
+            let examples = find_examples(content);
+            assert_eq!(examples.len(), 2);
+            assert_eq!(examples[0].start, 3);
+            assert_eq!(examples[0].end, 6);
+            assert_eq!(examples[0].prefix, "/// ");
+            assert_eq!(examples[0].code, "let x = add(1,2);\n\nassert_eq!(x, 3);");
+            assert_eq!(examples[1].prefix, "    /// ");
+            assert_eq!(examples[1].edition.as_deref(), Some("2021"));
+            assert_eq!(examples[1].code, "Foo::run( )");
+        }
+
+        #[test]
+        fn test_skips_other_blocks() {
+            let content = "\
+//! ```text
+//! not rust
+//! ```
+//! ```ignore
+//! let x=1;
+//! ```
+//! ```
+//! # fn main() {
+//! let x=1;
+//! # }
+//! ```
+//! ```
+//! unterminated
+";
+            assert!(find_examples(content).is_empty());
+        }
+
+        #[test]
+        fn test_unwrap_main() {
+            assert_eq!(
+                unwrap_main("fn main() {\n    let x = 1;\n\n    if x {\n        y();\n    }\n}\n")
+                    .as_deref(),
+                Some("let x = 1;\n\nif x {\n    y();\n}")
+            );
+            assert_eq!(
+                unwrap_main("fn main() {\n  let x = 1;\n  if x {\n    y();\n  }\n}\n").as_deref(),
+                Some("let x = 1;\nif x {\n  y();\n}")
+            );
+            assert_eq!(
+                unwrap_main("fn main() {\n\tif x {\n\t\ty();\n\t}\n}\n").as_deref(),
+                Some("if x {\n\ty();\n}")
+            );
+            assert_eq!(unwrap_main("fn other() {}\n"), None);
+        }
+    }
+}
+
+mod doc_wrap {
+    use super::doc_examples::split_doc_line;
+    use anyhow::Context;
+    use std::path::Path;
+
+    /// Rewraps the doc comments of `file` to `width` columns, returning
+    /// whether anything changed.
+    pub fn wrap_file(file: &Path, width: usize) -> anyhow::Result<bool> {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let wrapped = wrap(&content, width);
+        if wrapped == content {
+            return Ok(false);
+        }
+        super::interrupt::write(file, wrapped)
+            .with_context(|| format!("Failed to write file: {}", file.display()))?;
+        Ok(true)
+    }
+
+    /// Reflows `///` and `//!` paragraphs that have a line longer than
+    /// `width`. Code blocks, tables, headings, quotes and other markdown
+    /// that isn't running prose are kept as they are; list items are
+    /// wrapped with a hanging indent.
+    pub fn wrap(content: &str, width: usize) -> String {
+        let lines: Vec<&str> = content.lines().collect();
+        let mut out: Vec<String> = Vec::with_capacity(lines.len());
+        let mut in_fence = false;
+        let mut i = 0;
+        while i < lines.len() {
+            let Some((prefix, text)) = split_doc_line(lines[i]) else {
+                in_fence = false;
+                out.push(lines[i].to_string());
+                i += 1;
+                continue;
+            };
+            let trimmed = text.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+            }
+            if in_fence || !is_prose(text) {
+                out.push(lines[i].to_string());
+                i += 1;
+                continue;
+            }
+
+            let lead = &text[..text.len() - trimmed.len()];
+            let marker = list_marker(trimmed).unwrap_or("");
+            let first_prefix = format!("{prefix}{lead}{marker}");
+            let rest_prefix = format!("{prefix}{lead}{}", " ".repeat(marker.len()));
+
+            let mut end = i + 1;
+            if !is_hard_break(text) {
+                while end < lines.len() {
+                    let Some((next_prefix, next)) = split_doc_line(lines[end]) else {
+                        break;
+                    };
+                    let indent = next.len() - next.trim_start().len();
+                    if next_prefix.trim_end() != prefix.trim_end()
+                        || !is_prose(next)
+                        || list_marker(next.trim_start()).is_some()
+                        || indent != lead.len() + marker.len()
+                    {
+                        break;
+                    }
+                    end += 1;
+                    if is_hard_break(next) {
+                        break;
+                    }
+                }
+            }
+
+            let paragraph = &lines[i..end];
+            if paragraph.iter().any(|line| line.chars().count() > width) {
+                let words: Vec<&str> = paragraph
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(n, line)| {
+                        let text = split_doc_line(line).map_or("", |(_, text)| text);
+                        let text = if n == 0 {
+                            &text.trim_start()[marker.len()..]
+                        } else {
+                            text
+                        };
+                        text.split_whitespace()
+                    })
+                    .collect();
+                let hard_break = paragraph
+                    .last()
+                    .and_then(|line| split_doc_line(line))
+                    .filter(|(_, text)| text.ends_with('\\'))
+                    .map(|_| "\\");
+                out.extend(fill(&words, &first_prefix, &rest_prefix, width, hard_break));
+            } else {
+                out.extend(paragraph.iter().map(|line| line.to_string()));
+            }
+            i = end;
+        }
+
+        let mut result = out.join("\n");
+        if content.ends_with('\n') {
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Greedily fills lines with `words`. Words longer than a line (URLs)
+    /// get a line of their own.
+    fn fill(
+        words: &[&str],
+        first_prefix: &str,
+        rest_prefix: &str,
+        width: usize,
+        hard_break: Option<&str>,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = first_prefix.to_string();
+        let mut empty = true;
+        for word in words {
+            if !empty && line.chars().count() + 1 + word.chars().count() > width {
+                lines.push(std::mem::replace(&mut line, rest_prefix.to_string()));
+                empty = true;
+            }
+            if !empty {
+                line.push(' ');
+            }
+            line.push_str(word);
+            empty = false;
+        }
+        if hard_break.is_some() && !line.ends_with('\\') {
+            line.push('\\');
+        }
+        lines.push(line);
+        lines
+    }
+
+    fn is_prose(text: &str) -> bool {
+        let trimmed = text.trim();
+        let indent = text.len() - text.trim_start().len();
+        !trimmed.is_empty()
+            && indent < 4
+            && !trimmed.starts_with(['|', '#', '>', '<'])
+            && !trimmed.contains(" | ")
+            && !trimmed
+                .chars()
+                .all(|c| matches!(c, '-' | '=' | '*' | '_' | ' '))
+            && !is_link_definition(trimmed)
+    }
+
+    fn is_link_definition(trimmed: &str) -> bool {
+        trimmed.starts_with('[') && trimmed.contains("]:")
+    }
+
+    fn is_hard_break(text: &str) -> bool {
+        text.ends_with("  ") || text.ends_with('\\')
+    }
+
+    /// The marker (with its trailing space) of a list item like `- item`
+    /// or `12. item`.
+    fn list_marker(trimmed: &str) -> Option<&str> {
+        if trimmed.starts_with("- ") || trimmed.starts_with("* ") || trimmed.starts_with("+ ") {
+            return Some(&trimmed[..2]);
+        }
+        let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+        let rest = &trimmed[digits..];
+        (digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")))
+            .then(|| &trimmed[..digits + 2])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_wrap_long_paragraph() {
+            let content = "\
+/// Short line stays.
+///
+/// This sentence is far too long for the configured width and must be wrapped onto more lines.
+fn f() {}
+";
+            assert_eq!(
+                wrap(content, 40),
+                "\
+/// Short line stays.
+///
+/// This sentence is far too long for
+/// the configured width and must be
+/// wrapped onto more lines.
+fn f() {}
+"
+            );
+        }
+
+        #[test]
+        fn test_wrap_joins_paragraph_lines() {
+            let content =
+                "    //! A long first line that goes beyond the limit\n    //! and a short one.\n";
+            assert_eq!(
+                wrap(content, 40),
+                "    //! A long first line that goes\n    //! beyond the limit and a short\n    //! one.\n"
+            );
+        }
+
+        #[test]
+        fn test_wrap_list_items() {
+            let content = "\
+/// - first item that is much too long to fit on one line
+/// - second
+/// 1. numbered item that is also too long for the width
+";
+            assert_eq!(
+                wrap(content, 32),
+                "\
+/// - first item that is much
+///   too long to fit on one
+///   line
+/// - second
+/// 1. numbered item that is
+///    also too long for the
+///    width
+"
+            );
+        }
+
+        #[test]
+        fn test_wrap_keeps_code_and_tables() {
+            let content = "\
+/// ```
+/// let a_very_long_variable_name = compute_something_really_long(argument);
+/// ```
+///
+/// | column one header | column two header | column three |
+/// |-------------------|-------------------|--------------|
+/// # A very long heading that should not be wrapped at all
+/// [reference]: https://example.com/a/very/long/link/that/cannot/be/wrapped
+// A regular comment that is way longer than the width but is not documentation.
+";
+            assert_eq!(wrap(content, 40), content);
+        }
+
+        #[test]
+        fn test_wrap_long_word_and_hard_break() {
+            let content =
+                "/// see https://example.com/a/very/long/url/path here\\\n/// next line\n";
+            assert_eq!(
+                wrap(content, 24),
+                "/// see\n/// https://example.com/a/very/long/url/path\n/// here\\\n/// next line\n"
+            );
+        }
+    }
+}
+
+mod derive_sort {
+    use super::rustfmt::{self, LineRange};
+    use anyhow::Context;
+    use std::path::Path;
+
+    /// Sorts the `#[derive(...)]` lists touching changed lines of `file`,
+    /// or else the given `lines`, returning whether anything changed.
+    pub fn sort_file(
+        git_root: &Path,
+        file: &Path,
+        base: &str,
+        lines: Option<Vec<LineRange>>,
+        order: &[String],
+    ) -> anyhow::Result<bool> {
+        let ranges = match lines {
+            Some(lines) => lines,
+            None => rustfmt::changed_line_ranges(git_root, file, base)?,
+        };
+        if ranges.is_empty() {
+            return Ok(false);
+        }
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let sorted = sort_derives(&content, &ranges, order);
+        if sorted == content {
+            return Ok(false);
+        }
+        super::interrupt::write(file, sorted)
+            .with_context(|| format!("Failed to write file: {}", file.display()))?;
+        Ok(true)
+    }
+
+    /// Sorts derive lists overlapping `ranges` (1-based lines). Derives
+    /// named in `order` come first, in that order; the rest follow
+    /// alphabetically. Lists containing comments are left alone.
+    pub fn sort_derives(content: &str, ranges: &[LineRange], order: &[String]) -> String {
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let mut i = 0;
+        while i < lines.len() {
+            let Some(start) = lines[i].find("#[derive(") else {
+                i += 1;
+                continue;
+            };
+            let Some(end) = (i..lines.len()).find(|&j| lines[j].contains(")]")) else {
+                break;
+            };
+            let touched = ranges.iter().any(|r| r.start <= end + 1 && i < r.end);
+            if touched {
+                if let Some(replacement) = sort_attribute(&lines[i..=end], start, order) {
+                    lines.splice(i..=end, replacement);
+                }
+            }
+            i = end + 1;
+        }
+
+        let mut result = lines.join("\n");
+        if content.ends_with('\n') {
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Rewrites one attribute spanning `lines`, keeping its layout: a
+    /// single line stays a single line, one derive per line stays so.
+    fn sort_attribute(lines: &[String], start: usize, order: &[String]) -> Option<Vec<String>> {
+        let first = &lines[0];
+        let last = lines.last()?;
+        let open = start + "#[derive(".len();
+        let close = last.find(")]")?;
+
+        let inner = if lines.len() == 1 {
+            first[open..close].to_string()
+        } else {
+            let mut inner = first[open..].to_string();
+            for line in &lines[1..lines.len() - 1] {
+                inner.push_str(line);
+            }
+            inner.push_str(&last[..close]);
+            inner
+        };
+        if inner.contains("//") || inner.contains("/*") {
+            return None;
+        }
+
+        let mut derives: Vec<&str> = inner
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .collect();
+        derives.sort_by_key(|d| sort_key(d, order));
+
+        if lines.len() == 1 {
+            return Some(vec![format!(
+                "{}{}{}",
+                &first[..open],
+                derives.join(", "),
+                &first[close..]
+            )]);
+        }
+        let indent_of = |line: &str| line[..line.len() - line.trim_start().len()].to_string();
+        let item_indent = if lines.len() > 2 {
+            indent_of(&lines[1])
+        } else {
+            format!("{}    ", indent_of(first))
+        };
+        let mut out = vec![first[..open].to_string()];
+        out.extend(derives.iter().map(|d| format!("{item_indent}{d},")));
+        out.push(format!("{}{}", indent_of(first), &last[close..]));
+        Some(out)
+    }
+
+    /// Position in the configured order (matching the full path or its
+    /// last segment), then the name for everything else.
+    fn sort_key(derive: &str, order: &[String]) -> (usize, String) {
+        let name = derive.rsplit("::").next().unwrap_or(derive);
+        let rank = order
+            .iter()
+            .position(|o| o == derive || o == name)
+            .unwrap_or(order.len());
+        (rank, name.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn all() -> Vec<LineRange> {
+            vec![LineRange { start: 1, end: 100 }]
+        }
+
+        #[test]
+        fn test_sort_alphabetically() {
+            let content = "#[derive(Debug, Clone, PartialEq, Copy)]\nstruct A;\n";
+            assert_eq!(
+                sort_derives(content, &all(), &[]),
+                "#[derive(Clone, Copy, Debug, PartialEq)]\nstruct A;\n"
+            );
+        }
+
+        #[test]
+        fn test_sort_canonical_order() {
+            let order: Vec<String> = ["Debug", "Clone", "Copy"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            let content = "    #[derive(serde::Serialize, Copy, Eq, Clone, Debug)] struct A;\n";
+            assert_eq!(
+                sort_derives(content, &all(), &order),
+                "    #[derive(Debug, Clone, Copy, Eq, serde::Serialize)] struct A;\n"
+            );
+        }
+
+        #[test]
+        fn test_sort_multiline() {
+            let content = "#[derive(\n    Debug,\n    Clone,\n    Default,\n)]\nstruct A;\n";
+            assert_eq!(
+                sort_derives(content, &all(), &[]),
+                "#[derive(\n    Clone,\n    Debug,\n    Default,\n)]\nstruct A;\n"
+            );
+        }
+
+        #[test]
+        fn test_only_changed_lines() {
+            let content =
+                "#[derive(Debug, Clone)]\nstruct A;\n#[derive(Debug, Clone)]\nstruct B;\n";
+            assert_eq!(
+                sort_derives(content, &[LineRange { start: 3, end: 4 }], &[]),
+                "#[derive(Debug, Clone)]\nstruct A;\n#[derive(Clone, Debug)]\nstruct B;\n"
+            );
+        }
+
+        #[test]
+        fn test_keeps_commented_lists() {
+            let content = "#[derive(Debug, /* needed */ Clone)]\nstruct A;\n";
+            assert_eq!(sort_derives(content, &all(), &[]), content);
+        }
+    }
+}
+
+mod safety {
+    use super::rust_grouping;
+    use super::rustfmt::LineRange;
+
+    #[derive(Debug, PartialEq)]
+    pub struct Violation {
+        /// 1-based line of the `unsafe` keyword
+        pub line: usize,
+        pub message: String,
+    }
+
+    /// A line of source split into its code, with comments and literals
+    /// blanked out so that columns still line up, and the text of its
+    /// comments.
+    #[derive(Default)]
+    struct Line {
+        code: String,
+        comment: String,
+    }
+
+    /// `unsafe` blocks, functions and impls on the `changed` lines of
+    /// `content` without a `// SAFETY:` comment on their line or in the
+    /// comments right above them. Functions may document a `# Safety`
+    /// section instead.
+    pub fn unjustified(content: &str, changed: &[LineRange]) -> Vec<Violation> {
+        let lines = split_lines(content);
+        let mut violations = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            if !changed.iter().any(|r| (r.start..=r.end).contains(&(i + 1))) {
+                continue;
+            }
+            let mut rest = line.code.as_str();
+            while let Some(at) = find_keyword(rest) {
+                let offset = line.code.len() - rest.len() + at;
+                let column = line.code[..offset].chars().count() + 1;
+                rest = &rest[at + "unsafe".len()..];
+                let following = if rest.trim().is_empty() {
+                    lines[i + 1..]
+                        .iter()
+                        .map(|l| l.code.as_str())
+                        .find(|code| !code.trim().is_empty())
+                        .unwrap_or_default()
+                } else {
+                    rest
+                };
+                let kind = match rust_grouping::tokens(following).as_slice() {
+                    ["{", ..] => "block",
+                    ["fn", name, ..] if *name != "(" => "function",
+                    ["impl", ..] => "impl",
+                    _ => continue,
+                };
+                if !justified(&lines, i, kind == "function") {
+                    violations.push(Violation {
+                        line: i + 1,
+                        message: format!(
+                            "`unsafe` {kind} at column {column} has no `// SAFETY:` comment"
+                        ),
+                    });
+                }
+            }
+        }
+        violations
+    }
+
+    /// Whether line `i` or the comment lines above it, past any attributes,
+    /// say why the `unsafe` on it is sound.
+    fn justified(lines: &[Line], i: usize, function: bool) -> bool {
+        let says_why = |comment: &str| {
+            comment.contains("SAFETY:") || (function && comment.contains("# Safety"))
+        };
+        if says_why(&lines[i].comment) {
+            return true;
+        }
+        for line in lines[..i].iter().rev() {
+            let code = line.code.trim();
+            if code.is_empty() && !line.comment.is_empty() {
+                if says_why(&line.comment) {
+                    return true;
+                }
+            } else if !code.starts_with("#[") {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Byte offset of the first `unsafe` keyword in `code`.
+    fn find_keyword(code: &str) -> Option<usize> {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let mut from = 0;
+        while let Some(at) = code[from..].find("unsafe").map(|at| from + at) {
+            let end = at + "unsafe".len();
+            let before = code[..at].chars().next_back();
+            let after = code[end..].chars().next();
+            if !before.is_some_and(is_ident) && !after.is_some_and(is_ident) {
+                return Some(at);
+            }
+            from = end;
+        }
+        None
+    }
+
+    /// Splits `content` into lines, telling code from comments and blanking
+    /// out string and char literals so that neither is mistaken for code.
+    fn split_lines(content: &str) -> Vec<Line> {
+        enum State {
+            Code,
+            /// Inside this many nested block comments
+            Block(usize),
+            Str,
+            /// Inside a raw string closed by `"` and this many `#`s
+            Raw(usize),
+        }
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let mut state = State::Code;
+        let mut lines = Vec::new();
+        for text in content.lines() {
+            let chars: Vec<char> = text.chars().collect();
+            let mut line = Line::default();
+            let mut i = 0;
+            while i < chars.len() {
+                let c = chars[i];
+                let next = chars.get(i + 1).copied();
+                match state {
+                    State::Block(depth) if c == '*' && next == Some('/') => {
+                        state = if depth == 1 {
+                            State::Code
+                        } else {
+                            State::Block(depth - 1)
+                        };
+                        line.code.push_str("  ");
+                        i += 1;
+                    }
+                    State::Block(depth) if c == '/' && next == Some('*') => {
+                        state = State::Block(depth + 1);
+                        line.code.push_str("  ");
+                        i += 1;
+                    }
+                    State::Block(_) => {
+                        line.comment.push(c);
+                        line.code.push(' ');
+                    }
+                    State::Str if c == '\\' => {
+                        line.code.push_str("  ");
+                        i += 1;
+                    }
+                    State::Str if c == '"' => {
+                        state = State::Code;
+                        line.code.push(c);
+                    }
+                    State::Raw(hashes)
+                        if c == '"'
+                            && chars[i + 1..].iter().take_while(|c| **c == '#').count()
+                                >= hashes =>
+                    {
+                        state = State::Code;
+                        line.code.push(c);
+                        line.code.extend(std::iter::repeat_n(' ', hashes));
+                        i += hashes;
+                    }
+                    State::Str | State::Raw(_) => line.code.push(' '),
+                    State::Code if c == '/' && next == Some('/') => {
+                        line.comment.extend(&chars[i + 2..]);
+                        break;
+                    }
+                    State::Code if c == '/' && next == Some('*') => {
+                        state = State::Block(1);
+                        line.code.push_str("  ");
+                        i += 1;
+                    }
+                    State::Code if c == '"' => {
+                        state = State::Str;
+                        line.code.push(c);
+                    }
+                    State::Code if c == '\'' => {
+                        // A char literal, else a lifetime or label
+                        let close = match next {
+                            Some('\\') => chars[i + 2..]
+                                .iter()
+                                .position(|c| *c == '\'')
+                                .map(|at| i + 2 + at),
+                            _ => Some(i + 2).filter(|at| chars.get(*at) == Some(&'\'')),
+                        };
+                        line.code.push(c);
+                        if let Some(close) = close {
+                            line.code.extend(std::iter::repeat_n(' ', close - i - 1));
+                            line.code.push(c);
+                            i = close;
+                        }
+                    }
+                    State::Code => {
+                        // `r"`, `r#"`, `br"` and so on start raw strings
+                        let starts_raw = c == 'r'
+                            && match i.checked_sub(1).map(|p| chars[p]) {
+                                Some('b') => i < 2 || !is_ident(chars[i - 2]),
+                                Some(previous) => !is_ident(previous),
+                                None => true,
+                            };
+                        let hashes = chars[i + 1..].iter().take_while(|c| **c == '#').count();
+                        if starts_raw && chars.get(i + 1 + hashes) == Some(&'"') {
+                            state = State::Raw(hashes);
+                            line.code.extend(std::iter::repeat_n(' ', 1 + hashes));
+                            line.code.push('"');
+                            i += 1 + hashes;
+                        } else {
+                            line.code.push(c);
+                        }
+                    }
+                }
+                i += 1;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_unjustified() {
+            let content = r##"fn read(p: *const u8) -> u8 {
+    // SAFETY: the caller passes a valid pointer
+    let a = unsafe { *p };
+    let b = unsafe { *p }; // SAFETY: as above
+    let c = unsafe { *p };
+    let d = /* why */ unsafe { *p };
+    let text = "unsafe { }"; // unsafe { in a comment }
+    let raw = r#"unsafe {"#;
+    a + b + c + d
+}
+
+/// Reads `p`.
+///
+/// # Safety
+///
+/// `p` must be valid.
+#[inline]
+pub unsafe fn read_unchecked(p: *const u8) -> u8 {
+    *p
+}
+
+unsafe impl Send for Wrapper {}
+
+type Callback = unsafe fn(u8);
+"##;
+            let everything = [LineRange {
+                start: 1,
+                end: content.lines().count(),
+            }];
+            assert_eq!(
+                unjustified(content, &everything),
+                [
+                    Violation {
+                        line: 5,
+                        message: "`unsafe` block at column 13 has no `// SAFETY:` comment"
+                            .to_string(),
+                    },
+                    Violation {
+                        line: 6,
+                        message: "`unsafe` block at column 23 has no `// SAFETY:` comment"
+                            .to_string(),
+                    },
+                    Violation {
+                        line: 22,
+                        message: "`unsafe` impl at column 1 has no `// SAFETY:` comment"
+                            .to_string(),
+                    },
+                ]
+            );
+            // Only `unsafe` on changed lines is checked
+            assert!(unjustified(content, &[LineRange { start: 1, end: 4 }]).is_empty());
+        }
+    }
+}
+
+mod modules {
+    use super::rust_grouping;
+    use anyhow::Context;
+    use serde::{Deserialize, Serialize};
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    /// How a module with its own file is laid out on disk.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum ModuleStyle {
+        /// `foo.rs`, with submodules in `foo/`
+        File,
+        /// `foo/mod.rs`
+        ModRs,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct ModuleIssue {
+        /// 1-based line of the `mod` declaration
+        pub line: usize,
+        pub message: String,
+        pub fix: Option<ModuleFix>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum ModuleFix {
+        /// Move a module file into the required layout
+        Rename { from: PathBuf, to: PathBuf },
+        /// Add `mod name;` to the parent module's file
+        Declare { parent: PathBuf, name: String },
+    }
+
+    impl ModuleFix {
+        /// Applies the fix, returning a description of what was done.
+        pub fn apply(&self) -> anyhow::Result<String> {
+            match self {
+                ModuleFix::Rename { from, to } => {
+                    rename(from, to)?;
+                    Ok(format!("Renamed {} to {}", from.display(), to.display()))
+                }
+                ModuleFix::Declare { parent, name } => {
+                    let content = std::fs::read_to_string(parent)
+                        .with_context(|| format!("Failed to read file: {}", parent.display()))?;
+                    super::interrupt::write(parent, insert_declaration(&content, name))
+                        .with_context(|| format!("Failed to write file: {}", parent.display()))?;
+                    Ok(format!("Declared `mod {name};` in {}", parent.display()))
+                }
+            }
+        }
+    }
+
+    /// Checks that every `mod foo;` in `file` has exactly one of `foo.rs`
+    /// and `foo/mod.rs`, laid out in `style` if one is required. `is_root`
+    /// marks crate roots and other files whose submodules live next to
+    /// them rather than in a directory named after them.
+    pub fn check_file(
+        file: &Path,
+        is_root: bool,
+        style: Option<ModuleStyle>,
+    ) -> anyhow::Result<Vec<ModuleIssue>> {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let dir = child_dir(file, is_root);
+        let shares_target_dir = is_root && !is_package_root(file);
+        let mut issues = Vec::new();
+        for (line, name) in declarations(&content) {
+            let as_file = dir.join(format!("{name}.rs"));
+            let as_mod_rs = dir.join(&name).join("mod.rs");
+            let issue = |message: String, fix| ModuleIssue { line, message, fix };
+            match (as_file.exists(), as_mod_rs.exists(), style) {
+                (false, false, _) => issues.push(issue(
+                    format!(
+                        "module `{name}` has no file, expected {} or {}",
+                        as_file.display(),
+                        as_mod_rs.display()
+                    ),
+                    None,
+                )),
+                (true, true, _) => issues.push(issue(
+                    format!(
+                        "module `{name}` has both {} and {}",
+                        as_file.display(),
+                        as_mod_rs.display()
+                    ),
+                    None,
+                )),
+                (true, false, Some(ModuleStyle::ModRs)) => issues.push(issue(
+                    format!("module `{name}` should be in {}", as_mod_rs.display()),
+                    Some(ModuleFix::Rename {
+                        from: as_file,
+                        to: as_mod_rs,
+                    }),
+                )),
+                (false, true, Some(ModuleStyle::File)) if !shares_target_dir => issues.push(issue(
+                    format!("module `{name}` should be in {}", as_file.display()),
+                    Some(ModuleFix::Rename {
+                        from: as_mod_rs,
+                        to: as_file,
+                    }),
+                )),
+                _ => {}
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Checks that `file` itself, when it's a module rather than a target
+    /// root, is laid out in `style`, so moved or newly added modules are
+    /// caught even if their parent didn't change.
+    pub fn check_layout(
+        file: &Path,
+        is_root: impl Fn(&Path) -> bool,
+        style: ModuleStyle,
+    ) -> Option<ModuleIssue> {
+        if is_root(file) {
+            return None;
+        }
+        let is_mod_rs = file.file_name()? == "mod.rs";
+        let to = match style {
+            ModuleStyle::File if is_mod_rs => {
+                let dir = file.parent()?;
+                // `tests/common/mod.rs` can't become `tests/common.rs`, cargo
+                // would build it as a test of its own
+                let (_, parents) = parent_candidates(file, &is_root)?;
+                if parents.iter().any(|p| is_root(p) && !is_package_root(p)) {
+                    return None;
+                }
+                dir.with_file_name(format!("{}.rs", dir.file_name()?.to_str()?))
+            }
+            ModuleStyle::ModRs if !is_mod_rs => file.with_extension("").join("mod.rs"),
+            _ => return None,
+        };
+        if to.exists() {
+            return None;
+        }
+        Some(ModuleIssue {
+            line: 1,
+            message: format!("module file should be {}", to.display()),
+            fix: Some(ModuleFix::Rename {
+                from: file.to_path_buf(),
+                to,
+            }),
+        })
+    }
+
+    /// `lib.rs`, `main.rs` and `build.rs`, the roots whose directory holds
+    /// no other targets cargo discovers on its own.
+    fn is_package_root(file: &Path) -> bool {
+        file.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| matches!(name, "lib.rs" | "main.rs" | "build.rs"))
+    }
+
+    /// Checks that a file added since `base` is declared as a module by its
+    /// parent, which would otherwise silently leave it out of the build.
+    /// Target roots (`src/bin/*.rs`, `tests/*.rs`...) are skipped.
+    pub fn check_new_file(
+        git_root: &Path,
+        file: &Path,
+        base: &str,
+        is_root: impl Fn(&Path) -> bool,
+    ) -> anyhow::Result<Option<ModuleIssue>> {
+        if is_root(file) || !is_new(git_root, file, base)? {
+            return Ok(None);
+        }
+        let Some((name, parents)) = parent_candidates(file, &is_root) else {
+            return Ok(None);
+        };
+        for parent in &parents {
+            let content = std::fs::read_to_string(parent)
+                .with_context(|| format!("Failed to read file: {}", parent.display()))?;
+            if content
+                .lines()
+                .any(|line| declared_module(line.trim_start()).as_deref() == Some(name.as_str()))
+            {
+                return Ok(None);
+            }
+        }
+
+        let issue = match parents.into_iter().next() {
+            Some(parent) => ModuleIssue {
+                line: 1,
+                message: format!(
+                    "new file is not compiled, add `mod {name};` to {}",
+                    parent.display()
+                ),
+                fix: Some(ModuleFix::Declare { parent, name }),
+            },
+            None => ModuleIssue {
+                line: 1,
+                message: format!("new file is not compiled, no parent module declares `{name}`"),
+                fix: None,
+            },
+        };
+        Ok(Some(issue))
+    }
+
+    /// The module name of `file` and the existing files that could declare
+    /// it: `mod.rs` or a target root in the same directory, or the file
+    /// named after the directory.
+    fn parent_candidates(
+        file: &Path,
+        is_root: impl Fn(&Path) -> bool,
+    ) -> Option<(String, Vec<PathBuf>)> {
+        let mut dir = file.parent()?;
+        let mut name = file.file_stem()?.to_str()?;
+        if name == "mod" {
+            name = dir.file_name()?.to_str()?;
+            dir = dir.parent()?;
+        }
+
+        let mut candidates = Vec::new();
+        let mod_rs = dir.join("mod.rs");
+        if mod_rs.exists() && mod_rs != file {
+            candidates.push(mod_rs);
+        }
+        if let Ok(entries) = std::fs::read_dir(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        }) {
+            let mut roots: Vec<PathBuf> = entries
+                .filter_map(Result::ok)
+                .map(|entry| dir.join(entry.file_name()))
+                .filter(|p| p.extension().is_some_and(|e| e == "rs") && p != file && is_root(p))
+                .collect();
+            roots.sort();
+            candidates.extend(roots);
+        }
+        if let (Some(up), Some(dir_name)) = (dir.parent(), dir.file_name()) {
+            let named = up.join(format!("{}.rs", dir_name.to_string_lossy()));
+            if named.exists() {
+                candidates.push(named);
+            }
+        }
+        Some((name.to_string(), candidates))
+    }
+
+    /// Whether `file` didn't exist in `base`.
+    fn is_new(git_root: &Path, file: &Path, base: &str) -> anyhow::Result<bool> {
+        let absolute = git_root.join(file);
+        let relative = absolute.strip_prefix(git_root).unwrap_or(file);
+        let status = Command::new("git")
+            .args(["cat-file", "-e"])
+            .arg(format!(
+                "{base}:{}",
+                relative.to_string_lossy().replace('\\', "/")
+            ))
+            .current_dir(git_root)
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run git cat-file")?;
+        Ok(!status.success())
+    }
+
+    /// Inserts `mod name;` after the last top-level `mod` declaration, or
+    /// else after the file's inner attributes and doc comments.
+    pub fn insert_declaration(content: &str, name: &str) -> String {
+        let lines: Vec<&str> = content.lines().collect();
+        let declaration = format!("mod {name};");
+        let mut out: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        let last_mod = lines.iter().rposition(|line| {
+            declared_module(line).is_some() && !line.starts_with(char::is_whitespace)
+        });
+        match last_mod {
+            Some(i) => out.insert(i + 1, declaration),
+            None => {
+                let header = lines
+                    .iter()
+                    .take_while(|line| line.starts_with("//!") || line.starts_with("#!["))
+                    .count();
+                let mut insert = vec![declaration];
+                if header > 0 {
+                    insert.insert(0, String::new());
+                }
+                if lines
+                    .get(header)
+                    .is_some_and(|line| !line.trim().is_empty())
+                {
+                    insert.push(String::new());
+                }
+                out.splice(header..header, insert);
+            }
+        }
+        let mut result = out.join("\n");
+        result.push('\n');
+        result
+    }
+
+    /// Moves a module file, with `git mv` if it's tracked so the move is
+    /// staged and history follows it.
+    pub fn rename(from: &Path, to: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let tracked = Command::new("git")
+            .args(["ls-files", "--error-unmatch"])
+            .arg(from)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if tracked {
+            let status = Command::new("git")
+                .arg("mv")
+                .arg(from)
+                .arg(to)
+                .status()
+                .context("Failed to run git mv")?;
+            if !status.success() {
+                anyhow::bail!("git mv {} {} failed", from.display(), to.display());
+            }
+        }
+        if from.exists() {
+            std::fs::rename(from, to).with_context(|| {
+                format!("Failed to rename {} to {}", from.display(), to.display())
+            })?;
+        }
+        // `foo/mod.rs` -> `foo.rs` leaves `foo/` behind if it has no submodules
+        if let Some(parent) = from.parent() {
+            let _ = std::fs::remove_dir(parent);
+        }
+        Ok(())
+    }
+
+    /// Directory holding the files of modules declared in `file`.
+    pub fn child_dir(file: &Path, is_root: bool) -> PathBuf {
+        let parent = file.parent().unwrap_or(Path::new(""));
+        if is_root || file.file_name().is_some_and(|name| name == "mod.rs") {
+            parent.to_path_buf()
+        } else {
+            parent.join(file.file_stem().unwrap_or_default())
+        }
+    }
+
+    /// Top-level `mod foo;` declarations with their 1-based line, skipping
+    /// those whose file is given by a `#[path]` attribute. Declarations
+    /// nested in inline modules are not considered.
+    pub fn declarations(content: &str) -> Vec<(usize, String)> {
+        let mut result = Vec::new();
+        let mut has_path = false;
+        for (i, line) in content.lines().enumerate() {
+            if line.starts_with("#[") {
+                has_path |= line.starts_with("#[path");
+                continue;
+            }
+            if let Some(name) = declared_module(line) {
+                if !has_path {
+                    result.push((i + 1, name));
+                }
+            }
+            if !line.trim().is_empty() && !line.trim_start().starts_with("//") {
+                has_path = false;
+            }
+        }
+        result
+    }
+
+    fn declared_module(line: &str) -> Option<String> {
+        // Indented declarations belong to inline modules
+        if line.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let tokens = rust_grouping::tokens(line);
+        let ["mod", name, ";", rest @ ..] = rust_grouping::after_visibility(&tokens)? else {
+            return None;
+        };
+        if !rest.is_empty() && !rest.starts_with(&["/", "/"]) {
+            return None;
+        }
+        let name = name.strip_prefix("r#").unwrap_or(name);
+        name.chars()
+            .all(|c| c.is_alphanumeric() || c == '_')
+            .then(|| name.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_declarations() {
+            let content = "\
+mod a;
+pub mod b;
+pub(crate) mod r#async;
+#[path = \"gen.rs\"]
+mod generated;
+#[cfg(test)]
+mod tests;
+mod inline {
+    mod nested;
+}
+// mod commented;
+pub (crate)\tmod\tspaced; // with a comment
+";
+            assert_eq!(
+                declarations(content),
+                [
+                    (1, "a".to_string()),
+                    (2, "b".to_string()),
+                    (3, "async".to_string()),
+                    (7, "tests".to_string()),
+                    (12, "spaced".to_string())
+                ]
+            );
+        }
+
+        #[test]
+        fn test_child_dir() {
+            assert_eq!(child_dir(Path::new("src/lib.rs"), true), Path::new("src"));
+            assert_eq!(
+                child_dir(Path::new("src/a/mod.rs"), false),
+                Path::new("src/a")
+            );
+            assert_eq!(child_dir(Path::new("src/a.rs"), false), Path::new("src/a"));
+            assert_eq!(
+                child_dir(Path::new("src/bin/tool.rs"), true),
+                Path::new("src/bin")
+            );
+        }
+
+        #[test]
+        fn test_check_file() {
+            let dir = TempDir::new("modules");
+            std::fs::create_dir_all(dir.join("b")).unwrap();
+            std::fs::create_dir_all(dir.join("both")).unwrap();
+            for file in ["a.rs", "b/mod.rs", "both.rs", "both/mod.rs"] {
+                std::fs::write(dir.join(file), "").unwrap();
+            }
+            let lib = dir.join("lib.rs");
+            std::fs::write(&lib, "mod a;\nmod b;\nmod both;\nmod missing;\n").unwrap();
+
+            let issues = check_file(&lib, true, None).unwrap();
+            assert_eq!(issues.iter().map(|i| i.line).collect::<Vec<_>>(), [3, 4]);
+            assert!(issues.iter().all(|i| i.fix.is_none()));
+
+            let issues = check_file(&lib, true, Some(ModuleStyle::File)).unwrap();
+            assert_eq!(issues[0].line, 2);
+            assert_eq!(
+                issues[0].fix,
+                Some(ModuleFix::Rename {
+                    from: dir.join("b").join("mod.rs"),
+                    to: dir.join("b.rs")
+                })
+            );
+
+            let issues = check_file(&lib, true, Some(ModuleStyle::ModRs)).unwrap();
+            assert_eq!(issues[0].line, 1);
+            assert_eq!(
+                issues[0].fix,
+                Some(ModuleFix::Rename {
+                    from: dir.join("a.rs"),
+                    to: dir.join("a").join("mod.rs")
+                })
+            );
+        }
+
+        #[test]
+        fn test_check_layout() {
+            let dir = TempDir::new("layout");
+            std::fs::create_dir_all(dir.join("src/a")).unwrap();
+            std::fs::create_dir_all(dir.join("tests/common")).unwrap();
+            for file in ["src/lib.rs", "src/a/mod.rs", "src/b.rs", "tests/it.rs"] {
+                std::fs::write(dir.join(file), "").unwrap();
+            }
+            std::fs::write(dir.join("tests/common/mod.rs"), "").unwrap();
+            let roots = [dir.join("src/lib.rs"), dir.join("tests/it.rs")];
+            let is_root = |file: &Path| roots.iter().any(|root| root == file);
+
+            let issue = check_layout(&dir.join("src/a/mod.rs"), is_root, ModuleStyle::File);
+            assert_eq!(
+                issue.unwrap().fix,
+                Some(ModuleFix::Rename {
+                    from: dir.join("src/a/mod.rs"),
+                    to: dir.join("src/a.rs")
+                })
+            );
+            assert!(check_layout(&dir.join("src/b.rs"), is_root, ModuleStyle::File).is_none());
+            assert!(check_layout(&dir.join("src/lib.rs"), is_root, ModuleStyle::ModRs).is_none());
+            let common = dir.join("tests/common/mod.rs");
+            assert!(check_layout(&common, is_root, ModuleStyle::File).is_none());
+
+            let issue = check_layout(&dir.join("src/b.rs"), is_root, ModuleStyle::ModRs);
+            assert_eq!(
+                issue.unwrap().message,
+                format!(
+                    "module file should be {}",
+                    dir.join("src/b/mod.rs").display()
+                )
+            );
+        }
+
+        #[test]
+        fn test_parent_candidates() {
+            let dir = TempDir::new("parents");
+            std::fs::create_dir_all(dir.join("a/b")).unwrap();
+            for file in ["lib.rs", "a.rs", "a/b/mod.rs"] {
+                std::fs::write(dir.join(file), "").unwrap();
+            }
+            let lib = dir.join("lib.rs");
+            let is_root = |p: &Path| p == lib;
+
+            let (name, parents) = parent_candidates(&dir.join("new.rs"), is_root).unwrap();
+            assert_eq!(name, "new");
+            assert_eq!(parents, [dir.join("lib.rs")]);
+
+            let (name, parents) = parent_candidates(&dir.join("a/new/mod.rs"), is_root).unwrap();
+            assert_eq!(name, "new");
+            assert_eq!(parents, [dir.join("a.rs")]);
+
+            let (_, parents) = parent_candidates(&dir.join("a/b/c.rs"), is_root).unwrap();
+            assert_eq!(parents, [dir.join("a/b/mod.rs")]);
+        }
+
+        #[test]
+        fn test_insert_declaration() {
+            assert_eq!(
+                insert_declaration("mod a;\npub mod b;\n\nuse std::fmt;\n", "c"),
+                "mod a;\npub mod b;\nmod c;\n\nuse std::fmt;\n"
+            );
+            assert_eq!(
+                insert_declaration("//! Crate docs\n#![allow(dead_code)]\nuse std::fmt;\n", "c"),
+                "//! Crate docs\n#![allow(dead_code)]\n\nmod c;\n\nuse std::fmt;\n"
+            );
+            assert_eq!(insert_declaration("", "c"), "mod c;\n");
+        }
+    }
+}
+
+mod targets {
+    use super::metadata::FileTarget;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeSet;
+
+    /// Kinds of cargo targets clippy can be restricted to.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TargetKind {
+        Lib,
+        Bins,
+        Tests,
+        Benches,
+        Examples,
+    }
+
+    impl TargetKind {
+        pub const ALL: [TargetKind; 5] = [
+            TargetKind::Lib,
+            TargetKind::Bins,
+            TargetKind::Tests,
+            TargetKind::Benches,
+            TargetKind::Examples,
+        ];
+
+        fn flag(self) -> &'static str {
+            match self {
+                TargetKind::Lib => "--lib",
+                TargetKind::Bins => "--bins",
+                TargetKind::Tests => "--tests",
+                TargetKind::Benches => "--benches",
+                TargetKind::Examples => "--examples",
+            }
+        }
+    }
+
+    /// Cargo target-selection flags for linting the changed files, limited
+    /// to `selected` kinds. Library changes affect every target. `--lib` is
+    /// dropped when a member has no library, since cargo rejects it there.
+    pub fn cargo_args(selected: &[TargetKind], changed: &[FileTarget]) -> Vec<String> {
+        let mut kinds: BTreeSet<TargetKind> = BTreeSet::new();
+        for file in changed {
+            match file.kind {
+                Some(TargetKind::Lib) | None => kinds.extend(TargetKind::ALL),
+                Some(kind) => {
+                    kinds.insert(kind);
+                }
+            }
+        }
+        let all_have_lib = changed.iter().all(|file| file.package.has_lib());
+        select_args(selected, &kinds, all_have_lib)
+    }
+
+    fn select_args(
+        selected: &[TargetKind],
+        affected: &BTreeSet<TargetKind>,
+        all_have_lib: bool,
+    ) -> Vec<String> {
+        let kinds: BTreeSet<TargetKind> = selected
+            .iter()
+            .copied()
+            .filter(|k| affected.contains(k))
+            .collect();
+        if kinds.len() == TargetKind::ALL.len() {
+            return vec!["--all-targets".to_string()];
+        }
+        kinds
+            .into_iter()
+            .filter(|&k| k != TargetKind::Lib || all_have_lib)
+            .map(|k| k.flag().to_string())
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_select_args() {
+            let all = BTreeSet::from(TargetKind::ALL);
+            assert_eq!(select_args(&TargetKind::ALL, &all, true), ["--all-targets"]);
+
+            let benches = BTreeSet::from([TargetKind::Benches]);
+            assert_eq!(select_args(&TargetKind::ALL, &benches, true), ["--benches"]);
+
+            let selected = [TargetKind::Lib, TargetKind::Tests];
+            assert_eq!(select_args(&selected, &all, true), ["--lib", "--tests"]);
+            assert_eq!(select_args(&selected, &all, false), ["--tests"]);
+        }
+    }
+}
+
+mod results_cache {
+    use super::clippy_cache::Fnv1a;
+    use anyhow::{bail, Context};
+    use serde::{Deserialize, Serialize};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// Environment variables that change what a run does.
+    const ENV_VARS: [&str; 5] = [
+        super::config::SKIP_VAR,
+        "RUSTUP_TOOLCHAIN",
+        "RUSTFLAGS",
+        "CARGO_ENCODED_RUSTFLAGS",
+        "CLIPPY_CONF_DIR",
+    ];
+
+    /// The outcome of the last run, replayed when nothing that could change
+    /// it differs: same HEAD and diff base commit, same staged, unstaged and
+    /// untracked content, same config, arguments, polish version and cargo,
+    /// clippy and rustfmt versions.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Entry {
+        pub key: String,
+        pub success: bool,
+        pub error: Option<String>,
+        /// What the run printed to stdout
+        pub stdout: String,
+        /// What it wrote to `--metrics-file`
+        #[serde(default)]
+        pub metrics: Option<super::metrics::Metrics>,
+    }
+
+    pub struct ResultsCache {
+        path: PathBuf,
+        git_root: PathBuf,
+        key: String,
+    }
+
+    impl ResultsCache {
+        /// Computes the key of the current state, `None` outside a git
+        /// repository or if git fails; the run just isn't cached then.
+        pub fn open(cli: &super::Cli, args: &[String]) -> Option<ResultsCache> {
+            let git_root =
+                PathBuf::from(git_text(Path::new("."), &["rev-parse", "--show-toplevel"]).ok()?);
+            let path = git_root.join(
+                git_text(
+                    &git_root,
+                    &["rev-parse", "--git-path", "polish-results.json"],
+                )
+                .ok()?,
+            );
+            let key = key(&git_root, &base(cli, &git_root), args).ok()?;
+            Some(ResultsCache {
+                path,
+                git_root,
+                key,
+            })
+        }
+
+        /// The last run's entry if it was made in the same state.
+        pub fn hit(&self) -> Option<Entry> {
+            let content = std::fs::read_to_string(&self.path).ok()?;
+            let entry: Entry = serde_json::from_str(&content).ok()?;
+            (entry.key == self.key).then_some(entry)
+        }
+
+        /// Records the outcome (its key is filled in), unless the run changed
+        /// the working tree: its verdict was about the state before those
+        /// changes.
+        pub fn store(
+            &self,
+            cli: &super::Cli,
+            args: &[String],
+            outcome: Entry,
+        ) -> anyhow::Result<()> {
+            if key(&self.git_root, &base(cli, &self.git_root), args)? != self.key {
+                let _ = std::fs::remove_file(&self.path);
+                return Ok(());
+            }
+            let entry = Entry {
+                key: self.key.clone(),
+                ..outcome
+            };
+            std::fs::write(&self.path, serde_json::to_string_pretty(&entry)?)
+                .with_context(|| format!("Failed to write {}", self.path.display()))
+        }
+    }
+
+    /// The revision `--since`/`--since-tag` names, resolved like
+    /// `diff_base` does outside `--ci` (which isn't cached): a branch or tag
+    /// that moves to another commit changes the changed files without
+    /// changing the arguments.
+    fn base(cli: &super::Cli, git_root: &Path) -> String {
+        match &cli.since_tag {
+            // No matching tag fails the run, which is cached like any other
+            Some(pattern) => super::latest_tag(git_root, pattern).unwrap_or_default(),
+            None => cli.since.clone().unwrap_or_else(|| "HEAD~1".to_string()),
+        }
+    }
+
+    fn key(git_root: &Path, base: &str, args: &[String]) -> anyhow::Result<String> {
+        let mut hasher = Fnv1a::default();
+        let mut field = |bytes: &[u8]| {
+            hasher.write(&(bytes.len() as u64).to_le_bytes());
+            hasher.write(bytes);
+        };
+        field(env!("CARGO_PKG_VERSION").as_bytes());
+        // A toolchain update changes lints and formatting without touching
+        // the tree
+        field(super::toolchain::versions(git_root).as_bytes());
+        for arg in args {
+            field(arg.as_bytes());
+        }
+        let cwd = std::env::current_dir().context("Failed to get the current directory")?;
+        field(cwd.to_string_lossy().as_bytes());
+        for var in ENV_VARS {
+            field(std::env::var(var).unwrap_or_default().as_bytes());
+        }
+        // The merged config, so that edits to the files it extends count too
+        let config = super::config::load(git_root)
+            .and_then(|config| Ok(toml::to_string(&config)?))
+            .unwrap_or_else(|e| format!("{e:#}"));
+        field(config.as_bytes());
+
+        // An unborn branch has no HEAD; its state is all in the index
+        field(
+            git(git_root, &["rev-parse", "--verify", "--quiet", "HEAD"])
+                .unwrap_or_default()
+                .as_slice(),
+        );
+        field(
+            git(
+                git_root,
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("{base}^{{commit}}"),
+                ],
+            )
+            .unwrap_or_default()
+            .as_slice(),
+        );
+        field(&git(
+            git_root,
+            &[
+                "diff",
+                "--cached",
+                "--binary",
+                "--no-color",
+                "--no-ext-diff",
+            ],
+        )?);
+        field(&git(
+            git_root,
+            &["diff", "--binary", "--no-color", "--no-ext-diff"],
+        )?);
+        let untracked = git(git_root, &["ls-files", "-z", "-o", "--exclude-standard"])?;
+        for file in super::git_output::split_paths(&untracked) {
+            field(file.to_string_lossy().as_bytes());
+            field(&std::fs::read(git_root.join(&file)).unwrap_or_default());
+        }
+        Ok(format!("{:016x}", hasher.finish()))
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        if !output.status.success() {
+            bail!("git {} failed", args.join(" "));
+        }
+        Ok(output.stdout)
+    }
+
+    fn git_text(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+        Ok(String::from_utf8(git(dir, args)?)?.trim().to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_key_tracks_working_tree() {
+            let dir = TempDir::new("results");
+            git(&dir, &["init", "-q"]).unwrap();
+            let args = vec!["--no-clippy".to_string()];
+
+            let empty = key(&dir, "HEAD~1", &args).unwrap();
+            assert_eq!(key(&dir, "HEAD~1", &args).unwrap(), empty);
+            std::fs::write(dir.join("lib.rs"), "fn a() {}\n").unwrap();
+            let untracked = key(&dir, "HEAD~1", &args).unwrap();
+            assert_ne!(untracked, empty);
+            git(&dir, &["add", "lib.rs"]).unwrap();
+            let staged = key(&dir, "HEAD~1", &args).unwrap();
+            assert_ne!(staged, untracked);
+            std::fs::write(dir.join("lib.rs"), "fn b() {}\n").unwrap();
+            assert_ne!(key(&dir, "HEAD~1", &args).unwrap(), staged);
+            assert_ne!(
+                key(&dir, "HEAD~1", &[]).unwrap(),
+                key(&dir, "HEAD~1", &args).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_key_tracks_base_commit() {
+            let dir = TempDir::new("results-base");
+            git(&dir, &["init", "-q"]).unwrap();
+            let commit = |message: &str| {
+                git(
+                    &dir,
+                    &[
+                        "-c",
+                        "user.name=polish",
+                        "-c",
+                        "user.email=polish@example.com",
+                        "commit",
+                        "-q",
+                        "--allow-empty",
+                        "-m",
+                        message,
+                    ],
+                )
+                .unwrap();
+            };
+            commit("first");
+            git(&dir, &["branch", "base"]).unwrap();
+            commit("second");
+            let args = vec!["--since".to_string(), "base".to_string()];
+
+            let before = key(&dir, "base", &args).unwrap();
+            git(&dir, &["branch", "-f", "base", "HEAD"]).unwrap();
+            assert_ne!(key(&dir, "base", &args).unwrap(), before);
+        }
+    }
+}
+
+mod clippy_cache {
+    use super::CargoOptions;
+    use anyhow::{bail, Context};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// Last clean clippy fingerprint per member, persisted between runs.
+    #[derive(Debug, Default)]
+    pub struct ClippyCache {
+        path: PathBuf,
+        entries: BTreeMap<String, String>,
+    }
+
+    pub fn cache_path(git_root: &Path, cargo: &CargoOptions) -> PathBuf {
+        cargo
+            .target_dir
+            .clone()
+            .unwrap_or_else(|| git_root.join("target"))
+            .join("polish-cache")
+            .join("clippy.json")
+    }
+
+    impl ClippyCache {
+        /// A missing or unreadable cache is treated as empty.
+        pub fn load(path: &Path) -> Self {
+            let entries = std::fs::read_to_string(path)
+                .ok()
+                .and_then(|content| serde_json::from_str(&content).ok())
+                .unwrap_or_default();
+            ClippyCache {
+                path: path.to_path_buf(),
+                entries,
+            }
+        }
+
+        pub fn is_fresh(&self, member: &str, fingerprint: &str) -> bool {
+            self.entries.get(member).is_some_and(|f| f == fingerprint)
+        }
+
+        pub fn record_pass(&mut self, member: &str, fingerprint: &str) {
+            self.entries
+                .insert(member.to_string(), fingerprint.to_string());
+        }
+
+        pub fn save(&self) -> anyhow::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)
+                .with_context(|| format!("Failed to write {}", self.path.display()))
+        }
+    }
+
+    /// Hash of everything that can change a package's clippy verdict: the
+    /// files of the package and of its path dependencies, `Cargo.lock`, the
+    /// clippy arguments and the flags passed to rustc.
+    pub fn fingerprint(git_root: &Path, manifest: &Path, args: &str) -> anyhow::Result<String> {
+        let mut hasher = Fnv1a::default();
+        hasher.write(args.as_bytes());
+        for var in ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS", "CLIPPY_CONF_DIR"] {
+            hasher.write(std::env::var(var).unwrap_or_default().as_bytes());
+        }
+        if let Ok(lock) = std::fs::read(git_root.join("Cargo.lock")) {
+            hasher.write(&lock);
+        }
+
+        let mut package_dirs = BTreeSet::new();
+        collect_package_dirs(git_root, manifest, &mut package_dirs)?;
+        for dir in &package_dirs {
+            for file in list_files(git_root, dir)? {
+                hasher.write(file.to_string_lossy().as_bytes());
+                // Deleted-but-tracked files hash as empty
+                hasher.write(&std::fs::read(git_root.join(&file)).unwrap_or_default());
+            }
+        }
+        Ok(format!("{:016x}", hasher.finish()))
+    }
+
+    /// The package directory plus, transitively, those of its path
+    /// dependencies, whether declared in place or inherited from
+    /// `[workspace.dependencies]`.
+    fn collect_package_dirs(
+        git_root: &Path,
+        manifest: &Path,
+        dirs: &mut BTreeSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let Some(dir) = manifest.parent() else {
+            return Ok(());
+        };
+        let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        if !dirs.insert(dir.clone()) {
+            return Ok(());
+        }
+        let content = std::fs::read_to_string(manifest)
+            .with_context(|| format!("Failed to read {}", manifest.display()))?;
+        let declared = path_dependencies(&content)
+            .into_iter()
+            .map(|path| dir.join(path));
+        let inherited = inherited_path_dependencies(git_root, &dir, &content);
+        for dep_dir in declared.chain(inherited) {
+            let dep_manifest = dep_dir.join("Cargo.toml");
+            if dep_manifest.exists() {
+                collect_package_dirs(git_root, &dep_manifest, dirs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Directories of the `workspace = true` dependencies of the manifest
+    /// in `dir` that its workspace's `[workspace.dependencies]` gives a
+    /// `path`, resolved against the workspace root.
+    fn inherited_path_dependencies(git_root: &Path, dir: &Path, manifest: &str) -> Vec<PathBuf> {
+        let Ok(manifest) = manifest.parse::<toml::Table>() else {
+            return Vec::new();
+        };
+        let mut sections: Vec<&toml::Value> = Vec::new();
+        let platforms = manifest.get("target").and_then(toml::Value::as_table);
+        for table in std::iter::once(&manifest).chain(
+            platforms
+                .into_iter()
+                .flat_map(|targets| targets.values().filter_map(toml::Value::as_table)),
+        ) {
+            for section in ["dependencies", "dev-dependencies", "build-dependencies"] {
+                sections.extend(table.get(section));
+            }
+        }
+        let inherited: Vec<&str> = sections
+            .iter()
+            .filter_map(|section| section.as_table())
+            .flatten()
+            .filter(|(_, dep)| dep.get("workspace").and_then(toml::Value::as_bool) == Some(true))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if inherited.is_empty() {
+            return Vec::new();
+        }
+        let Some((root, workspace)) = workspace_manifest(git_root, dir) else {
+            return Vec::new();
+        };
+        let Some(declared) = workspace
+            .get("workspace")
+            .and_then(|workspace| workspace.get("dependencies"))
+        else {
+            return Vec::new();
+        };
+        inherited
+            .into_iter()
+            .filter_map(|name| declared.get(name)?.get("path")?.as_str())
+            .map(|path| root.join(path))
+            .collect()
+    }
+
+    /// The closest manifest with a `[workspace]` table at or above `dir`,
+    /// within the repository, and its directory.
+    fn workspace_manifest(git_root: &Path, dir: &Path) -> Option<(PathBuf, toml::Table)> {
+        let git_root = std::fs::canonicalize(git_root).unwrap_or_else(|_| git_root.to_path_buf());
+        dir.ancestors()
+            .take_while(|ancestor| ancestor.starts_with(&git_root))
+            .find_map(|ancestor| {
+                let content = std::fs::read_to_string(ancestor.join("Cargo.toml")).ok()?;
+                let manifest = content.parse::<toml::Table>().ok()?;
+                manifest
+                    .contains_key("workspace")
+                    .then(|| (ancestor.to_path_buf(), manifest))
+            })
+    }
+
+    /// Values of `path = "..."` keys in dependency entries of a manifest.
+    fn path_dependencies(manifest: &str) -> Vec<String> {
+        let mut paths = Vec::new();
+        for line in manifest.lines() {
+            let mut rest = line;
+            while let Some(pos) = rest.find("path") {
+                let after = rest[pos + 4..].trim_start();
+                rest = &rest[pos + 4..];
+                let Some(value) = after.strip_prefix('=') else {
+                    continue;
+                };
+                let value = value.trim_start();
+                let Some(value) = value.strip_prefix('"') else {
+                    continue;
+                };
+                if let Some(end) = value.find('"') {
+                    paths.push(value[..end].to_string());
+                }
+            }
+        }
+        paths
+    }
+
+    /// Tracked and untracked (non-ignored) files under `dir`, relative to
+    /// the git root and sorted, so build output never affects the hash.
+    fn list_files(git_root: &Path, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let output = Command::new("git")
+            .args(["ls-files", "-z", "-co", "--exclude-standard", "--"])
+            .arg(dir)
+            .current_dir(git_root)
+            .output()
+            .context("Failed to list package files")?;
+        if !output.status.success() {
+            bail!("Failed to list files of {}", dir.display());
+        }
+        let mut files = super::git_output::split_paths(&output.stdout);
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// 64-bit FNV-1a; unlike `DefaultHasher` it is stable across Rust
+    /// versions, which matters for a hash persisted on disk.
+    pub struct Fnv1a(u64);
+
+    impl Default for Fnv1a {
+        fn default() -> Self {
+            Fnv1a(0xcbf29ce484222325)
+        }
+    }
+
+    impl Fnv1a {
+        pub fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 ^= u64::from(byte);
+                self.0 = self.0.wrapping_mul(0x100000001b3);
+            }
+            // Separator, so that ("ab", "c") and ("a", "bc") differ
+            self.0 ^= 0xff;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+
+        pub fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_path_dependencies() {
+            let manifest = r#"[dependencies]
+serde = "1.0"
+a = { path = "../a" }
+b = { version = "0.1", path="../b" }
+"#;
+            assert_eq!(path_dependencies(manifest), vec!["../a", "../b"]);
+        }
+
+        #[test]
+        fn test_inherited_path_dependencies() {
+            let root = TempDir::new("clippy-cache-workspace");
+            let root = std::fs::canonicalize(&*root).unwrap();
+            for dir in ["crates/app", "crates/core", "crates/util"] {
+                std::fs::create_dir_all(root.join(dir)).unwrap();
+            }
+            std::fs::write(
+                root.join("Cargo.toml"),
+                "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.dependencies]\n\
+                 core = { path = \"crates/core\" }\nutil = { path = \"crates/util\" }\n\
+                 serde = \"1\"\n",
+            )
+            .unwrap();
+            std::fs::write(
+                root.join("crates/app/Cargo.toml"),
+                "[dependencies]\ncore.workspace = true\nserde = { workspace = true }\n\n\
+                 [target.'cfg(unix)'.dev-dependencies]\nutil = { workspace = true }\n",
+            )
+            .unwrap();
+            std::fs::write(root.join("crates/core/Cargo.toml"), "[package]\n").unwrap();
+            std::fs::write(root.join("crates/util/Cargo.toml"), "[package]\n").unwrap();
+
+            let mut dirs = BTreeSet::new();
+            collect_package_dirs(&root, &root.join("crates/app/Cargo.toml"), &mut dirs).unwrap();
+            assert_eq!(
+                dirs,
+                BTreeSet::from(["crates/app", "crates/core", "crates/util"].map(|d| root.join(d)))
+            );
+        }
+
+        #[test]
+        fn test_fnv_is_stable_and_separated() {
+            let hash = |parts: &[&str]| {
+                let mut hasher = Fnv1a::default();
+                for part in parts {
+                    hasher.write(part.as_bytes());
+                }
+                hasher.finish()
+            };
+            assert_eq!(hash(&["abc"]), hash(&["abc"]));
+            assert_ne!(hash(&["ab", "c"]), hash(&["a", "bc"]));
+        }
+
+        #[test]
+        fn test_cache_roundtrip() {
+            let dir = TempDir::new("cache");
+            let path = dir.join("clippy.json");
+            let mut cache = ClippyCache::load(&path);
+            assert!(!cache.is_fresh("a", "1234"));
+
+            cache.record_pass("a", "1234");
+            cache.save().unwrap();
+
+            let reloaded = ClippyCache::load(&path);
+            assert!(reloaded.is_fresh("a", "1234"));
+            assert!(!reloaded.is_fresh("a", "5678"));
+        }
+    }
+}
+
+mod codeowners {
+    use anyhow::Context;
+    use serde::{Deserialize, Serialize};
+    use std::path::{Path, PathBuf};
+
+    /// Where GitHub looks for the file, in order.
+    const LOCATIONS: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+    /// A CODEOWNERS line: a gitignore-style pattern and its owners.
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct Rule {
+        pub pattern: String,
+        pub owners: Vec<String>,
+    }
+
+    /// The repository's CODEOWNERS file, if it has one.
+    pub fn find(git_root: &Path) -> Option<PathBuf> {
+        LOCATIONS
+            .iter()
+            .map(|location| git_root.join(location))
+            .find(|path| path.is_file())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Vec<Rule>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Vec<Rule> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let line = line.split(" #").next().unwrap_or(line);
+                let mut fields = line.split_whitespace();
+                let pattern = fields.next()?.to_string();
+                Some(Rule {
+                    pattern,
+                    owners: fields.map(str::to_string).collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Owners of `path` (relative to the repository root): those of the last
+    /// matching rule, like GitHub.
+    pub fn owners<'a>(rules: &'a [Rule], path: &str) -> &'a [String] {
+        rules
+            .iter()
+            .rev()
+            .find(|rule| pattern_matches(&rule.pattern, path))
+            .map_or(&[], |rule| &rule.owners)
+    }
+
+    /// Whether any rule lists `owner`, compared case-insensitively.
+    pub fn mentions(rules: &[Rule], owner: &str) -> bool {
+        rules
+            .iter()
+            .flat_map(|rule| &rule.owners)
+            .any(|o| o.eq_ignore_ascii_case(owner))
+    }
+
+    /// Whether any of `me` owns `path`; handles and emails compare
+    /// case-insensitively.
+    pub fn is_owned(rules: &[Rule], path: &str, me: &[String]) -> bool {
+        owners(rules, path)
+            .iter()
+            .any(|owner| me.iter().any(|m| m.eq_ignore_ascii_case(owner)))
+    }
+
+    /// gitignore semantics: a leading or inner `/` anchors the pattern to
+    /// the root, otherwise it matches at any depth; a pattern naming a
+    /// directory covers everything below it. As on GitHub, a trailing `/*`
+    /// only covers the files directly in the directory.
+    fn pattern_matches(pattern: &str, path: &str) -> bool {
+        if pattern.ends_with("/*") {
+            // The `/` before `*` is an inner one, so this is always anchored
+            return super::generated::glob_matches(pattern.trim_start_matches('/'), path);
+        }
+        let directory = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let trimmed = trimmed.trim_start_matches('/');
+        let glob = if anchored {
+            trimmed.to_string()
+        } else {
+            format!("**/{trimmed}")
+        };
+        let below = format!("{glob}/**");
+        super::generated::glob_matches(&below, path)
+            || (!directory && super::generated::glob_matches(&glob, path))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_pattern_matches() {
+            assert!(pattern_matches("*", "src/lib.rs"));
+            assert!(pattern_matches("*.rs", "a/b/lib.rs"));
+            assert!(pattern_matches("/services/", "services/api/src/lib.rs"));
+            assert!(!pattern_matches("/services/", "libs/services/lib.rs"));
+            assert!(pattern_matches("apps/", "libs/apps/x.rs"));
+            assert!(!pattern_matches("apps/", "apps"));
+            assert!(pattern_matches("docs/*", "docs/a.md"));
+            assert!(!pattern_matches("docs/*", "docs/x/a.md"));
+            assert!(!pattern_matches("docs/*", "x/docs/a.md"));
+            assert!(pattern_matches("/libs/core", "libs/core/src/lib.rs"));
+        }
+
+        #[test]
+        fn test_owners() {
+            let rules = parse(
+                "# Default owners\n*       @acme/core\n\n/services/pay/ @acme/payments @alice # inline\n/services/pay/vendor/\n",
+            );
+            assert_eq!(owners(&rules, "libs/x.rs"), ["@acme/core"]);
+            assert_eq!(
+                owners(&rules, "services/pay/src/lib.rs"),
+                ["@acme/payments", "@alice"]
+            );
+            assert!(owners(&rules, "services/pay/vendor/x.rs").is_empty());
+            assert!(is_owned(
+                &rules,
+                "services/pay/src/lib.rs",
+                &["@ALICE".to_string()]
+            ));
+            assert!(!is_owned(&rules, "libs/x.rs", &["@alice".to_string()]));
+            assert!(mentions(&rules, "@Alice"));
+            assert!(!mentions(&rules, "alice@acme.com"));
+        }
+    }
+}
+
+mod generated {
+    use anyhow::{bail, Context};
+    use std::collections::BTreeSet;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    /// Whether `file` is generated code, either because it matches one of
+    /// the configured globs or because its content says so.
+    pub fn is_generated(git_root: &Path, file: &Path, globs: &[String]) -> bool {
+        let relative = file.strip_prefix(git_root).unwrap_or(file);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if globs.iter().any(|glob| glob_matches(glob, &relative)) {
+            return true;
+        }
+        std::fs::read_to_string(git_root.join(file))
+            .map(|content| has_generated_marker(&content))
+            .unwrap_or(false)
+    }
+
+    /// The `files` (relative to `git_root`) that `.gitattributes` marks as
+    /// generated, with `linguist-generated` or `-diff`.
+    pub fn marked_in_gitattributes(
+        git_root: &Path,
+        files: &[PathBuf],
+    ) -> anyhow::Result<BTreeSet<PathBuf>> {
+        if files.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+        let mut child = Command::new("git")
+            .args(["check-attr", "--stdin", "-z", "linguist-generated", "diff"])
+            .current_dir(git_root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to run git check-attr")?;
+        let mut input = Vec::new();
+        for file in files {
+            input.extend_from_slice(file.to_string_lossy().as_bytes());
+            input.push(0);
+        }
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("Failed to open git check-attr stdin")?;
+        // git answers as it reads, so write from another thread: writing
+        // everything before reading blocks for good once both pipes are full
+        let output = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(&input));
+            let output = child.wait_with_output();
+            writer
+                .join()
+                .map_err(|_| anyhow::anyhow!("Writing to git check-attr panicked"))??;
+            anyhow::Ok(output?)
+        })?;
+        if !output.status.success() {
+            bail!("git check-attr failed");
+        }
+        Ok(parse_check_attr(&output.stdout))
+    }
+
+    /// Reads `git check-attr -z` output, `path\0attribute\0value\0` per
+    /// attribute, into the paths marked as generated.
+    fn parse_check_attr(output: &[u8]) -> BTreeSet<PathBuf> {
+        let fields: Vec<String> = output
+            .split(|&b| b == 0)
+            .map(|field| String::from_utf8_lossy(field).into_owned())
+            .collect();
+        fields
+            .chunks_exact(3)
+            .filter(|entry| match (entry[1].as_str(), entry[2].as_str()) {
+                ("linguist-generated", value) => value == "true" || value == "set",
+                ("diff", value) => value == "unset",
+                _ => false,
+            })
+            .map(|entry| PathBuf::from(&entry[0]))
+            .collect()
+    }
+
+    /// Detects an `@generated` or bindgen header, bindgen-style output full
+    /// of `#[automatically_derived]` impls, and files that only include code
+    /// generated into `OUT_DIR`.
+    pub fn has_generated_marker(content: &str) -> bool {
+        let header = content.lines().take(10).any(|line| {
+            let line = line.trim_start();
+            (line.starts_with("//") || line.starts_with("/*") || line.starts_with('#'))
+                && (line.contains("@generated") || line.contains("automatically generated by"))
+        });
+        if header {
+            return true;
+        }
+
+        let lines = content.lines().count();
+        let derived = content
+            .lines()
+            .filter(|line| line.trim() == "#[automatically_derived]")
+            .count();
+        if derived >= 5 && derived * 50 >= lines {
+            return true;
+        }
+
+        let mut code = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('#'))
+            .peekable();
+        code.peek().is_some()
+            && code.all(|line| line.starts_with("include!") && line.contains("OUT_DIR"))
+    }
+
+    /// Matches `/`-separated paths against a glob where `*` and `?` stay
+    /// within one path component and `**` spans any number of them.
+    pub fn glob_matches(glob: &str, path: &str) -> bool {
+        matches(glob.as_bytes(), path.as_bytes())
+    }
+
+    fn matches(glob: &[u8], path: &[u8]) -> bool {
+        match glob {
+            [] => path.is_empty(),
+            [b'*', b'*', b'/', rest @ ..] => {
+                matches(rest, path)
+                    || (0..path.len())
+                        .filter(|&i| path[i] == b'/')
+                        .any(|i| matches(rest, &path[i + 1..]))
+            }
+            [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            [b'*', rest @ ..] => {
+                let component = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+                (0..=component).any(|i| matches(rest, &path[i..]))
+            }
+            [b'?', rest @ ..] => {
+                matches!(path.first(), Some(&c) if c != b'/') && matches(rest, &path[1..])
+            }
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_glob_matches() {
+            assert!(glob_matches("src/bindings.rs", "src/bindings.rs"));
+            assert!(glob_matches("src/*.rs", "src/lib.rs"));
+            assert!(!glob_matches("src/*.rs", "src/proto/lib.rs"));
+            assert!(glob_matches("src/**/*.rs", "src/lib.rs"));
+            assert!(glob_matches("src/**/*.rs", "src/proto/v1/msg.rs"));
+            assert!(glob_matches("**/proto/*.rs", "crates/api/proto/msg.rs"));
+            assert!(glob_matches("**/proto/*.rs", "proto/msg.rs"));
+            assert!(glob_matches("gen/**", "gen/a/b.rs"));
+            assert!(glob_matches("src/mod?.rs", "src/mod1.rs"));
+            assert!(!glob_matches("src/mod?.rs", "src/mod10.rs"));
+        }
+
+        #[test]
+        fn test_parse_check_attr() {
+            let output = b"a.rs\0linguist-generated\0true\0a.rs\0diff\0unspecified\0\
+b.rs\0linguist-generated\0unspecified\0b.rs\0diff\0unset\0\
+c.rs\0linguist-generated\0false\0c.rs\0diff\0set\0\
+d.rs\0linguist-generated\0set\0d.rs\0diff\0unspecified\0";
+            assert_eq!(
+                parse_check_attr(output),
+                BTreeSet::from(["a.rs", "b.rs", "d.rs"].map(PathBuf::from))
+            );
+        }
+
+        #[test]
+        fn test_marked_in_gitattributes() {
+            let dir = TempDir::new("gitattributes");
+            let init = Command::new("git")
+                .args(["init", "-q"])
+                .current_dir(&dir)
+                .status()
+                .unwrap();
+            assert!(init.success());
+            std::fs::write(dir.join(".gitattributes"), "gen/** linguist-generated\n").unwrap();
+            // Enough paths to fill both pipes if they weren't written and
+            // read at once
+            let mut files: Vec<PathBuf> = (0..20_000)
+                .map(|i| PathBuf::from(format!("src/module_{i}.rs")))
+                .collect();
+            files.push(PathBuf::from("gen/bindings.rs"));
+            assert_eq!(
+                marked_in_gitattributes(&dir, &files).unwrap(),
+                BTreeSet::from([PathBuf::from("gen/bindings.rs")])
+            );
+        }
+
+        #[test]
+        fn test_generated_header() {
+            assert!(has_generated_marker(
+                "// @generated by prost-build\npub struct A;\n"
+            ));
+            assert!(has_generated_marker(
+                "/* automatically generated by rust-bindgen 0.69.4 */\n\npub const X: u32 = 1;\n"
+            ));
+            assert!(!has_generated_marker(
+                "const MARKER: &str = \"@generated\";\n"
+            ));
+            assert!(!has_generated_marker("use std::fmt;\n\nfn main() {}\n"));
+        }
+
+        #[test]
+        fn test_automatically_derived() {
+            let item = "#[automatically_derived]\nimpl Clone for A {\n    fn clone(&self) -> A { *self }\n}\n";
+            assert!(has_generated_marker(&item.repeat(5)));
+            assert!(!has_generated_marker(item));
+        }
+
+        #[test]
+        fn test_out_dir_include() {
+            assert!(has_generated_marker(
+                "// Bindings generated by build.rs\n#![allow(warnings)]\ninclude!(concat!(env!(\"OUT_DIR\"), \"/bindings.rs\"));\n"
+            ));
+            assert!(!has_generated_marker(
+                "include!(concat!(env!(\"OUT_DIR\"), \"/bindings.rs\"));\npub fn wrapper() {}\n"
+            ));
+            assert!(!has_generated_marker(""));
+        }
+    }
+}
+
+mod autostash {
+    use anyhow::{bail, Context};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// Unstaged changes set aside for the duration of a run, as a patch
+    /// kept in the git directory so it survives a crash. They are put back by
+    /// [`Autostash::restore`], or on drop if the run bails early.
+    pub struct Autostash {
+        git_root: PathBuf,
+        patch: Option<PathBuf>,
+    }
+
+    impl Autostash {
+        /// Saves the unstaged changes to tracked files and resets those
+        /// files to their staged content.
+        pub fn stash(git_root: &Path) -> anyhow::Result<Autostash> {
+            // Explicit prefixes, since `git apply` expects `a/` and `b/`
+            // whatever `diff.noprefix` or `diff.mnemonicPrefix` say
+            let diff = git(
+                git_root,
+                &[
+                    "diff",
+                    "--binary",
+                    "--no-color",
+                    "--no-ext-diff",
+                    "--src-prefix=a/",
+                    "--dst-prefix=b/",
+                ],
+            )?;
+            let mut stash = Autostash {
+                git_root: git_root.to_path_buf(),
+                patch: None,
+            };
+            if diff.is_empty() {
+                return Ok(stash);
+            }
+
+            let path = git(
+                git_root,
+                &["rev-parse", "--git-path", "polish-autostash.patch"],
+            )?;
+            let path = git_root.join(String::from_utf8(path)?.trim());
+            if path.exists() {
+                bail!(
+                    "{} exists, left by an earlier run; apply it with `git apply` or delete it",
+                    path.display()
+                );
+            }
+            std::fs::write(&path, &diff)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            stash.patch = Some(path);
+            git(git_root, &["checkout", "--", "."])?;
+            eprintln!("Stashed unstaged changes");
+            Ok(stash)
+        }
+
+        /// Puts the stashed changes back on top of whatever the run changed.
+        /// If they conflict, the run's changes to the working tree are rolled
+        /// back so that the stashed changes apply cleanly, and an error lists
+        /// the conflict.
+        pub fn restore(&mut self) -> anyhow::Result<()> {
+            let Some(patch) = self.patch.take() else {
+                return Ok(());
+            };
+            let patch_arg = patch.to_string_lossy().into_owned();
+            let result = match git(
+                &self.git_root,
+                &["apply", "--whitespace=nowarn", &patch_arg],
+            ) {
+                Ok(_) => Ok(()),
+                Err(conflict) => {
+                    git(&self.git_root, &["checkout", "--", "."])?;
+                    git(
+                        &self.git_root,
+                        &["apply", "--whitespace=nowarn", &patch_arg],
+                    )
+                    .with_context(|| {
+                        format!("Failed to restore unstaged changes from {patch_arg}")
+                    })?;
+                    Err(conflict
+                        .context("Fixes conflict with unstaged changes; rolled the fixes back"))
+                }
+            };
+            std::fs::remove_file(&patch)
+                .with_context(|| format!("Failed to remove {}", patch.display()))?;
+            eprintln!("Restored unstaged changes");
+            result
+        }
+    }
+
+    impl Drop for Autostash {
+        fn drop(&mut self) {
+            if let Err(e) = self.restore() {
+                eprintln!("{e:#}");
+            }
+        }
+    }
+
+    fn git(git_root: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(git_root)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        fn repo(name: &str) -> TempDir {
+            let dir = TempDir::new(name);
+            git(&dir, &["init", "-q"]).unwrap();
+            std::fs::write(dir.join("lib.rs"), "a\nb\nc\nd\ne\n").unwrap();
+            git(&dir, &["add", "lib.rs"]).unwrap();
+            dir
+        }
+
+        #[test]
+        fn test_restore() {
+            let dir = repo("autostash");
+            std::fs::write(dir.join("lib.rs"), "a\nb\nc\nd\nE\n").unwrap();
+            let mut stash = Autostash::stash(&dir).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+                "a\nb\nc\nd\ne\n"
+            );
+            std::fs::write(dir.join("lib.rs"), "A\nb\nc\nd\ne\n").unwrap();
+            stash.restore().unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+                "A\nb\nc\nd\nE\n"
+            );
+        }
+
+        #[test]
+        fn test_restore_without_diff_prefixes() {
+            let dir = repo("autostash-noprefix");
+            git(&dir, &["config", "diff.noprefix", "true"]).unwrap();
+            std::fs::write(dir.join("lib.rs"), "a\nb\nc\nd\nE\n").unwrap();
+            let mut stash = Autostash::stash(&dir).unwrap();
+            stash.restore().unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+                "a\nb\nc\nd\nE\n"
+            );
+        }
+
+        #[test]
+        fn test_conflict_rolls_back() {
+            let dir = repo("autostash-conflict");
+            std::fs::write(dir.join("lib.rs"), "a\nb\nc\nd\nE\n").unwrap();
+            let mut stash = Autostash::stash(&dir).unwrap();
+            std::fs::write(dir.join("lib.rs"), "a\nb\nc\nd\nF\n").unwrap();
+            assert!(stash.restore().is_err());
+            assert_eq!(
+                std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+                "a\nb\nc\nd\nE\n"
+            );
+        }
+    }
+}
+
+mod staged {
+    use anyhow::{bail, Context};
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    use super::rustfmt::{self, LineRange};
+
+    /// Files with staged changes, excluding deletions.
+    pub fn changed_files(git_root: &Path) -> anyhow::Result<super::git_output::NameStatus> {
+        let output = git(
+            git_root,
+            &[
+                "diff",
+                "--cached",
+                "-z",
+                "--find-renames",
+                "--diff-filter=d",
+                "--name-status",
+            ],
+        )?;
+        super::git_output::parse_name_status(&output)
+    }
+
+    /// The staged content of `file`.
+    pub fn read(git_root: &Path, file: &Path) -> anyhow::Result<String> {
+        let (_, blob) = entry(git_root, file)?;
+        Ok(String::from_utf8(git(
+            git_root,
+            &["cat-file", "blob", &blob],
+        )?)?)
+    }
+
+    /// Line ranges of `file` whose staged content differs from `HEAD`.
+    pub fn changed_line_ranges(git_root: &Path, file: &Path) -> anyhow::Result<Vec<LineRange>> {
+        let diff = git(
+            git_root,
+            &[
+                "diff",
+                "--cached",
+                "--no-color",
+                "-U0",
+                "--",
+                &file.to_string_lossy(),
+            ],
+        )?;
+        Ok(rustfmt::parse_hunk_ranges(&String::from_utf8(diff)?))
+    }
+
+    /// Replaces the staged content of `file` with `polish` applied to it and
+    /// returns whether it changed. The working tree copy is only updated if
+    /// it matches what was staged; otherwise it holds unstaged changes and
+    /// is left alone.
+    pub fn update(
+        git_root: &Path,
+        file: &Path,
+        polish: impl FnOnce(&str) -> anyhow::Result<String>,
+    ) -> anyhow::Result<bool> {
+        let (mode, blob) = entry(git_root, file)?;
+        let staged = String::from_utf8(git(git_root, &["cat-file", "blob", &blob])?)?;
+        let polished = polish(&staged)?;
+        if polished == staged {
+            return Ok(false);
+        }
+
+        let mut child = Command::new("git")
+            .args(["hash-object", "-w", "--stdin"])
+            .current_dir(git_root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to run git hash-object")?;
+        child
+            .stdin
+            .take()
+            .context("Failed to open git hash-object stdin")?
+            .write_all(polished.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("git hash-object failed on {}", file.display());
+        }
+        let new_blob = String::from_utf8(output.stdout)?.trim().to_string();
+        git(
+            git_root,
+            &[
+                "update-index",
+                "--cacheinfo",
+                &format!("{mode},{new_blob},{}", file.to_string_lossy()),
+            ],
+        )?;
+
+        let path = git_root.join(file);
+        if std::fs::read_to_string(&path).ok().as_deref() == Some(staged.as_str()) {
+            super::interrupt::write(&path, &polished)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        } else {
+            eprintln!(
+                "Polished the staged content of {}, leaving its unstaged changes alone",
+                file.display()
+            );
+        }
+        Ok(true)
+    }
+
+    /// Mode and blob hash of `file` in the index.
+    fn entry(git_root: &Path, file: &Path) -> anyhow::Result<(String, String)> {
+        let output = git(
+            git_root,
+            &["ls-files", "--stage", "--", &file.to_string_lossy()],
+        )?;
+        let output = String::from_utf8(output)?;
+        let mut fields = output.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(mode), Some(blob)) => Ok((mode.to_string(), blob.to_string())),
+            _ => bail!("{} is not in the index", file.display()),
+        }
+    }
+
+    fn git(git_root: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(git_root)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+        use std::path::PathBuf;
+
+        #[test]
+        fn test_update_keeps_unstaged_changes() {
+            let dir = TempDir::new("staged");
+            git(&dir, &["init", "-q"]).unwrap();
+            let file = Path::new("lib.rs");
+            std::fs::write(dir.join(file), "a\n").unwrap();
+            git(&dir, &["add", "lib.rs"]).unwrap();
+
+            // Fully staged: the working tree follows the index
+            assert!(update(&dir, file, |c| Ok(c.to_uppercase())).unwrap());
+            assert_eq!(read(&dir, file).unwrap(), "A\n");
+            assert_eq!(std::fs::read_to_string(dir.join(file)).unwrap(), "A\n");
+
+            // Partially staged: only the index changes
+            std::fs::write(dir.join(file), "A\nb\n").unwrap();
+            assert!(update(&dir, file, |c| Ok(format!("{c}c\n"))).unwrap());
+            assert_eq!(read(&dir, file).unwrap(), "A\nc\n");
+            assert_eq!(std::fs::read_to_string(dir.join(file)).unwrap(), "A\nb\n");
+
+            assert!(!update(&dir, file, |c| Ok(c.to_string())).unwrap());
+            assert_eq!(
+                changed_files(&dir).unwrap().paths,
+                [PathBuf::from("lib.rs")]
+            );
+        }
+    }
+}
+
+mod snapshot {
+    use anyhow::{bail, Context};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    /// A change `--check` would have made to a file, as recorded in the JSON
+    /// report so that it can be applied elsewhere with `apply --from`.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub struct FileEdit {
+        /// Path relative to the repository root
+        pub file: PathBuf,
+        /// Hash of the file's contents before the edit
+        pub before: String,
+        pub after: String,
+        /// The edit as a diff for `git apply`
+        #[serde(default)]
+        pub diff: String,
+        #[serde(default)]
+        pub stats: super::diff::Stats,
+    }
+
+    impl FileEdit {
+        /// Writes the edited contents if the file under `root` is still the
+        /// one the edit was recorded against. Returns `false` if the edit is
+        /// already in place.
+        pub fn apply(&self, root: &Path) -> anyhow::Result<bool> {
+            let path = root.join(&self.file);
+            let current = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if current == self.after.as_bytes() {
+                return Ok(false);
+            }
+            if content_hash(&current) != self.before {
+                bail!("{} changed since the report was made", self.file.display());
+            }
+            super::interrupt::write(&path, &self.after)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(true)
+        }
+    }
+
+    pub fn content_hash(bytes: &[u8]) -> String {
+        let mut hasher = super::clippy_cache::Fnv1a::default();
+        hasher.write(bytes);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Contents of files before a run, to tell what it changed.
+    pub struct Snapshot {
+        root: PathBuf,
+        files: BTreeMap<PathBuf, Original>,
+    }
+
+    struct Original {
+        content: Vec<u8>,
+        mode: Option<u32>,
+    }
+
+    #[cfg(unix)]
+    fn file_mode(path: &Path) -> Option<u32> {
+        use std::os::unix::fs::PermissionsExt;
+        Some(std::fs::metadata(path).ok()?.permissions().mode())
+    }
+
+    #[cfg(not(unix))]
+    fn file_mode(_path: &Path) -> Option<u32> {
+        None
+    }
+
+    impl Snapshot {
+        /// Records `paths`, relative to `root` or absolute; missing files are
+        /// skipped.
+        pub fn take(root: &Path, paths: impl IntoIterator<Item = PathBuf>) -> Snapshot {
+            let files = paths
+                .into_iter()
+                .map(|path| root.join(path))
+                .filter_map(|path| {
+                    let original = Original {
+                        content: std::fs::read(&path).ok()?,
+                        mode: file_mode(&path),
+                    };
+                    Some((path, original))
+                })
+                .collect();
+            Snapshot {
+                root: root.to_path_buf(),
+                files,
+            }
+        }
+
+        /// Returns the changes made since the snapshot.
+        pub fn keep(&mut self) -> anyhow::Result<Vec<FileEdit>> {
+            let mut edits = Vec::new();
+            for (path, original) in std::mem::take(&mut self.files) {
+                let Ok(current) = std::fs::read(&path) else {
+                    continue;
+                };
+                let mode = file_mode(&path);
+                if current == original.content && mode == original.mode {
+                    continue;
+                }
+                let file = path.strip_prefix(&self.root).unwrap_or(&path).to_path_buf();
+                let diff =
+                    unified_diff(&file, (&original.content, original.mode), (&current, mode))?;
+                let stats = match (
+                    std::str::from_utf8(&original.content),
+                    std::str::from_utf8(&current),
+                ) {
+                    (Ok(old), Ok(new)) => super::diff::stats(&file, old, new),
+                    _ => super::diff::Stats::default(),
+                };
+                edits.push(FileEdit {
+                    file,
+                    before: content_hash(&original.content),
+                    after: String::from_utf8_lossy(&current).into_owned(),
+                    diff,
+                    stats,
+                });
+            }
+            Ok(edits)
+        }
+    }
+
+    /// A `git apply` compatible diff of `file` going from `before` to
+    /// `after`, each given as contents and permission bits.
+    pub fn unified_diff(
+        file: &Path,
+        before: (&[u8], Option<u32>),
+        after: (&[u8], Option<u32>),
+    ) -> anyhow::Result<String> {
+        let file = file.to_string_lossy().replace('\\', "/");
+        let mut diff = format!("diff --git a/{file} b/{file}\n");
+        if let (Some(old), Some(new)) = (before.1, after.1) {
+            if old & 0o111 != new & 0o111 {
+                diff.push_str(&format!(
+                    "old mode {}\nnew mode {}\n",
+                    git_mode(old),
+                    git_mode(new)
+                ));
+            }
+        }
+        if before.0 == after.0 {
+            return Ok(diff);
+        }
+        let (Ok(old), Ok(new)) = (std::str::from_utf8(before.0), std::str::from_utf8(after.0))
+        else {
+            bail!("{file} is not UTF-8 text");
+        };
+        diff.push_str(&format!("--- a/{file}\n+++ b/{file}\n"));
+        diff.push_str(&super::diff::hunks(old, new));
+        Ok(diff)
+    }
+
+    /// The mode git records for a regular file with these permissions.
+    fn git_mode(mode: u32) -> &'static str {
+        if mode & 0o111 != 0 {
+            "100755"
+        } else {
+            "100644"
+        }
+    }
+
+    /// Rust sources under `dir`, skipping `target` and hidden directories.
+    pub fn rust_files_under(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return files;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if path.is_dir() {
+                if name != "target" && !name.starts_with('.') {
+                    files.extend(rust_files_under(&path));
+                }
+            } else if name.ends_with(".rs") {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_keep() {
+            let dir = TempDir::new("snapshot");
+            std::fs::create_dir_all(dir.join("src/target")).unwrap();
+            std::fs::write(dir.join("src/lib.rs"), "mod a;\n").unwrap();
+            std::fs::write(dir.join("src/a.rs"), "fn a() {}\n").unwrap();
+            std::fs::write(dir.join("src/target/out.rs"), "").unwrap();
+
+            let mut files = rust_files_under(&dir);
+            files.sort();
+            assert_eq!(files, [dir.join("src/a.rs"), dir.join("src/lib.rs")]);
+
+            let mut snapshot = Snapshot::take(&dir, files);
+            std::fs::write(dir.join("src/lib.rs"), "pub mod a;\n").unwrap();
+            let edits = snapshot.keep().unwrap();
+            assert_eq!(
+                edits,
+                [FileEdit {
+                    file: PathBuf::from("src/lib.rs"),
+                    before: content_hash(b"mod a;\n"),
+                    after: "pub mod a;\n".to_string(),
+                    diff: "diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-mod a;\n+pub mod a;\n".to_string(),
+                    stats: super::super::diff::Stats {
+                        hunks: 1,
+                        ..Default::default()
+                    },
+                }]
+            );
+            assert_eq!(
+                std::fs::read_to_string(dir.join("src/lib.rs")).unwrap(),
+                "pub mod a;\n"
+            );
+
+            std::fs::write(dir.join("src/lib.rs"), "mod a;\n").unwrap();
+            assert!(edits[0].apply(&dir).unwrap());
+            assert!(!edits[0].apply(&dir).unwrap());
+            std::fs::write(dir.join("src/lib.rs"), "mod b;\n").unwrap();
+            assert!(edits[0].apply(&dir).is_err());
+        }
+
+        #[test]
+        fn test_unified_diff() {
+            let diff = unified_diff(
+                Path::new("build.sh"),
+                (b"a\nb\n", Some(0o100644)),
+                (b"a\nc", Some(0o100755)),
+            )
+            .unwrap();
+            assert_eq!(
+                diff,
+                "diff --git a/build.sh b/build.sh\nold mode 100644\nnew mode 100755\n--- a/build.sh\n+++ b/build.sh\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n\\ No newline at end of file\n"
+            );
+        }
+    }
+}
+
+mod scratch {
+    use anyhow::{bail, Context};
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// A copy of the working tree for check mode to run the fixing stages
+    /// in, so that the real one is never written to: an edit made during
+    /// the run, or a crash, can't lose anything. Git commands in the copy
+    /// see the repository through a `.git` file, with their own copy of
+    /// the index. Removed on drop.
+    pub struct Scratch {
+        dir: PathBuf,
+        /// The copy of the working tree.
+        pub root: PathBuf,
+        index: PathBuf,
+    }
+
+    impl Scratch {
+        /// Copies the tracked and untracked, not ignored, files of the
+        /// working tree at `git_root`.
+        pub fn new(git_root: &Path) -> anyhow::Result<Scratch> {
+            let dir = std::env::temp_dir().join(format!("polish-check-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let scratch = Scratch {
+                root: dir.join("tree"),
+                index: dir.join("index"),
+                dir,
+            };
+            std::fs::create_dir_all(&scratch.root)
+                .with_context(|| format!("Failed to create {}", scratch.root.display()))?;
+
+            let files = git(
+                git_root,
+                &[
+                    "ls-files",
+                    "-z",
+                    "--cached",
+                    "--others",
+                    "--exclude-standard",
+                ],
+            )?;
+            for file in super::git_output::split_paths(&files) {
+                copy(&git_root.join(&file), &scratch.root.join(&file))
+                    .with_context(|| format!("Failed to copy {}", file.display()))?;
+            }
+
+            let git_dir = String::from_utf8(git(git_root, &["rev-parse", "--absolute-git-dir"])?)?;
+            std::fs::write(
+                scratch.root.join(".git"),
+                format!("gitdir: {}\n", git_dir.trim()),
+            )?;
+            let index = String::from_utf8(git(git_root, &["rev-parse", "--git-path", "index"])?)?;
+            let index = git_root.join(index.trim());
+            if index.exists() {
+                std::fs::copy(&index, &scratch.index)
+                    .with_context(|| format!("Failed to copy {}", index.display()))?;
+            }
+            Ok(scratch)
+        }
+
+        /// Makes the copy the current directory, so relative paths resolve
+        /// into it, and its index git's, until the guard is dropped.
+        pub fn enter(&self) -> anyhow::Result<Entered> {
+            let entered = Entered {
+                cwd: std::env::current_dir().context("Failed to get the current directory")?,
+                index: std::env::var_os("GIT_INDEX_FILE"),
+            };
+            std::env::set_current_dir(&self.root)
+                .with_context(|| format!("Failed to enter {}", self.root.display()))?;
+            std::env::set_var("GIT_INDEX_FILE", &self.index);
+            Ok(entered)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Restores the current directory and `GIT_INDEX_FILE` on drop.
+    pub struct Entered {
+        cwd: PathBuf,
+        index: Option<OsString>,
+    }
+
+    impl Drop for Entered {
+        fn drop(&mut self) {
+            if let Err(e) = std::env::set_current_dir(&self.cwd) {
+                eprintln!("Failed to return to {}: {e}", self.cwd.display());
+            }
+            match &self.index {
+                Some(index) => std::env::set_var("GIT_INDEX_FILE", index),
+                None => std::env::remove_var("GIT_INDEX_FILE"),
+            }
+        }
+    }
+
+    /// Copies a file or symlink, with its permissions. Submodules and files
+    /// deleted from the working tree are skipped.
+    fn copy(from: &Path, to: &Path) -> std::io::Result<()> {
+        let Ok(metadata) = std::fs::symlink_metadata(from) else {
+            return Ok(());
+        };
+        if metadata.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        #[cfg(unix)]
+        if metadata.file_type().is_symlink() {
+            return std::os::unix::fs::symlink(std::fs::read_link(from)?, to);
+        }
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_copy() {
+            let dir = TempDir::new("scratch");
+            git(&dir, &["init", "-q"]).unwrap();
+            std::fs::create_dir_all(dir.join("src")).unwrap();
+            std::fs::write(dir.join("src/lib.rs"), "mod a;\n").unwrap();
+            std::fs::write(dir.join("src/new.rs"), "").unwrap();
+            std::fs::write(dir.join(".gitignore"), "/target\n").unwrap();
+            std::fs::create_dir_all(dir.join("target")).unwrap();
+            std::fs::write(dir.join("target/out"), "").unwrap();
+            git(&dir, &["add", "src/lib.rs", ".gitignore"]).unwrap();
+
+            let scratch = Scratch::new(&dir).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(scratch.root.join("src/lib.rs")).unwrap(),
+                "mod a;\n"
+            );
+            assert!(scratch.root.join("src/new.rs").exists());
+            assert!(!scratch.root.join("target").exists());
+
+            // Git in the copy compares its files with the repository's index
+            std::fs::write(scratch.root.join("src/lib.rs"), "pub mod a;\n").unwrap();
+            let diff = Command::new("git")
+                .args(["diff", "--name-only"])
+                .current_dir(&scratch.root)
+                .env("GIT_INDEX_FILE", &scratch.index)
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(diff.stdout).unwrap(), "src/lib.rs\n");
+            assert_eq!(
+                std::fs::read_to_string(dir.join("src/lib.rs")).unwrap(),
+                "mod a;\n"
+            );
+
+            let root = scratch.root.clone();
+            drop(scratch);
+            assert!(!root.exists());
+        }
+    }
+}
+
+mod diff {
+    use super::rustfmt::LineRange;
+    use super::{rust_grouping, toml_grouping};
+    use serde::{Deserialize, Serialize};
+    use std::collections::{HashMap, HashSet};
+    use std::path::Path;
+
+    /// Lines of context around each change in a hunk, as git uses.
+    const CONTEXT: usize = 3;
+
+    /// A run of changed lines: `text` replaces lines `start..end` (0-based)
+    /// of the old contents, or is inserted before `start` if they're equal.
+    #[derive(Debug, PartialEq)]
+    pub struct Replacement {
+        pub start: usize,
+        pub end: usize,
+        pub text: String,
+    }
+
+    /// The changes from `old` to `new`, one per run of changed lines.
+    pub fn replacements(old: &str, new: &str) -> Vec<Replacement> {
+        let old: Vec<&str> = old.split_inclusive('\n').collect();
+        let new: Vec<&str> = new.split_inclusive('\n').collect();
+        let ops = edit_script(&old, &new);
+
+        let mut replacements = Vec::new();
+        let (mut i, mut j, mut k) = (0, 0, 0);
+        while k < ops.len() {
+            if ops[k] == Op::Equal {
+                (i, j, k) = (i + 1, j + 1, k + 1);
+                continue;
+            }
+            let (i0, j0) = (i, j);
+            while k < ops.len() && ops[k] != Op::Equal {
+                match ops[k] {
+                    Op::Delete => i += 1,
+                    _ => j += 1,
+                }
+                k += 1;
+            }
+            replacements.push(Replacement {
+                start: i0,
+                end: i,
+                text: new[j0..j].concat(),
+            });
+        }
+        replacements
+    }
+
+    /// How much an edit of a file changes, for gauging it before accepting
+    /// it.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct Stats {
+        pub hunks: usize,
+        /// Lines removed in one place and added back in another
+        pub lines_moved: usize,
+        /// How many fewer `use` declarations there are
+        pub imports_merged: usize,
+        /// Moved dependency entries of a `Cargo.toml`
+        pub dependencies_reordered: usize,
+    }
+
+    impl std::fmt::Display for Stats {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "hunks: {}", self.hunks)?;
+            for (name, count) in [
+                ("lines moved", self.lines_moved),
+                ("imports merged", self.imports_merged),
+                ("dependencies reordered", self.dependencies_reordered),
+            ] {
+                if count > 0 {
+                    write!(f, ", {name}: {count}")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// The [`Stats`] of changing `file` from `old` to `new`.
+    pub fn stats(file: &Path, old: &str, new: &str) -> Stats {
+        let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+        let ops = edit_script(&old_lines, &new_lines);
+
+        // Removed lines matched with added ones, ignoring indentation
+        let mut removed: HashMap<&str, usize> = HashMap::new();
+        let mut added = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        for op in &ops {
+            match op {
+                Op::Equal => (i, j) = (i + 1, j + 1),
+                Op::Delete => {
+                    *removed.entry(old_lines[i].trim()).or_default() += 1;
+                    i += 1;
+                }
+                Op::Insert => {
+                    added.push(new_lines[j].trim());
+                    j += 1;
+                }
+            }
+        }
+        let mut moved = Vec::new();
+        for line in added.into_iter().filter(|l| !l.is_empty()) {
+            if let Some(count) = removed.get_mut(line).filter(|c| **c > 0) {
+                *count -= 1;
+                moved.push(line);
+            }
+        }
+
+        let uses = |content: &str| {
+            content
+                .lines()
+                .filter(|line| {
+                    let tokens = rust_grouping::tokens(line);
+                    rust_grouping::after_visibility(&tokens)
+                        .is_some_and(|rest| rest.first() == Some(&"use"))
+                })
+                .count()
+        };
+        let (imports_merged, dependencies_reordered) =
+            match file.extension().and_then(|e| e.to_str()) {
+                Some("rs") => (uses(old).saturating_sub(uses(new)), 0),
+                Some("toml") => (
+                    0,
+                    moved
+                        .iter()
+                        .filter(|l| toml_grouping::line_key(l).is_some())
+                        .count(),
+                ),
+                _ => (0, 0),
+            };
+        Stats {
+            hunks: hunks(old, new)
+                .lines()
+                .filter(|l| l.starts_with("@@ "))
+                .count(),
+            lines_moved: moved.len(),
+            imports_merged,
+            dependencies_reordered,
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op {
+        Equal,
+        Delete,
+        Insert,
+    }
+
+    /// The hunks of a unified diff from `old` to `new`, as `git diff` prints
+    /// them after the `---`/`+++` header.
+    pub fn hunks(old: &str, new: &str) -> String {
+        let old: Vec<&str> = old.split_inclusive('\n').collect();
+        let new: Vec<&str> = new.split_inclusive('\n').collect();
+        let ops = edit_script(&old, &new);
+
+        // Position in `old` and `new` before each op
+        let mut positions = Vec::with_capacity(ops.len());
+        let (mut i, mut j) = (0, 0);
+        for op in &ops {
+            positions.push((i, j));
+            match op {
+                Op::Equal => (i, j) = (i + 1, j + 1),
+                Op::Delete => i += 1,
+                Op::Insert => j += 1,
+            }
+        }
+
+        let mut out = String::new();
+        let mut next = 0;
+        while let Some(first) = (next..ops.len()).find(|&k| ops[k] != Op::Equal) {
+            // Changes less than two contexts apart share a hunk
+            let mut last = first;
+            while let Some(change) = (last + 1..ops.len()).find(|&k| ops[k] != Op::Equal) {
+                if change - last - 1 > 2 * CONTEXT {
+                    break;
+                }
+                last = change;
+            }
+            let start = first.saturating_sub(CONTEXT);
+            let end = (last + CONTEXT + 1).min(ops.len());
+            let (old_start, new_start) = positions[start];
+            let old_count = ops[start..end]
+                .iter()
+                .filter(|op| **op != Op::Insert)
+                .count();
+            let new_count = ops[start..end]
+                .iter()
+                .filter(|op| **op != Op::Delete)
+                .count();
+            out.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                range(old_start, old_count),
+                range(new_start, new_count)
+            ));
+            for (op, &(i, j)) in ops[start..end].iter().zip(&positions[start..end]) {
+                let (sign, line) = match op {
+                    Op::Equal => (' ', old[i]),
+                    Op::Delete => ('-', old[i]),
+                    Op::Insert => ('+', new[j]),
+                };
+                out.push(sign);
+                out.push_str(line);
+                if !line.ends_with('\n') {
+                    out.push_str("\n\\ No newline at end of file\n");
+                }
+            }
+            next = end;
+        }
+        out
+    }
+
+    /// `new` with only the changes from `old` that touch `ranges` (1-based
+    /// lines of `old`) applied. `None` if the changes taken don't just
+    /// rearrange the lines they replace, ignoring blank ones: a line moved
+    /// across the edge of the ranges would be lost or duplicated.
+    pub fn apply_within(old: &str, new: &str, ranges: &[LineRange]) -> Option<String> {
+        let old: Vec<&str> = old.split_inclusive('\n').collect();
+        let new: Vec<&str> = new.split_inclusive('\n').collect();
+        let ops = edit_script(&old, &new);
+        let within = |i: usize| ranges.iter().any(|r| (r.start..=r.end).contains(&(i + 1)));
+
+        let mut out = String::new();
+        let (mut removed, mut added) = (Vec::new(), Vec::new());
+        let (mut i, mut j, mut k) = (0, 0, 0);
+        while k < ops.len() {
+            if ops[k] == Op::Equal {
+                out.push_str(old[i]);
+                (i, j, k) = (i + 1, j + 1, k + 1);
+                continue;
+            }
+            let (i0, j0) = (i, j);
+            while k < ops.len() && ops[k] != Op::Equal {
+                match ops[k] {
+                    Op::Delete => i += 1,
+                    _ => j += 1,
+                }
+                k += 1;
+            }
+            // Insertions and blank line changes touch the closest lines with
+            // content on either side
+            let touches = if old[i0..i].iter().any(|l| !l.trim().is_empty()) {
+                (i0..i).any(within)
+            } else {
+                let content = |&x: &usize| !old[x].trim().is_empty();
+                (i0..i).any(within)
+                    || (0..i0).rev().find(content).is_some_and(within)
+                    || (i..old.len()).find(content).is_some_and(within)
+            };
+            if touches {
+                out.extend(new[j0..j].iter().copied());
+                removed.extend(old[i0..i].iter().map(|l| l.trim_end()));
+                added.extend(new[j0..j].iter().map(|l| l.trim_end()));
+            } else {
+                out.extend(old[i0..i].iter().copied());
+            }
+        }
+        for lines in [&mut removed, &mut added] {
+            lines.retain(|l| !l.is_empty());
+            lines.sort_unstable();
+        }
+        (removed == added).then_some(out)
+    }
+
+    /// A hunk header range: 1-based start and line count, with the count
+    /// left out when it's 1 and the start naming the preceding line when
+    /// it's 0.
+    fn range(start: usize, count: usize) -> String {
+        match count {
+            0 => format!("{start},0"),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{count}", start + 1),
+        }
+    }
+
+    /// Shortest edit script turning `a` into `b`.
+    fn edit_script<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Op> {
+        let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let mut ops = vec![Op::Equal; prefix];
+        ops.extend(myers(
+            &a[prefix..a.len() - suffix],
+            &b[prefix..b.len() - suffix],
+        ));
+        ops.extend(vec![Op::Equal; suffix]);
+        ops
+    }
+
+    /// Myers' O(ND) difference algorithm, keeping each round's furthest
+    /// reaching paths to backtrack through.
+    fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Op> {
+        let (n, m) = (a.len() as isize, b.len() as isize);
+        let offset = n + m + 1;
+        let index = |k: isize| (k + offset) as usize;
+        let mut v = vec![0isize; 2 * offset as usize + 1];
+        // Round `d` only reads diagonals `-d - 1..=d + 1`, so only those are
+        // kept for the backtrack: O(D²) memory instead of O(D·(N+M))
+        let mut trace = Vec::new();
+        'search: for d in 0..=n + m {
+            trace.push(v[index(-d - 1)..=index(d + 1)].to_vec());
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                    v[index(k + 1)]
+                } else {
+                    v[index(k - 1)] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                v[index(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+
+        let mut ops = Vec::new();
+        let (mut x, mut y) = (n, m);
+        for (d, window) in trace.iter().enumerate().rev() {
+            let d = d as isize;
+            let v = |k: isize| window[(k + d + 1) as usize];
+            let k = x - y;
+            let prev_k = if k == -d || (k != d && v(k - 1) < v(k + 1)) {
+                k + 1
+            } else {
+                k - 1
+            };
+            let prev_x = v(prev_k);
+            let prev_y = prev_x - prev_k;
+            while x > prev_x && y > prev_y {
+                ops.push(Op::Equal);
+                x -= 1;
+                y -= 1;
+            }
+            if d > 0 {
+                if x == prev_x {
+                    ops.push(Op::Insert);
+                } else {
+                    ops.push(Op::Delete);
+                }
+            }
+            (x, y) = (prev_x, prev_y);
+        }
+        ops.reverse();
+        ops
+    }
+
+    const RESET: &str = "\x1b[0m";
+    const BOLD: &str = "\x1b[1m";
+    const DIM: &str = "\x1b[2m";
+    const RED: &str = "\x1b[31m";
+    const GREEN: &str = "\x1b[32m";
+    const CYAN: &str = "\x1b[36m";
+    const REVERSE: &str = "\x1b[7m";
+    const NO_REVERSE: &str = "\x1b[27m";
+
+    /// Colors a unified diff for a terminal. A removed line that comes back
+    /// unchanged elsewhere (a reordered dependency or a moved import) is
+    /// dimmed; otherwise removed and added lines are paired up and the words
+    /// that differ between them are highlighted.
+    pub fn highlight(diff: &str) -> String {
+        let lines: Vec<&str> = diff.lines().collect();
+        let mut out = String::new();
+        let mut k = 0;
+        while k < lines.len() {
+            let line = lines[k];
+            if line.starts_with("diff --git")
+                || line.starts_with("--- ")
+                || line.starts_with("+++ ")
+                || line.starts_with("old mode")
+                || line.starts_with("new mode")
+            {
+                out.push_str(&format!("{BOLD}{line}{RESET}\n"));
+            } else if line.starts_with("@@") {
+                let end = (k + 1..lines.len())
+                    .find(|&e| lines[e].starts_with("@@") || lines[e].starts_with("diff --git"))
+                    .unwrap_or(lines.len());
+                out.push_str(&format!("{CYAN}{line}{RESET}\n"));
+                highlight_hunk(&lines[k + 1..end], &mut out);
+                k = end;
+                continue;
+            } else {
+                out.push_str(line);
+                out.push('\n');
+            }
+            k += 1;
+        }
+        out
+    }
+
+    fn highlight_hunk(lines: &[&str], out: &mut String) {
+        let removed: HashSet<&str> = lines.iter().filter_map(|l| l.strip_prefix('-')).collect();
+        let added: HashSet<&str> = lines.iter().filter_map(|l| l.strip_prefix('+')).collect();
+        let changed = |k: usize, sign: char| {
+            let line = lines[k];
+            line.starts_with(sign)
+                && !match sign {
+                    '-' => added.contains(&line[1..]),
+                    _ => removed.contains(&line[1..]),
+                }
+        };
+
+        // Pair each removed line with the added line for the same key (a
+        // dependency or item name) anywhere in the hunk, else with the added
+        // line at the same position in its block of changes
+        let mut partner: Vec<Option<usize>> = vec![None; lines.len()];
+        let key = |k: usize| tokenize(lines[k][1..].trim_start()).first().copied();
+        for d in (0..lines.len()).filter(|&d| changed(d, '-')) {
+            if let Some(i) = (0..lines.len())
+                .find(|&i| changed(i, '+') && partner[i].is_none() && key(i) == key(d))
+            {
+                partner[d] = Some(i);
+                partner[i] = Some(d);
+            }
+        }
+        let mut k = 0;
+        while k < lines.len() {
+            let end = (k..lines.len())
+                .find(|&e| !lines[e].starts_with(['-', '+']))
+                .unwrap_or(lines.len());
+            let changes = |sign| (k..end).filter(move |&e| changed(e, sign));
+            let deletes: Vec<usize> = changes('-').filter(|&e| partner[e].is_none()).collect();
+            let inserts: Vec<usize> = changes('+').filter(|&e| partner[e].is_none()).collect();
+            for (&d, &i) in deletes.iter().zip(&inserts) {
+                partner[d] = Some(i);
+                partner[i] = Some(d);
+            }
+            k = end + 1;
+        }
+
+        for (k, line) in lines.iter().enumerate() {
+            let Some(sign @ ('-' | '+')) = line.chars().next() else {
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            };
+            let rendered = match (sign, partner[k]) {
+                ('-', Some(other)) => words(&line[1..], &lines[other][1..], '-'),
+                (_, Some(other)) => words(&lines[other][1..], &line[1..], '+'),
+                (_, None) if !changed(k, sign) => format!("{DIM}{line}{RESET}\n"),
+                ('-', None) => format!("{RED}{line}{RESET}\n"),
+                (_, None) => format!("{GREEN}{line}{RESET}\n"),
+            };
+            out.push_str(&rendered);
+        }
+    }
+
+    /// One side (`-` for `old`, `+` for `new`) of a changed line, with the
+    /// words not shared with the other side in reverse video.
+    fn words(old: &str, new: &str, side: char) -> String {
+        let (old_words, new_words) = (tokenize(old), tokenize(new));
+        let ops = edit_script(&old_words, &new_words);
+        let (color, words, skip) = match side {
+            '-' => (RED, &old_words, Op::Insert),
+            _ => (GREEN, &new_words, Op::Delete),
+        };
+        let mut out = format!("{color}{side}");
+        let mut words = words.iter();
+        for op in ops.into_iter().filter(|op| *op != skip) {
+            let Some(word) = words.next() else { break };
+            if op == Op::Equal {
+                out.push_str(word);
+            } else {
+                out.push_str(&format!("{REVERSE}{word}{NO_REVERSE}"));
+            }
+        }
+        out.push_str(RESET);
+        out.push('\n');
+        out
+    }
+
+    /// Identifiers and numbers, runs of whitespace, and single punctuation
+    /// characters.
+    fn tokenize(line: &str) -> Vec<&str> {
+        let class = |c: char| {
+            if c.is_alphanumeric() || c == '_' {
+                0
+            } else if c.is_whitespace() {
+                1
+            } else {
+                2
+            }
+        };
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let joins = chars
+                .peek()
+                .is_some_and(|&(_, next)| class(c) != 2 && class(next) == class(c));
+            if !joins {
+                tokens.push(&line[start..i + c.len_utf8()]);
+                start = i + c.len_utf8();
+            }
+        }
+        tokens
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_hunks() {
+            assert_eq!(hunks("a\nb\nc\n", "a\nb\nc\n"), "");
+            assert_eq!(
+                hunks("a\nb\nc\n", "a\nc\nd"),
+                "@@ -1,3 +1,3 @@\n a\n-b\n c\n+d\n\\ No newline at end of file\n"
+            );
+            assert_eq!(hunks("", "a\n"), "@@ -0,0 +1 @@\n+a\n");
+
+            // Changes more than six lines apart get separate hunks
+            let old: String = (1..=20).map(|i| format!("{i}\n")).collect();
+            let new = old
+                .replacen("2\n", "two\n", 1)
+                .replace("18\n", "eighteen\n");
+            assert_eq!(
+                hunks(&old, &new),
+                "@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n@@ -15,6 +15,6 @@\n 15\n 16\n 17\n-18\n+eighteen\n 19\n 20\n"
+            );
+        }
+
+        #[test]
+        fn test_myers() {
+            // Every other line changed, so the search takes many rounds
+            let a: Vec<usize> = (0..400).collect();
+            let b: Vec<usize> = (0..400)
+                .map(|i| if i % 2 == 0 { i } else { i + 1000 })
+                .collect();
+            let ops = myers(&a, &b);
+            let count = |op: Op| ops.iter().filter(|o| **o == op).count();
+            assert_eq!((count(Op::Delete), count(Op::Insert)), (200, 200));
+
+            let (mut i, mut j) = (0, 0);
+            for op in ops {
+                match op {
+                    Op::Equal => {
+                        assert_eq!(a[i], b[j]);
+                        (i, j) = (i + 1, j + 1);
+                    }
+                    Op::Delete => i += 1,
+                    Op::Insert => j += 1,
+                }
+            }
+            assert_eq!((i, j), (a.len(), b.len()));
+            assert_eq!(myers::<usize>(&[], &[]), []);
+            assert_eq!(myers(&[1], &[]), [Op::Delete]);
+            assert_eq!(myers(&[], &[1]), [Op::Insert]);
+        }
+
+        #[test]
+        fn test_stats() {
+            let old = "use b::x;\nuse a::y;\nuse a::z;\n\nfn f() {}\n";
+            let new = "use a::{y, z};\nuse b::x;\n\nfn f() {}\n";
+            assert_eq!(
+                stats(Path::new("src/lib.rs"), old, new),
+                Stats {
+                    hunks: 1,
+                    lines_moved: 0,
+                    imports_merged: 1,
+                    dependencies_reordered: 0,
+                }
+            );
+
+            let old = "[dependencies]\nb = \"1\"\na = \"1\"\n";
+            let new = "[dependencies]\na = \"1\"\nb = \"1\"\n";
+            let stats = stats(Path::new("Cargo.toml"), old, new);
+            assert_eq!(stats.dependencies_reordered, 1);
+            assert_eq!(
+                stats.to_string(),
+                "hunks: 1, lines moved: 1, dependencies reordered: 1"
+            );
+        }
+
+        #[test]
+        fn test_apply_within() {
+            let range = |start, end| [LineRange { start, end }];
+            let old = "mod b;\npub mod a;\n\nfn f() {}\n\nmod {\n    mod d;\n    pub mod c;\n}\n";
+            let new =
+                "pub mod a;\n\nmod b;\n\nfn f() {}\n\nmod {\n    pub mod c;\n\n    mod d;\n}\n";
+            assert_eq!(
+                apply_within(old, new, &range(1, 2)).unwrap(),
+                "pub mod a;\n\nmod b;\n\nfn f() {}\n\nmod {\n    mod d;\n    pub mod c;\n}\n"
+            );
+            assert_eq!(
+                apply_within(old, new, &range(7, 8)).unwrap(),
+                "mod b;\npub mod a;\n\nfn f() {}\n\nmod {\n    pub mod c;\n\n    mod d;\n}\n"
+            );
+            assert_eq!(apply_within(old, new, &range(6, 6)).unwrap(), old);
+            assert_eq!(apply_within(old, new, &range(1, 10)).unwrap(), new);
+
+            // `c` would be taken out of the range but not put back in
+            let old = "mod a;\nmod b;\npub mod c;\n";
+            let new = "pub mod c;\n\nmod a;\nmod b;\n";
+            assert_eq!(apply_within(old, new, &range(3, 3)), None);
+        }
+
+        #[test]
+        fn test_highlight() {
+            let diff = "@@ -1,3 +1,3 @@\n-serde = \"1\"\n-tokio = \"1.0\"\n anyhow = \"1\"\n+serde = \"1\"\n+tokio = \"1.2\"\n";
+            let highlighted = highlight(diff);
+            assert!(highlighted.contains(&format!("{DIM}-serde = \"1\"{RESET}")));
+            assert!(highlighted.contains(&format!(
+                "{RED}-tokio = \"1.{REVERSE}0{NO_REVERSE}\"{RESET}"
+            )));
+            assert!(highlighted.contains(&format!(
+                "{GREEN}+tokio = \"1.{REVERSE}2{NO_REVERSE}\"{RESET}"
+            )));
+        }
+
+        #[test]
+        fn test_tokenize() {
+            assert_eq!(
+                tokenize("use std::io;  // x"),
+                ["use", " ", "std", ":", ":", "io", ";", "  ", "/", "/", " ", "x"]
+            );
+        }
+    }
+}
+
+mod config {
+    use super::modules::ModuleStyle;
+    use super::targets::TargetKind;
+    use anyhow::{bail, Context};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    pub const CONFIG_FILE: &str = "polish.toml";
+
+    /// Top-level key naming the config a file builds on, e.g.
+    /// `extends = "../polish-base.toml"` or `extends = "acme"`.
+    pub const EXTENDS_KEY: &str = "extends";
+
+    /// Directory holding the presets `extends` can name, by default
+    /// `polish/presets` in the user's config directory.
+    pub const PRESETS_VAR: &str = "POLISH_PRESETS_DIR";
+
+    /// Key of a profile listing command-line arguments rather than settings.
+    const PROFILE_ARGS_KEY: &str = "args";
+
+    /// Comma-separated stages to skip for one invocation, e.g.
+    /// `POLISH_SKIP=clippy,toml`.
+    pub const SKIP_VAR: &str = "POLISH_SKIP";
+
+    /// Contents of `polish.toml` at the repository root. Every section is
+    /// optional; command-line flags take precedence over these values.
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct Config {
+        /// Stages to run, in order
+        pub stages: Vec<Stage>,
+        /// Directories holding Rust code, e.g. `["services/", "libs/"]`;
+        /// changes elsewhere are ignored. The whole repository if empty
+        pub rust_roots: Vec<PathBuf>,
+        /// Directories whose changes are always ignored, on top of cargo's
+        /// target directory, e.g. `["vendor/", "third_party/"]`. Just
+        /// `["vendor/"]` by default
+        pub exclude_dirs: Vec<PathBuf>,
+        pub clippy: ClippyConfig,
+        pub toolchain: ToolchainConfig,
+        /// rustfmt options forwarded via `--config`, e.g. `max_width = 120`
+        pub rustfmt: BTreeMap<String, toml::Value>,
+        pub cargo: CargoConfig,
+        /// Extra environment variables for every spawned command, e.g.
+        /// `RUSTC_WRAPPER = "sccache"`
+        pub env: BTreeMap<String, String>,
+        pub generated: GeneratedConfig,
+        pub derive: DeriveConfig,
+        pub modules: ModulesConfig,
+        pub manifest: ManifestConfig,
+        pub safety: SafetyConfig,
+        pub owners: OwnersConfig,
+        pub grouping: super::rust_grouping::Options,
+        pub dependencies: super::toml_grouping::Options,
+        pub adoption: AdoptionConfig,
+        /// Files outside every package directory, such as ones pulled in with
+        /// `#[path]` or `include!`, by glob relative to the repository root,
+        /// and the packages compiling them, e.g.
+        /// `"shared/proto/*.rs" = ["api", "server"]`
+        pub extra_sources: BTreeMap<String, Vec<String>>,
+        /// Named sets of overrides selected with `--profile`, each laid over
+        /// the rest of the file, plus `args` put before the command line
+        pub profile: BTreeMap<String, toml::Table>,
+    }
+
+    impl Config {
+        /// The `args` of profile `name`.
+        pub fn profile_args(&self, name: &str) -> anyhow::Result<Vec<String>> {
+            let profile = self
+                .profile
+                .get(name)
+                .with_context(|| self.unknown_profile(name))?;
+            let Some(args) = profile.get(PROFILE_ARGS_KEY) else {
+                return Ok(Vec::new());
+            };
+            let args = args
+                .as_array()
+                .and_then(|args| args.iter().map(|a| a.as_str().map(String::from)).collect());
+            args.with_context(|| format!("`args` of profile `{name}` must be a list of strings"))
+        }
+
+        fn unknown_profile(&self, name: &str) -> String {
+            let names: Vec<&str> = self.profile.keys().map(String::as_str).collect();
+            if names.is_empty() {
+                format!("No profile `{name}`: {CONFIG_FILE} defines none")
+            } else {
+                format!("No profile `{name}`, only {}", names.join(", "))
+            }
+        }
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                stages: Stage::DEFAULT.to_vec(),
+                rust_roots: Vec::new(),
+                exclude_dirs: vec![PathBuf::from("vendor/")],
+                clippy: ClippyConfig::default(),
+                toolchain: ToolchainConfig::default(),
+                rustfmt: BTreeMap::new(),
+                cargo: CargoConfig::default(),
+                env: BTreeMap::new(),
+                generated: GeneratedConfig::default(),
+                derive: DeriveConfig::default(),
+                modules: ModulesConfig::default(),
+                manifest: ManifestConfig::default(),
+                safety: SafetyConfig::default(),
+                owners: OwnersConfig::default(),
+                grouping: super::rust_grouping::Options::default(),
+                dependencies: super::toml_grouping::Options::default(),
+                adoption: AdoptionConfig::default(),
+                extra_sources: BTreeMap::new(),
+                profile: BTreeMap::new(),
+            }
+        }
+    }
+
+    /// A step of a run; `--no-grouping` skips both `group` and `toml`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Stage {
+        /// Declaration grouping, derive sorting and polish's own checks
+        Group,
+        /// Dependency organization in `Cargo.toml`
+        Toml,
+        Fmt,
+        Clippy,
+        /// Checks that `Cargo.lock` is up to date with changed manifests
+        Lockfile,
+        /// Tests of members with changed `unsafe` code under Miri
+        Miri,
+    }
+
+    impl Stage {
+        pub const ALL: [Stage; 6] = [
+            Stage::Group,
+            Stage::Toml,
+            Stage::Fmt,
+            Stage::Clippy,
+            Stage::Lockfile,
+            Stage::Miri,
+        ];
+
+        /// Stages run when `polish.toml` doesn't list any.
+        pub const DEFAULT: [Stage; 4] = [Stage::Group, Stage::Toml, Stage::Fmt, Stage::Clippy];
+
+        /// Whether the stage writes its fixes to the working tree.
+        pub fn fixes(self) -> bool {
+            matches!(
+                self,
+                Stage::Group | Stage::Toml | Stage::Fmt | Stage::Lockfile
+            )
+        }
+    }
+
+    impl std::str::FromStr for Stage {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Stage> {
+            Stage::ALL
+                .into_iter()
+                .find(|stage| stage.to_string() == s)
+                .with_context(|| {
+                    format!(
+                        "unknown stage `{s}`, expected one of group, toml, fmt, clippy, lockfile, miri"
+                    )
+                })
+        }
+    }
+
+    /// Parses the value of [`SKIP_VAR`]; blank entries are ignored.
+    pub fn parse_skip(value: &str) -> anyhow::Result<Vec<Stage>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    impl std::fmt::Display for Stage {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                Stage::Group => "group",
+                Stage::Toml => "toml",
+                Stage::Fmt => "fmt",
+                Stage::Clippy => "clippy",
+                Stage::Lockfile => "lockfile",
+                Stage::Miri => "miri",
+            })
+        }
+    }
+
+    /// Oldest tool versions a run accepts, as each tool's `--version`
+    /// prints them, e.g. `rustfmt = "1.7.1"` or `clippy = "0.1.80"`
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ToolchainConfig {
+        pub cargo: Option<String>,
+        pub rustfmt: Option<String>,
+        pub clippy: Option<String>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ModulesConfig {
+        /// Check that every `mod foo;` in a changed file has exactly one
+        /// module file, and the layout given by `style`
+        pub enable: bool,
+        /// Required layout for modules with their own file, if any
+        pub style: Option<ModuleStyle>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct OwnersConfig {
+        /// Owners that count as you for `--owned-only`, e.g.
+        /// `["@alice", "@acme/payments"]`; git's `user.email` if empty
+        pub me: Vec<String>,
+        /// Ownership rules used instead of CODEOWNERS; the last matching
+        /// rule wins
+        pub rules: Vec<super::codeowners::Rule>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ManifestConfig {
+        /// `[package]` keys every changed manifest must set, e.g.
+        /// `["license", "rust-version", "repository"]`
+        pub required: Vec<String>,
+        /// Edition every changed manifest must use, e.g. `"2021"`
+        pub edition: Option<String>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct SafetyConfig {
+        /// Require a `// SAFETY:` comment on every `unsafe` block, function
+        /// and impl added to a changed file
+        pub require_comments: bool,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct DeriveConfig {
+        /// Canonical derive order for `--sort-derives`, e.g.
+        /// `["Debug", "Clone", "Copy"]`; unlisted derives follow
+        /// alphabetically
+        pub order: Vec<String>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct AdoptionConfig {
+        /// Paths relative to the repository root that grouping, polish's own
+        /// checks and strict clippy apply to, e.g. `"crates/new-*/**"`;
+        /// everywhere if empty. Other files only get the non-invasive stages
+        pub include: Vec<String>,
+    }
+
+    impl AdoptionConfig {
+        /// Whether `path`, relative to the repository root, has opted in.
+        pub fn includes(&self, path: &Path) -> bool {
+            let path = path.to_string_lossy().replace('\\', "/");
+            self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|glob| super::generated::glob_matches(glob, &path))
+        }
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct GeneratedConfig {
+        /// Paths relative to the repository root that are always treated as
+        /// generated, e.g. `"src/proto/**/*.rs"`
+        pub globs: Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct CargoConfig {
+        /// Target directory for polish's cargo runs, relative to the
+        /// repository root; empty to share cargo's default one
+        pub target_dir: Option<PathBuf>,
+    }
+
+    impl Default for CargoConfig {
+        fn default() -> Self {
+            CargoConfig {
+                target_dir: Some(PathBuf::from("target/polish")),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ClippyConfig {
+        /// Number of warnings allowed before the run fails
+        pub max_warnings: Option<usize>,
+        /// Per-lint warning budgets, e.g. `"clippy::too_many_arguments" = 3`
+        pub lint_budgets: BTreeMap<String, usize>,
+        /// Target kinds to lint, narrowed further to those with changed files
+        pub targets: Vec<TargetKind>,
+        /// Most packages linted by one cargo invocation; more affected
+        /// packages are split across several
+        pub batch_size: usize,
+        /// Cargo subcommand and arguments that lint instead of clippy, e.g.
+        /// `["dylint", "--all", "--", "{cargo_args}"]`. It gets the same `-p`
+        /// arguments, `{cargo_args}` stands for `--message-format=json` and
+        /// the target selection, and it must print cargo's JSON messages
+        pub command: Vec<String>,
+    }
+
+    impl ClippyConfig {
+        /// Whether the lint command is plain `cargo clippy`.
+        pub fn is_stock(&self) -> bool {
+            self.command.first().is_none_or(|first| first == "clippy")
+        }
+    }
+
+    impl Default for ClippyConfig {
+        fn default() -> Self {
+            ClippyConfig {
+                max_warnings: None,
+                lint_budgets: BTreeMap::new(),
+                targets: TargetKind::ALL.to_vec(),
+                batch_size: 50,
+                command: vec!["clippy".to_string()],
+            }
+        }
+    }
+
+    pub fn load(git_root: &Path) -> anyhow::Result<Config> {
+        load_profile(git_root, None)
+    }
+
+    /// The config with the settings of `profile`, if given, laid over it.
+    pub fn load_profile(git_root: &Path, profile: Option<&str>) -> anyhow::Result<Config> {
+        let path = git_root.join(CONFIG_FILE);
+        if !path.exists() {
+            if let Some(profile) = profile {
+                bail!("{}", Config::default().unknown_profile(profile));
+            }
+            return Ok(Config::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Invalid config in {}", path.display()))?;
+        let extends = table.contains_key(EXTENDS_KEY);
+        if !extends && profile.is_none() {
+            return parse(&content)
+                .with_context(|| format!("Invalid config in {}", path.display()));
+        }
+        if extends {
+            table = load_chain(&path, &mut Vec::new())?;
+        }
+        if let Some(profile) = profile {
+            apply_profile(&mut table, profile)?;
+        }
+        toml::Value::Table(table).try_into().with_context(|| {
+            format!(
+                "Invalid config in {} or the files it extends",
+                path.display()
+            )
+        })
+    }
+
+    /// Lays the settings of profile `name` over `table`.
+    fn apply_profile(table: &mut toml::Table, name: &str) -> anyhow::Result<()> {
+        let profile = table
+            .get("profile")
+            .and_then(|profiles| profiles.get(name))
+            .and_then(toml::Value::as_table)
+            .cloned();
+        let Some(mut profile) = profile else {
+            let config: Config = toml::Value::Table(table.clone()).try_into()?;
+            bail!("{}", config.unknown_profile(name));
+        };
+        profile.remove(PROFILE_ARGS_KEY);
+        merge(table, profile);
+        Ok(())
+    }
+
+    /// The table of the config at `path` laid over the configs it extends.
+    /// `chain` holds the files that led to it, to catch cycles.
+    fn load_chain(path: &Path, chain: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if chain.contains(&canonical) {
+            let cycle: Vec<String> = chain
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            bail!("Config extends itself: {}", cycle.join(" -> "));
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Invalid config in {}", path.display()))?;
+        let Some(extends) = table.remove(EXTENDS_KEY) else {
+            return Ok(table);
+        };
+        let Some(extends) = extends.as_str() else {
+            bail!("`{EXTENDS_KEY}` in {} must be a string", path.display());
+        };
+        let base_path = resolve_extends(path, extends)?;
+        chain.push(canonical);
+        let mut base = load_chain(&base_path, chain)?;
+        chain.pop();
+        merge(&mut base, table);
+        Ok(base)
+    }
+
+    /// `extends` of the config at `path`: a path relative to it if it looks
+    /// like one, else the name of a preset.
+    fn resolve_extends(path: &Path, extends: &str) -> anyhow::Result<PathBuf> {
+        if extends.ends_with(".toml") || extends.contains('/') {
+            return Ok(path.parent().unwrap_or(Path::new(".")).join(extends));
+        }
+        let dir = presets_dir().with_context(|| {
+            format!("Can't find preset `{extends}`: neither {PRESETS_VAR} nor HOME is set")
+        })?;
+        Ok(dir.join(format!("{extends}.toml")))
+    }
+
+    fn presets_dir() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os(PRESETS_VAR) {
+            return Some(PathBuf::from(dir));
+        }
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("polish").join("presets"))
+    }
+
+    /// Lays `over` on `base`: tables are merged key by key, any other value
+    /// (arrays included) replaces the base one.
+    fn merge(base: &mut toml::Table, over: toml::Table) {
+        for (key, value) in over {
+            match (base.get_mut(&key), value) {
+                (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+                (_, value) => {
+                    base.insert(key, value);
+                }
+            }
+        }
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Config> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Everything wrong with a `polish.toml`: syntax and type errors, then
+    /// keys the schema doesn't know, which serde would silently ignore.
+    pub fn check(content: &str) -> Vec<String> {
+        let mut table: toml::Table = match toml::from_str(content) {
+            Ok(table) => table,
+            Err(e) => return vec![e.to_string()],
+        };
+        let mut problems: Vec<String> = parse(content)
+            .err()
+            .into_iter()
+            .map(|e| e.to_string())
+            .collect();
+        let schema = serde_json::to_value(Config::default()).expect("config serializes");
+        if let Some(extends) = table.remove(EXTENDS_KEY) {
+            if !extends.is_str() {
+                problems.push(format!("`{EXTENDS_KEY}` must be a string"));
+            }
+        }
+        unknown_keys("", &table, &schema, &mut problems);
+        let profiles = table.get("profile").and_then(toml::Value::as_table);
+        for (name, profile) in profiles.into_iter().flatten() {
+            let Some(profile) = profile.as_table() else {
+                problems.push(format!("`profile.{name}` must be a table"));
+                continue;
+            };
+            let mut profile = profile.clone();
+            profile.remove(PROFILE_ARGS_KEY);
+            unknown_keys(
+                &format!("profile.{name}."),
+                &profile,
+                &schema,
+                &mut problems,
+            );
+        }
+        problems
+    }
+
+    /// Walks `table` alongside the serialized defaults. Tables that default
+    /// to empty (`[env]`, `[rustfmt]`, ...) take arbitrary keys and arrays
+    /// aren't descended into.
+    fn unknown_keys(
+        prefix: &str,
+        table: &toml::Table,
+        schema: &serde_json::Value,
+        problems: &mut Vec<String>,
+    ) {
+        let Some(schema) = schema.as_object().filter(|fields| !fields.is_empty()) else {
+            return;
+        };
+        for (key, value) in table {
+            let path = format!("{prefix}{key}");
+            match (schema.get(key), value) {
+                (Some(field), toml::Value::Table(table)) => {
+                    unknown_keys(&format!("{path}."), table, field, problems)
+                }
+                (Some(_), _) => {}
+                (None, _) => {
+                    let mut problem = format!("unknown key `{path}`");
+                    if let Some(suggestion) = closest(key, schema.keys()) {
+                        problem += &format!(", did you mean `{prefix}{suggestion}`?");
+                    }
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+
+    /// The candidate within a third of `key`'s length in edits, if any.
+    fn closest<'a>(key: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+        candidates
+            .map(|candidate| (edit_distance(key, candidate), candidate))
+            .filter(|(distance, _)| *distance <= (key.len() / 3).max(1))
+            .min()
+            .map(|(_, candidate)| candidate.as_str())
+    }
+
+    fn edit_distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, a) in a.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, b) in b.iter().enumerate() {
+                let substitution = diagonal + usize::from(a != *b);
+                diagonal = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+            }
+        }
+        row[b.len()]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_empty_config() {
+            let config = parse("").unwrap();
+            assert_eq!(config.clippy.max_warnings, None);
+            assert!(config.clippy.lint_budgets.is_empty());
+            assert_eq!(
+                config.cargo.target_dir,
+                Some(PathBuf::from("target/polish"))
+            );
+        }
+
+        #[test]
+        fn test_env_table() {
+            let config = parse(
+                r#"[env]
+RUSTC_WRAPPER = "sccache"
+RUSTFLAGS = "--cfg polish"
+"#,
+            )
+            .unwrap();
+            assert_eq!(config.env["RUSTC_WRAPPER"], "sccache");
+            assert_eq!(config.env["RUSTFLAGS"], "--cfg polish");
+        }
+
+        #[test]
+        fn test_grouping_reexports() {
+            let config = parse("[grouping.reexports]\nsort = true\nglobs = \"forbid\"\n").unwrap();
+            assert!(config.grouping.reexports.sort);
+            assert_eq!(
+                config.grouping.reexports.globs,
+                crate::rust_grouping::GlobReexports::Forbid
+            );
+        }
+
+        #[test]
+        fn test_stages() {
+            assert_eq!(parse("").unwrap().stages, Stage::DEFAULT);
+            let config = parse("stages = [\"clippy\", \"fmt\", \"lockfile\"]\n").unwrap();
+            assert_eq!(config.stages, [Stage::Clippy, Stage::Fmt, Stage::Lockfile]);
+            assert!(parse("stages = [\"lint\"]\n").is_err());
+        }
+
+        #[test]
+        fn test_parse_skip() {
+            assert_eq!(
+                parse_skip("clippy, toml").unwrap(),
+                [Stage::Clippy, Stage::Toml]
+            );
+            assert!(parse_skip("").unwrap().is_empty());
+            assert!(parse_skip("clippy,lint").is_err());
+        }
+
+        #[test]
+        fn test_manifest_policy() {
+            let config =
+                parse("[manifest]\nrequired = [\"license\"]\nedition = \"2021\"\n").unwrap();
+            assert_eq!(config.manifest.required, ["license"]);
+            assert_eq!(config.manifest.edition.as_deref(), Some("2021"));
+            assert!(parse("").unwrap().manifest.required.is_empty());
+        }
+
+        #[test]
+        fn test_owners() {
+            let config = parse(
+                r#"[owners]
+me = ["@alice"]
+
+[[owners.rules]]
+pattern = "/services/"
+owners = ["@acme/backend"]
+"#,
+            )
+            .unwrap();
+            assert_eq!(config.owners.me, ["@alice"]);
+            assert_eq!(config.owners.rules[0].pattern, "/services/");
+            assert_eq!(config.owners.rules[0].owners, ["@acme/backend"]);
+        }
+
+        #[test]
+        fn test_rust_roots() {
+            let config = parse("rust_roots = [\"services/\", \"libs\"]\n").unwrap();
+            assert_eq!(
+                config.rust_roots,
+                [PathBuf::from("services/"), PathBuf::from("libs")]
+            );
+        }
+
+        #[test]
+        fn test_derive_order() {
+            let config = parse("[derive]\norder = [\"Debug\", \"Clone\"]\n").unwrap();
+            assert_eq!(config.derive.order, ["Debug", "Clone"]);
+        }
+
+        #[test]
+        fn test_cargo_target_dir() {
+            let config = parse("[cargo]\ntarget_dir = \"/tmp/polish-target\"\n").unwrap();
+            assert_eq!(
+                config.cargo.target_dir,
+                Some(PathBuf::from("/tmp/polish-target"))
+            );
+        }
+
+        #[test]
+        fn test_clippy_budgets() {
+            let config = parse(
+                r#"[clippy]
+max_warnings = 10
+
+[clippy.lint_budgets]
+"clippy::too_many_arguments" = 3
+"#,
+            )
+            .unwrap();
+            assert_eq!(config.clippy.max_warnings, Some(10));
+            assert_eq!(config.clippy.lint_budgets["clippy::too_many_arguments"], 3);
+            assert_eq!(config.clippy.targets, TargetKind::ALL);
+        }
+
+        #[test]
+        fn test_clippy_targets() {
+            let config = parse("[clippy]\ntargets = [\"lib\", \"tests\"]\n").unwrap();
+            assert_eq!(
+                config.clippy.targets,
+                vec![TargetKind::Lib, TargetKind::Tests]
+            );
+            assert!(parse("[clippy]\ntargets = [\"docs\"]\n").is_err());
+        }
+
+        #[test]
+        fn test_clippy_command() {
+            assert!(Config::default().clippy.is_stock());
+            let config =
+                parse("[clippy]\ncommand = [\"dylint\", \"--all\", \"--\", \"{cargo_args}\"]\n")
+                    .unwrap();
+            assert_eq!(
+                config.clippy.command,
+                vec!["dylint", "--all", "--", "{cargo_args}"]
+            );
+            assert!(!config.clippy.is_stock());
+        }
+
+        #[test]
+        fn test_rustfmt_section() {
+            let config = parse(
+                r#"[rustfmt]
+max_width = 120
+imports_granularity = "Crate"
+"#,
+            )
+            .unwrap();
+            assert_eq!(config.rustfmt["max_width"], toml::Value::Integer(120));
+            assert_eq!(config.rustfmt.len(), 2);
+        }
+
+        #[test]
+        fn test_adoption_include() {
+            assert!(parse("")
+                .unwrap()
+                .adoption
+                .includes(Path::new("src/lib.rs")));
+            let config = parse("[adoption]\ninclude = [\"crates/new-*/**\"]\n").unwrap();
+            assert!(config
+                .adoption
+                .includes(Path::new("crates/new-api/src/lib.rs")));
+            assert!(config
+                .adoption
+                .includes(Path::new("crates/new-api/Cargo.toml")));
+            assert!(!config
+                .adoption
+                .includes(Path::new("crates/legacy/src/lib.rs")));
+        }
+
+        #[test]
+        fn test_check_unknown_keys() {
+            let problems = check(
+                r#"stagse = ["fmt"]
+
+[grouping]
+max_dpeth = 1
+
+[grouping.imports]
+globs = "deny"
+allowed_paths = []
+
+[env]
+ANYTHING = "goes"
+
+[owners]
+rules = [{ pattern = "*", owners = ["@a"] }]
+"#,
+            );
+            assert_eq!(
+                problems,
+                [
+                    "unknown key `grouping.imports.allowed_paths`",
+                    "unknown key `grouping.max_dpeth`, did you mean `grouping.max_depth`?",
+                    "unknown key `stagse`, did you mean `stages`?",
+                ]
+            );
+            assert!(check("[clippy]\nmax_warnings = 3\n").is_empty());
+        }
+
+        #[test]
+        fn test_extends() {
+            let dir = TempDir::new("extends");
+            std::fs::create_dir_all(dir.join("repo")).unwrap();
+            std::fs::write(
+                dir.join("polish-base.toml"),
+                "stages = [\"fmt\", \"clippy\"]\n\n[clippy]\nmax_warnings = 5\nbatch_size = 10\n",
+            )
+            .unwrap();
+            std::fs::write(
+                dir.join("repo/polish.toml"),
+                "extends = \"../polish-base.toml\"\n\n[clippy]\nmax_warnings = 1\n",
+            )
+            .unwrap();
+            let config = load(&dir.join("repo")).unwrap();
+            assert_eq!(config.stages, [Stage::Fmt, Stage::Clippy]);
+            assert_eq!(config.clippy.max_warnings, Some(1));
+            assert_eq!(config.clippy.batch_size, 10);
+            assert!(check("extends = \"base.toml\"\n").is_empty());
+
+            std::fs::write(
+                dir.join("polish-base.toml"),
+                "extends = \"repo/polish.toml\"\n",
+            )
+            .unwrap();
+            let error = format!("{:#}", load(&dir.join("repo")).unwrap_err());
+            assert!(error.contains("extends itself"), "{error}");
+        }
+
+        #[test]
+        fn test_profiles() {
+            let content = r#"stages = ["group", "fmt", "clippy"]
+
+[clippy]
+max_warnings = 5
+
+[profile.hook]
+args = ["--staged", "--fast-fmt"]
+stages = ["group", "fmt"]
+
+[profile.ci.clippy]
+max_warnings = 0
+"#;
+            let config = parse(content).unwrap();
+            assert_eq!(
+                config.profile_args("hook").unwrap(),
+                ["--staged", "--fast-fmt"]
+            );
+            assert!(config.profile_args("ci").unwrap().is_empty());
+            let error = config.profile_args("release").unwrap_err().to_string();
+            assert_eq!(error, "No profile `release`, only ci, hook");
+
+            let mut table: toml::Table = toml::from_str(content).unwrap();
+            apply_profile(&mut table, "ci").unwrap();
+            let ci: Config = toml::Value::Table(table).try_into().unwrap();
+            assert_eq!(ci.clippy.max_warnings, Some(0));
+            assert_eq!(ci.stages, [Stage::Group, Stage::Fmt, Stage::Clippy]);
+
+            let mut table: toml::Table = toml::from_str(content).unwrap();
+            apply_profile(&mut table, "hook").unwrap();
+            let hook: Config = toml::Value::Table(table).try_into().unwrap();
+            assert_eq!(hook.stages, [Stage::Group, Stage::Fmt]);
+            assert_eq!(hook.clippy.max_warnings, Some(5));
+
+            assert!(check(content).is_empty());
+            assert_eq!(
+                check(
+                    "[profile.ci]
+stagse = []
+"
+                ),
+                ["unknown key `profile.ci.stagse`, did you mean `profile.ci.stages`?"]
+            );
+        }
+
+        #[test]
+        fn test_check_type_error() {
+            let problems = check("[clippy]\nmax_warnings = \"many\"\n");
+            assert_eq!(problems.len(), 1);
+            assert!(problems[0].contains("max_warnings"), "{}", problems[0]);
+        }
+    }
+}
+
+mod git_output {
+    use anyhow::{bail, Context};
+    use std::path::PathBuf;
+
+    /// Changed paths listed by `git diff -z --name-status`.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct NameStatus {
+        /// Paths that still exist: the new path of renames and copies, no
+        /// deletions.
+        pub paths: Vec<PathBuf>,
+        /// Old and new path of each rename.
+        pub renames: Vec<(PathBuf, PathBuf)>,
+        /// Deleted paths.
+        pub deleted: Vec<PathBuf>,
+    }
+
+    pub fn parse_name_status(output: &[u8]) -> anyhow::Result<NameStatus> {
+        let mut fields = output.split(|&b| b == 0).filter(|f| !f.is_empty());
+        let mut result = NameStatus::default();
+        while let Some(status) = fields.next() {
+            let status = String::from_utf8_lossy(status);
+            let truncated = || format!("Truncated git diff output after status {status}");
+            // `R100\0old\0new`: two paths
+            let old = match status.chars().next() {
+                Some('R' | 'C') => Some(fields.next().with_context(truncated)?),
+                _ => None,
+            };
+            let path = fields.next().with_context(truncated)?;
+            if status.starts_with('D') {
+                result.deleted.push(path_from_bytes(path));
+                continue;
+            }
+            if !status.starts_with(|c: char| c.is_ascii_uppercase()) {
+                bail!("Unexpected git diff status {status:?}");
+            }
+            if let (Some(old), true) = (old, status.starts_with('R')) {
+                result
+                    .renames
+                    .push((path_from_bytes(old), path_from_bytes(path)));
+            }
+            result.paths.push(path_from_bytes(path));
+        }
+        Ok(result)
+    }
+
+    /// Paths of NUL-separated output such as `git diff -z --name-only`.
+    pub fn split_paths(output: &[u8]) -> Vec<PathBuf> {
+        output
+            .split(|&b| b == 0)
+            .filter(|f| !f.is_empty())
+            .map(path_from_bytes)
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+
+    #[cfg(not(unix))]
+    fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_name_status() {
+            let output =
+                b"M\0src/a file.rs\0R087\0old.rs\0src/n\xc3\xa9w.rs\0D\0gone.rs\0A\0\"q\".rs\0";
+            let parsed = parse_name_status(output).unwrap();
+            assert_eq!(
+                parsed.paths,
+                vec![
+                    PathBuf::from("src/a file.rs"),
+                    PathBuf::from("src/néw.rs"),
+                    PathBuf::from("\"q\".rs"),
+                ]
+            );
+            assert_eq!(
+                parsed.renames,
+                vec![(PathBuf::from("old.rs"), PathBuf::from("src/néw.rs"))]
+            );
+            assert_eq!(parsed.deleted, vec![PathBuf::from("gone.rs")]);
+            assert!(parse_name_status(b"M\0").is_err());
+            assert!(parse_name_status(b"R100\0old.rs\0").is_err());
+            assert_eq!(parse_name_status(b"").unwrap(), NameStatus::default());
+        }
+
+        #[test]
+        fn test_split_paths() {
+            assert_eq!(
+                split_paths(b"a b.rs\0c.rs\0"),
+                vec![PathBuf::from("a b.rs"), PathBuf::from("c.rs")]
+            );
+        }
+    }
+}
+
+mod interrupt {
+    use anyhow::bail;
+    use std::path::Path;
+    use std::process::{Child, Command, ExitStatus};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    /// Exit code of an interrupted run, as shells report a SIGINT death.
+    pub const EXIT_CODE: i32 = 130;
+
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+    /// Number of file rewrites under way, which a second signal waits for.
+    static REWRITING: AtomicUsize = AtomicUsize::new(0);
+
+    /// A second signal arrived during a rewrite; exit once it's done.
+    static EXIT_PENDING: AtomicBool = AtomicBool::new(false);
+
+    #[cfg(unix)]
+    mod sys {
+        use std::ffi::c_int;
+
+        pub const SIGINT: c_int = 2;
+        pub const SIGKILL: c_int = 9;
+        pub const SIGTERM: c_int = 15;
+
+        extern "C" {
+            pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+            pub fn kill(pid: c_int, signum: c_int) -> c_int;
+            pub fn _exit(status: c_int) -> !;
+            #[cfg(target_os = "linux")]
+            pub fn listxattr(
+                path: *const std::ffi::c_char,
+                list: *mut std::ffi::c_char,
+                size: usize,
+            ) -> isize;
+        }
+    }
+
+    /// Only records the signal: the run notices it between stages and while
+    /// waiting for children, and unwinds so that snapshots and stashes are
+    /// put back by their `Drop`. A second signal exits on the spot, or
+    /// right after the files being rewritten are complete.
+    #[cfg(unix)]
+    extern "C" fn on_signal(_signum: std::ffi::c_int) {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            if REWRITING.load(Ordering::SeqCst) > 0 {
+                EXIT_PENDING.store(true, Ordering::SeqCst);
+            } else {
+                // SAFETY: `_exit` is async-signal-safe and takes any status
+                unsafe { sys::_exit(EXIT_CODE) };
+            }
+        }
+    }
+
+    /// Holds off the exit of a second signal while files are rewritten.
+    struct Rewriting;
+
+    impl Rewriting {
+        fn start() -> Self {
+            REWRITING.fetch_add(1, Ordering::SeqCst);
+            Rewriting
+        }
+    }
+
+    impl Drop for Rewriting {
+        fn drop(&mut self) {
+            if REWRITING.fetch_sub(1, Ordering::SeqCst) == 1 && EXIT_PENDING.load(Ordering::SeqCst)
+            {
+                #[cfg(unix)]
+                // SAFETY: `_exit` takes any status; skipping destructors is
+                // what the second signal asked for
+                unsafe {
+                    sys::_exit(EXIT_CODE)
+                };
+            }
+        }
+    }
+
+    /// Rewrites the source file or manifest at `path`. A second signal waits
+    /// for the write to complete. A regular file is replaced all at once,
+    /// through a temporary file renamed over it, so that not even a crash
+    /// leaves it half-written, as long as the rename keeps what the file is:
+    /// anything else, like a hard-linked file, a file with extended
+    /// attributes or one whose owner can't be carried over, is written in
+    /// place. Output paths chosen by the user are written with
+    /// `std::fs::write`, since they may well be pipes or devices.
+    pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+        let _rewriting = Rewriting::start();
+        // Rename over the file a symlink points to, not the link
+        let path = std::fs::canonicalize(path.as_ref()).unwrap_or(path.as_ref().to_path_buf());
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() && !has_identity(&path, &metadata) => metadata,
+            _ => return std::fs::write(&path, contents),
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{name}.polish-tmp"));
+        if let Err(e) = std::fs::write(&temp, &contents)
+            .and_then(|()| std::fs::set_permissions(&temp, metadata.permissions()))
+        {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+        if !same_owner(&temp, &metadata) {
+            let _ = std::fs::remove_file(&temp);
+            return std::fs::write(&path, contents);
+        }
+        let result = std::fs::rename(&temp, &path);
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Whether the file has more to it than contents and permissions, which
+    /// replacing it by another file would lose: other hard links or
+    /// extended attributes.
+    #[cfg(unix)]
+    fn has_identity(path: &Path, metadata: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() > 1 || has_xattrs(path)
+    }
+
+    #[cfg(not(unix))]
+    fn has_identity(_path: &Path, _metadata: &std::fs::Metadata) -> bool {
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    fn has_xattrs(path: &Path) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+            return true;
+        };
+        // SAFETY: `path` is a NUL-terminated string that outlives the call,
+        // and a null buffer of size 0 only asks for the size of the list
+        unsafe { sys::listxattr(path.as_ptr(), std::ptr::null_mut(), 0) != 0 }
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn has_xattrs(_path: &Path) -> bool {
+        false
+    }
+
+    /// Gives `temp` the owner and group of the file it replaces, if they
+    /// differ. `false` if that isn't allowed.
+    #[cfg(unix)]
+    fn same_owner(temp: &Path, metadata: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        match std::fs::metadata(temp) {
+            Ok(current) if (current.uid(), current.gid()) == (metadata.uid(), metadata.gid()) => {
+                true
+            }
+            Ok(_) => {
+                std::os::unix::fs::chown(temp, Some(metadata.uid()), Some(metadata.gid())).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn same_owner(_temp: &Path, _metadata: &std::fs::Metadata) -> bool {
+        true
+    }
+
+    /// Handles Ctrl-C and SIGTERM for the rest of the process.
+    pub fn install() {
+        #[cfg(unix)]
+        // SAFETY: `on_signal` only touches atomics and calls `_exit`, both
+        // async-signal-safe
+        unsafe {
+            sys::signal(sys::SIGINT, on_signal);
+            sys::signal(sys::SIGTERM, on_signal);
+        }
+    }
+
+    pub fn interrupted() -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+
+    /// Fails once the run has been interrupted.
+    pub fn check() -> anyhow::Result<()> {
+        if interrupted() {
+            bail!("Interrupted");
+        }
+        Ok(())
+    }
+
+    /// Spawns `cmd` in a process group of its own, so that killing it also
+    /// kills the rustc and build script processes it started. Ctrl-C no
+    /// longer reaches the child then, so it has to be waited for with
+    /// [`wait`] or [`finish`], which stop it when the run is interrupted.
+    pub fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(cmd, 0);
+        cmd.spawn()
+    }
+
+    /// Kills `child` along with its process group, if it leads one.
+    fn kill(child: &mut Child) {
+        #[cfg(unix)]
+        // SAFETY: a negative pid signals the process group the child leads;
+        // it only leads one when started by `spawn`
+        unsafe {
+            sys::kill(-(child.id() as std::ffi::c_int), sys::SIGKILL);
+        }
+        let _ = child.kill();
+    }
+
+    /// Waits for `child`, killing it if the run is interrupted (an error) or
+    /// once `deadline` passes (`None`).
+    pub fn wait(
+        child: &mut Child,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Option<ExitStatus>> {
+        loop {
+            if interrupted() {
+                kill(child);
+                let _ = child.wait();
+                bail!("Interrupted");
+            }
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                kill(child);
+                child.wait()?;
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits for `child`, which is rewriting files, to finish even if the
+    /// run is interrupted, and fails only then. The child must run in its
+    /// own process group so that Ctrl-C doesn't reach it either.
+    pub fn finish(child: &mut Child) -> anyhow::Result<ExitStatus> {
+        let _rewriting = Rewriting::start();
+        let status = child.wait()?;
+        check()?;
+        Ok(status)
+    }
+
+    #[cfg(all(test, unix))]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+        use std::process::Command;
+
+        #[test]
+        fn test_wait() {
+            let mut child = Command::new("true").spawn().unwrap();
+            assert!(wait(&mut child, None).unwrap().unwrap().success());
+
+            let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+            let start = Instant::now();
+            assert!(wait(&mut child, Some(start)).unwrap().is_none());
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+
+        #[test]
+        fn test_write() {
+            let dir = TempDir::new("write");
+            let file = dir.join("lib.rs");
+            std::fs::write(&file, "old").unwrap();
+            std::os::unix::fs::symlink(&file, dir.join("link.rs")).unwrap();
+            write(dir.join("link.rs"), "new").unwrap();
+            assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+            assert!(std::fs::symlink_metadata(dir.join("link.rs"))
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+            // Hard links stay linked
+            std::fs::hard_link(&file, dir.join("other.rs")).unwrap();
+            write(&file, "newer").unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.join("other.rs")).unwrap(),
+                "newer"
+            );
+        }
+    }
+}
+
+/// Live progress for GUI wrappers and editor status bars, written as JSON
+/// lines to `--progress-fd`: a file descriptor the wrapper left open, or
+/// the path of a Unix socket it listens on.
+mod progress {
+    use serde::Serialize;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "event", rename_all = "snake_case")]
+    pub enum Event<'a> {
+        StageStarted {
+            stage: &'a str,
+        },
+        StageFinished {
+            stage: &'a str,
+            success: bool,
+            seconds: f64,
+        },
+        FileProcessed {
+            stage: &'a str,
+            file: &'a Path,
+        },
+        Diagnostic {
+            file: Option<&'a Path>,
+            line: Option<usize>,
+            level: &'a str,
+            /// Lint name, or `polish` for polish's own checks.
+            lint: &'a str,
+            message: &'a str,
+        },
+    }
+
+    static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+    #[cfg(unix)]
+    mod sys {
+        use std::ffi::c_int;
+
+        pub const F_GETFD: c_int = 1;
+
+        extern "C" {
+            pub fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+        }
+    }
+
+    /// Sends events to `target` from now on.
+    #[cfg(unix)]
+    pub fn open(target: &str) -> anyhow::Result<()> {
+        use anyhow::Context;
+        use std::os::fd::FromRawFd;
+
+        let sink: Box<dyn Write + Send> = match target.parse() {
+            Ok(fd) => {
+                // SAFETY: `F_GETFD` only reads the flags of `fd`, failing
+                // with `EBADF` when it isn't open; it takes no third argument
+                if unsafe { sys::fcntl(fd, sys::F_GETFD) } == -1 {
+                    anyhow::bail!("--progress-fd {fd} is not an open file descriptor");
+                }
+                // SAFETY: `fd` is open, and the wrapper hands it over for the
+                // rest of the run: nothing else in polish reads, writes or
+                // closes it
+                Box::new(unsafe { std::fs::File::from_raw_fd(fd) })
+            }
+            Err(_) => Box::new(
+                std::os::unix::net::UnixStream::connect(target)
+                    .with_context(|| format!("Failed to connect to {target}"))?,
+            ),
+        };
+        *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(sink);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(_target: &str) -> anyhow::Result<()> {
+        anyhow::bail!("--progress-fd is only supported on Unix")
+    }
+
+    /// Writes `event` if progress was asked for. A wrapper that went away
+    /// stops the events, not the run.
+    pub fn emit(event: Event) {
+        let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(writer) = sink.as_mut() else {
+            return;
+        };
+        let Ok(line) = serde_json::to_string(&event) else {
+            return;
+        };
+        if writeln!(writer, "{line}")
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            *sink = None;
+        }
+    }
+
+    #[cfg(all(test, unix))]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+        use std::io::{BufRead, BufReader};
+
+        #[test]
+        fn test_closed_fd() {
+            let error = open("987654").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "--progress-fd 987654 is not an open file descriptor"
+            );
+        }
+
+        #[test]
+        fn test_socket() {
+            let dir = TempDir::new("progress");
+            let path = dir.join("progress.sock");
+            let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            open(path.to_str().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+
+            emit(Event::StageStarted { stage: "group" });
+            emit(Event::FileProcessed {
+                stage: "group",
+                file: Path::new("src/lib.rs"),
+            });
+            *SINK.lock().unwrap() = None;
+
+            let lines: Vec<String> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+            assert_eq!(
+                lines,
+                [
+                    r#"{"event":"stage_started","stage":"group"}"#,
+                    r#"{"event":"file_processed","stage":"group","file":"src/lib.rs"}"#,
+                ]
+            );
+        }
+    }
+}
+
+mod miri {
+    use serde::Serialize;
+    use std::path::PathBuf;
+
+    /// Undefined Behavior Miri stopped a test of `package` with.
+    #[derive(Debug, PartialEq, Serialize)]
+    pub struct Finding {
+        pub package: String,
+        /// Where it happened, relative to the workspace root as Miri prints
+        /// it.
+        pub file: Option<PathBuf>,
+        pub line: Option<usize>,
+        pub message: String,
+    }
+
+    /// Whether `source` uses the `unsafe` keyword anywhere, comments
+    /// included.
+    pub fn uses_unsafe(source: &str) -> bool {
+        source
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .any(|word| word == "unsafe")
+    }
+
+    /// The Undefined Behavior errors in the stderr of `cargo miri test`,
+    /// each located by the `-->` line after it.
+    pub fn findings(package: &str, stderr: &str) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut lines = stderr.lines().peekable();
+        while let Some(line) = lines.next() {
+            // The test harness's `test name ... ` may come first on the line
+            const PREFIX: &str = "error: Undefined Behavior: ";
+            let Some(start) = line.find(PREFIX) else {
+                continue;
+            };
+            let message = &line[start + PREFIX.len()..];
+            let mut finding = Finding {
+                package: package.to_string(),
+                file: None,
+                line: None,
+                message: message.to_string(),
+            };
+            while let Some(next) = lines.next_if(|l| !l.starts_with("error")) {
+                let Some(location) = next.trim_start().strip_prefix("--> ") else {
+                    continue;
+                };
+                let mut parts = location.rsplitn(3, ':');
+                let (_column, line, file) = (parts.next(), parts.next(), parts.next());
+                finding.line = line.and_then(|l| l.parse().ok());
+                finding.file = file.map(PathBuf::from);
+                break;
+            }
+            findings.push(finding);
+        }
+        findings
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_findings() {
+            let stderr = "\
+test tests::aliasing ... error: Undefined Behavior: attempting a read access using <1> at alloc1[0x0], but that tag does not exist in the borrow stack for this location
+  --> a/src/lib.rs:12:9
+   |
+12 |         *a
+   |         ^^ attempting a read access
+error: aborting due to 1 previous error
+";
+            assert_eq!(
+                findings("a", stderr),
+                [Finding {
+                    package: "a".to_string(),
+                    file: Some(PathBuf::from("a/src/lib.rs")),
+                    line: Some(12),
+                    message: "attempting a read access using <1> at alloc1[0x0], but that tag \
+                              does not exist in the borrow stack for this location"
+                        .to_string(),
+                }]
+            );
+            assert!(uses_unsafe("fn f() { unsafe { g() } }"));
+            assert!(!uses_unsafe("fn unsafe_free() {}"));
+        }
+    }
+}
+
+mod toolchain {
+    use anyhow::{bail, Context};
+    use std::path::Path;
+    use std::process::Command;
+
+    /// The version in `--version` output such as `rustfmt 1.7.1-stable
+    /// (...)`: the first word starting with a digit, without any `-`
+    /// suffix.
+    pub fn parse_version(output: &str) -> Option<Vec<u64>> {
+        let word = output
+            .split_whitespace()
+            .find(|w| w.starts_with(|c: char| c.is_ascii_digit()))?;
+        let version = word.split(['-', '+']).next()?;
+        version.split('.').map(|part| part.parse().ok()).collect()
+    }
+
+    /// Whether `version` is `minimum` or newer; missing parts count as 0.
+    pub fn at_least(version: &[u64], minimum: &[u64]) -> bool {
+        let len = version.len().max(minimum.len());
+        let padded =
+            |v: &[u64]| -> Vec<u64> { (0..len).map(|i| v.get(i).copied().unwrap_or(0)).collect() };
+        padded(version) >= padded(minimum)
+    }
+
+    /// Fails unless `cargo [subcommand] --version`, run in `dir`, reports
+    /// `tool` at version `minimum` or newer.
+    pub fn require(
+        dir: &Path,
+        tool: &str,
+        subcommand: Option<&str>,
+        minimum: &str,
+    ) -> anyhow::Result<()> {
+        let wanted = parse_version(minimum)
+            .with_context(|| format!("Invalid [toolchain] {tool} version `{minimum}`"))?;
+        let printed = version(dir, subcommand)
+            .with_context(|| format!("Failed to get the {tool} version"))?;
+        let printed = printed.trim();
+        let found = parse_version(printed)
+            .with_context(|| format!("Could not read the {tool} version from `{printed}`"))?;
+        if !at_least(&found, &wanted) {
+            bail!(
+                "polish.toml requires {tool} {minimum} or newer, but found `{printed}`; update \
+                 the toolchain (e.g. `rustup update`) so formatting and lints match everyone \
+                 else's"
+            );
+        }
+        Ok(())
+    }
+
+    /// What `cargo [subcommand] --version` prints when run in `dir`.
+    fn version(dir: &Path, subcommand: Option<&str>) -> anyhow::Result<String> {
+        let output = Command::new("cargo")
+            .args(subcommand)
+            .arg("--version")
+            .current_dir(dir)
+            .output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The cargo, clippy and rustfmt versions the toolchain active in `dir`
+    /// reports, one per line; a line is empty if its tool is missing.
+    pub fn versions(dir: &Path) -> String {
+        [None, Some("clippy"), Some("fmt")]
+            .into_iter()
+            .map(|subcommand| version(dir, subcommand).unwrap_or_default())
+            .map(|printed| printed.trim().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_versions() {
+            assert_eq!(
+                parse_version("rustfmt 1.7.1-stable (3f5fd8dd41 2024-08-06)"),
+                Some(vec![1, 7, 1])
+            );
+            assert_eq!(
+                parse_version("cargo 1.80.0 (376290515 2024-07-16)"),
+                Some(vec![1, 80, 0])
+            );
+            assert_eq!(parse_version("clippy 0.1.80"), Some(vec![0, 1, 80]));
+            assert_eq!(parse_version("error: no such command"), None);
+
+            assert!(at_least(&[1, 80, 0], &[1, 80]));
+            assert!(at_least(&[1, 81], &[1, 80, 5]));
+            assert!(!at_least(&[0, 1, 79], &[0, 1, 80]));
+        }
+    }
+}
+
+mod default_branch {
+    use anyhow::bail;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Variables CI providers set to the branch a pull/merge request targets
+    /// (or, for the last one, the project's default branch), in the order
+    /// they are checked.
+    const ENV_VARS: &[&str] = &[
+        "GITHUB_BASE_REF",
+        "CI_MERGE_REQUEST_TARGET_BRANCH_NAME",
+        "BITBUCKET_PR_DESTINATION_BRANCH",
+        "CI_DEFAULT_BRANCH",
+    ];
+
+    /// Revision of the branch changes should be compared against: the
+    /// target branch named by the CI environment, else origin's `HEAD`,
+    /// else the first of `main`/`master` that exists (remote-tracking
+    /// branches first). `env` looks up environment variables.
+    pub fn detect(git_root: &Path, env: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+        for var in ENV_VARS {
+            let Some(branch) = env(var).filter(|b| !b.is_empty()) else {
+                continue;
+            };
+            match [format!("origin/{branch}"), branch.clone()]
+                .into_iter()
+                .find(|rev| exists(git_root, rev))
+            {
+                Some(rev) => return Ok(rev),
+                None => eprintln!("{var} names {branch}, which isn't fetched; ignoring it"),
+            }
+        }
+
+        if let Some(head) = git(
+            git_root,
+            &["symbolic-ref", "--short", "refs/remotes/origin/HEAD"],
+        ) {
+            return Ok(head);
+        }
+
+        let candidates = ["origin/main", "origin/master", "main", "master"];
+        match candidates.into_iter().find(|rev| exists(git_root, rev)) {
+            Some(rev) => Ok(rev.to_string()),
+            None => bail!(
+                "Could not detect the default branch (tried {}, origin/HEAD and {}); pass --since <REV>",
+                ENV_VARS.join(", "),
+                candidates.join(", ")
+            ),
+        }
+    }
+
+    pub fn exists(git_root: &Path, rev: &str) -> bool {
+        git(
+            git_root,
+            &[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("{rev}^{{commit}}"),
+            ],
+        )
+        .is_some()
+    }
+
+    /// Trimmed stdout of a successful git command.
+    fn git(git_root: &Path, args: &[&str]) -> Option<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(git_root)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        Some(stdout.trim().to_string()).filter(|s| !s.is_empty())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        fn run(dir: &Path, args: &[&str]) {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(dir)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        }
+
+        #[test]
+        fn test_detect() {
+            let dir = TempDir::new("default-branch");
+            run(&dir, &["init", "-q", "-b", "trunk"]);
+            run(
+                &dir,
+                &[
+                    "-c",
+                    "user.name=t",
+                    "-c",
+                    "user.email=t@t",
+                    "commit",
+                    "-q",
+                    "--allow-empty",
+                    "-m",
+                    "init",
+                ],
+            );
+            let no_env = |_: &str| None;
+            assert!(detect(&dir, no_env).is_err());
+
+            run(&dir, &["branch", "master"]);
+            assert_eq!(detect(&dir, no_env).unwrap(), "master");
+            run(&dir, &["update-ref", "refs/remotes/origin/main", "HEAD"]);
+            assert_eq!(detect(&dir, no_env).unwrap(), "origin/main");
+            run(
+                &dir,
+                &[
+                    "symbolic-ref",
+                    "refs/remotes/origin/HEAD",
+                    "refs/remotes/origin/main",
+                ],
+            );
+            assert_eq!(detect(&dir, no_env).unwrap(), "origin/main");
+
+            let github = |var: &str| (var == "GITHUB_BASE_REF").then(|| "trunk".to_string());
+            assert_eq!(detect(&dir, github).unwrap(), "trunk");
+            let missing = |var: &str| (var == "GITHUB_BASE_REF").then(|| "gone".to_string());
+            assert_eq!(detect(&dir, missing).unwrap(), "origin/main");
+        }
+    }
+}
+
+mod lsp {
+    use super::{config, rust_grouping, toml_grouping};
+    use anyhow::{bail, Context};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{BufRead, Write};
+    use std::path::{Path, PathBuf};
+
+    /// JSON-RPC error code for requests the server doesn't implement.
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INTERNAL_ERROR: i64 = -32603;
+
+    /// Serves the Language Server Protocol on stdin/stdout until the client
+    /// sends `exit`. Formatting a Rust document groups its declarations,
+    /// formatting a `Cargo.toml` organizes its dependencies; both are also
+    /// offered as code actions.
+    pub fn serve() -> anyhow::Result<()> {
+        let mut input = std::io::stdin().lock();
+        let mut output = std::io::stdout().lock();
+        let mut server = Server::default();
+        while let Some(message) = read_message(&mut input)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                break;
+            }
+            let result = server.handle(method, &message["params"]);
+            let Some(id) = message.get("id") else {
+                if let Err(e) = result {
+                    eprintln!("{method}: {e:#}");
+                }
+                continue;
+            };
+            let response = match result {
+                Ok(Some(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Ok(None) => {
+                    error_response(id, METHOD_NOT_FOUND, &format!("unknown method {method}"))
+                }
+                Err(e) => error_response(id, INTERNAL_ERROR, &format!("{e:#}")),
+            };
+            write_message(&mut output, &response)?;
+        }
+        Ok(())
+    }
+
+    fn error_response(id: &Value, code: i64, message: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+    }
+
+    /// Open documents by URI, kept in sync in full (`TextDocumentSyncKind::Full`).
+    #[derive(Default)]
+    struct Server {
+        root: Option<PathBuf>,
+        documents: HashMap<String, String>,
+    }
+
+    impl Server {
+        /// The result of a request, `None` for unknown methods.
+        fn handle(&mut self, method: &str, params: &Value) -> anyhow::Result<Option<Value>> {
+            let uri = || {
+                params["textDocument"]["uri"]
+                    .as_str()
+                    .map(str::to_string)
+                    .context("missing textDocument.uri")
+            };
+            match method {
+                "initialize" => {
+                    self.root = params["rootUri"].as_str().and_then(uri_to_path);
+                    Ok(Some(json!({
+                        "capabilities": {
+                            "textDocumentSync": 1,
+                            "documentFormattingProvider": true,
+                            "codeActionProvider": {"codeActionKinds": ["source.organizeImports"]},
+                        },
+                        "serverInfo": {"name": "polish-rs", "version": env!("CARGO_PKG_VERSION")},
+                    })))
+                }
+                "initialized" | "shutdown" | "$/setTrace" => Ok(Some(Value::Null)),
+                "textDocument/didOpen" => {
+                    let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                    self.documents.insert(uri()?, text.to_string());
+                    Ok(Some(Value::Null))
+                }
+                "textDocument/didChange" => {
+                    // Full sync: the last change holds the whole document
+                    if let Some(text) = params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change["text"].as_str())
+                    {
+                        self.documents.insert(uri()?, text.to_string());
+                    }
+                    Ok(Some(Value::Null))
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(&uri()?);
+                    Ok(Some(Value::Null))
+                }
+                "textDocument/formatting" => {
+                    let edits: Vec<Value> = self
+                        .polish(&uri()?)?
+                        .map(|(_, edit)| edit)
+                        .into_iter()
+                        .collect();
+                    Ok(Some(json!(edits)))
+                }
+                "textDocument/codeAction" => {
+                    let uri = uri()?;
+                    let actions: Vec<Value> = self
+                        .polish(&uri)?
+                        .map(|(title, edit)| {
+                            json!({
+                                "title": title,
+                                "kind": "source.organizeImports",
+                                "edit": {"changes": {uri.clone(): [edit]}},
+                            })
+                        })
+                        .into_iter()
+                        .collect();
+                    Ok(Some(json!(actions)))
+                }
+                _ => Ok(None),
+            }
+        }
+
+        /// The action's title and a whole-document edit, if polishing the
+        /// document changes it.
+        fn polish(&self, uri: &str) -> anyhow::Result<Option<(&'static str, Value)>> {
+            let text = self
+                .documents
+                .get(uri)
+                .with_context(|| format!("{uri} is not open"))?;
+            let path = uri_to_path(uri).with_context(|| format!("not a file URI: {uri}"))?;
+            let Some((title, polished)) = polish_document(&path, text, self.root.as_deref())?
+            else {
+                return Ok(None);
+            };
+            let end_line = text.matches('\n').count() + 1;
+            let edit = json!({
+                "range": {
+                    "start": {"line": 0, "character": 0},
+                    "end": {"line": end_line, "character": 0},
+                },
+                "newText": polished,
+            });
+            Ok(Some((title, edit)))
+        }
+    }
+
+    /// What polishing `text`, the contents of `path`, changes: the action's
+    /// title and the new contents, or `None` if it's already polished or is
+    /// neither Rust nor a manifest. Settings come from the
+    /// repository containing `path`, else from `fallback_root`.
+    pub fn polish_document(
+        path: &Path,
+        text: &str,
+        fallback_root: Option<&Path>,
+    ) -> anyhow::Result<Option<(&'static str, String)>> {
+        let root = repo_root(path).or_else(|| fallback_root.map(Path::to_path_buf));
+        let config = match &root {
+            Some(root) => config::load(root)?,
+            None => config::Config::default(),
+        };
+        let (title, polished) = if path.file_name().is_some_and(|n| n == "Cargo.toml") {
+            (
+                "Organize Cargo.toml dependencies",
+                toml_grouping::organize_toml_with(text, &config.dependencies)?,
+            )
+        } else if path.extension().is_some_and(|e| e == "rs") {
+            let mut options = config.grouping.clone();
+            options.pin_macro_use = root.as_ref().is_some_and(|root| {
+                super::find_manifest_for_file(root, &config.rust_roots, path)
+                    .and_then(|manifest| super::find_package_edition(root, &manifest))
+                    .is_ok_and(|edition| edition == "2015")
+            });
+            (
+                "Group declarations",
+                rust_grouping::group_items_with(text, &options)?,
+            )
+        } else {
+            return Ok(None);
+        };
+        Ok((polished != text).then_some((title, polished)))
+    }
+
+    /// The repository containing `path`.
+    pub fn repo_root(path: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .skip(1)
+            .find(|dir| dir.join(".git").exists())
+            .map(Path::to_path_buf)
+    }
+
+    /// Reads one `Content-Length` framed message, `None` at end of input.
+    fn read_message(input: &mut impl BufRead) -> anyhow::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = Some(
+                        value
+                            .trim()
+                            .parse::<usize>()
+                            .context("Invalid Content-Length")?,
+                    );
+                }
+            }
+        }
+        let Some(length) = length else {
+            bail!("Message without Content-Length");
+        };
+        let mut body = vec![0; length];
+        input.read_exact(&mut body)?;
+        Ok(Some(
+            serde_json::from_slice(&body).context("Invalid JSON-RPC message")?,
+        ))
+    }
+
+    fn write_message(output: &mut impl Write, message: &Value) -> anyhow::Result<()> {
+        let body = serde_json::to_string(message)?;
+        write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Path of a `file://` URI, percent-decoded.
+    fn uri_to_path(uri: &str) -> Option<PathBuf> {
+        let encoded = uri.strip_prefix("file://")?;
+        let mut bytes = Vec::with_capacity(encoded.len());
+        let mut iter = encoded.bytes();
+        while let Some(b) = iter.next() {
+            if b == b'%' {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            } else {
+                bytes.push(b);
+            }
+        }
+        let path = String::from_utf8(bytes).ok()?;
+        // `file:///C:/src` on Windows
+        let path = match path.strip_prefix('/') {
+            Some(rest) if rest.as_bytes().get(1) == Some(&b':') => rest.to_string(),
+            _ => path,
+        };
+        Some(PathBuf::from(path))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_message_framing() {
+            let mut buffer = Vec::new();
+            write_message(&mut buffer, &json!({"jsonrpc": "2.0", "id": 1})).unwrap();
+            assert!(buffer.starts_with(b"Content-Length: 24\r\n\r\n"));
+            let mut input = buffer.as_slice();
+            assert_eq!(
+                read_message(&mut input).unwrap(),
+                Some(json!({"jsonrpc": "2.0", "id": 1}))
+            );
+            assert_eq!(read_message(&mut input).unwrap(), None);
+        }
+
+        #[test]
+        fn test_uri_to_path() {
+            assert_eq!(
+                uri_to_path("file:///home/me/my%20crate/lib.rs"),
+                Some(PathBuf::from("/home/me/my crate/lib.rs"))
+            );
+            assert_eq!(uri_to_path("untitled:Untitled-1"), None);
+        }
+
+        #[test]
+        fn test_format_manifest() {
+            let uri = "file:///nonexistent/polish-lsp/Cargo.toml";
+            let mut server = Server::default();
+            let open = json!({"textDocument": {
+                "uri": uri,
+                "text": "[dependencies]\nserde = \"1\"\nanyhow = \"1\"\n",
+            }});
+            server.handle("textDocument/didOpen", &open).unwrap();
+            let params = json!({"textDocument": {"uri": uri}});
+            let edits = server
+                .handle("textDocument/formatting", &params)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                edits[0]["newText"],
+                "[dependencies]\nanyhow = \"1\"\nserde = \"1\"\n"
+            );
+            assert_eq!(edits[0]["range"]["end"]["line"], 4);
+
+            let actions = server
+                .handle("textDocument/codeAction", &params)
+                .unwrap()
+                .unwrap();
+            assert_eq!(actions[0]["title"], "Organize Cargo.toml dependencies");
+            assert!(server
+                .handle("textDocument/hover", &params)
+                .unwrap()
+                .is_none());
+        }
+    }
+}
+
+mod serve {
+    use super::metadata::Metadata;
+    use super::{config, lsp, manifest_checks, snapshot, CargoOptions, Cli};
+    use anyhow::{bail, Context};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+
+    /// Serves newline-delimited JSON-RPC on a unix socket at `socket`, one
+    /// connection at a time, until interrupted. Workspace metadata is kept
+    /// between requests and reloaded when a manifest changes.
+    #[cfg(unix)]
+    pub fn serve(cli: &Cli, socket: &Path) -> anyhow::Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        // A socket left behind by a previous server that didn't shut down;
+        // anything else at the path is the user's
+        if let Ok(metadata) = std::fs::symlink_metadata(socket) {
+            if !metadata.file_type().is_socket() {
+                bail!(
+                    "{} exists and is not a socket; pass a path that doesn't exist yet",
+                    socket.display()
+                );
+            }
+            std::fs::remove_file(socket)
+                .with_context(|| format!("Failed to remove {}", socket.display()))?;
+        }
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+        eprintln!("Listening on {}", socket.display());
+        let mut server = Server::new(cli);
+        for stream in listener.incoming() {
+            let stream = stream.context("Failed to accept connection")?;
+            let reader = BufReader::new(stream.try_clone()?);
+            if let Err(e) = server.serve_connection(reader, stream) {
+                eprintln!("Connection closed: {e:#}");
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve(_cli: &Cli, _socket: &Path) -> anyhow::Result<()> {
+        bail!("serve needs unix domain sockets, which this platform lacks");
+    }
+
+    struct Server<'a> {
+        cli: &'a Cli,
+        /// Loaded metadata by git root, with the manifests it came from
+        workspaces: HashMap<PathBuf, Workspace>,
+    }
+
+    struct Workspace {
+        metadata: Metadata,
+        manifests: Vec<(PathBuf, Option<SystemTime>)>,
+    }
+
+    impl Workspace {
+        fn load(git_root: &Path, cargo: &CargoOptions) -> anyhow::Result<Workspace> {
+            let metadata = Metadata::load(git_root, cargo)?;
+            let manifests = std::iter::once(metadata.workspace_root.join("Cargo.toml"))
+                .chain(metadata.packages.iter().map(|p| p.manifest_path.clone()))
+                .map(|manifest| {
+                    let modified = modified(&manifest);
+                    (manifest, modified)
+                })
+                .collect();
+            Ok(Workspace {
+                metadata,
+                manifests,
+            })
+        }
+
+        /// Whether a manifest was edited, added or removed since loading;
+        /// new members always touch the workspace manifest.
+        fn is_stale(&self) -> bool {
+            self.manifests
+                .iter()
+                .any(|(manifest, modified)| self::modified(manifest) != *modified)
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    impl<'a> Server<'a> {
+        fn new(cli: &'a Cli) -> Self {
+            Server {
+                cli,
+                workspaces: HashMap::new(),
+            }
+        }
+
+        fn serve_connection(
+            &mut self,
+            reader: impl BufRead,
+            mut writer: impl Write,
+        ) -> anyhow::Result<()> {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = match serde_json::from_str::<Value>(&line) {
+                    Ok(request) => self.respond(&request),
+                    Err(e) => Some(error_response(&Value::Null, -32700, &e.to_string())),
+                };
+                if let Some(response) = response {
+                    writeln!(writer, "{response}")?;
+                    writer.flush()?;
+                }
+            }
+            Ok(())
+        }
+
+        /// The response to `request`, `None` for notifications.
+        fn respond(&mut self, request: &Value) -> Option<Value> {
+            let id = request.get("id")?;
+            let method = request["method"].as_str().unwrap_or_default();
+            let params = &request["params"];
+            let result = match method {
+                "polishFile" => self.polish_file(params),
+                "checkFile" => self.check_file(params),
+                "affectedPackages" => self.affected_packages(params),
+                _ => {
+                    let message = format!("unknown method {method}");
+                    return Some(error_response(id, METHOD_NOT_FOUND, &message));
+                }
+            };
+            Some(match result {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err(e) => error_response(id, INVALID_PARAMS, &format!("{e:#}")),
+            })
+        }
+
+        /// `{path, write?}`: the polished contents of a Rust file or
+        /// manifest, written back when `write` is true.
+        fn polish_file(&self, params: &Value) -> anyhow::Result<Value> {
+            let path = path_param(params)?;
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read file: {}", path.display()))?;
+            let polished = lsp::polish_document(&path, &text, None)?;
+            let changed = polished.is_some();
+            let content = polished.map_or(text, |(_, polished)| polished);
+            if changed && params["write"].as_bool() == Some(true) {
+                super::interrupt::write(&path, &content)
+                    .with_context(|| format!("Failed to write file: {}", path.display()))?;
+            }
+            Ok(json!({"changed": changed, "content": content}))
+        }
+
+        /// `{path}`: whether the file is polished, the diff that would make
+        /// it so, and for a manifest its dependency and policy issues.
+        fn check_file(&self, params: &Value) -> anyhow::Result<Value> {
+            let path = path_param(params)?;
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read file: {}", path.display()))?;
+            let root = lsp::repo_root(&path);
+            let diff = match lsp::polish_document(&path, &text, None)? {
+                Some((_, polished)) => {
+                    let relative = root
+                        .as_ref()
+                        .and_then(|root| path.strip_prefix(root).ok())
+                        .unwrap_or(&path);
+                    let before = (text.as_bytes(), None);
+                    Some(snapshot::unified_diff(
+                        relative,
+                        before,
+                        (polished.as_bytes(), None),
+                    )?)
+                }
+                None => None,
+            };
+            let mut issues = Vec::new();
+            if path.file_name().is_some_and(|n| n == "Cargo.toml") {
+                let config = match &root {
+                    Some(root) => config::load(root)?,
+                    None => config::Config::default(),
+                };
+                issues.extend(manifest_checks::check_path_dependencies(&path)?);
+                issues.extend(manifest_checks::check_policy(&path, &config.manifest)?);
+            }
+            let issues: Vec<Value> = issues
+                .iter()
+                .map(|issue| json!({"line": issue.line, "message": issue.message}))
+                .collect();
+            Ok(json!({
+                "polished": diff.is_none() && issues.is_empty(),
+                "diff": diff,
+                "issues": issues,
+            }))
+        }
+
+        /// `{files}`: the workspace package each file belongs to, with the
+        /// reasoning `--explain-affected` prints.
+        fn affected_packages(&mut self, params: &Value) -> anyhow::Result<Value> {
+            let Some(files) = params["files"].as_array() else {
+                bail!("missing files");
+            };
+            let mut affected = Vec::new();
+            for file in files {
+                let file = file.as_str().context("files must be paths")?;
+                let path = super::normalize_path(&absolute(Path::new(file))?);
+                let Some(git_root) = lsp::repo_root(&path) else {
+                    bail!("{} is not in a git repository", path.display());
+                };
+                let workspace = self.workspace(&git_root)?;
+                let (target, reasons) = workspace.metadata.explain(&path);
+                affected.push(json!({
+                    "file": file,
+                    "package": target.as_ref().map(|t| &t.package.name),
+                    "manifest": target.as_ref().map(|t| &t.package.manifest_path),
+                    "reasons": reasons,
+                }));
+            }
+            Ok(json!(affected))
+        }
+
+        /// The cached workspace of `git_root`, reloaded if it went stale.
+        fn workspace(&mut self, git_root: &Path) -> anyhow::Result<&Workspace> {
+            let fresh = self
+                .workspaces
+                .get(git_root)
+                .is_some_and(|workspace| !workspace.is_stale());
+            if !fresh {
+                let config = config::load(git_root)?;
+                let cargo = CargoOptions::new(git_root, self.cli, &config.cargo);
+                let workspace = Workspace::load(git_root, &cargo)?;
+                self.workspaces.insert(git_root.to_path_buf(), workspace);
+            }
+            Ok(&self.workspaces[git_root])
+        }
+    }
+
+    fn error_response(id: &Value, code: i64, message: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+    }
+
+    fn path_param(params: &Value) -> anyhow::Result<PathBuf> {
+        let path = params["path"].as_str().context("missing path")?;
+        absolute(Path::new(path))
+    }
+
+    /// Relative paths are resolved against the server's directory.
+    fn absolute(path: &Path) -> anyhow::Result<PathBuf> {
+        Ok(std::env::current_dir()?.join(path))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+        use clap::Parser;
+
+        fn request(server: &mut Server, line: &str) -> Value {
+            let mut output = Vec::new();
+            server
+                .serve_connection(line.as_bytes(), &mut output)
+                .unwrap();
+            serde_json::from_slice(&output).unwrap()
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_serve_keeps_other_files() {
+            let dir = TempDir::new("serve-socket");
+            let file = dir.join("notes.txt");
+            std::fs::write(&file, "keep me").unwrap();
+            let cli = Cli::parse_from(["polish"]);
+            let error = serve(&cli, &file).unwrap_err();
+            assert!(error.to_string().contains("is not a socket"));
+            assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+        }
+
+        #[test]
+        fn test_polish_and_check_file() {
+            let dir = TempDir::new("serve");
+            let manifest = dir.join("Cargo.toml");
+            std::fs::write(&manifest, "[dependencies]\nserde = \"1\"\nanyhow = \"1\"\n").unwrap();
+            let cli = Cli::parse_from(["polish"]);
+            let mut server = Server::new(&cli);
+
+            let check = format!(
+                r#"{{"jsonrpc":"2.0","id":1,"method":"checkFile","params":{{"path":{:?}}}}}"#,
+                manifest
+            );
+            let response = request(&mut server, &check);
+            assert_eq!(response["result"]["polished"], false);
+            assert!(response["result"]["diff"].as_str().unwrap().contains("@@ "));
+
+            let polish = format!(
+                r#"{{"jsonrpc":"2.0","id":2,"method":"polishFile","params":{{"path":{:?},"write":true}}}}"#,
+                manifest
+            );
+            let response = request(&mut server, &polish);
+            assert_eq!(response["result"]["changed"], true);
+            assert_eq!(
+                std::fs::read_to_string(&manifest).unwrap(),
+                "[dependencies]\nanyhow = \"1\"\nserde = \"1\"\n"
+            );
+            assert_eq!(request(&mut server, &check)["result"]["polished"], true);
+
+            let unknown = r#"{"jsonrpc":"2.0","id":3,"method":"lint"}"#;
+            assert_eq!(
+                request(&mut server, unknown)["error"]["code"],
+                METHOD_NOT_FOUND
+            );
+        }
+    }
+}
+
+mod bench {
+    use super::source_error::SourceError;
+    use super::{rust_grouping, toml_grouping};
+    use serde::Serialize;
+    use std::path::{Path, PathBuf};
+    use std::time::Instant;
+
+    #[cfg(feature = "bench")]
+    use counting::counted;
+
+    // Only built with `--features bench`: a global allocator replaces the
+    // system one for the whole process, which no other command should pay for
+    #[cfg(feature = "bench")]
+    mod counting {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        // The allocator has to be global, but it only counts on a thread
+        // that is inside `counted`; everywhere else it costs one
+        // thread-local load before handing over to the system allocator
+        #[global_allocator]
+        static ALLOCATOR: Counting = Counting;
+
+        thread_local! {
+            static COUNTER: Counter = const {
+                Counter {
+                    enabled: Cell::new(false),
+                    allocations: Cell::new(0),
+                    allocated_bytes: Cell::new(0),
+                }
+            };
+        }
+
+        struct Counter {
+            enabled: Cell<bool>,
+            allocations: Cell<u64>,
+            allocated_bytes: Cell<u64>,
+        }
+
+        fn count(size: usize) {
+            // `try_with` fails once the thread's locals are torn down, when
+            // there is nothing left to count for. The const initializer and
+            // the destructor-free `Cell`s mean the access never allocates,
+            // which would recurse into the allocator
+            let _ = COUNTER.try_with(|counter| {
+                if counter.enabled.get() {
+                    counter.allocations.set(counter.allocations.get() + 1);
+                    counter
+                        .allocated_bytes
+                        .set(counter.allocated_bytes.get() + size as u64);
+                }
+            });
+        }
+
+        /// Runs `f`, returning its result with the number of allocations and
+        /// bytes allocated on this thread meanwhile.
+        pub fn counted<T>(f: impl FnOnce() -> T) -> (T, Option<(u64, u64)>) {
+            COUNTER.with(|counter| {
+                counter.allocations.set(0);
+                counter.allocated_bytes.set(0);
+                counter.enabled.set(true);
+            });
+            let result = f();
+            COUNTER.with(|counter| {
+                counter.enabled.set(false);
+                (
+                    result,
+                    Some((counter.allocations.get(), counter.allocated_bytes.get())),
+                )
+            })
+        }
+
+        struct Counting;
+
+        // SAFETY: every method forwards to `System` with the arguments it was
+        // given, so `Counting` upholds the `GlobalAlloc` contract exactly as
+        // `System` does. The counting around it never allocates and never
+        // unwinds.
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                count(layout.size());
+                // SAFETY: the caller upholds `alloc`'s contract for `layout`.
+                unsafe { System.alloc(layout) }
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                // SAFETY: `ptr` was allocated by `System` through this
+                // allocator with `layout`, as the caller guarantees.
+                unsafe { System.dealloc(ptr, layout) }
+            }
+
+            unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+                count(new_size);
+                // SAFETY: `ptr` and `layout` come from a `System` allocation
+                // and the caller upholds `realloc`'s contract for `new_size`.
+                unsafe { System.realloc(ptr, layout, new_size) }
+            }
+        }
+    }
+
+    /// Without the counting allocator there is nothing to count.
+    #[cfg(not(feature = "bench"))]
+    fn counted<T>(f: impl FnOnce() -> T) -> (T, Option<(u64, u64)>) {
+        (f(), None)
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    pub struct Report {
+        pub group_items: Pass,
+        pub organize_toml: Pass,
+    }
+
+    /// Totals of one function over the corpus, across all iterations.
+    #[derive(Debug, Default, Serialize)]
+    pub struct Pass {
+        pub files: usize,
+        pub bytes: u64,
+        pub seconds: f64,
+        /// `None` unless built with `--features bench`
+        pub allocations: Option<u64>,
+        pub allocated_bytes: Option<u64>,
+        /// Files the function rejected, with the error
+        pub errors: Vec<(PathBuf, String)>,
+        /// Files whose output changes when polished again
+        pub not_idempotent: Vec<PathBuf>,
+    }
+
+    impl Report {
+        pub fn render(&self) -> String {
+            let mut out = String::new();
+            self.group_items.render(&mut out, "group_items");
+            self.organize_toml.render(&mut out, "organize_toml");
+            out
+        }
+
+        pub fn is_idempotent(&self) -> bool {
+            self.group_items.not_idempotent.is_empty()
+                && self.organize_toml.not_idempotent.is_empty()
+        }
+    }
+
+    impl Pass {
+        /// Times `polish` on `content`, then checks that polishing its
+        /// output again changes nothing.
+        fn measure(
+            &mut self,
+            file: &Path,
+            content: &str,
+            iterations: usize,
+            polish: impl Fn(&str) -> Result<String, SourceError>,
+        ) {
+            self.files += 1;
+            let start = Instant::now();
+            let (result, counts) = counted(|| {
+                let mut result = polish(content);
+                for _ in 1..iterations {
+                    result = polish(content);
+                }
+                result
+            });
+            self.seconds += start.elapsed().as_secs_f64();
+            if let Some((allocations, allocated_bytes)) = counts {
+                *self.allocations.get_or_insert(0) += allocations;
+                *self.allocated_bytes.get_or_insert(0) += allocated_bytes;
+            }
+            self.bytes += (content.len() * iterations) as u64;
+
+            match result {
+                Ok(polished) => {
+                    if !polish(&polished).is_ok_and(|again| again == polished) {
+                        self.not_idempotent.push(file.to_path_buf());
+                    }
+                }
+                Err(e) => self.errors.push((
+                    file.to_path_buf(),
+                    format!("line {}: {}", e.line, e.message),
+                )),
+            }
+        }
+
+        fn render(&self, out: &mut String, name: &str) {
+            const MIB: f64 = 1024.0 * 1024.0;
+            let mib = self.bytes as f64 / MIB;
+            out.push_str(&format!(
+                "{name}: {} files, {mib:.1} MiB in {:.2}s ({:.1} MiB/s)",
+                self.files,
+                self.seconds,
+                mib / self.seconds.max(f64::EPSILON),
+            ));
+            if let (Some(allocations), Some(bytes)) = (self.allocations, self.allocated_bytes) {
+                out.push_str(&format!(
+                    ", {allocations} allocations ({:.1} MiB)",
+                    bytes as f64 / MIB
+                ));
+            }
+            out.push('\n');
+            if !self.errors.is_empty() {
+                out.push_str(&format!("  {} file(s) failed:\n", self.errors.len()));
+                for (file, error) in &self.errors {
+                    out.push_str(&format!("    {}: {error}\n", file.display()));
+                }
+            }
+            if !self.not_idempotent.is_empty() {
+                out.push_str(&format!(
+                    "  {} file(s) not idempotent:\n",
+                    self.not_idempotent.len()
+                ));
+                for file in &self.not_idempotent {
+                    out.push_str(&format!("    {}\n", file.display()));
+                }
+            }
+        }
+    }
+
+    /// Runs both passes over every Rust file and `Cargo.toml` under
+    /// `corpus`, each `iterations` times.
+    pub fn run(corpus: &Path, iterations: usize) -> anyhow::Result<Report> {
+        let mut files = Vec::new();
+        collect(corpus, &mut files)?;
+        files.sort();
+        let mut report = Report::default();
+        for file in &files {
+            // Non-UTF-8 files can't be polished either
+            let Ok(content) = std::fs::read_to_string(file) else {
+                continue;
+            };
+            if file.file_name().is_some_and(|n| n == "Cargo.toml") {
+                report.organize_toml.measure(
+                    file,
+                    &content,
+                    iterations,
+                    toml_grouping::organize_toml,
+                );
+            } else {
+                report
+                    .group_items
+                    .measure(file, &content, iterations, rust_grouping::group_items);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Rust files and manifests under `dir`, skipping build output and
+    /// hidden directories such as `.git`.
+    fn collect(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() {
+                if name != "target" && !name.starts_with('.') {
+                    collect(&path, files)?;
+                }
+            } else if name == "Cargo.toml" || path.extension().is_some_and(|e| e == "rs") {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_run() {
+            let corpus = TempDir::new("bench");
+            std::fs::create_dir_all(corpus.join("src")).unwrap();
+            std::fs::create_dir_all(corpus.join("target/debug")).unwrap();
+            std::fs::write(
+                corpus.join("src/lib.rs"),
+                "mod a;\nuse std::io;\npub mod b;\n",
+            )
+            .unwrap();
+            std::fs::write(corpus.join("target/debug/out.rs"), "mod a;\n").unwrap();
+            std::fs::write(
+                corpus.join("Cargo.toml"),
+                "[dependencies]\nserde = \"1\"\nanyhow = \"1\"\n",
+            )
+            .unwrap();
+
+            let report = run(&corpus, 2).unwrap();
+            assert_eq!(report.group_items.files, 1);
+            assert_eq!(report.organize_toml.files, 1);
+            assert!(report.group_items.errors.is_empty());
+            assert!(report.is_idempotent());
+            assert_eq!(
+                report.group_items.allocations.is_some_and(|n| n > 0),
+                cfg!(feature = "bench")
+            );
+        }
+    }
+}
+
+mod fuzz {
+    use super::rust_grouping;
+    use serde::Serialize;
+
+    /// Outcome of `fuzz`: how many headers were generated and the ones
+    /// grouping got wrong.
+    #[derive(Debug, Default, Serialize)]
+    pub struct Report {
+        pub cases: usize,
+        pub failures: Vec<Failure>,
+    }
+
+    /// A generated header that broke a property, with the seed that
+    /// generates it again.
+    #[derive(Debug, Serialize)]
+    pub struct Failure {
+        pub seed: u64,
+        pub problem: String,
+        pub input: String,
+    }
+
+    impl Report {
+        pub fn render(&self) -> String {
+            let mut out = format!(
+                "{} case(s), {} failure(s)\n",
+                self.cases,
+                self.failures.len()
+            );
+            for failure in &self.failures {
+                out.push_str(&format!(
+                    "\nseed {}: {}\n{}",
+                    failure.seed, failure.problem, failure.input
+                ));
+            }
+            out
+        }
+    }
+
+    /// Groups `cases` generated headers, the first from `seed` and each
+    /// next one from the following seed, and checks every property on them.
+    pub fn run(seed: u64, cases: usize) -> Report {
+        let mut report = Report {
+            cases,
+            ..Default::default()
+        };
+        for case in 0..cases as u64 {
+            let seed = seed.wrapping_add(case);
+            let input = header(&mut Rng(seed));
+            if let Err(problem) = check(&input) {
+                report.failures.push(Failure {
+                    seed,
+                    problem,
+                    input,
+                });
+            }
+        }
+        report
+    }
+
+    /// The properties grouping must hold on any valid header: the output is
+    /// accepted by grouping again, doesn't change when grouped again, and
+    /// has the same lines as the input apart from blank lines and comments.
+    fn check(input: &str) -> Result<(), String> {
+        let options = rust_grouping::Options::default();
+        let grouped = rust_grouping::group_items_with(input, &options)
+            .map_err(|e| format!("rejected: line {}: {}", e.line, e.message))?;
+        if let Some(hunks) = rust_grouping::regrouping_diff(&grouped, &options) {
+            return Err(format!("not idempotent:\n{hunks}"));
+        }
+        if let Some(lines) = rust_grouping::lost_lines(input, &grouped, &options) {
+            return Err(format!(
+                "lines lost or duplicated:\n{lines}output:\n{grouped}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// SplitMix64, so a seed always generates the same header.
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    const VISIBILITIES: [&str; 8] = [
+        "",
+        "pub ",
+        "pub\t",
+        "pub(crate) ",
+        "pub (crate) ",
+        "pub(super) ",
+        "pub(self) ",
+        "pub(in crate::a) ",
+    ];
+    const ATTRIBUTES: [&str; 4] = [
+        "#[cfg(test)]",
+        "#[allow(unused)]",
+        "#[macro_use]",
+        "#[cfg(feature = \"x\")]",
+    ];
+    const CRATE_ATTRIBUTES: [&str; 4] = [
+        "#![feature(test)]",
+        "#![allow(dead_code)]",
+        "#![warn(missing_docs)]",
+        "#![recursion_limit = \"256\"]",
+    ];
+    const PATHS: [&str; 6] = [
+        "std::fmt", "crate::a", "super::b", "serde", "self::c", "r#async",
+    ];
+
+    /// Inline modules nest at most this deep.
+    const MAX_DEPTH: usize = 2;
+
+    /// A module header: crate attributes (indices into
+    /// [`CRATE_ATTRIBUTES`]) and declarations, followed by some code.
+    #[derive(Debug, Clone)]
+    pub struct Header {
+        pub crate_attributes: Vec<usize>,
+        pub items: Vec<Item>,
+    }
+
+    /// A declaration with what surrounds it.
+    #[derive(Debug, Clone)]
+    pub struct Item {
+        pub blank_before: bool,
+        pub leading: Leading,
+        /// Index into [`VISIBILITIES`]
+        pub visibility: usize,
+        pub trailing_comment: bool,
+        pub decl: Decl,
+    }
+
+    /// The line before a declaration.
+    #[derive(Debug, Clone)]
+    pub enum Leading {
+        None,
+        Comment,
+        Doc,
+        /// Index into [`ATTRIBUTES`]
+        Attribute(usize),
+    }
+
+    /// A declaration; paths are indices into [`PATHS`].
+    #[derive(Debug, Clone)]
+    pub enum Decl {
+        Mod,
+        Use(usize),
+        /// `use path::{a, b};`
+        UseGroup(usize),
+        /// `use path::{` with one name per line
+        UseMultiline(usize),
+        ExternCrate,
+        /// `mod a; use path::a;` on one line
+        ModAndUse(usize),
+        /// `mod a { ... }` with a header of its own
+        Inline(Vec<Item>),
+    }
+
+    impl Header {
+        /// A random header, always the same one for the same seed.
+        pub fn random(rng: &mut Rng) -> Header {
+            Header {
+                crate_attributes: (0..rng.below(3))
+                    .map(|_| rng.below(CRATE_ATTRIBUTES.len()))
+                    .collect(),
+                items: random_items(rng, 0),
+            }
+        }
+
+        pub fn render(&self) -> String {
+            let mut out = String::new();
+            for attribute in &self.crate_attributes {
+                out.push_str(CRATE_ATTRIBUTES[*attribute]);
+                out.push('\n');
+            }
+            let mut names = 0;
+            render_items(&self.items, &mut out, "", &mut names);
+            out.push_str("\nfn main() {}\n");
+            out
+        }
+    }
+
+    fn random_items(rng: &mut Rng, depth: usize) -> Vec<Item> {
+        (0..1 + rng.below(8))
+            .map(|_| Item {
+                blank_before: rng.below(4) == 0,
+                leading: match rng.below(6) {
+                    0 => Leading::Comment,
+                    1 => Leading::Doc,
+                    2 => Leading::Attribute(rng.below(ATTRIBUTES.len())),
+                    _ => Leading::None,
+                },
+                visibility: rng.below(VISIBILITIES.len()),
+                trailing_comment: rng.below(4) == 0,
+                decl: match rng.below(if depth < MAX_DEPTH { 7 } else { 6 }) {
+                    0 => Decl::Mod,
+                    1 => Decl::Use(rng.below(PATHS.len())),
+                    2 => Decl::UseGroup(rng.below(PATHS.len())),
+                    3 => Decl::UseMultiline(rng.below(PATHS.len())),
+                    4 => Decl::ExternCrate,
+                    5 => Decl::ModAndUse(rng.below(PATHS.len())),
+                    _ => Decl::Inline(random_items(rng, depth + 1)),
+                },
+            })
+            .collect()
+    }
+
+    fn render_items(items: &[Item], out: &mut String, indent: &str, names: &mut usize) {
+        for item in items {
+            // Every declaration gets a unique name, so the lines of the
+            // input are told apart when counted
+            *names += 1;
+            let name = format!("n{names}");
+            if item.blank_before {
+                out.push('\n');
+            }
+            match item.leading {
+                Leading::Comment => out.push_str(&format!("{indent}// about {name}\n")),
+                Leading::Doc => out.push_str(&format!("{indent}/// Docs for {name}\n")),
+                Leading::Attribute(i) => out.push_str(&format!("{indent}{}\n", ATTRIBUTES[i])),
+                Leading::None => {}
+            }
+            let visibility = VISIBILITIES[item.visibility];
+            let comment = if item.trailing_comment {
+                format!(" // needed for {name}")
+            } else {
+                String::new()
+            };
+            match &item.decl {
+                Decl::Mod => out.push_str(&format!("{indent}{visibility}mod {name};{comment}\n")),
+                Decl::Use(path) => out.push_str(&format!(
+                    "{indent}{visibility}use {}::{name};{comment}\n",
+                    PATHS[*path]
+                )),
+                Decl::UseGroup(path) => out.push_str(&format!(
+                    "{indent}{visibility}use {}::{{{name}, {name}b}};{comment}\n",
+                    PATHS[*path]
+                )),
+                Decl::UseMultiline(path) => out.push_str(&format!(
+                    "{indent}{visibility}use {}::{{\n{indent}    {name},\n{indent}    {name}b,\n{indent}}};{comment}\n",
+                    PATHS[*path]
+                )),
+                Decl::ExternCrate => {
+                    out.push_str(&format!("{indent}extern crate {name};{comment}\n"))
+                }
+                Decl::ModAndUse(path) => out.push_str(&format!(
+                    "{indent}{visibility}mod {name}; use {}::{name};{comment}\n",
+                    PATHS[*path]
+                )),
+                Decl::Inline(items) => {
+                    out.push_str(&format!("{indent}{visibility}mod {name} {{\n"));
+                    let inner = format!("{indent}    ");
+                    render_items(items, out, &inner, names);
+                    out.push_str(&format!("\n{inner}fn f() {{}}\n{indent}}}\n"));
+                }
+            }
+        }
+    }
+
+    /// A random module header: crate attributes, `extern crate`, `mod` and
+    /// `use` declarations with random visibilities, attributes, comments
+    /// before and after them, several on one line and blank lines, and
+    /// inline modules with headers of their own, followed by some code.
+    pub fn header(rng: &mut Rng) -> String {
+        Header::random(rng).render()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn item() -> impl Strategy<Value = Item> {
+            let leaf_decl = prop_oneof![
+                Just(Decl::Mod),
+                (0..PATHS.len()).prop_map(Decl::Use),
+                (0..PATHS.len()).prop_map(Decl::UseGroup),
+                (0..PATHS.len()).prop_map(Decl::UseMultiline),
+                Just(Decl::ExternCrate),
+                (0..PATHS.len()).prop_map(Decl::ModAndUse),
+            ];
+            let decl = leaf_decl.prop_recursive(MAX_DEPTH as u32, 32, 8, |inner| {
+                prop::collection::vec(item_with(inner), 1..8).prop_map(Decl::Inline)
+            });
+            item_with(decl)
+        }
+
+        fn item_with(decl: impl Strategy<Value = Decl>) -> impl Strategy<Value = Item> {
+            let leading = prop_oneof![
+                3 => Just(Leading::None),
+                1 => Just(Leading::Comment),
+                1 => Just(Leading::Doc),
+                1 => (0..ATTRIBUTES.len()).prop_map(Leading::Attribute),
+            ];
+            (
+                prop::bool::weighted(0.25),
+                leading,
+                0..VISIBILITIES.len(),
+                prop::bool::weighted(0.25),
+                decl,
+            )
+                .prop_map(
+                    |(blank_before, leading, visibility, trailing_comment, decl)| Item {
+                        blank_before,
+                        leading,
+                        visibility,
+                        trailing_comment,
+                        decl,
+                    },
+                )
+        }
+
+        fn header() -> impl Strategy<Value = Header> {
+            (
+                prop::collection::vec(0..CRATE_ATTRIBUTES.len(), 0..3),
+                prop::collection::vec(item(), 1..8),
+            )
+                .prop_map(|(crate_attributes, items)| Header {
+                    crate_attributes,
+                    items,
+                })
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(500))]
+
+            /// Failures shrink to the smallest header still breaking a
+            /// property.
+            #[test]
+            fn test_generated_headers_hold_properties(header in header()) {
+                let input = header.render();
+                if let Err(problem) = check(&input) {
+                    prop_assert!(false, "{}\ninput:\n{}", problem, input);
+                }
+            }
+        }
+
+        #[test]
+        fn test_seed_reproduces_header() {
+            assert_eq!(super::header(&mut Rng(42)), super::header(&mut Rng(42)));
+            assert_ne!(super::header(&mut Rng(42)), super::header(&mut Rng(43)));
+        }
+    }
+}
+
+mod fixtures {
+    use super::{config, rust_grouping, snapshot, toml_grouping};
+    use std::path::{Path, PathBuf};
+
+    /// A golden test: `name.input.rs` polished must equal `name.expected.rs`
+    /// (likewise for `.toml`).
+    #[derive(Debug)]
+    pub struct Fixture {
+        pub input: PathBuf,
+        pub expected: PathBuf,
+        /// `expected` relative to the fixture directory, as diffs show it
+        pub name: PathBuf,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Outcome {
+        Passed,
+        /// Diff from the expected output to the actual one
+        Failed(String),
+        /// No expected file yet
+        Missing,
+        /// Polishing the input failed
+        Error(String),
+    }
+
+    /// Every fixture under `dir`, sorted by input path.
+    pub fn find(dir: &Path) -> anyhow::Result<Vec<Fixture>> {
+        let mut fixtures = Vec::new();
+        collect(dir, dir, &mut fixtures)?;
+        fixtures.sort_by(|a, b| a.input.cmp(&b.input));
+        Ok(fixtures)
+    }
+
+    fn collect(root: &Path, dir: &Path, fixtures: &mut Vec<Fixture>) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                collect(root, &path, fixtures)?;
+                continue;
+            }
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            for extension in ["rs", "toml"] {
+                if let Some(stem) = name.strip_suffix(&format!(".input.{extension}")) {
+                    let expected = path.with_file_name(format!("{stem}.expected.{extension}"));
+                    fixtures.push(Fixture {
+                        name: expected
+                            .strip_prefix(root)
+                            .unwrap_or(&expected)
+                            .to_path_buf(),
+                        expected,
+                        input: path.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    impl Fixture {
+        /// Polishes the input and compares it with the expected output;
+        /// with `bless` the expected output is overwritten instead.
+        pub fn run(&self, config: &config::Config, bless: bool) -> anyhow::Result<Outcome> {
+            let input = std::fs::read_to_string(&self.input)?;
+            let actual = if self.input.extension().is_some_and(|e| e == "rs") {
+                rust_grouping::group_items_with(&input, &config.grouping)
+            } else {
+                toml_grouping::organize_toml_with(&input, &config.dependencies)
+            };
+            let actual = match actual {
+                Ok(actual) => actual,
+                Err(e) => return Ok(Outcome::Error(e.in_file(&self.input).to_string())),
+            };
+            if bless {
+                std::fs::write(&self.expected, &actual)?;
+                return Ok(Outcome::Passed);
+            }
+            let Ok(expected) = std::fs::read_to_string(&self.expected) else {
+                return Ok(Outcome::Missing);
+            };
+            if actual == expected {
+                return Ok(Outcome::Passed);
+            }
+            let diff = snapshot::unified_diff(
+                &self.name,
+                (expected.as_bytes(), None),
+                (actual.as_bytes(), None),
+            )?;
+            Ok(Outcome::Failed(diff))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
+
+        #[test]
+        fn test_fixtures() {
+            let dir = TempDir::new("fixtures");
+            std::fs::create_dir_all(dir.join("toml")).unwrap();
+            std::fs::write(dir.join("mods.input.rs"), "mod a;\npub mod b;\n").unwrap();
+            std::fs::write(dir.join("mods.expected.rs"), "pub mod b;\n\nmod a;\n").unwrap();
+            std::fs::write(dir.join("wrong.input.rs"), "mod a;\npub mod b;\n").unwrap();
+            std::fs::write(dir.join("wrong.expected.rs"), "mod a;\npub mod b;\n").unwrap();
+            std::fs::write(
+                dir.join("toml/deps.input.toml"),
+                "[dependencies]\nserde = \"1\"\nanyhow = \"1\"\n",
+            )
+            .unwrap();
+
+            let fixtures = find(&dir).unwrap();
+            let names: Vec<_> = fixtures
+                .iter()
+                .map(|f| f.input.strip_prefix(&dir).unwrap().to_path_buf())
+                .collect();
+            assert_eq!(
+                names,
+                [
+                    PathBuf::from("mods.input.rs"),
+                    PathBuf::from("toml/deps.input.toml"),
+                    PathBuf::from("wrong.input.rs"),
+                ]
+            );
+
+            let options = config::Config::default();
+            assert_eq!(fixtures[0].run(&options, false).unwrap(), Outcome::Passed);
+            assert_eq!(fixtures[1].run(&options, false).unwrap(), Outcome::Missing);
+            let Outcome::Failed(diff) = fixtures[2].run(&options, false).unwrap() else {
+                panic!("expected a mismatch");
+            };
+            assert!(diff.contains("@@ "));
+
+            assert_eq!(fixtures[1].run(&options, true).unwrap(), Outcome::Passed);
+            assert_eq!(fixtures[1].run(&options, false).unwrap(), Outcome::Passed);
+        }
+    }
+}
+
+mod annotations {
+    use super::Summary;
+    use std::path::Path;
+
+    /// GitHub Actions workflow commands (`::error file=...,line=...::...`)
+    /// for everything in `summary` that points at a location, so the
+    /// problems show up inline on the pull request.
+    pub fn from_summary(summary: &Summary) -> Vec<String> {
+        let mut lines = Vec::new();
+        for finding in &summary.findings {
+            lines.push(command(
+                "error",
+                Some(&finding.file),
+                Some(finding.line),
+                &finding.message,
+            ));
+        }
+        for warning in &summary.warnings {
+            lines.push(command(
+                "warning",
+                Some(&warning.file),
+                Some(warning.line),
+                &warning.message,
+            ));
+        }
+        for edit in &summary.edits {
+            lines.push(command(
+                "error",
+                Some(&edit.file),
+                None,
+                "File needs polishing (run polish-rs locally)",
+            ));
+        }
+        for diagnostic in summary.clippy.iter().flat_map(|c| &c.diagnostics) {
+            let level = if diagnostic.level == "error" {
+                "error"
+            } else {
+                "warning"
+            };
+            lines.push(command(
+                level,
+                Some(&diagnostic.file),
+                Some(diagnostic.line),
+                &format!("{}: {}", diagnostic.lint, diagnostic.message),
+            ));
+        }
+        if let Some(error) = &summary.error {
+            lines.push(command("error", None, None, error));
+        }
+        lines
+    }
+
+    fn command(level: &str, file: Option<&Path>, line: Option<usize>, message: &str) -> String {
+        let mut properties = Vec::new();
+        if let Some(file) = file {
+            let file = file.to_string_lossy().replace('\\', "/");
+            properties.push(format!("file={}", escape_property(&file)));
+        }
+        if let Some(line) = line {
+            properties.push(format!("line={line}"));
+        }
+        let separator = if properties.is_empty() { "" } else { " " };
+        format!(
+            "::{level}{separator}{}::{}",
+            properties.join(","),
+            escape_data(message)
+        )
+    }
+
+    fn escape_data(s: &str) -> String {
+        s.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    }
+
+    fn escape_property(s: &str) -> String {
+        escape_data(s).replace(':', "%3A").replace(',', "%2C")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_command() {
+            assert_eq!(
+                command("error", Some(Path::new("a/src/lib.rs")), Some(3), "bad"),
+                "::error file=a/src/lib.rs,line=3::bad"
+            );
+            assert_eq!(
+                command("warning", None, None, "50% done\nnext: x"),
+                "::warning::50%25 done%0Anext: x"
+            );
+            assert_eq!(
+                command("error", Some(Path::new("a,b:c.rs")), None, "m"),
+                "::error file=a%2Cb%3Ac.rs::m"
+            );
+        }
+    }
+}
+
+mod suggestions {
+    use serde::Serialize;
+    use std::path::Path;
+
+    /// A diagnostic as `rustc --error-format=json` prints it, so the tools
+    /// that apply rustc's suggestions can apply polish's one by one.
+    #[derive(Debug, Serialize)]
+    pub struct Diagnostic {
+        pub message: String,
+        pub code: Option<Code>,
+        level: &'static str,
+        pub spans: Vec<Span>,
+        pub children: Vec<Diagnostic>,
+        rendered: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Code {
+        pub code: &'static str,
+        explanation: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Span {
+        pub file_name: String,
+        byte_start: usize,
+        byte_end: usize,
+        pub line_start: usize,
+        pub line_end: usize,
+        column_start: usize,
+        column_end: usize,
+        is_primary: bool,
+        text: Vec<SpanLine>,
+        label: Option<String>,
+        pub suggested_replacement: Option<String>,
+        suggestion_applicability: Option<&'static str>,
+        expansion: Option<()>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct SpanLine {
+        text: String,
+        highlight_start: usize,
+        highlight_end: usize,
+    }
+
+    /// One warning per run of changed lines in the edit of `file` from
+    /// `old` to `new`, carrying the replacement as a machine-applicable
+    /// suggestion. Spans cover whole lines, ending at the start of the line
+    /// after the last one replaced.
+    pub fn from_edit(file: &Path, old: &str, new: &str) -> Vec<Diagnostic> {
+        let file_name = file.to_string_lossy().replace('\\', "/");
+        let (code, message) = if file_name.ends_with(".toml") {
+            ("polish::toml", "manifest is not organized")
+        } else {
+            ("polish::grouping", "items are not grouped and formatted")
+        };
+        let lines: Vec<&str> = old.split_inclusive('\n').collect();
+        let offset = |line: usize| lines[..line].iter().map(|l| l.len()).sum::<usize>();
+
+        let mut diagnostics = Vec::new();
+        for replacement in super::diff::replacements(old, new) {
+            let span = |suggestion: Option<String>| Span {
+                file_name: file_name.clone(),
+                byte_start: offset(replacement.start),
+                byte_end: offset(replacement.end),
+                line_start: replacement.start + 1,
+                line_end: replacement.end + 1,
+                column_start: 1,
+                column_end: 1,
+                is_primary: true,
+                text: lines[replacement.start..replacement.end]
+                    .iter()
+                    .map(|l| {
+                        let text = l.trim_end_matches(['\n', '\r']).to_string();
+                        SpanLine {
+                            highlight_start: 1,
+                            highlight_end: text.chars().count() + 1,
+                            text,
+                        }
+                    })
+                    .collect(),
+                label: None,
+                suggestion_applicability: suggestion.as_ref().map(|_| "MachineApplicable"),
+                suggested_replacement: suggestion,
+                expansion: None,
+            };
+            let help = Diagnostic {
+                message: "apply the polished version".to_string(),
+                code: None,
+                level: "help",
+                spans: vec![span(Some(replacement.text.clone()))],
+                children: Vec::new(),
+                rendered: None,
+            };
+            diagnostics.push(Diagnostic {
+                message: message.to_string(),
+                code: Some(Code {
+                    code,
+                    explanation: None,
+                }),
+                level: "warning",
+                spans: vec![span(None)],
+                children: vec![help],
+                rendered: Some(format!(
+                    "warning: {message}\n --> {file_name}:{}:1\n",
+                    replacement.start + 1
+                )),
+            });
+        }
+        diagnostics
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_from_edit() {
+            let old = "use b;\nuse a;\n\nfn f() {}\nfn g( ) {}\n";
+            let new = "use a;\nuse b;\n\nfn f() {}\nfn g() {}\n";
+            let diagnostics = from_edit(Path::new("src/lib.rs"), old, new);
+            let json = serde_json::to_value(&diagnostics).unwrap();
+            assert_eq!(json.as_array().unwrap().len(), 3);
+            assert_eq!(json[0]["code"]["code"], "polish::grouping");
+            let suggestion = &json[0]["children"][0]["spans"][0];
+            assert_eq!(suggestion["byte_start"], 0);
+            assert_eq!(suggestion["line_start"], 1);
+            assert_eq!(suggestion["suggestion_applicability"], "MachineApplicable");
+            assert_eq!(json[2]["spans"][0]["line_start"], 5);
+
+            // Applying every suggestion by its byte range gives the new file
+            let mut applied = old.to_string();
+            for diagnostic in json.as_array().unwrap().iter().rev() {
+                let span = &diagnostic["children"][0]["spans"][0];
+                let range = span["byte_start"].as_u64().unwrap() as usize
+                    ..span["byte_end"].as_u64().unwrap() as usize;
+                applied.replace_range(range, span["suggested_replacement"].as_str().unwrap());
+            }
+            assert_eq!(applied, new);
+        }
+    }
+}
+
+mod rdjson {
+    use super::{suggestions, Summary};
+    use serde_json::{json, Value};
+    use std::path::Path;
+
+    /// The run as a Reviewdog Diagnostic Format result: polish's findings,
+    /// clippy's diagnostics and, with `--check`, each change the run would
+    /// make as a suggestion, so reviewdog can comment on them inline.
+    pub fn from_summary(summary: &Summary) -> Value {
+        let mut diagnostics = Vec::new();
+        for (severity, findings) in [("ERROR", &summary.findings), ("WARNING", &summary.warnings)] {
+            for finding in findings {
+                diagnostics.push(json!({
+                    "message": finding.message,
+                    "location": location(&finding.file, finding.line, finding.line),
+                    "severity": severity,
+                    "source": {"name": "polish-rs"},
+                }));
+            }
+        }
+        for diagnostic in summary.clippy.iter().flat_map(|c| &c.diagnostics) {
+            let severity = if diagnostic.level == "error" {
+                "ERROR"
+            } else {
+                "WARNING"
+            };
+            diagnostics.push(json!({
+                "message": diagnostic.message,
+                "location": location(&diagnostic.file, diagnostic.line, diagnostic.line),
+                "severity": severity,
+                "source": {"name": "clippy"},
+                "code": {"value": diagnostic.lint},
+            }));
+        }
+        for diagnostic in &summary.suggestions {
+            diagnostics.push(suggestion(diagnostic));
+        }
+        json!({
+            "source": {"name": "polish-rs"},
+            "diagnostics": diagnostics,
+        })
+    }
+
+    /// A change as a diagnostic on the lines it replaces, with the
+    /// replacement as a suggestion.
+    fn suggestion(diagnostic: &suggestions::Diagnostic) -> Value {
+        let span = &diagnostic.spans[0];
+        let range = range(span.line_start, span.line_end);
+        let suggestions: Vec<Value> = diagnostic
+            .children
+            .iter()
+            .flat_map(|child| &child.spans)
+            .filter_map(|span| span.suggested_replacement.as_ref())
+            .map(|text| json!({"range": range, "text": text}))
+            .collect();
+        json!({
+            "message": diagnostic.message,
+            "location": {"path": span.file_name, "range": range},
+            "severity": "ERROR",
+            "source": {"name": "polish-rs"},
+            "code": {"value": diagnostic.code.as_ref().map(|c| c.code)},
+            "suggestions": suggestions,
+        })
+    }
+
+    /// Whole lines `start..=end`, or the first line of the file for a
+    /// finding without one.
+    fn location(file: &Path, start: usize, end: usize) -> Value {
+        json!({
+            "path": file.to_string_lossy().replace('\\', "/"),
+            "range": range(start.max(1), end.max(1) + 1),
+        })
+    }
+
+    /// From the start of line `start` to the start of line `end`, as
+    /// rustc's suggestion spans do.
+    fn range(start: usize, end: usize) -> Value {
+        json!({
+            "start": {"line": start, "column": 1},
+            "end": {"line": end, "column": 1},
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::Finding;
+
+        #[test]
+        fn test_from_summary() {
+            let summary = Summary {
+                findings: vec![Finding {
+                    file: "a/src/lib.rs".into(),
+                    line: 3,
+                    message: "missing docs".to_string(),
+                }],
+                suggestions: suggestions::from_edit(
+                    Path::new("a/Cargo.toml"),
+                    "[dependencies]\nb = \"1\"\na = \"1\"\n",
+                    "[dependencies]\na = \"1\"\nb = \"1\"\n",
+                ),
+                ..Default::default()
+            };
+            let result = from_summary(&summary);
+            let diagnostics = result["diagnostics"].as_array().unwrap();
+            assert_eq!(diagnostics[0]["location"]["path"], "a/src/lib.rs");
+            assert_eq!(diagnostics[0]["location"]["range"]["start"]["line"], 3);
+            assert_eq!(diagnostics[0]["severity"], "ERROR");
+            let change = &diagnostics[1];
+            assert_eq!(change["code"]["value"], "polish::toml");
+            assert_eq!(change["location"]["path"], "a/Cargo.toml");
+            assert!(change["suggestions"][0]["text"].is_string());
+        }
+    }
+}
+
+mod metrics {
+    use anyhow::Context;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    /// Bumped whenever a key changes meaning or goes away.
+    const SCHEMA_VERSION: u32 = 1;
+
+    /// What `--metrics-file` writes. The keys are a stable interface for
+    /// build systems and merge queues; add to them, don't rename them.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Metrics {
+        pub schema_version: u32,
+        pub success: bool,
+        pub files_scanned: usize,
+        /// Files the run changed, or with `--check` would have changed
+        pub files_modified: usize,
+        /// Packages clippy ran on, not counting cache hits
+        pub packages_linted: usize,
+        pub cache_hits: CacheHits,
+        pub durations: Durations,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct CacheHits {
+        /// Packages whose last clean clippy run was reused
+        pub clippy: usize,
+        /// Whether the whole result was replayed from the last identical run
+        pub results: bool,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct Durations {
+        pub total_seconds: f64,
+        /// Seconds per stage that ran, e.g. `grouping`, `fmt`, `clippy`
+        pub stages: BTreeMap<String, f64>,
+    }
+
+    impl Metrics {
+        pub fn new(summary: &super::Summary, total_seconds: f64) -> Metrics {
+            let clippy_hits = summary.clippy.as_ref().map_or(0, |c| c.cached.len());
+            let packages_linted = match &summary.clippy {
+                Some(_) => summary.members.len().saturating_sub(clippy_hits),
+                None => 0,
+            };
+            Metrics {
+                schema_version: SCHEMA_VERSION,
+                success: summary.success,
+                files_scanned: summary.files.len(),
+                files_modified: summary.modified.len(),
+                packages_linted,
+                cache_hits: CacheHits {
+                    clippy: clippy_hits,
+                    results: false,
+                },
+                durations: Durations {
+                    total_seconds,
+                    stages: summary
+                        .timings
+                        .stages
+                        .iter()
+                        .map(|t| (t.name.clone(), t.seconds))
+                        .collect(),
+                },
+            }
+        }
+
+        /// These metrics as replayed by a results cache hit: nothing ran, so
+        /// only the total time is the current run's.
+        pub fn replayed(self, total_seconds: f64) -> Metrics {
+            Metrics {
+                packages_linted: 0,
+                cache_hits: CacheHits {
+                    clippy: 0,
+                    results: true,
+                },
+                durations: Durations {
+                    total_seconds,
+                    stages: BTreeMap::new(),
+                },
+                ..self
+            }
+        }
+
+        pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+            std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+                .with_context(|| format!("Failed to write {}", path.display()))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_keys_are_stable() {
+            let metrics = Metrics {
+                schema_version: SCHEMA_VERSION,
+                success: true,
+                files_scanned: 3,
+                files_modified: 1,
+                packages_linted: 2,
+                cache_hits: CacheHits::default(),
+                durations: Durations {
+                    total_seconds: 1.5,
+                    stages: BTreeMap::from([("clippy".to_string(), 1.0)]),
+                },
+            };
+            assert_eq!(
+                serde_json::to_string(&metrics).unwrap(),
+                r#"{"schema_version":1,"success":true,"files_scanned":3,"files_modified":1,"packages_linted":2,"cache_hits":{"clippy":0,"results":false},"durations":{"total_seconds":1.5,"stages":{"clippy":1.0}}}"#
+            );
+
+            let replayed = metrics.clone().replayed(0.1);
+            assert!(replayed.cache_hits.results);
+            assert_eq!(replayed.files_modified, 1);
+            assert_eq!(replayed.packages_linted, 0);
+            assert!(replayed.durations.stages.is_empty());
+        }
+    }
+}
+
+mod notify {
+    /// Shows a desktop notification: through D-Bus on Linux and the BSDs,
+    /// Notification Center on macOS and a toast on Windows.
+    pub fn send(title: &str, body: &str) -> anyhow::Result<()> {
+        notify_rust::Notification::new()
+            .appname("polish-rs")
+            .summary(title)
+            .body(body)
+            .show()?;
+        Ok(())
+    }
+}
+
+mod teamcity {
+    use super::Summary;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    /// A service message (`##teamcity[name key='value' ...]`) for TeamCity
+    /// to pick up from the build log.
+    pub fn message(name: &str, attributes: &[(&str, &str)]) -> String {
+        let mut out = format!("##teamcity[{name}");
+        for (key, value) in attributes {
+            out.push_str(&format!(" {key}='{}'", escape(value)));
+        }
+        out.push(']');
+        out
+    }
+
+    /// Inspections for everything in `summary` that points at a location,
+    /// and a build problem if the run failed, so TeamCity lists and counts
+    /// them on the build's Inspections tab.
+    pub fn from_summary(summary: &Summary) -> Vec<String> {
+        let mut inspections = Vec::new();
+        for (findings, severity) in [(&summary.findings, "ERROR"), (&summary.warnings, "WARNING")] {
+            for finding in findings {
+                inspections.push(Inspection {
+                    type_id: "polish-rs.check",
+                    category: "polish-rs",
+                    file: &finding.file,
+                    line: Some(finding.line),
+                    message: &finding.message,
+                    severity,
+                });
+            }
+        }
+        for edit in &summary.edits {
+            inspections.push(Inspection {
+                type_id: "polish-rs.change",
+                category: "polish-rs",
+                file: &edit.file,
+                line: None,
+                message: "File needs polishing (run polish-rs locally)",
+                severity: "ERROR",
+            });
+        }
+        for diagnostic in summary.clippy.iter().flat_map(|c| &c.diagnostics) {
+            inspections.push(Inspection {
+                type_id: &diagnostic.lint,
+                category: "clippy",
+                file: &diagnostic.file,
+                line: Some(diagnostic.line),
+                message: &diagnostic.message,
+                severity: if diagnostic.level == "error" {
+                    "ERROR"
+                } else {
+                    "WARNING"
+                },
+            });
+        }
+
+        // Each inspection type is declared once, before its inspections
+        let types: BTreeMap<&str, &str> = inspections
+            .iter()
+            .map(|i| (i.type_id, i.category))
+            .collect();
+        let mut lines: Vec<String> = types
+            .into_iter()
+            .map(|(id, category)| {
+                message(
+                    "inspectionType",
+                    &[
+                        ("id", id),
+                        ("name", id),
+                        ("category", category),
+                        ("description", id),
+                    ],
+                )
+            })
+            .collect();
+        for inspection in &inspections {
+            let file = inspection.file.to_string_lossy().replace('\\', "/");
+            let line = inspection.line.map(|l| l.to_string());
+            let mut attributes = vec![
+                ("typeId", inspection.type_id),
+                ("message", inspection.message),
+                ("file", &file),
+            ];
+            if let Some(line) = &line {
+                attributes.push(("line", line));
+            }
+            attributes.push(("SEVERITY", inspection.severity));
+            lines.push(message("inspection", &attributes));
+        }
+        if let Some(error) = &summary.error {
+            lines.push(message("buildProblem", &[("description", error)]));
+        }
+        lines
+    }
+
+    struct Inspection<'a> {
+        type_id: &'a str,
+        category: &'static str,
+        file: &'a Path,
+        line: Option<usize>,
+        message: &'a str,
+        severity: &'static str,
+    }
+
+    fn escape(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '|' => out.push_str("||"),
+                '\'' => out.push_str("|'"),
+                '\n' => out.push_str("|n"),
+                '\r' => out.push_str("|r"),
+                '[' => out.push_str("|["),
+                ']' => out.push_str("|]"),
+                '\u{0085}' => out.push_str("|x"),
+                '\u{2028}' => out.push_str("|l"),
+                '\u{2029}' => out.push_str("|p"),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_message() {
+            assert_eq!(
+                message("blockOpened", &[("name", "polish: clippy")]),
+                "##teamcity[blockOpened name='polish: clippy']"
+            );
+            assert_eq!(
+                message("buildProblem", &[("description", "it's [1|2]\nnext")]),
+                "##teamcity[buildProblem description='it|'s |[1||2|]|nnext']"
+            );
+        }
+    }
+}
+
+mod timings {
+    use serde::Serialize;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Number of entries shown per section of the rendered breakdown.
+    const TOP_ENTRIES: usize = 10;
+
+    #[derive(Debug, Default, Serialize)]
+    pub struct Timings {
+        pub stages: Vec<Timing>,
+        pub files: Vec<Timing>,
+        pub packages: Vec<Timing>,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Timing {
+        pub name: String,
+        pub seconds: f64,
+    }
+
+    impl Timings {
+        pub fn record_stage(&mut self, stage: &str, elapsed: Duration) {
+            add(&mut self.stages, stage, elapsed);
+        }
+
+        pub fn record_file(&mut self, file: &Path, elapsed: Duration) {
+            add(&mut self.files, &file.display().to_string(), elapsed);
+        }
+
+        pub fn record_package(&mut self, package: &str, elapsed: Duration) {
+            add(&mut self.packages, package, elapsed);
+        }
+
+        pub fn render(&self) -> String {
+            let mut out = String::from("Timings:\n");
+            render_section(&mut out, "stages", &self.stages);
+            render_section(&mut out, "slowest files (grouping)", &self.files);
+            render_section(&mut out, "slowest packages (clippy)", &self.packages);
+            out
+        }
+    }
+
+    // Repeated entries (e.g. a package with several targets) are accumulated
+    fn add(entries: &mut Vec<Timing>, name: &str, elapsed: Duration) {
+        match entries.iter_mut().find(|t| t.name == name) {
+            Some(timing) => timing.seconds += elapsed.as_secs_f64(),
+            None => entries.push(Timing {
+                name: name.to_string(),
+                seconds: elapsed.as_secs_f64(),
+            }),
+        }
+    }
+
+    fn render_section(out: &mut String, title: &str, entries: &[Timing]) {
+        if entries.is_empty() {
+            return;
+        }
+        let mut sorted = entries.to_vec();
+        sorted.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+
+        out.push_str(&format!("  {title}:\n"));
+        let width = sorted.iter().map(|t| t.name.len()).max().unwrap_or(0);
+        for timing in sorted.iter().take(TOP_ENTRIES) {
+            out.push_str(&format!(
+                "    {:<width$}  {:>8.2}s\n",
+                timing.name, timing.seconds
+            ));
+        }
+        if sorted.len() > TOP_ENTRIES {
+            out.push_str(&format!(
+                "    ... and {} more\n",
+                sorted.len() - TOP_ENTRIES
+            ));
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_render_sorted_slowest_first() {
+            let mut timings = Timings::default();
+            timings.record_stage("grouping", Duration::from_millis(10));
+            timings.record_stage("clippy", Duration::from_secs(3));
+            timings.record_package("fast", Duration::from_millis(500));
+            timings.record_package("slow", Duration::from_secs(2));
+            timings.record_package("fast", Duration::from_millis(500));
+
+            let expected = "Timings:
+  stages:
+    clippy        3.00s
+    grouping      0.01s
+  slowest packages (clippy):
+    slow      2.00s
+    fast      1.00s
+";
+            assert_eq!(timings.render(), expected);
+        }
+
+        #[test]
+        fn test_render_truncates_long_sections() {
+            let mut timings = Timings::default();
+            for i in 0..12 {
+                timings.record_file(Path::new(&format!("f{i:02}.rs")), Duration::from_millis(i));
+            }
+
+            let rendered = timings.render();
+            assert!(rendered.contains("f11.rs"));
+            assert!(!rendered.contains("f00.rs"));
+            assert!(rendered.contains("... and 2 more"));
+        }
+    }
+}
+
+mod manifest_checks {
+    use super::config::ManifestConfig;
+    use anyhow::Context;
+    use std::path::{Path, PathBuf};
+
+    /// Dependency tables that end up in the published manifest; path-only
+    /// `dev-dependencies` are stripped by `cargo publish` instead.
+    const PUBLISHED_TABLES: [&str; 2] = ["dependencies", "build-dependencies"];
+
+    #[derive(Debug, PartialEq)]
+    pub struct ManifestIssue {
+        /// 1-based line of the offending entry
+        pub line: usize,
+        pub message: String,
+        pub fix: Option<ManifestFix>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum ManifestFix {
+        /// Add `version = "..."` to the dependency at `line`
+        InsertVersion {
+            manifest: PathBuf,
+            line: usize,
+            version: String,
+        },
+        /// Add `key.workspace = true` to `[package]`
+        Inherit { manifest: PathBuf, key: String },
+    }
+
+    impl ManifestFix {
+        /// Applies the fix, returning a description of what was done.
+        pub fn apply(&self) -> anyhow::Result<String> {
+            match self {
+                ManifestFix::InsertVersion {
+                    manifest,
+                    line,
+                    version,
+                } => {
+                    let content = std::fs::read_to_string(manifest)
+                        .with_context(|| format!("Failed to read file: {}", manifest.display()))?;
+                    let fixed = insert_version(&content, *line, version)
+                        .with_context(|| format!("Failed to fix {}", manifest.display()))?;
+                    super::interrupt::write(manifest, fixed)
+                        .with_context(|| format!("Failed to write file: {}", manifest.display()))?;
+                    Ok(format!(
+                        "Added version = \"{version}\" to line {line} of {}",
+                        manifest.display()
+                    ))
+                }
+                ManifestFix::Inherit { manifest, key } => {
+                    let content = std::fs::read_to_string(manifest)
+                        .with_context(|| format!("Failed to read file: {}", manifest.display()))?;
+                    let fixed = insert_package_key(&content, &format!("{key}.workspace = true"))
+                        .with_context(|| format!("No [package] in {}", manifest.display()))?;
+                    super::interrupt::write(manifest, fixed)
+                        .with_context(|| format!("Failed to write file: {}", manifest.display()))?;
+                    Ok(format!(
+                        "Added {key}.workspace = true to {}",
+                        manifest.display()
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Checks that every `path` dependency of a publishable package also has
+    /// a `version`, without which `cargo publish` refuses the package. The
+    /// fix uses the version of the package the path points to.
+    pub fn check_path_dependencies(manifest: &Path) -> anyhow::Result<Vec<ManifestIssue>> {
+        let content = std::fs::read_to_string(manifest)
+            .with_context(|| format!("Failed to read file: {}", manifest.display()))?;
+        let table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+        let Some(package) = table.get("package").and_then(|p| p.as_table()) else {
+            return Ok(Vec::new());
+        };
+        if !is_published(package) {
+            return Ok(Vec::new());
+        }
 
-    // Group declarations and organize dependencies
-    if !cli.no_grouping {
-        for (file_path, file_type) in &files_to_process {
-            match file_type {
-                FileType::Rust => {
-                    rust_grouping::group_file_declarations(file_path)?;
+        let mut sections = Vec::new();
+        for name in PUBLISHED_TABLES {
+            sections.push((name.to_string(), table.get(name)));
+        }
+        if let Some(targets) = table.get("target").and_then(|t| t.as_table()) {
+            for (cfg, target) in targets {
+                for name in PUBLISHED_TABLES {
+                    sections.push((format!("target.{cfg}.{name}"), target.get(name)));
                 }
-                FileType::CargoToml => {
-                    toml_grouping::organize_dependencies(file_path)?;
+            }
+        }
+
+        let dir = manifest.parent().unwrap_or(Path::new(""));
+        let mut issues = Vec::new();
+        for (section, deps) in sections {
+            let Some(deps) = deps.and_then(|d| d.as_table()) else {
+                continue;
+            };
+            for (name, dep) in deps {
+                let Some(dep) = dep.as_table() else {
+                    continue;
+                };
+                let Some(path) = dep.get("path").and_then(|p| p.as_str()) else {
+                    continue;
+                };
+                if dep.contains_key("version") {
+                    continue;
                 }
+                let line = locate(&content, &section, name);
+                let version = package_version(&dir.join(path).join("Cargo.toml"));
+                issues.push(ManifestIssue {
+                    line: line.unwrap_or(1),
+                    message: format!(
+                        "path dependency `{name}` in [{section}] has no `version`, which `cargo publish` requires"
+                    ),
+                    fix: line.zip(version).map(|(line, version)| ManifestFix::InsertVersion {
+                        manifest: manifest.to_path_buf(),
+                        line,
+                        version,
+                    }),
+                });
             }
         }
+        // Bottom-up, so a fix adding a line doesn't shift the ones before it
+        issues.sort_by_key(|issue| std::cmp::Reverse(issue.line));
+        Ok(issues)
     }
 
-    // Find affected workspace members (only for Rust files)
-    let rust_files: Vec<PathBuf> = files_to_process
-        .iter()
-        .filter(|(_, ft)| *ft == FileType::Rust)
-        .map(|(p, _)| p.clone())
-        .collect();
-
-    if rust_files.is_empty() {
-        println!("No Rust files to format/lint");
-        return Ok(());
+    /// Checks `[package]` against the `[manifest]` policy: required keys and
+    /// the edition. A missing key the workspace defines in
+    /// `[workspace.package]` can be fixed by inheriting it.
+    pub fn check_policy(
+        manifest: &Path,
+        policy: &ManifestConfig,
+    ) -> anyhow::Result<Vec<ManifestIssue>> {
+        if policy.required.is_empty() && policy.edition.is_none() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(manifest)
+            .with_context(|| format!("Failed to read file: {}", manifest.display()))?;
+        let table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+        let Some(package) = table.get("package").and_then(|p| p.as_table()) else {
+            return Ok(Vec::new());
+        };
+        let line = locate_header(&content, "package").unwrap_or(1);
+        let workspace = workspace_package(manifest);
+        let inherit = |key: &str| {
+            workspace
+                .as_ref()
+                .filter(|w| w.contains_key(key))
+                .map(|_| ManifestFix::Inherit {
+                    manifest: manifest.to_path_buf(),
+                    key: key.to_string(),
+                })
+        };
+
+        let mut issues = Vec::new();
+        for key in &policy.required {
+            if !package.contains_key(key) {
+                issues.push(ManifestIssue {
+                    line,
+                    message: format!("[package] is missing `{key}`"),
+                    fix: inherit(key),
+                });
+            }
+        }
+        if let Some(required) = &policy.edition {
+            let edition = match package.get("edition") {
+                Some(toml::Value::String(edition)) => Some(edition.clone()),
+                Some(toml::Value::Table(_)) => workspace
+                    .as_ref()
+                    .and_then(|w| w.get("edition")?.as_str().map(str::to_string)),
+                _ => None,
+            };
+            match edition {
+                Some(edition) if edition == *required => {}
+                Some(edition) => issues.push(ManifestIssue {
+                    line: locate(&content, "package", "edition").unwrap_or(line),
+                    message: format!("edition is {edition}, expected {required}"),
+                    fix: None,
+                }),
+                None => issues.push(ManifestIssue {
+                    line,
+                    message: format!("[package] is missing `edition` (expected {required})"),
+                    fix: workspace
+                        .as_ref()
+                        .and_then(|w| w.get("edition")?.as_str())
+                        .filter(|edition| edition == required)
+                        .and_then(|_| inherit("edition")),
+                }),
+            }
+        }
+        Ok(issues)
     }
 
-    let workspace_members = find_affected_projects(&git_root, &rust_files)?;
+    /// Whether `publish` allows the package to be published somewhere.
+    fn is_published(package: &toml::Table) -> bool {
+        match package.get("publish") {
+            Some(toml::Value::Boolean(publish)) => *publish,
+            Some(toml::Value::Array(registries)) => !registries.is_empty(),
+            _ => true,
+        }
+    }
 
-    if workspace_members.is_empty() {
-        println!("No Rust workspace members affected");
-        return Ok(());
+    /// Version of the package in `manifest`, following `version.workspace =
+    /// true` to the enclosing workspace's `[workspace.package]`.
+    fn package_version(manifest: &Path) -> Option<String> {
+        let table = read_table(manifest)?;
+        match table.get("package")?.get("version")? {
+            toml::Value::String(version) => Some(version.clone()),
+            toml::Value::Table(inherited) if inherited.get("workspace")?.as_bool()? => Some(
+                workspace_package(manifest)?
+                    .get("version")?
+                    .as_str()?
+                    .to_string(),
+            ),
+            _ => None,
+        }
     }
 
-    println!("Affected workspace members: {:?}", workspace_members);
+    /// `[workspace.package]` of the workspace `manifest` belongs to: the
+    /// closest manifest with a `[workspace]` table, starting with its own.
+    fn workspace_package(manifest: &Path) -> Option<toml::Table> {
+        let root = manifest
+            .parent()?
+            .ancestors()
+            .filter_map(|dir| read_table(&dir.join("Cargo.toml")))
+            .find(|table| table.contains_key("workspace"))?;
+        root.get("workspace")?.get("package")?.as_table().cloned()
+    }
 
-    // Run cargo fmt on all affected members in a single call
-    if !cli.no_fmt {
-        run_cargo_fmt(&git_root, &workspace_members)?;
+    fn read_table(manifest: &Path) -> Option<toml::Table> {
+        toml::from_str(&std::fs::read_to_string(manifest).ok()?).ok()
     }
 
-    // Run cargo clippy on all affected members in a single call
-    if !cli.no_clippy {
-        run_cargo_clippy(&git_root, &workspace_members)?;
+    /// 1-based line declaring dependency `name` of `section` (e.g.
+    /// `target.cfg(unix).dependencies`): its `name = ...` entry or its own
+    /// `[section.name]` header.
+    fn locate(content: &str, section: &str, name: &str) -> Option<usize> {
+        let section = normalize_header(section);
+        let own_header = format!("{section}.{}", normalize_header(name));
+        let mut current = String::new();
+        for (i, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+            if let Some(header) = trimmed
+                .strip_prefix('[')
+                .and_then(|h| h.split('#').next())
+                .and_then(|h| h.trim_end().strip_suffix(']'))
+            {
+                current = normalize_header(header);
+                if current == own_header {
+                    return Some(i + 1);
+                }
+                continue;
+            }
+            let key = trimmed.split('=').next().unwrap_or("").trim();
+            if current == section && trimmed.contains('=') && normalize_header(key) == name {
+                return Some(i + 1);
+            }
+        }
+        None
     }
 
-    println!("✓ All checks passed!");
-    Ok(())
-}
+    /// 1-based line of the `[section]` header.
+    fn locate_header(content: &str, section: &str) -> Option<usize> {
+        content
+            .lines()
+            .position(|line| {
+                line.trim()
+                    .strip_prefix('[')
+                    .and_then(|h| h.split('#').next())
+                    .and_then(|h| h.trim_end().strip_suffix(']'))
+                    .is_some_and(|h| normalize_header(h) == section)
+            })
+            .map(|i| i + 1)
+    }
 
-fn is_git_repo() -> anyhow::Result<bool> {
-    let output = Command::new("git")
-        .args(["rev-parse", "--git-dir"])
-        .output()?;
-    Ok(output.status.success())
-}
+    /// Adds `entry` after the last key of `[package]`.
+    fn insert_package_key(content: &str, entry: &str) -> Option<String> {
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let header = locate_header(content, "package")?;
+        let mut end = header;
+        for (i, line) in lines.iter().enumerate().skip(header) {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') {
+                break;
+            }
+            if !trimmed.is_empty() {
+                end = i + 1;
+            }
+        }
+        lines.insert(end, entry.to_string());
+        Some(lines.join("\n") + "\n")
+    }
 
-fn get_git_root() -> anyhow::Result<PathBuf> {
-    let output = Command::new("git")
-        .args(["rev-parse", "--show-toplevel"])
-        .output()
-        .context("Failed to get git root")?;
+    /// A table header with quotes and whitespace removed, so
+    /// `target.'cfg(unix)'.dependencies` matches `target.cfg(unix).dependencies`.
+    fn normalize_header(header: &str) -> String {
+        header
+            .chars()
+            .filter(|c| !matches!(c, '\'' | '"') && !c.is_whitespace())
+            .collect()
+    }
 
-    if !output.status.success() {
-        bail!("Failed to get git root");
+    /// Adds `version = "..."` to the dependency at 1-based `line`: into its
+    /// inline table, or as the first key under its own table header.
+    fn insert_version(content: &str, line: usize, version: &str) -> Option<String> {
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let target = lines.get_mut(line.checked_sub(1)?)?;
+        if target.trim_start().starts_with('[') {
+            lines.insert(line, format!("version = \"{version}\""));
+        } else {
+            let brace = target.find('{')?;
+            target.insert_str(brace + 1, &format!(" version = \"{version}\","));
+        }
+        Some(lines.join("\n") + "\n")
     }
 
-    let path = String::from_utf8(output.stdout)?.trim().to_string();
-    Ok(PathBuf::from(path))
-}
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::temp_dir::TempDir;
 
-fn get_changed_files() -> anyhow::Result<Vec<(PathBuf, FileType)>> {
-    // Get changed files (staged and unstaged), excluding deleted files
-    // For renames, --name-only will show the new name
-    let output = Command::new("git")
-        .args(["diff", "--diff-filter=d", "--name-only", "HEAD~1"])
-        .output()
-        .context("Failed to get changed files")?;
+        #[test]
+        fn test_locate() {
+            let content = r#"[package]
+name = "b"
 
-    if !output.status.success() {
-        bail!("Failed to get changed files");
-    }
+[dev-dependencies]
+a = { path = "../a" }
 
-    let paths: Vec<PathBuf> = String::from_utf8(output.stdout)?
-        .lines()
-        .map(|line| PathBuf::from(line.trim()))
-        .filter(|path| !path.as_os_str().is_empty())
-        .collect();
+[dependencies]
+a = { path = "../a" }
 
-    classify_files(&paths)
-}
+[target.'cfg(unix)'.dependencies.c]
+path = "../c"
+"#;
+            assert_eq!(locate(content, "dependencies", "a"), Some(8));
+            assert_eq!(locate(content, "dev-dependencies", "a"), Some(5));
+            assert_eq!(
+                locate(content, "target.cfg(unix).dependencies", "c"),
+                Some(10)
+            );
+            assert_eq!(locate(content, "dependencies", "c"), None);
+        }
 
-fn classify_files(paths: &[PathBuf]) -> anyhow::Result<Vec<(PathBuf, FileType)>> {
-    let mut result = Vec::new();
+        #[test]
+        fn test_insert_version() {
+            assert_eq!(
+                insert_version("[dependencies]\na = { path = \"../a\" }\n", 2, "0.1.0").unwrap(),
+                "[dependencies]\na = { version = \"0.1.0\", path = \"../a\" }\n"
+            );
+            assert_eq!(
+                insert_version("[dependencies.a]\npath = \"../a\"\n", 1, "0.1.0").unwrap(),
+                "[dependencies.a]\nversion = \"0.1.0\"\npath = \"../a\"\n"
+            );
+        }
 
-    for path in paths {
-        if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-            if file_name == "Cargo.toml" {
-                result.push((path.clone(), FileType::CargoToml));
-            } else if path.extension() == Some(ffi::OsStr::new("rs")) {
-                result.push((path.clone(), FileType::Rust));
-            }
-            // Ignore other file types
+        #[test]
+        fn test_insert_package_key() {
+            assert_eq!(
+                insert_package_key(
+                    "[package]\nname = \"a\"\n\n[dependencies]\n",
+                    "license.workspace = true"
+                )
+                .unwrap(),
+                "[package]\nname = \"a\"\nlicense.workspace = true\n\n[dependencies]\n"
+            );
+            assert!(insert_package_key("[workspace]\n", "x = 1").is_none());
         }
-    }
 
-    Ok(result)
+        #[test]
+        fn test_check_policy() {
+            let dir = TempDir::new("policy");
+            std::fs::create_dir_all(dir.join("a")).unwrap();
+            std::fs::write(
+                dir.join("Cargo.toml"),
+                "[workspace]\nmembers = [\"a\"]\n\n[workspace.package]\nlicense = \"MIT\"\nedition = \"2021\"\n",
+            )
+            .unwrap();
+            let manifest = dir.join("a/Cargo.toml");
+            std::fs::write(&manifest, "[package]\nname = \"a\"\nedition = \"2018\"\n").unwrap();
+            let policy = ManifestConfig {
+                required: vec!["license".to_string(), "repository".to_string()],
+                edition: Some("2021".to_string()),
+            };
+
+            let issues = check_policy(&manifest, &policy).unwrap();
+            let messages: Vec<&str> = issues.iter().map(|i| i.message.as_str()).collect();
+            assert_eq!(
+                messages,
+                [
+                    "[package] is missing `license`",
+                    "[package] is missing `repository`",
+                    "edition is 2018, expected 2021",
+                ]
+            );
+            assert_eq!(issues[2].line, 3);
+            assert!(issues[1].fix.is_none());
+            issues[0].fix.as_ref().unwrap().apply().unwrap();
+
+            std::fs::write(
+                &manifest,
+                std::fs::read_to_string(&manifest)
+                    .unwrap()
+                    .replace("edition = \"2018\"", "edition.workspace = true"),
+            )
+            .unwrap();
+            let issues = check_policy(&manifest, &policy).unwrap();
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].message, "[package] is missing `repository`");
+        }
+
+        #[test]
+        fn test_check_path_dependencies() {
+            let dir = TempDir::new("path-deps");
+            std::fs::create_dir_all(dir.join("a")).unwrap();
+            std::fs::create_dir_all(dir.join("b")).unwrap();
+            std::fs::write(
+                dir.join("Cargo.toml"),
+                "[workspace]\nmembers = [\"a\", \"b\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+            )
+            .unwrap();
+            std::fs::write(
+                dir.join("a/Cargo.toml"),
+                "[package]\nname = \"a\"\nversion.workspace = true\n",
+            )
+            .unwrap();
+            let manifest = dir.join("b/Cargo.toml");
+            std::fs::write(
+                &manifest,
+                "[package]\nname = \"b\"\n\n[dependencies]\na = { path = \"../a\" }\n\n[dev-dependencies]\nc = { path = \"../c\" }\n",
+            )
+            .unwrap();
+
+            let issues = check_path_dependencies(&manifest).unwrap();
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].line, 5);
+            assert_eq!(
+                issues[0].fix,
+                Some(ManifestFix::InsertVersion {
+                    manifest: manifest.clone(),
+                    line: 5,
+                    version: "1.2.3".to_string(),
+                })
+            );
+            issues[0].fix.as_ref().unwrap().apply().unwrap();
+            assert!(check_path_dependencies(&manifest).unwrap().is_empty());
+
+            std::fs::write(
+                &manifest,
+                "[package]\nname = \"b\"\npublish = false\n\n[dependencies]\na = { path = \"../a\" }\n",
+            )
+            .unwrap();
+            assert!(check_path_dependencies(&manifest).unwrap().is_empty());
+        }
+    }
 }
 
-fn find_affected_projects(
-    git_root: &Path,
-    changed_files: &[PathBuf],
-) -> anyhow::Result<HashSet<String>> {
-    let mut affected_members = HashSet::new();
+mod source_error {
+    use std::path::{Path, PathBuf};
+
+    /// A line polish can't make sense of, shown like a compiler diagnostic:
+    /// the message, the location, and the offending line with a label.
+    #[derive(Debug, PartialEq)]
+    pub struct SourceError {
+        pub message: String,
+        pub file: Option<PathBuf>,
+        /// 1-based
+        pub line: usize,
+        /// The offending line as written
+        pub source: String,
+        pub label: String,
+    }
 
-    for changed_file in changed_files {
-        // Skip non-Rust files
-        if changed_file.extension() != Some(ffi::OsStr::new("rs")) {
-            continue;
+    impl SourceError {
+        /// An error at `lines[index]`.
+        pub fn new(
+            lines: &[String],
+            index: usize,
+            message: impl Into<String>,
+            label: impl Into<String>,
+        ) -> Self {
+            SourceError {
+                message: message.into(),
+                file: None,
+                line: index + 1,
+                source: lines.get(index).cloned().unwrap_or_default(),
+                label: label.into(),
+            }
         }
 
-        // Find the package for this file by walking up the directory tree
-        let package_name = find_project_for_file(git_root, changed_file)?;
-        affected_members.insert(package_name);
+        pub fn in_file(mut self, file: &Path) -> Self {
+            self.file = Some(file.to_path_buf());
+            self
+        }
     }
 
-    Ok(affected_members)
-}
+    impl std::fmt::Display for SourceError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let indent = self.source.len() - self.source.trim_start().len();
+            let column = indent + 1;
+            let number = self.line.to_string();
+            let gutter = " ".repeat(number.len());
+            writeln!(f, "{}", self.message)?;
+            match &self.file {
+                Some(file) => writeln!(f, "{gutter}--> {}:{}:{column}", file.display(), self.line)?,
+                None => writeln!(f, "{gutter}--> line {}:{column}", self.line)?,
+            }
+            writeln!(f, "{gutter} |")?;
+            writeln!(f, "{number} | {}", self.source.trim_end())?;
+            write!(
+                f,
+                "{gutter} | {}{} {}",
+                " ".repeat(indent),
+                "^".repeat(self.source.trim().chars().count().max(1)),
+                self.label
+            )
+        }
+    }
 
-fn find_project_for_file(git_root: &Path, file: &Path) -> anyhow::Result<String> {
-    // Start from the file's directory
-    let full_path = git_root.join(file);
-    let mut current_dir = if full_path.is_file() {
-        full_path.parent()
-    } else {
-        Some(full_path.as_path())
-    };
+    impl std::error::Error for SourceError {}
 
-    // Walk up the directory tree until we find a Cargo.toml or reach git root
-    while let Some(dir) = current_dir {
-        let cargo_toml = dir.join("Cargo.toml");
-        if cargo_toml.exists() {
-            // Parse Cargo.toml to extract the package name
-            let content = std::fs::read_to_string(&cargo_toml)
-                .with_context(|| format!("Failed to read {}", cargo_toml.display()))?;
-            return extract_package_name(&content);
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_render() {
+            let lines = vec!["mod a;".to_string(), "    use foo::{".to_string()];
+            let error = SourceError::new(
+                &lines,
+                1,
+                "unterminated `use` declaration",
+                "no `;` before the end of the file",
+            )
+            .in_file(Path::new("src/lib.rs"));
+            assert_eq!(
+                error.to_string(),
+                "unterminated `use` declaration\n --> src/lib.rs:2:5\n  |\n2 |     use foo::{\n  |     ^^^^^^^^^^ no `;` before the end of the file"
+            );
         }
-        anyhow::ensure!(dir != git_root, "Can't go beyond git's root directory");
-        // Go up one directory
-        current_dir = dir.parent();
     }
-    anyhow::bail!("There are no Cargo.toml in the repo?")
 }
 
-fn extract_package_name(toml_content: &str) -> anyhow::Result<String> {
-    let mut in_package_section = false;
+mod toml_grouping {
+    use super::source_error::SourceError;
+    use anyhow::Context;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
 
-    for line in toml_content.lines() {
-        let trimmed = line.trim();
+    /// Dependency settings from the `[dependencies]` section of polish.toml.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct Options {
+        /// Order of the dependency groups, e.g.
+        /// `["workspace", "external", "path"]`; unlisted kinds follow in
+        /// their default order. Workspace-inherited dependencies only get a
+        /// group of their own when listed, else they count as external
+        pub order: Vec<DependencyKind>,
+        /// Sort within each blank-line-separated cluster instead of
+        /// regrouping the section, when a cluster starts with a header
+        /// comment like `# async stack`
+        pub keep_clusters: bool,
+        /// How the sorted dependencies are laid out, `"grouped"` or
+        /// `"letters"`
+        pub layout: Layout,
+    }
 
-        if trimmed == "[package]" {
-            in_package_section = true;
-            continue;
+    impl Default for Options {
+        fn default() -> Self {
+            Options {
+                order: DependencyKind::DEFAULT.to_vec(),
+                keep_clusters: false,
+                layout: Layout::default(),
+            }
         }
+    }
 
-        if in_package_section {
-            // Stop at next section
-            if trimmed.starts_with('[') {
-                break;
+    impl Options {
+        /// Every kind with a group once, listed ones first.
+        fn groups(&self) -> Vec<DependencyKind> {
+            let mut groups = Vec::new();
+            for kind in self.order.iter().chain(&DependencyKind::DEFAULT) {
+                if !groups.contains(kind) {
+                    groups.push(*kind);
+                }
             }
+            groups
+        }
 
-            // Split on = and check if we have exactly 2 parts
-            let parts: Vec<&str> = trimmed.split('=').collect();
-            if parts.len() == 2 {
-                let key = parts[0].trim();
-                if key == "name" {
-                    let value = parts[1].trim();
-                    // Remove quotes
-                    let name = value.trim_matches('"').trim_matches('\'');
-                    return Ok(name.to_string());
+        /// The group `dep` goes in.
+        fn kind(&self, dep: &str) -> DependencyKind {
+            match dependency_kind(dep) {
+                DependencyKind::Workspace if !self.order.contains(&DependencyKind::Workspace) => {
+                    DependencyKind::External
                 }
+                kind => kind,
             }
         }
     }
 
-    anyhow::bail!("Could not find package name in Cargo.toml")
-}
-
-fn run_cargo_fmt(git_root: &Path, members: &HashSet<String>) -> anyhow::Result<()> {
-    let mut cmd = Command::new("cargo");
-    cmd.arg("fmt");
-    // Add -p flag for each member
-    for member in members {
-        cmd.arg("-p").arg(member);
-    }
-    let status = cmd
-        .current_dir(git_root)
-        .status()
-        .context("Failed to run cargo fmt")?;
-    println!("Running {cmd:?}");
-    if !status.success() {
-        bail!("cargo fmt failed");
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DependencyKind {
+        /// From a registry or git: `serde = "1"`
+        External,
+        /// Inherited from the workspace: `serde = { workspace = true }`
+        Workspace,
+        /// A local crate: `core = { path = "../core" }`
+        Path,
     }
-    Ok(())
-}
 
-fn run_cargo_clippy(git_root: &Path, members: &HashSet<String>) -> anyhow::Result<()> {
-    let mut cmd = Command::new("cargo");
-    cmd.arg("clippy");
-    // Add -p flag for each member
-    for member in members {
-        cmd.arg("-p").arg(member);
+    /// How the sorted dependencies of a section are laid out.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Layout {
+        /// One block per dependency kind
+        #[default]
+        Grouped,
+        /// One block per dependency kind and first letter, so that two
+        /// branches adding dependencies rarely touch neighbouring lines
+        Letters,
     }
-    cmd.args(["--all-targets", "--", "-D", "warnings"]);
-    println!("Running {cmd:?}");
-    let status = cmd
-        .current_dir(git_root)
-        .status()
-        .context("Failed to run cargo clippy")?;
 
-    if !status.success() {
-        bail!("cargo clippy found warnings");
+    impl Layout {
+        /// Joins the sorted, non-empty `groups` (one per dependency kind, or
+        /// per cluster where clusters are kept) with blank lines.
+        fn lay_out(self, groups: Vec<Vec<String>>) -> Vec<String> {
+            let mut result = Vec::new();
+            for group in groups {
+                // First letter of the previous dependency in the group
+                let mut previous = None;
+                for dep in group {
+                    let letter = extract_dep_name(&dep).to_lowercase().chars().next();
+                    let new_block = match (self, previous) {
+                        (_, None) => true,
+                        (Layout::Grouped, Some(_)) => false,
+                        (Layout::Letters, Some(previous)) => previous != letter,
+                    };
+                    if new_block && !result.is_empty() {
+                        result.push(String::new()); // Blank line between blocks
+                    }
+                    previous = Some(letter);
+                    result.push(dep);
+                }
+            }
+            result
+        }
     }
 
-    Ok(())
-}
-
-mod toml_grouping {
-    use anyhow::Context;
-    use std::fs;
-    use std::path::Path;
+    impl DependencyKind {
+        /// The groups without a configured `order`; `Workspace` is opt-in.
+        pub const DEFAULT: [DependencyKind; 2] = [DependencyKind::External, DependencyKind::Path];
+    }
 
-    pub fn organize_dependencies(file_path: &Path) -> anyhow::Result<()> {
+    /// Organizes the manifest at `file_path`, with [`organize_changed`]
+    /// against `base` if given.
+    pub fn organize_dependencies(
+        file_path: &Path,
+        base: Option<&str>,
+        options: &Options,
+    ) -> anyhow::Result<()> {
         let content = fs::read_to_string(file_path)
             .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
 
-        let organized_content = organize_toml(&content)?;
+        let organized_content = match base {
+            Some(base) => organize_changed(&content, base, options),
+            None => organize_toml_with(&content, options),
+        }
+        .map_err(|e| e.in_file(file_path))?;
+        if organized_content == content {
+            return Ok(());
+        }
 
-        fs::write(file_path, organized_content)
+        super::interrupt::write(file_path, organized_content)
             .with_context(|| format!("Failed to write file: {}", file_path.display()))?;
 
         Ok(())
     }
 
-    fn organize_toml(content: &str) -> anyhow::Result<String> {
+    pub fn organize_toml(content: &str) -> Result<String, SourceError> {
+        organize_toml_with(content, &Options::default())
+    }
+
+    pub fn organize_toml_with(content: &str, options: &Options) -> Result<String, SourceError> {
         let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
         let mut result = Vec::new();
         let mut i = 0;
@@ -330,7 +14055,7 @@ mod toml_grouping {
                 let (deps, next_idx) = collect_dependencies(&lines, i);
 
                 // Organize and sort the dependencies
-                let organized = organize_dependency_group(&deps);
+                let organized = organize_dependency_group(&lines, i, &deps, options)?;
                 result.extend(organized);
 
                 i = next_idx;
@@ -343,6 +14068,123 @@ mod toml_grouping {
         Ok(result.join("\n") + "\n")
     }
 
+    /// Like [`organize_toml_with`], but for manifests that bots bump dependencies
+    /// in: entries unchanged since `base` stay where they are, byte for
+    /// byte, and only the changed or added ones are moved into place.
+    pub fn organize_changed(
+        content: &str,
+        base: &str,
+        options: &Options,
+    ) -> Result<String, SourceError> {
+        let base_entries = section_entries(base);
+        let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+        let mut result = Vec::new();
+        let mut i = 0;
+
+        while i < lines.len() {
+            let section = lines[i].trim();
+            result.push(lines[i].clone());
+            i += 1;
+            if section != "[dependencies]" && section != "[dev-dependencies]" {
+                continue;
+            }
+            let (deps, next_idx) = collect_dependencies(&lines, i);
+            let clusters = parse_clusters(&lines, i, &deps, options)?;
+            let unchanged = |dep: &str| {
+                base_entries.get(&(section.to_string(), extract_dep_name(dep)))
+                    == Some(&dep.to_string())
+            };
+            if clusters.iter().flatten().all(|(_, dep)| unchanged(dep)) {
+                result.extend(deps);
+            } else {
+                result.extend(reposition(clusters, unchanged, options));
+            }
+            i = next_idx;
+        }
+
+        Ok(result.join("\n") + "\n")
+    }
+
+    /// Entries of the dependency sections of `content` by section header
+    /// and dependency name. Sections that don't parse are left out.
+    fn section_entries(content: &str) -> HashMap<(String, String), String> {
+        let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+        let mut entries = HashMap::new();
+        for (i, line) in lines.iter().enumerate() {
+            let section = line.trim();
+            if section != "[dependencies]" && section != "[dev-dependencies]" {
+                continue;
+            }
+            let (deps, _) = collect_dependencies(&lines, i + 1);
+            let clusters =
+                parse_clusters(&lines, i + 1, &deps, &Options::default()).unwrap_or_default();
+            for (_, dep) in clusters.into_iter().flatten() {
+                entries.insert((section.to_string(), extract_dep_name(&dep)), dep);
+            }
+        }
+        entries
+    }
+
+    /// Takes the entries that aren't `unchanged` out of `clusters` and puts
+    /// each back after the last remaining entry of its kind that sorts
+    /// before it, else before the first one of its kind, else as a cluster
+    /// of its own where its kind goes in the configured order.
+    fn reposition(
+        mut clusters: Vec<Vec<(DependencyKind, String)>>,
+        unchanged: impl Fn(&str) -> bool,
+        options: &Options,
+    ) -> Vec<String> {
+        let mut moved = Vec::new();
+        for cluster in &mut clusters {
+            cluster.retain(|(kind, dep)| {
+                let keep = unchanged(dep);
+                if !keep {
+                    moved.push((*kind, dep.clone()));
+                }
+                keep
+            });
+        }
+        clusters.retain(|cluster| !cluster.is_empty());
+
+        let groups = options.groups();
+        let rank = |kind: DependencyKind| groups.iter().position(|k| *k == kind);
+        for (kind, dep) in moved {
+            let name = extract_dep_name(&dep).to_lowercase();
+            let same_kind: Vec<(usize, usize)> = clusters
+                .iter()
+                .enumerate()
+                .flat_map(|(c, cluster)| {
+                    cluster
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (k, _))| *k == kind)
+                        .map(move |(e, _)| (c, e))
+                })
+                .collect();
+            let at = same_kind
+                .iter()
+                .rfind(|&&(c, e)| extract_dep_name(&clusters[c][e].1).to_lowercase() < name)
+                .map(|&(c, e)| (c, e + 1))
+                .or_else(|| same_kind.first().copied());
+            match at {
+                Some((c, e)) => clusters[c].insert(e, (kind, dep)),
+                None => {
+                    let c = clusters
+                        .iter()
+                        .position(|cluster| cluster.iter().any(|(k, _)| rank(*k) > rank(kind)))
+                        .unwrap_or(clusters.len());
+                    clusters.insert(c, vec![(kind, dep)]);
+                }
+            }
+        }
+
+        let clusters = clusters
+            .into_iter()
+            .map(|cluster| cluster.into_iter().map(|(_, dep)| dep).collect())
+            .collect();
+        options.layout.lay_out(clusters)
+    }
+
     fn collect_dependencies(lines: &[String], start: usize) -> (Vec<String>, usize) {
         let mut deps = Vec::new();
         let mut i = start;
@@ -378,17 +14220,36 @@ mod toml_grouping {
         (deps, i)
     }
 
-    fn organize_dependency_group(deps: &[String]) -> Vec<String> {
-        let mut workspace_deps = Vec::new();
-        let mut external_deps = Vec::new();
-        let mut current_dep = Vec::new();
+    /// Splits `deps`, the lines of a dependency section starting at
+    /// `lines[start]`, into entries with their comments, by
+    /// blank-line-separated cluster.
+    fn parse_clusters(
+        lines: &[String],
+        start: usize,
+        deps: &[String],
+        options: &Options,
+    ) -> Result<Vec<Vec<(DependencyKind, String)>>, SourceError> {
+        // Dependencies by blank-line-separated cluster
+        let mut clusters: Vec<Vec<(DependencyKind, String)>> = vec![Vec::new()];
+        let mut current_dep: Vec<String> = Vec::new();
         let mut pending_comments = Vec::new();
         let mut is_multiline = false;
+        // Where the dependency being collected starts
+        let mut dep_start = start;
 
-        for line in deps {
+        for (offset, line) in deps.iter().enumerate() {
             let trimmed = line.trim();
 
             if trimmed.is_empty() {
+                if !is_multiline && !current_dep.is_empty() {
+                    let dep_text = current_dep.join("\n");
+                    clusters
+                        .last_mut()
+                        .unwrap()
+                        .push((options.kind(&dep_text), dep_text));
+                    current_dep.clear();
+                    clusters.push(Vec::new());
+                }
                 continue;
             }
 
@@ -398,25 +14259,45 @@ mod toml_grouping {
                 continue;
             }
 
+            // A dotted key for the dependency being collected, like the
+            // `serde.features` after `serde.version`, continues it
+            let continues_dotted = || {
+                let key = line_key(trimmed);
+                let previous = current_dep
+                    .iter()
+                    .rev()
+                    .find(|l| !l.trim().starts_with('#'))
+                    .and_then(|l| line_key(l.trim()));
+                matches!((key, previous), (Some(key), Some(previous))
+                    if key.len() > 1 && previous.len() > 1 && key[0] == previous[0])
+            };
+
             // Check if this starts a new dependency (has '=')
-            if !is_multiline && trimmed.contains('=') {
+            if !is_multiline && trimmed.contains('=') && !continues_dotted() {
                 // Finish previous dependency if any
                 if !current_dep.is_empty() {
                     let dep_text = current_dep.join("\n");
-                    if is_workspace_dep(&dep_text) {
-                        workspace_deps.push(dep_text);
-                    } else {
-                        external_deps.push(dep_text);
-                    }
+                    clusters
+                        .last_mut()
+                        .unwrap()
+                        .push((options.kind(&dep_text), dep_text));
                     current_dep.clear();
                 }
 
                 // Start new dependency with pending comments
                 current_dep.append(&mut pending_comments);
                 current_dep.push(line.clone());
+                dep_start = start + offset;
 
                 // Check if this is a multiline dependency (ends with { but no })
                 is_multiline = trimmed.contains('{') && !trimmed.contains('}');
+            } else if current_dep.is_empty() {
+                return Err(SourceError::new(
+                    lines,
+                    start + offset,
+                    "expected a dependency",
+                    "not a `name = ...` entry",
+                ));
             } else {
                 // Continuation of current dependency
                 current_dep.push(line.clone());
@@ -426,60 +14307,179 @@ mod toml_grouping {
             }
         }
 
+        if is_multiline {
+            return Err(SourceError::new(
+                lines,
+                dep_start,
+                "unterminated multi-line dependency",
+                "no closing `}` before the end of the section",
+            ));
+        }
+
         // Add last dependency
         if !current_dep.is_empty() {
             let dep_text = current_dep.join("\n");
-            if is_workspace_dep(&dep_text) {
-                workspace_deps.push(dep_text);
-            } else {
-                external_deps.push(dep_text);
-            }
+            clusters
+                .last_mut()
+                .unwrap()
+                .push((options.kind(&dep_text), dep_text));
         }
+        clusters.retain(|cluster| !cluster.is_empty());
+        Ok(clusters)
+    }
 
-        // Sort each group
-        workspace_deps.sort_by_key(|d| extract_dep_name(d).to_lowercase());
-        external_deps.sort_by_key(|d| extract_dep_name(d).to_lowercase());
-
-        // Combine groups with blank line separator - external deps first
-        let mut result = Vec::new();
-
-        for dep in &external_deps {
-            result.push(dep.clone());
+    /// Sorts `deps`, the lines of a dependency section starting at
+    /// `lines[start]`.
+    fn organize_dependency_group(
+        lines: &[String],
+        start: usize,
+        deps: &[String],
+        options: &Options,
+    ) -> Result<Vec<String>, SourceError> {
+        let clusters = parse_clusters(lines, start, deps, options)?;
+        let has_header = |cluster: &Vec<(DependencyKind, String)>| cluster[0].1.starts_with('#');
+        if options.keep_clusters && clusters.len() > 1 && clusters.iter().any(has_header) {
+            return Ok(sort_clusters(clusters, options.layout));
         }
+        let mut grouped: Vec<_> = clusters.into_iter().flatten().collect();
+
+        // Sort within each group
+        grouped.sort_by_key(|(_, d)| extract_dep_name(d).to_lowercase());
+
+        // Combine groups in the configured order
+        let groups = options
+            .groups()
+            .into_iter()
+            .map(|kind| {
+                grouped
+                    .iter()
+                    .filter(|(k, _)| *k == kind)
+                    .map(|(_, dep)| dep.clone())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| !group.is_empty())
+            .collect();
+
+        Ok(options.layout.lay_out(groups))
+    }
 
-        if !workspace_deps.is_empty() && !external_deps.is_empty() {
-            result.push(String::new()); // Blank line between groups
+    /// Sorts the dependencies of each cluster by name, keeping the clusters
+    /// and the comments heading them in place.
+    fn sort_clusters(clusters: Vec<Vec<(DependencyKind, String)>>, layout: Layout) -> Vec<String> {
+        let mut sorted = Vec::new();
+        for cluster in clusters {
+            let mut deps: Vec<String> = cluster.into_iter().map(|(_, dep)| dep).collect();
+            // The first dependency's comments head the whole cluster
+            let first = deps[0].clone();
+            let header: Vec<&str> = first
+                .lines()
+                .take_while(|line| line.trim_start().starts_with('#'))
+                .collect();
+            deps[0] = first
+                .lines()
+                .skip(header.len())
+                .collect::<Vec<_>>()
+                .join("\n");
+            deps.sort_by_key(|d| extract_dep_name(d).to_lowercase());
+            if !header.is_empty() {
+                deps[0] = format!("{}\n{}", header.join("\n"), deps[0]);
+            }
+            sorted.push(deps);
         }
+        layout.lay_out(sorted)
+    }
 
-        for dep in &workspace_deps {
-            result.push(dep.clone());
+    /// Whether `dep` has a `path`, inline (`foo = { path = "../foo" }`) or
+    /// dotted (`foo.path = "../foo"`), else whether it's inherited with
+    /// `workspace = true`.
+    fn dependency_kind(dep: &str) -> DependencyKind {
+        let (path, workspace) = match dep.parse::<toml::Table>() {
+            Ok(table) => {
+                let fields: Vec<_> = table.values().filter_map(toml::Value::as_table).collect();
+                (
+                    fields.iter().any(|t| t.contains_key("path")),
+                    fields
+                        .iter()
+                        .any(|t| t.get("workspace").and_then(toml::Value::as_bool) == Some(true)),
+                )
+            }
+            Err(_) => (
+                dep.contains("path =") || dep.contains("path="),
+                dep.contains("workspace = true") || dep.contains("workspace=true"),
+            ),
+        };
+        if path {
+            DependencyKind::Path
+        } else if workspace {
+            DependencyKind::Workspace
+        } else {
+            DependencyKind::External
         }
-
-        result
     }
 
-    fn is_workspace_dep(dep: &str) -> bool {
-        dep.contains("path =") || dep.contains("path=")
+    /// The name of the dependency, unquoted and without the rest of a
+    /// dotted key: `serde` for `serde.workspace = true`.
+    fn extract_dep_name(dep: &str) -> String {
+        dep.lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .find_map(line_key)
+            .and_then(|key| key.into_iter().next())
+            .unwrap_or_default()
     }
 
-    fn extract_dep_name(dep: &str) -> String {
-        // Extract dependency name from lines like: name = "version"
-        for line in dep.lines() {
-            let trimmed = line.trim();
-            if trimmed.starts_with('#') {
-                continue;
-            }
-            if let Some(eq_pos) = trimmed.find('=') {
-                return trimmed[..eq_pos].trim().to_string();
+    /// The parts of the key a `key = value` line starts with, unquoted:
+    /// `["serde", "workspace"]` for `serde . workspace = true`, `["a b"]`
+    /// for `"a b" = "1"`. `None` if the line doesn't start with a key.
+    pub fn line_key(line: &str) -> Option<Vec<String>> {
+        let mut parts = Vec::new();
+        let mut rest = line.trim_start();
+        loop {
+            let (part, after) = if let Some(quoted) = rest.strip_prefix('"') {
+                let mut part = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => part.push(chars.next()?.1),
+                        (i, '"') => break i,
+                        (_, c) => part.push(c),
+                    }
+                };
+                (part, &quoted[end + 1..])
+            } else if let Some(literal) = rest.strip_prefix('\'') {
+                let (part, after) = literal.split_once('\'')?;
+                (part.to_string(), after)
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                    .unwrap_or(rest.len());
+                if end == 0 {
+                    return None;
+                }
+                (rest[..end].to_string(), &rest[end..])
+            };
+            parts.push(part);
+            rest = after.trim_start();
+            match rest.chars().next()? {
+                '.' => rest = rest[1..].trim_start(),
+                '=' => return Some(parts),
+                _ => return None,
             }
         }
-        String::new()
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
 
+        #[test]
+        fn test_unterminated_dependency() {
+            let input = "[package]\nname = \"a\"\n\n[dependencies]\nserde = { version = \"1\",\n\n[features]\n";
+            let error = organize_toml(input).unwrap_err();
+            assert_eq!(error.line, 5);
+            assert_eq!(error.message, "unterminated multi-line dependency");
+        }
+
         #[test]
         fn test_basic_sorting() {
             let input = r#"[dependencies]
@@ -627,6 +14627,210 @@ my_crate = { path = "../my_crate" }
             assert_eq!(result, expected);
         }
 
+        #[test]
+        fn test_dotted_and_quoted_names() {
+            let input = r#"[dependencies]
+xpath = "1"
+"zeta" = "1"
+serde.workspace = true
+local.path = "../local"
+local.features = ["a"]
+anyhow . version = "1"
+'beta-crate' = { path = "../beta" }
+"#;
+
+            let expected = r#"[dependencies]
+anyhow . version = "1"
+serde.workspace = true
+xpath = "1"
+"zeta" = "1"
+
+'beta-crate' = { path = "../beta" }
+local.path = "../local"
+local.features = ["a"]
+"#;
+
+            let result = organize_toml(input).unwrap();
+            assert_eq!(result, expected);
+            assert_eq!(
+                line_key(r#""a.b" . 'c' = 1"#),
+                Some(vec!["a.b".to_string(), "c".to_string()])
+            );
+            assert_eq!(line_key("= 1"), None);
+        }
+
+        #[test]
+        fn test_workspace_inherited_deps() {
+            let input = r#"[dependencies]
+local = { path = "../local" }
+serde.workspace = true
+anyhow = "1"
+tokio = { workspace = true, features = ["rt"] }
+"#;
+
+            // Without `workspace` in `order` they are external ones
+            let expected = r#"[dependencies]
+anyhow = "1"
+serde.workspace = true
+tokio = { workspace = true, features = ["rt"] }
+
+local = { path = "../local" }
+"#;
+            assert_eq!(organize_toml(input).unwrap(), expected);
+
+            let options = Options {
+                order: vec![DependencyKind::Workspace, DependencyKind::Path],
+                ..Options::default()
+            };
+            let expected = r#"[dependencies]
+serde.workspace = true
+tokio = { workspace = true, features = ["rt"] }
+
+local = { path = "../local" }
+
+anyhow = "1"
+"#;
+            assert_eq!(organize_toml_with(input, &options).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_letters_layout() {
+            let input = r#"[dependencies]
+bytes = "1"
+anyhow = "1"
+local = { path = "../local" }
+arc-swap = "1"
+clap = "4"
+bitflags = "2"
+"#;
+
+            let expected = r#"[dependencies]
+anyhow = "1"
+arc-swap = "1"
+
+bitflags = "2"
+bytes = "1"
+
+clap = "4"
+
+local = { path = "../local" }
+"#;
+            let options = Options {
+                layout: Layout::Letters,
+                ..Options::default()
+            };
+            assert_eq!(organize_toml_with(input, &options).unwrap(), expected);
+            assert_eq!(organize_toml_with(expected, &options).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_keep_clusters() {
+            let input = r#"[dependencies]
+# async stack
+tokio = "1"
+# needed for streams
+futures = "0.3"
+
+local = { path = "../local" }
+anyhow = "1"
+"#;
+
+            let expected = r#"[dependencies]
+# async stack
+# needed for streams
+futures = "0.3"
+tokio = "1"
+
+anyhow = "1"
+local = { path = "../local" }
+"#;
+            let options = Options {
+                keep_clusters: true,
+                ..Options::default()
+            };
+            assert_eq!(organize_toml_with(input, &options).unwrap(), expected);
+            assert_eq!(organize_toml_with(expected, &options).unwrap(), expected);
+
+            // Without a header comment the section is regrouped as usual
+            let input = "[dependencies]\nb = \"1\"\n\nlocal = { path = \"../local\" }\na = \"1\"\n";
+            assert_eq!(
+                organize_toml_with(input, &options).unwrap(),
+                "[dependencies]\na = \"1\"\nb = \"1\"\n\nlocal = { path = \"../local\" }\n"
+            );
+        }
+
+        #[test]
+        fn test_letters_layout_of_kept_and_changed_clusters() {
+            let options = Options {
+                keep_clusters: true,
+                layout: Layout::Letters,
+                ..Options::default()
+            };
+            let input = "[dependencies]\n# async stack\ntokio = \"1\"\nfutures = \"0.3\"\nflume = \"0.11\"\n\nanyhow = \"1\"\n";
+            let expected = "[dependencies]\n# async stack\nflume = \"0.11\"\nfutures = \"0.3\"\n\ntokio = \"1\"\n\nanyhow = \"1\"\n";
+            assert_eq!(organize_toml_with(input, &options).unwrap(), expected);
+            assert_eq!(organize_toml_with(expected, &options).unwrap(), expected);
+
+            let options = Options {
+                layout: Layout::Letters,
+                ..Options::default()
+            };
+            let base = "[dependencies]\nanyhow = \"1\"\n\ntokio = \"1\"\n";
+            let input = "[dependencies]\nanyhow = \"1\"\n\ntokio = \"1\"\nclap = \"4\"\n";
+            assert_eq!(
+                organize_changed(input, base, &options).unwrap(),
+                "[dependencies]\nanyhow = \"1\"\n\nclap = \"4\"\n\ntokio = \"1\"\n"
+            );
+        }
+
+        #[test]
+        fn test_organize_changed() {
+            let base = r#"[dependencies]
+zstd = "0.13"
+anyhow = "1"
+tokio = { version = "1", features = ["full"] }
+
+local = { path = "../local" }
+"#;
+            // A bot bumped tokio and appended clap; the unsorted zstd/anyhow
+            // pair is left as it was
+            let input = r#"[dependencies]
+zstd = "0.13"
+anyhow = "1"
+tokio = { version = "1.38", features = ["full"] }
+
+local = { path = "../local" }
+clap = "4"
+"#;
+            let expected = r#"[dependencies]
+zstd = "0.13"
+anyhow = "1"
+clap = "4"
+tokio = { version = "1.38", features = ["full"] }
+
+local = { path = "../local" }
+"#;
+            let options = Options::default();
+            assert_eq!(organize_changed(input, base, &options).unwrap(), expected);
+            assert_eq!(organize_changed(base, base, &options).unwrap(), base);
+
+            // A new kind gets a cluster of its own, in the configured order
+            let options = Options {
+                order: vec![
+                    DependencyKind::External,
+                    DependencyKind::Workspace,
+                    DependencyKind::Path,
+                ],
+                ..Options::default()
+            };
+            let input = "[dependencies]\nb = \"1\"\n\nlocal = { path = \"../local\" }\nw = { workspace = true }\n";
+            let base = "[dependencies]\nb = \"1\"\n\nlocal = { path = \"../local\" }\n";
+            assert_eq!(
+                organize_changed(input, base, &options).unwrap(),
+                "[dependencies]\nb = \"1\"\n\nw = { workspace = true }\n\nlocal = { path = \"../local\" }\n"
+            );
+        }
+
         #[test]
         fn test_empty_braces_without_path() {
             let input = r#"[dependencies]
@@ -653,9 +14857,78 @@ my_local = { path = "../local" }
 }
 
 mod rust_grouping {
-    use anyhow::Context;
-    use std::fs;
-    use std::path::Path;
+    use super::source_error::SourceError;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    /// Grouping settings from the `[grouping]` section of polish.toml.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct Options {
+        pub reexports: ReexportPolicy,
+        pub imports: ImportPolicy,
+        /// Deepest inline module (`mod foo { ... }`) whose header is
+        /// grouped; 0 groups only the file's own header
+        pub max_depth: Option<usize>,
+        /// Keep `#[macro_use]` modules with the extern crates, above all
+        /// other modules. Set for 2015-edition packages, where macros are
+        /// only visible to modules declared after them.
+        #[serde(skip)]
+        pub pin_macro_use: bool,
+    }
+
+    /// Which glob imports (`use foo::*;`) are acceptable. `super::*` in
+    /// `#[cfg(test)]` modules and globs of `prelude` modules always are.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ImportPolicy {
+        pub globs: GlobImports,
+        /// Further paths whose globs are allowed, e.g. `"crate::ast"`
+        pub allowed: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum GlobImports {
+        #[default]
+        Allow,
+        /// Reported without failing the run
+        Warn,
+        /// Reported as a problem
+        Deny,
+    }
+
+    /// A policy violation found in a file.
+    #[derive(Debug, PartialEq)]
+    pub struct Violation {
+        /// 1-based line
+        pub line: usize,
+        pub message: String,
+        /// Whether the violation fails the run rather than only warning
+        pub deny: bool,
+    }
+
+    /// How `pub use` re-exports are ordered.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct ReexportPolicy {
+        /// Sort re-exports by path, with `#[doc(hidden)]` ones in a trailing
+        /// sub-group
+        pub sort: bool,
+        pub globs: GlobReexports,
+    }
+
+    /// Policy for glob re-exports (`pub use foo::*;`).
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum GlobReexports {
+        #[default]
+        Allow,
+        /// Placed after all other re-exports
+        Last,
+        /// Reported as a problem
+        Forbid,
+    }
 
     #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
     enum Visibility {
@@ -716,39 +14989,310 @@ mod rust_grouping {
         Pending,
     }
 
+    /// A header item: the comments, attributes and blank lines before it,
+    /// then the declaration. A comment after the declaration stays on its
+    /// line, so it moves with the item when the item changes groups.
     #[derive(Debug, Clone)]
     struct Item {
         lines: Vec<String>,
     }
 
-    pub fn group_file_declarations(file_path: &Path) -> anyhow::Result<()> {
-        let content = fs::read_to_string(file_path)
-            .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
-
-        let grouped_content = group_items(&content)?;
+    impl Item {
+        /// The declaration's lines without the leading lines and without
+        /// trailing comments, joined with spaces: what sorting and
+        /// classification should look at.
+        fn declaration(&self) -> String {
+            self.lines
+                .iter()
+                .map(|line| line.trim())
+                .skip_while(|line| matches!(classify_line(line), LineClassification::Pending))
+                .map(strip_comment)
+                .filter(|code| !code.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
 
-        fs::write(file_path, grouped_content)
-            .with_context(|| format!("Failed to write file: {}", file_path.display()))?;
+        /// Whether comments or attributes come before the declaration.
+        fn is_decorated(&self) -> bool {
+            // Check if any line before the actual item line is a comment or attribute
+            for line in &self.lines {
+                let trimmed = line.trim();
+                if trimmed.starts_with("//")
+                    || trimmed.starts_with("/*")
+                    || trimmed.starts_with('*')
+                    || trimmed.starts_with("#[")
+                {
+                    return true;
+                }
+                // Stop when we hit the actual item (not blank, not comment, not attribute)
+                if !trimmed.is_empty()
+                    && !trimmed.starts_with("//")
+                    && !trimmed.starts_with("/*")
+                    && !trimmed.starts_with('*')
+                    && !trimmed.starts_with("#[")
+                {
+                    break;
+                }
+            }
+            false
+        }
+    }
 
-        Ok(())
+    pub fn group_items(content: &str) -> Result<String, SourceError> {
+        group_items_with(content, &Options::default())
     }
 
-    pub fn group_items(content: &str) -> anyhow::Result<String> {
-        let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+    pub fn group_items_with(content: &str, options: &Options) -> Result<String, SourceError> {
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
         let mut result = String::new();
         let mut index = 0;
 
-        process_scope(&lines, &mut index, &mut result, 0)?;
+        process_scope(&mut lines, &mut index, &mut result, 0, false, options)?;
 
         Ok(result)
     }
 
+    /// The lines of `content` as grouping sees them: each header
+    /// declaration that shares a line with another on a line of its own.
+    /// Nothing outside the headers is split.
+    fn split_lines(content: &str, options: &Options) -> Vec<String> {
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        let _ = process_scope(&mut lines, &mut 0, &mut String::new(), 0, false, options);
+        lines
+    }
+
+    /// The hunks a second pass of [`group_items_with`] would change in its own
+    /// output `grouped`, if any. Grouping should always be a fixed point.
+    pub fn regrouping_diff(grouped: &str, options: &Options) -> Option<String> {
+        let regrouped = match group_items_with(grouped, options) {
+            Ok(regrouped) => regrouped,
+            Err(e) => return Some(e.to_string()),
+        };
+        (regrouped != grouped).then(|| super::diff::hunks(grouped, &regrouped))
+    }
+
+    /// The lines `grouped` has more or fewer times than `content`, one per
+    /// line with both counts, if any. Grouping only moves lines and adds or
+    /// removes blank ones, so anything here would be code lost or duplicated.
+    /// Blank lines and comments are left out and whitespace is normalized.
+    pub fn lost_lines(content: &str, grouped: &str, options: &Options) -> Option<String> {
+        fn counts(lines: impl IntoIterator<Item = impl AsRef<str>>) -> BTreeMap<String, usize> {
+            let mut counts = BTreeMap::new();
+            for line in lines {
+                let line = line.as_ref();
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with("//") {
+                    let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+                    *counts.entry(line).or_default() += 1;
+                }
+            }
+            counts
+        }
+
+        let before = counts(split_lines(content, options));
+        let after = counts(grouped.lines());
+        let mut out = String::new();
+        for line in before
+            .keys()
+            .chain(after.keys().filter(|l| !before.contains_key(*l)))
+        {
+            let (b, a) = (before.get(line), after.get(line));
+            if b != a {
+                out.push_str(&format!(
+                    "  `{line}`: {} -> {}\n",
+                    b.copied().unwrap_or(0),
+                    a.copied().unwrap_or(0)
+                ));
+            }
+        }
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// Policy violations in `content`: glob re-exports when they are
+    /// forbidden and glob imports not allowed by the import policy.
+    pub fn violations(content: &str, options: &Options) -> Vec<Violation> {
+        let mut found = Vec::new();
+        // Indentation of the enclosing `#[cfg(test)]` modules
+        let mut test_modules: Vec<usize> = Vec::new();
+        let mut cfg_test = false;
+        for (i, line) in content.lines().enumerate() {
+            let trimmed = strip_comment(line.trim())
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let trimmed = trimmed.as_str();
+            let indent = line.len() - line.trim_start().len();
+            if trimmed == "}" && test_modules.last() == Some(&indent) {
+                test_modules.pop();
+            }
+            if trimmed.starts_with("#[cfg(test)]") {
+                cfg_test = true;
+                continue;
+            }
+            if cfg_test && trimmed.contains("mod ") && trimmed.ends_with('{') {
+                test_modules.push(indent);
+            }
+            if !trimmed.starts_with("#[") && !trimmed.is_empty() {
+                cfg_test = false;
+            }
+
+            if trimmed.starts_with("pub use ") {
+                if options.reexports.globs == GlobReexports::Forbid && trimmed.ends_with("::*;") {
+                    found.push(Violation {
+                        line: i + 1,
+                        message: format!("glob re-export `{trimmed}` is not allowed"),
+                        deny: true,
+                    });
+                }
+                continue;
+            }
+
+            let policy = &options.imports;
+            if policy.globs == GlobImports::Allow || !is_use(trimmed) {
+                continue;
+            }
+            for path in glob_paths(trimmed) {
+                let in_tests = !test_modules.is_empty();
+                if !glob_allowed(&path, in_tests, &policy.allowed) {
+                    found.push(Violation {
+                        line: i + 1,
+                        message: format!("glob import `{path}::*` is not allowed"),
+                        deny: policy.globs == GlobImports::Deny,
+                    });
+                }
+            }
+        }
+        found
+    }
+
+    fn is_use(trimmed: &str) -> bool {
+        after_visibility(&tokens(trimmed)).is_some_and(|rest| rest.first() == Some(&"use"))
+    }
+
+    /// Paths glob-imported by a single-line `use`, e.g. `a::b` and `a::c`
+    /// for `use a::{b::*, c::*};`.
+    fn glob_paths(use_line: &str) -> Vec<String> {
+        let tree = use_line
+            .split_once("use ")
+            .map_or("", |(_, tree)| tree)
+            .trim_end_matches(';')
+            .trim();
+        let mut paths = Vec::new();
+        collect_glob_paths("", tree, &mut paths);
+        paths
+    }
+
+    fn collect_glob_paths(base: &str, tree: &str, paths: &mut Vec<String>) {
+        let join = |path: &str| match (base.is_empty(), path.is_empty()) {
+            (true, _) => path.to_string(),
+            (false, true) => base.to_string(),
+            (false, false) => format!("{base}::{path}"),
+        };
+        if let Some(open) = tree.find('{') {
+            let prefix = join(tree[..open].trim().trim_end_matches("::"));
+            let inner = tree[open + 1..].trim_end().trim_end_matches('}');
+            let mut depth = 0;
+            let mut start = 0;
+            for (i, c) in inner.char_indices() {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    ',' if depth == 0 => {
+                        collect_glob_paths(&prefix, inner[start..i].trim(), paths);
+                        start = i + 1;
+                    }
+                    _ => {}
+                }
+            }
+            collect_glob_paths(&prefix, inner[start..].trim(), paths);
+        } else if tree == "*" {
+            paths.push(base.to_string());
+        } else if let Some(path) = tree.strip_suffix("::*") {
+            paths.push(join(path));
+        }
+    }
+
+    fn glob_allowed(path: &str, in_tests: bool, allowed: &[String]) -> bool {
+        (in_tests && path == "super")
+            || path == "prelude"
+            || path.ends_with("::prelude")
+            || allowed
+                .iter()
+                .any(|prefix| path == prefix || path.starts_with(&format!("{prefix}::")))
+    }
+
+    /// The statements of a header line that starts with a declaration and holds
+    /// more than one, like `mod a; mod b;`, each with the line's indentation.
+    /// A trailing comment stays with the last one. `None` for other lines.
+    fn split_statements(line: &str) -> Option<Vec<String>> {
+        let trimmed = line.trim_start();
+        if !matches!(
+            classify_line(trimmed.trim_end()),
+            LineClassification::Item(LineType::Declaration(_) | LineType::ExternCrate)
+        ) {
+            return None;
+        }
+        let indent = &line[..line.len() - trimmed.len()];
+        let code = strip_comment(trimmed);
+        let comment = trimmed[code.len()..].trim();
+
+        let mut statements = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        let mut chars = code.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                ';' if depth == 0 => {
+                    statements.push(code[start..=i].trim());
+                    start = i + 1;
+                }
+                '"' => {
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let rest = code[start..].trim();
+        if !rest.is_empty() {
+            statements.push(rest);
+        }
+        if statements.len() < 2 {
+            return None;
+        }
+        let last = statements.len() - 1;
+        Some(
+            statements
+                .iter()
+                .enumerate()
+                .map(|(i, statement)| match comment {
+                    "" => format!("{indent}{statement}"),
+                    _ if i < last => format!("{indent}{statement}"),
+                    _ => format!("{indent}{statement} {comment}"),
+                })
+                .collect(),
+        )
+    }
+
+    /// Groups the header of the scope starting at `index`; `test_module` is
+    /// whether it's the body of a `#[cfg(test)]` module.
     fn process_scope(
-        lines: &[String],
+        lines: &mut Vec<String>,
         index: &mut usize,
         result: &mut String,
         indent_level: usize,
-    ) -> anyhow::Result<()> {
+        test_module: bool,
+        options: &Options,
+    ) -> Result<(), SourceError> {
         // Handle global attributes at the very beginning of the file
         // (expect, warn, recursion_limit, feature)
         if indent_level == 0 && *index < lines.len() {
@@ -779,6 +15323,8 @@ mod rust_grouping {
 
         let mut features = Vec::new();
         let mut extern_crates = Vec::new();
+        let mut macro_use_mods = Vec::new();
+        let mut super_glob = None;
         let mut declarations: std::collections::BTreeMap<
             Visibility,
             std::collections::BTreeMap<DeclarationKind, Vec<Item>>,
@@ -819,6 +15365,15 @@ mod rust_grouping {
                         continue;
                     }
 
+                    // Each declaration sharing a line with another gets a
+                    // line of its own, so it's grouped by itself
+                    if matches!(item_type, LineType::Declaration(_) | LineType::ExternCrate) {
+                        if let Some(statements) = split_statements(line) {
+                            lines.splice(*index..=*index, statements);
+                            continue;
+                        }
+                    }
+
                     // Check if this is first non-global-attribute (transition point)
                     let is_global_attr = matches!(item_type, LineType::GlobalAttribute(_));
                     if !features_done && !is_global_attr {
@@ -875,12 +15430,17 @@ mod rust_grouping {
                     match item_type {
                         LineType::OtherCode => {
                             // Flush all groups when transitioning out of header
+                            if let Some(item) = super_glob.take() {
+                                pin_super_glob(item, &mut macro_use_mods, &mut declarations);
+                            }
                             flush_groups(
                                 result,
                                 &features,
                                 &post_features_lines,
                                 &extern_crates,
+                                &macro_use_mods,
                                 &declarations,
+                                options,
                             );
                             in_header = false;
 
@@ -894,12 +15454,17 @@ mod rust_grouping {
                             if has_mod_block(&item_lines) =>
                         {
                             // Mod block with body - flush groups and process recursively
+                            if let Some(item) = super_glob.take() {
+                                pin_super_glob(item, &mut macro_use_mods, &mut declarations);
+                            }
                             flush_groups(
                                 result,
                                 &features,
                                 &post_features_lines,
                                 &extern_crates,
+                                &macro_use_mods,
                                 &declarations,
+                                options,
                             );
                             in_header = false;
 
@@ -913,12 +15478,26 @@ mod rust_grouping {
                                 result.push('\n');
                             }
 
-                            process_scope(lines, index, result, indent_level + 1)?;
-
-                            if *index < lines.len() {
-                                result.push_str(&lines[*index]);
-                                result.push('\n');
-                                *index += 1;
+                            // Past the depth limit the body is left as written:
+                            // outside the header every line is copied verbatim
+                            if options.max_depth.is_none_or(|max| indent_level < max) {
+                                let test_module = item_lines
+                                    .iter()
+                                    .any(|line| line.trim().starts_with("#[cfg(test)]"));
+                                process_scope(
+                                    lines,
+                                    index,
+                                    result,
+                                    indent_level + 1,
+                                    test_module,
+                                    options,
+                                )?;
+
+                                if *index < lines.len() {
+                                    result.push_str(&lines[*index]);
+                                    result.push('\n');
+                                    *index += 1;
+                                }
                             }
                         }
                         LineType::GlobalAttribute(_) => {
@@ -929,6 +15508,21 @@ mod rust_grouping {
                             extern_crates.push(Item { lines: item_lines });
                             has_items = true;
                         }
+                        LineType::Declaration(Declaration::Mod(_))
+                            if options.pin_macro_use && has_macro_use(&item_lines) =>
+                        {
+                            macro_use_mods.push(Item { lines: item_lines });
+                            has_items = true;
+                        }
+                        LineType::Declaration(_)
+                            if test_module
+                                && super_glob.is_none()
+                                && strip_comment(item_lines.last().map_or("", |l| l.trim()))
+                                    == "use super::*;" =>
+                        {
+                            super_glob = Some(Item { lines: item_lines });
+                            has_items = true;
+                        }
                         LineType::Declaration(ref decl) => {
                             let kind = decl.kind();
                             let visibility = decl.visibility();
@@ -947,12 +15541,17 @@ mod rust_grouping {
 
         // If we finished in header mode, flush groups
         if in_header {
+            if let Some(item) = super_glob.take() {
+                pin_super_glob(item, &mut macro_use_mods, &mut declarations);
+            }
             flush_groups(
                 result,
                 &features,
                 &post_features_lines,
                 &extern_crates,
+                &macro_use_mods,
                 &declarations,
+                options,
             );
 
             // Output any remaining pending lines (e.g., comments-only file)
@@ -965,6 +15564,40 @@ mod rust_grouping {
         Ok(())
     }
 
+    /// Puts the `use super::*;` of a test module first among its
+    /// declarations, whatever their visibility: at the front of the private
+    /// `use` group when that group is output first and nothing in it is
+    /// decorated, else in a group of its own before the others.
+    fn pin_super_glob(
+        item: Item,
+        pinned: &mut Vec<Item>,
+        declarations: &mut std::collections::BTreeMap<
+            Visibility,
+            std::collections::BTreeMap<DeclarationKind, Vec<Item>>,
+        >,
+    ) {
+        let first = declarations
+            .iter_mut()
+            .next()
+            .and_then(|(visibility, kinds)| {
+                let (kind, items) = kinds.iter_mut().next()?;
+                (*visibility == Visibility::Private && *kind == DeclarationKind::Use)
+                    .then_some(items)
+            });
+        match first {
+            Some(items) if !item.is_decorated() && !items.iter().any(Item::is_decorated) => {
+                items.insert(0, item)
+            }
+            _ => pinned.push(item),
+        }
+    }
+
+    fn has_macro_use(lines: &[String]) -> bool {
+        lines
+            .iter()
+            .any(|line| line.trim().starts_with("#[macro_use"))
+    }
+
     fn has_mod_block(lines: &[String]) -> bool {
         for line in lines {
             let trimmed = line.trim();
@@ -983,36 +15616,13 @@ mod rust_grouping {
         features: &[Item],
         post_features_lines: &[String],
         extern_crates: &[Item],
+        macro_use_mods: &[Item],
         declarations: &std::collections::BTreeMap<
             Visibility,
             std::collections::BTreeMap<DeclarationKind, Vec<Item>>,
         >,
+        options: &Options,
     ) {
-        // Helper to check if an item is decorated (has comments or attributes)
-        fn is_decorated(item: &Item) -> bool {
-            // Check if any line before the actual item line is a comment or attribute
-            for line in &item.lines {
-                let trimmed = line.trim();
-                if trimmed.starts_with("//")
-                    || trimmed.starts_with("/*")
-                    || trimmed.starts_with('*')
-                    || trimmed.starts_with("#[")
-                {
-                    return true;
-                }
-                // Stop when we hit the actual item (not blank, not comment, not attribute)
-                if !trimmed.is_empty()
-                    && !trimmed.starts_with("//")
-                    && !trimmed.starts_with("/*")
-                    && !trimmed.starts_with('*')
-                    && !trimmed.starts_with("#[")
-                {
-                    break;
-                }
-            }
-            false
-        }
-
         // Helper to output a group with decorated items first, then regular items
         fn output_group(result: &mut String, items: &[Item], first_group: &mut bool) {
             if items.is_empty() {
@@ -1023,7 +15633,7 @@ mod rust_grouping {
             let mut regular = Vec::new();
 
             for item in items {
-                if is_decorated(item) {
+                if item.is_decorated() {
                     decorated.push(item);
                 } else {
                     regular.push(item);
@@ -1073,58 +15683,156 @@ mod rust_grouping {
                     result.push('\n');
                 }
             }
-            first_group = false;
+            first_group = false;
+        }
+
+        // Post-features comments/lines go after features but before other groups
+        if !post_features_lines.is_empty() {
+            if !first_group {
+                result.push('\n');
+            }
+            for line in post_features_lines {
+                result.push_str(line);
+                result.push('\n');
+            }
+            first_group = false;
+        }
+
+        // Extern crates always come first (after features/post_features_lines)
+        if !extern_crates.is_empty() {
+            output_group(result, extern_crates, &mut first_group);
+        }
+        output_group(result, macro_use_mods, &mut first_group);
+
+        // Output declarations in BTreeMap order (automatically sorted)
+        // Outer map: different Visibility (Pub, PubCrate, PubSuper, PubIn, Private)
+        // Inner map: different DeclarationKind within same visibility (Mod, Use)
+        for (visibility, kind_map) in declarations {
+            // Output each declaration kind within this visibility level
+            for (kind, items) in kind_map {
+                if *visibility == Visibility::Pub && *kind == DeclarationKind::Use {
+                    output_reexports(result, items, &options.reexports, &mut first_group);
+                } else {
+                    output_group(result, items, &mut first_group);
+                }
+            }
+        }
+
+        // `pub use` items ordered by the re-export policy: sorted items
+        // first, then globs, then `#[doc(hidden)]` ones, each sub-group
+        // separated by a blank line
+        fn output_reexports(
+            result: &mut String,
+            items: &[Item],
+            policy: &ReexportPolicy,
+            first_group: &mut bool,
+        ) {
+            let mut main: Vec<Item> = Vec::new();
+            let mut globs = Vec::new();
+            let mut hidden = Vec::new();
+            for item in items {
+                if policy.sort && is_doc_hidden(item) {
+                    hidden.push(item.clone());
+                } else if policy.globs == GlobReexports::Last && is_glob(item) {
+                    globs.push(item.clone());
+                } else {
+                    main.push(item.clone());
+                }
+            }
+            if policy.sort {
+                for group in [&mut main, &mut globs, &mut hidden] {
+                    group.sort_by_cached_key(use_path);
+                }
+            }
+
+            output_group(result, &main, first_group);
+            for group in [globs, hidden] {
+                if group.is_empty() {
+                    continue;
+                }
+                if !*first_group {
+                    result.push('\n');
+                }
+                *first_group = false;
+                for item in &group {
+                    for line in item.lines.iter().skip_while(|l| l.trim().is_empty()) {
+                        result.push_str(line);
+                        result.push('\n');
+                    }
+                }
+            }
         }
 
-        // Post-features comments/lines go after features but before other groups
-        if !post_features_lines.is_empty() {
-            if !first_group {
-                result.push('\n');
-            }
-            for line in post_features_lines {
-                result.push_str(line);
-                result.push('\n');
-            }
-            first_group = false;
+        fn use_path(item: &Item) -> String {
+            let declaration = item.declaration();
+            declaration
+                .strip_prefix("pub use ")
+                .unwrap_or(&declaration)
+                .to_string()
         }
 
-        // Extern crates always come first (after features/post_features_lines)
-        if !extern_crates.is_empty() {
-            output_group(result, extern_crates, &mut first_group);
+        fn is_glob(item: &Item) -> bool {
+            item.declaration().ends_with("::*;")
         }
 
-        // Output declarations in BTreeMap order (automatically sorted)
-        // Outer map: different Visibility (Pub, PubCrate, PubSuper, PubIn, Private)
-        // Inner map: different DeclarationKind within same visibility (Mod, Use)
-        for kind_map in declarations.values() {
-            // Output each declaration kind within this visibility level
-            for items in kind_map.values() {
-                output_group(result, items, &mut first_group);
-            }
+        fn is_doc_hidden(item: &Item) -> bool {
+            item.lines
+                .iter()
+                .any(|l| l.trim().replace(' ', "") == "#[doc(hidden)]")
         }
     }
 
-    fn parse_visibility(decl_start: &str) -> Visibility {
-        if decl_start.starts_with("pub(crate)") {
-            Visibility::PubCrate
-        } else if decl_start.starts_with("pub(super)") {
-            Visibility::PubSuper
-        } else if decl_start.starts_with("pub(in ") {
-            // Extract path from pub(in path)
-            if let Some(end) = decl_start.find(')') {
-                let path = &decl_start[7..end]; // Skip "pub(in "
-                Visibility::PubIn(path.trim().to_string())
-            } else {
-                Visibility::Pub // Fallback
-            }
-        } else if decl_start.starts_with("pub(") {
-            // Other pub(...) variants we don't recognize
-            Visibility::Pub
-        } else if decl_start.starts_with("pub ") {
-            Visibility::Pub
-        } else {
-            Visibility::Private
+    /// The tokens of `code`, enough to tell what a line declares:
+    /// identifiers and keywords (raw ones like `r#async` included), `::`,
+    /// and any other character on its own. Whitespace, tabs included, only
+    /// separates them.
+    pub fn tokens(code: &str) -> Vec<&str> {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let ident_len = |s: &str| s.find(|c| !is_ident(c)).unwrap_or(s.len());
+        let mut tokens = Vec::new();
+        let mut rest = code.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let len = match rest.strip_prefix("r#") {
+                Some(raw) if raw.starts_with(is_ident) => 2 + ident_len(raw),
+                _ if is_ident(c) => ident_len(rest),
+                _ if rest.starts_with("::") => 2,
+                _ => c.len_utf8(),
+            };
+            tokens.push(&rest[..len]);
+            rest = rest[len..].trim_start();
         }
+        tokens
+    }
+
+    /// The visibility `tokens` start with and the tokens after it. `None`
+    /// for a `pub(...)` restriction that isn't valid Rust.
+    fn split_visibility<'a>(tokens: &'a [&'a str]) -> Option<(Visibility, &'a [&'a str])> {
+        let (visibility, len) = match tokens {
+            ["pub", "(", "crate", ")", ..] => (Visibility::PubCrate, 4),
+            ["pub", "(", "super", ")", ..] => (Visibility::PubSuper, 4),
+            ["pub", "(", "self", ")", ..] => (Visibility::Private, 4),
+            ["pub", "(", "in", path @ ..] => {
+                let end = path.iter().position(|t| *t == ")")?;
+                if end == 0 {
+                    return None;
+                }
+                (Visibility::PubIn(path[..end].concat()), 4 + end)
+            }
+            ["pub", "(", ..] => return None,
+            ["pub", ..] => (Visibility::Pub, 1),
+            _ => (Visibility::Private, 0),
+        };
+        Some((visibility, &tokens[len..]))
+    }
+
+    /// The tokens after the visibility `tokens` start with, if it's valid.
+    pub fn after_visibility<'a>(tokens: &'a [&'a str]) -> Option<&'a [&'a str]> {
+        split_visibility(tokens).map(|(_, rest)| rest)
+    }
+
+    /// `None` for a `pub(...)` restriction that isn't valid Rust.
+    fn parse_visibility(decl_start: &str) -> Option<Visibility> {
+        split_visibility(&tokens(decl_start)).map(|(visibility, _)| visibility)
     }
 
     fn classify_line(trimmed: &str) -> LineClassification {
@@ -1142,65 +15850,168 @@ mod rust_grouping {
             return LineClassification::Pending;
         }
 
-        // Global attributes
-        if trimmed.starts_with("#![feature(") {
+        // Global attributes, however they're spaced
+        let tokens = tokens(trimmed);
+        if tokens.starts_with(&["#", "!", "[", "feature", "("]) {
             return LineClassification::Item(LineType::GlobalAttribute(GlobalAttribute::Feature));
         }
 
-        if trimmed.starts_with("#![expect(") {
+        if tokens.starts_with(&["#", "!", "[", "expect", "("]) {
             return LineClassification::Item(LineType::GlobalAttribute(GlobalAttribute::Expect));
         }
 
-        if trimmed.starts_with("#![warn(") {
+        if tokens.starts_with(&["#", "!", "[", "warn", "("]) {
             return LineClassification::Item(LineType::GlobalAttribute(GlobalAttribute::Warn));
         }
 
-        if trimmed.starts_with("#![recursion_limit") {
+        if tokens.starts_with(&["#", "!", "[", "recursion_limit"]) {
             return LineClassification::Item(LineType::GlobalAttribute(
                 GlobalAttribute::RecursionLimit,
             ));
         }
 
-        // Declarations
-        if trimmed.starts_with("extern crate ") {
-            return LineClassification::Item(LineType::ExternCrate);
+        // Macro invocations and `macro_rules!` definitions are code, even
+        // when their body mentions `use` or `mod`
+        if is_macro_invocation(trimmed) {
+            return LineClassification::Item(LineType::OtherCode);
         }
 
-        // Check for use statements
-        if trimmed.contains(" use ") || trimmed.starts_with("use ") {
-            let visibility = parse_visibility(trimmed);
-            return LineClassification::Item(LineType::Declaration(Declaration::Use(visibility)));
-        }
+        // Declarations, after their visibility. One with a restriction that
+        // isn't valid is rejected when the item is collected
+        let (visibility, rest) = match split_visibility(&tokens) {
+            Some((visibility, rest)) => (visibility, rest),
+            None => {
+                let keyword = tokens.iter().position(|t| matches!(*t, "use" | "mod"));
+                (Visibility::Pub, keyword.map_or(&[][..], |k| &tokens[k..]))
+            }
+        };
+        let declaration = match rest {
+            ["extern", "crate", ..] => return LineClassification::Item(LineType::ExternCrate),
+            ["use", ..] => Declaration::Use(visibility),
+            ["mod", ..] => Declaration::Mod(visibility),
+            _ => return LineClassification::Item(LineType::OtherCode),
+        };
+        LineClassification::Item(LineType::Declaration(declaration))
+    }
 
-        // Check for mod declarations
-        if trimmed.contains(" mod ") || trimmed.starts_with("mod ") {
-            let visibility = parse_visibility(trimmed);
-            return LineClassification::Item(LineType::Declaration(Declaration::Mod(visibility)));
-        }
+    /// `name!`, `path::to::name!` or `macro_rules!` at the start of a line,
+    /// followed by a delimiter, whitespace or the macro's name.
+    fn is_macro_invocation(trimmed: &str) -> bool {
+        let Some(bang) = trimmed.find('!') else {
+            return false;
+        };
+        let path = &trimmed[..bang];
+        let after = trimmed[bang + 1..].chars().next();
+        !path.is_empty()
+            && !path.starts_with("::")
+            && path.split("::").all(|segment| {
+                !segment.is_empty() && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+            })
+            && !path.starts_with(|c: char| c.is_ascii_digit())
+            && after.is_none_or(|c| c.is_whitespace() || matches!(c, '(' | '[' | '{'))
+    }
 
-        LineClassification::Item(LineType::OtherCode)
+    /// Net change in `(`/`[`/`{` nesting over `line`, ignoring string and
+    /// char literals and line comments.
+    fn delimiter_balance(line: &str) -> i32 {
+        let mut balance = 0;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '(' | '[' | '{' => balance += 1,
+                ')' | ']' | '}' => balance -= 1,
+                '/' if chars.peek() == Some(&'/') => break,
+                '"' => {
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                // Char literals like '{' (but not lifetimes like 'a)
+                '\'' => {
+                    let mut lookahead = chars.clone();
+                    let literal_len = match lookahead.next() {
+                        Some('\\') => {
+                            lookahead.next();
+                            lookahead.position(|c| c == '\'').map(|n| n + 2)
+                        }
+                        Some(_) if lookahead.next() == Some('\'') => Some(2),
+                        _ => None,
+                    };
+                    for _ in 0..literal_len.unwrap_or(0) {
+                        chars.next();
+                    }
+                }
+                _ => {}
+            }
+        }
+        balance
     }
 
+    /// `s` up to a line comment, which may follow the code on the same line.
+    /// `//` inside a string literal, like a URL in an attribute, isn't one.
     fn strip_comment(s: &str) -> &str {
-        s.split_once("//")
-            .map(|(before, _)| before)
-            .unwrap_or(s)
-            .trim()
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '/' if chars.peek().is_some_and(|&(_, next)| next == '/') => {
+                    return s[..i].trim();
+                }
+                '"' => {
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        s.trim()
     }
 
     fn collect_complete_item(
         lines: &[String],
         start_index: usize,
         item_type: &LineType,
-    ) -> anyhow::Result<(Vec<String>, usize)> {
+    ) -> Result<(Vec<String>, usize), SourceError> {
         let mut result: Vec<String> = Vec::new();
         let mut index = start_index;
+        let unterminated = |what: &str, label: &str| {
+            SourceError::new(
+                lines,
+                start_index,
+                format!("unterminated {what}"),
+                format!("{label} before the end of the file"),
+            )
+        };
+        if let LineType::Declaration(_) = item_type {
+            if parse_visibility(lines[start_index].trim()).is_none() {
+                return Err(SourceError::new(
+                    lines,
+                    start_index,
+                    "could not determine the visibility of this declaration",
+                    "expected `pub`, `pub(crate)`, `pub(super)`, `pub(self)` or `pub(in path)`",
+                ));
+            }
+        }
 
         match item_type {
             LineType::Declaration(Declaration::Mod(_)) => {
                 // For mod blocks, we only collect until the opening brace or semicolon
-                while index < lines.len() {
-                    let line = lines[index].clone();
+                loop {
+                    let Some(line) = lines.get(index) else {
+                        return Err(unterminated("`mod` declaration", "no `;` or `{`"));
+                    };
                     result.push(line.clone());
                     index += 1;
 
@@ -1212,8 +16023,10 @@ mod rust_grouping {
             }
             LineType::GlobalAttribute(_) => {
                 // Global attributes are complete on a single line ending with ']'
-                while index < lines.len() {
-                    let line = lines[index].clone();
+                loop {
+                    let Some(line) = lines.get(index) else {
+                        return Err(unterminated("crate attribute", "no closing `]`"));
+                    };
                     result.push(line.clone());
                     index += 1;
 
@@ -1223,10 +16036,40 @@ mod rust_grouping {
                     }
                 }
             }
+            LineType::OtherCode if is_macro_invocation(lines[start_index].trim()) => {
+                // Macro invocations end where their delimiters balance out
+                let mut depth = 0;
+                let mut opened = false;
+                loop {
+                    let Some(line) = lines.get(index) else {
+                        return Err(unterminated("macro invocation", "unbalanced delimiters"));
+                    };
+                    result.push(line.clone());
+                    index += 1;
+
+                    let balance = delimiter_balance(line);
+                    opened |= balance != 0 || line.contains(['(', '[', '{']);
+                    depth += balance;
+                    if opened && depth <= 0 {
+                        break;
+                    }
+                }
+            }
             _ => {
                 // For other items (use, pub use, pub(crate) use, extern crate), collect until semicolon
-                while index < lines.len() {
-                    let line = lines[index].clone();
+                // Other code only ends the header, so it may run to the end
+                let what = match item_type {
+                    LineType::ExternCrate => Some("`extern crate` declaration"),
+                    LineType::Declaration(_) => Some("`use` declaration"),
+                    _ => None,
+                };
+                loop {
+                    let Some(line) = lines.get(index) else {
+                        match what {
+                            Some(what) => return Err(unterminated(what, "no `;`")),
+                            None => break,
+                        }
+                    };
                     result.push(line.clone());
                     index += 1;
 
@@ -1268,6 +16111,29 @@ use foo::bar;
             assert_eq!(result, expected);
         }
 
+        #[test]
+        fn test_regrouping_diff() {
+            let options = Options::default();
+            let grouped = group_items_with("use b;\nmod a;\n", &options).unwrap();
+            assert_eq!(regrouping_diff(&grouped, &options), None);
+
+            // Output that a second pass would still change is reported
+            let hunks = regrouping_diff("use b;\nmod a;\n", &options).unwrap();
+            assert!(hunks.starts_with("@@ "));
+            assert!(hunks.contains("-use b;"));
+        }
+
+        #[test]
+        fn test_lost_lines() {
+            let content = "use a;\n// note\nmod  b;\n";
+            let options = Options::default();
+            assert_eq!(lost_lines(content, "mod b;\n\nuse a;\n", &options), None);
+            assert_eq!(
+                lost_lines(content, "mod b;\nmod b;\n", &options),
+                Some("  `mod b;`: 1 -> 2\n  `use a;`: 1 -> 0\n".to_string())
+            );
+        }
+
         #[test]
         fn test_features_first() {
             let input = r#"use std::fs;
@@ -1348,6 +16214,23 @@ use std::fs;
             assert_eq!(result, expected);
         }
 
+        #[test]
+        fn test_unparsable_declarations() {
+            let error = group_items("mod a;\nuse foo::{\n    bar,\n").unwrap_err();
+            assert_eq!(error.line, 2);
+            assert_eq!(error.message, "unterminated `use` declaration");
+
+            let error = group_items("pub(crate mod a;\n").unwrap_err();
+            assert_eq!(
+                error.message,
+                "could not determine the visibility of this declaration"
+            );
+            assert_eq!(
+                group_items("mod a;\npub(self) mod b;\n").unwrap(),
+                "mod a;\npub(self) mod b;\n"
+            );
+        }
+
         #[test]
         fn test_stops_at_other_code() {
             let input = r#"use std::fs;
@@ -2182,6 +17065,447 @@ mod test2;
             assert_eq!(result, expected);
         }
 
+        #[test]
+        fn test_reexport_policy_sorts_and_trails_hidden() {
+            let input = r#"pub use crate::b::B;
+#[doc(hidden)]
+pub use crate::internal::Hidden;
+pub use crate::a::*;
+pub use crate::a::A;
+
+fn main() {}
+"#;
+            let options = Options {
+                reexports: ReexportPolicy {
+                    sort: true,
+                    globs: GlobReexports::Last,
+                },
+                ..Options::default()
+            };
+            let expected = r#"pub use crate::a::A;
+pub use crate::b::B;
+
+pub use crate::a::*;
+
+#[doc(hidden)]
+pub use crate::internal::Hidden;
+
+fn main() {}
+"#;
+            assert_eq!(group_items_with(input, &options).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_cfg_if_with_uses() {
+            let input = r#"use std::fs;
+pub use crate::api::Api;
+
+// Platform specific imports
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+    } else {
+        use std::os::windows::fs::MetadataExt;
+    }
+}
+
+use std::io;
+"#;
+            let expected = r#"pub use crate::api::Api;
+
+use std::fs;
+
+// Platform specific imports
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+    } else {
+        use std::os::windows::fs::MetadataExt;
+    }
+}
+
+use std::io;
+"#;
+            assert_eq!(group_items(input).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_single_line_macro_mentioning_use() {
+            let input = r#"use b;
+#[macro_export]
+macro_rules! import { ($x:ident) => { use $x; } }
+use a;
+"#;
+            assert_eq!(group_items(input).unwrap(), input);
+        }
+
+        #[test]
+        fn test_macro_in_nested_module() {
+            let input = r#"mod inner {
+    use b;
+    pub use a;
+
+    lazy_static::lazy_static! {
+        static ref NAMES: Vec<&'static str> = vec!["}", "{"];
+    }
+
+    fn f() {}
+}
+
+fn g() {}
+"#;
+            let expected = r#"mod inner {
+    pub use a;
+
+    use b;
+
+    lazy_static::lazy_static! {
+        static ref NAMES: Vec<&'static str> = vec!["}", "{"];
+    }
+
+    fn f() {}
+}
+
+fn g() {}
+"#;
+            assert_eq!(group_items(input).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_is_macro_invocation() {
+            assert!(is_macro_invocation("lazy_static! {"));
+            assert!(is_macro_invocation("cfg_if::cfg_if! {"));
+            assert!(is_macro_invocation("macro_rules! foo {"));
+            assert!(is_macro_invocation("thread_local!(static X: u8 = 0);"));
+            assert!(!is_macro_invocation("use foo::bar;"));
+            assert!(!is_macro_invocation("fn not() -> bool { !x }"));
+            assert!(!is_macro_invocation("#![feature(test)]"));
+            assert_eq!(delimiter_balance("vec!['{', \"(\", x[0]"), 1);
+            assert_eq!(delimiter_balance("fn f<'a>(x: &'a str) { // {"), 1);
+        }
+
+        #[test]
+        fn test_macro_use_extern_crate_stays_above_mods() {
+            let input = r#"pub mod api;
+mod util;
+#[macro_use]
+extern crate log;
+"#;
+            let expected = r#"#[macro_use]
+extern crate log;
+
+pub mod api;
+
+mod util;
+"#;
+            assert_eq!(group_items(input).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_pin_macro_use_mods() {
+            let input = r#"pub mod api;
+mod util;
+#[macro_use]
+mod macros;
+#[macro_use]
+extern crate log;
+"#;
+            let pinned = Options {
+                pin_macro_use: true,
+                ..Options::default()
+            };
+            let expected = r#"#[macro_use]
+extern crate log;
+
+#[macro_use]
+mod macros;
+
+pub mod api;
+
+mod util;
+"#;
+            assert_eq!(group_items_with(input, &pinned).unwrap(), expected);
+
+            let unpinned = group_items(input).unwrap();
+            assert!(unpinned.find("mod macros;") > unpinned.find("pub mod api;"));
+        }
+
+        #[test]
+        fn test_max_depth() {
+            let input = r#"use b;
+use a;
+pub mod a {
+    use y;
+    pub use x;
+    mod inner {
+        use q;
+        pub use p;
+    }
+}
+"#;
+            let top_level_only = Options {
+                max_depth: Some(0),
+                ..Options::default()
+            };
+            let grouped = group_items_with(input, &top_level_only).unwrap();
+            assert!(grouped.contains("    use y;\n    pub use x;\n"));
+            assert!(grouped.contains("        use q;\n        pub use p;\n"));
+
+            let one_level = Options {
+                max_depth: Some(1),
+                ..Options::default()
+            };
+            let grouped = group_items_with(input, &one_level).unwrap();
+            assert!(grouped.contains("    pub use x;\n\n    use y;\n"));
+            assert!(grouped.contains("        use q;\n        pub use p;\n"));
+
+            let grouped = group_items(input).unwrap();
+            assert!(grouped.contains("        pub use p;\n\n        use q;\n"));
+        }
+
+        #[test]
+        fn test_trailing_comments() {
+            let input = r#"#![expect(dead_code, reason = "see https://example.com")]
+pub use crate::b::B; // the main type
+pub use crate::a::*; // everything else
+use std::fs; // for reading
+mod a;
+
+fn main() {}
+"#;
+            let options = Options {
+                reexports: ReexportPolicy {
+                    sort: true,
+                    globs: GlobReexports::Last,
+                },
+                ..Options::default()
+            };
+            let expected = r#"#![expect(dead_code, reason = "see https://example.com")]
+pub use crate::b::B; // the main type
+
+pub use crate::a::*; // everything else
+
+mod a;
+
+use std::fs; // for reading
+
+fn main() {}
+"#;
+            assert_eq!(group_items_with(input, &options).unwrap(), expected);
+
+            let options = Options {
+                imports: ImportPolicy {
+                    globs: GlobImports::Deny,
+                    allowed: Vec::new(),
+                },
+                ..Options::default()
+            };
+            let found = violations("use crate::*; // all of it\n", &options);
+            assert_eq!(found.len(), 1);
+        }
+
+        #[test]
+        fn test_tokens() {
+            assert_eq!(
+                tokens("pub(in crate::a)\tuse r#async::r#impl;"),
+                [
+                    "pub", "(", "in", "crate", "::", "a", ")", "use", "r#async", "::", "r#impl",
+                    ";"
+                ]
+            );
+            assert_eq!(
+                parse_visibility("pub (crate) mod a;"),
+                Some(Visibility::PubCrate)
+            );
+            assert_eq!(parse_visibility("pub\tuse a;"), Some(Visibility::Pub));
+            assert_eq!(parse_visibility("pub(in ) use a;"), None);
+        }
+
+        #[test]
+        fn test_raw_identifiers_and_tabs() {
+            let input = "use r#async::runtime;\nmod r#impl;\npub\tuse\tfoo::Bar;\n\tpub(crate)\tmod\tinner;\nlet x = 1; // use y\n";
+            let expected = "pub\tuse\tfoo::Bar;\n\n\tpub(crate)\tmod\tinner;\n\nmod r#impl;\n\nuse r#async::runtime;\nlet x = 1; // use y\n";
+            assert_eq!(group_items(input).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_declarations_sharing_a_line() {
+            let input = r#"use std::fs; pub use crate::a::A;
+mod b; pub mod c; // both
+extern crate foo; use std::{io, fmt};
+
+fn main() { let a = 1; let b = 2; }
+"#;
+            let expected = r#"extern crate foo;
+
+pub mod c; // both
+
+pub use crate::a::A;
+
+mod b;
+
+use std::fs;
+use std::{io, fmt};
+
+fn main() { let a = 1; let b = 2; }
+"#;
+            let grouped = group_items(input).unwrap();
+            assert_eq!(grouped, expected);
+            assert_eq!(lost_lines(input, &grouped, &Options::default()), None);
+        }
+
+        #[test]
+        fn test_only_header_lines_are_split() {
+            let input = r#"use std::fs; mod a;
+
+pub const S: &str = "
+mod x; mod y;
+";
+
+fn f() {
+    mod z; use w;
+}
+"#;
+            let expected = r#"mod a;
+
+use std::fs;
+
+pub const S: &str = "
+mod x; mod y;
+";
+
+fn f() {
+    mod z; use w;
+}
+"#;
+            let grouped = group_items(input).unwrap();
+            assert_eq!(grouped, expected);
+            assert_eq!(lost_lines(input, &grouped, &Options::default()), None);
+
+            // Splitting a line outside the header is caught as lost lines
+            let split = expected.replace("mod x; mod y;", "mod x;\nmod y;");
+            assert!(lost_lines(input, &split, &Options::default()).is_some());
+        }
+
+        #[test]
+        fn test_super_glob_first_in_test_modules() {
+            let input = r#"use std::fs;
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    pub(crate) use crate::x::Y;
+    use std::fs;
+    use super::*;
+    mod helpers;
+
+    fn f() {}
+}
+"#;
+            let expected = r#"use std::fs;
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) use crate::x::Y;
+
+    mod helpers;
+
+    use std::fs;
+
+    fn f() {}
+}
+"#;
+            assert_eq!(group_items(input).unwrap(), expected);
+
+            // Joins the private uses when they come first
+            let input = "#[cfg(test)]\nmod tests {\n    use std::fs;\n    use super::*;\n}\n";
+            let expected = "#[cfg(test)]\nmod tests {\n    use super::*;\n    use std::fs;\n}\n";
+            assert_eq!(group_items(input).unwrap(), expected);
+        }
+
+        #[test]
+        fn test_reexport_globs_forbidden() {
+            let options = Options {
+                reexports: ReexportPolicy {
+                    sort: false,
+                    globs: GlobReexports::Forbid,
+                },
+                ..Options::default()
+            };
+            let content = "pub use crate::a::A;\npub use crate::prelude::*;\nuse super::*;\n";
+            assert_eq!(
+                violations(content, &options),
+                [Violation {
+                    line: 2,
+                    message: "glob re-export `pub use crate::prelude::*;` is not allowed"
+                        .to_string(),
+                    deny: true,
+                }]
+            );
+            assert!(violations(content, &Options::default()).is_empty());
+        }
+
+        #[test]
+        fn test_glob_imports_denied() {
+            let options = Options {
+                imports: ImportPolicy {
+                    globs: GlobImports::Deny,
+                    allowed: vec!["crate::ast".to_string()],
+                },
+                ..Options::default()
+            };
+            let content = r#"use crate::*;
+use std::io::prelude::*;
+use crate::ast::nodes::*;
+pub(crate) use crate::{parser::*, lexer::Token};
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::*;
+}
+"#;
+            let found: Vec<(usize, String, bool)> = violations(content, &options)
+                .into_iter()
+                .map(|v| (v.line, v.message, v.deny))
+                .collect();
+            assert_eq!(
+                found,
+                [
+                    (1, "glob import `crate::*` is not allowed".to_string(), true),
+                    (
+                        4,
+                        "glob import `crate::parser::*` is not allowed".to_string(),
+                        true
+                    ),
+                    (5, "glob import `super::*` is not allowed".to_string(), true),
+                    (
+                        10,
+                        "glob import `crate::util::*` is not allowed".to_string(),
+                        true
+                    ),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_glob_imports_warned() {
+            let options = Options {
+                imports: ImportPolicy {
+                    globs: GlobImports::Warn,
+                    allowed: Vec::new(),
+                },
+                ..Options::default()
+            };
+            let found = violations("use crate::*;\n", &options);
+            assert_eq!(found.len(), 1);
+            assert!(!found[0].deny);
+        }
+
         #[test]
         fn test_pub_use_after_pub_mod() {
             let input = r#"#![feature(never_type)]